@@ -0,0 +1,72 @@
+use std::io::Error;
+
+use crate::metrics::CountedResult;
+use crate::state::State;
+use crate::utils::*;
+
+/// Something done to the game process for as long as it is minimized because the player is afk.
+pub trait AfkAction {
+    fn name(&self) -> &'static str;
+    fn engage(&mut self, process_id: u32) -> Result<(), Error>;
+    /// Undoes `engage`. Also called when the game has exited in the meantime.
+    fn release(&mut self) -> Result<(), Error>;
+}
+
+/// Engages all configured actions when entering `State::Minimized` and releases them when leaving it.
+pub struct AfkActions {
+    actions: Vec<Box<dyn AfkAction>>,
+    engaged: bool,
+    /// released early, stays released until the game leaves `State::Minimized`
+    interrupted: bool,
+}
+
+impl AfkActions {
+    pub fn new(actions: Vec<Box<dyn AfkAction>>) -> Self {
+        AfkActions { actions, engaged: false, interrupted: false }
+    }
+
+    pub fn update(&mut self, state: State, process_id: Option<u32>) {
+        if state != State::Minimized {
+            self.interrupted = false;
+        }
+        if state == State::Minimized && !self.engaged && !self.interrupted {
+            let process_id = match process_id {
+                Some(process_id) => process_id,
+                None => return,
+            };
+            self.engaged = true;
+            for action in self.actions.iter_mut() {
+                info!("engaging {} for process {}", action.name(), process_id);
+                action.engage(process_id)
+                    .count_backend_error()
+                    .log_error_and_ignore(&format!("failed to engage {}", action.name()));
+            }
+        } else if state != State::Minimized && self.engaged {
+            self.release();
+        }
+    }
+
+    /// Releases all actions right away, e.g. so the game is responsive when a buyer shows up.
+    pub fn interrupt(&mut self) {
+        if self.engaged {
+            self.interrupted = true;
+            self.release();
+        }
+    }
+
+    fn release(&mut self) {
+        self.engaged = false;
+        for action in self.actions.iter_mut() {
+            info!("releasing {}", action.name());
+            action.release()
+                .count_backend_error()
+                .log_error_and_ignore(&format!("failed to release {}", action.name()));
+        }
+    }
+}
+
+impl Drop for AfkActions {
+    fn drop(&mut self) {
+        self.update(State::GameNotRunning, None);
+    }
+}
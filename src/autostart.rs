@@ -0,0 +1,459 @@
+use std::io::Error;
+use std::path::PathBuf;
+
+/// Whether poe-minimizer is started on login.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AutostartStatus {
+    Disabled,
+    Enabled,
+    /// there is an entry, but it starts another executable, e.g. because poe-minimizer was moved
+    Stale(PathBuf),
+}
+
+/// One way to start poe-minimizer on login.
+pub trait Autostart {
+    fn name(&self) -> &'static str;
+    fn status(&self) -> Result<AutostartStatus, Error>;
+    /// Adds an entry for the current executable, replacing an existing one.
+    fn enable(&self) -> Result<(), Error>;
+    fn disable(&self) -> Result<(), Error>;
+
+    /// Points a stale entry at the current executable, returns whether it was stale.
+    fn repair(&self) -> Result<bool, Error> {
+        match self.status()? {
+            AutostartStatus::Stale(previous) => {
+                info!("{} autostart started {:?}, pointing it at this executable", self.name(), previous);
+                self.enable().map(|()| true)
+            }
+            _ => Ok(false),
+        }
+    }
+}
+
+/// The `Run` key of the registry, `backend` is ignored.
+#[cfg(windows)]
+pub fn system_autostart(_backend: &str) -> Option<Box<dyn Autostart + Send + Sync>> {
+    let executable = std::env::current_exe().ok()?;
+    Some(Box::new(registry::RegistryAutostart::new(executable)))
+}
+
+/// `xdg` for an autostart desktop entry, `systemd` for a systemd user unit. Both go to `$XDG_CONFIG_HOME`.
+#[cfg(target_os = "linux")]
+pub fn system_autostart(backend: &str) -> Option<Box<dyn Autostart + Send + Sync>> {
+    let executable = std::env::current_exe().ok()?;
+    // also in portable mode, that is where desktops and systemd look
+    let config_home = crate::paths::base_directory(crate::paths::Directory::Config)?;
+    match backend {
+        "systemd" => Some(Box::new(systemd::SystemdAutostart::new(config_home, executable))),
+        "xdg" => Some(Box::new(xdg::XdgAutostart::new(config_home, executable))),
+        _ => {
+            warn!("unknown autostart backend {:?}, using xdg", backend);
+            Some(Box::new(xdg::XdgAutostart::new(config_home, executable)))
+        }
+    }
+}
+
+/// The first argument of a command line where it may be in double quotes with backslash escapes, like in desktop
+/// entries and systemd units.
+#[cfg(target_os = "linux")]
+fn first_argument(command: &str) -> Option<String> {
+    match command.strip_prefix('"') {
+        Some(quoted) => {
+            let mut argument = String::new();
+            let mut chars = quoted.chars();
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' => argument.push(chars.next()?),
+                    '"' => return Some(argument),
+                    c => argument.push(c),
+                }
+            }
+            None
+        }
+        None => command.split_whitespace().next().map(str::to_owned),
+    }
+}
+
+#[cfg(windows)]
+mod registry {
+    use std::io::Error;
+    use std::path::PathBuf;
+
+    use super::{Autostart, AutostartStatus};
+    use crate::winutils;
+
+    pub struct RegistryAutostart {
+        executable: PathBuf,
+    }
+
+    impl RegistryAutostart {
+        pub fn new(executable: PathBuf) -> Self {
+            RegistryAutostart { executable }
+        }
+    }
+
+    impl Autostart for RegistryAutostart {
+        fn name(&self) -> &'static str {
+            "registry"
+        }
+
+        fn status(&self) -> Result<AutostartStatus, Error> {
+            let command = match winutils::read_registry_string(winutils::AUTOSTART_KEY, winutils::AUTOSTART_VALUE)? {
+                Some(command) => command,
+                None => return Ok(AutostartStatus::Disabled),
+            };
+            let started = PathBuf::from(command.trim().trim_matches('"'));
+            // paths are case insensitive
+            if started.to_string_lossy().eq_ignore_ascii_case(&self.executable.to_string_lossy()) {
+                Ok(AutostartStatus::Enabled)
+            } else {
+                Ok(AutostartStatus::Stale(started))
+            }
+        }
+
+        fn enable(&self) -> Result<(), Error> {
+            winutils::add_to_autostart(&self.executable)
+        }
+
+        fn disable(&self) -> Result<(), Error> {
+            winutils::remove_from_autostart()
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod xdg {
+    use std::fs;
+    use std::io::{Error, ErrorKind};
+    use std::path::{Path, PathBuf};
+
+    use super::{Autostart, AutostartStatus};
+    use crate::ini::Ini;
+
+    const DESKTOP_ENTRY: &str = "Desktop Entry";
+
+    /// `~/.config/autostart/poe-minimizer.desktop`, started by every desktop that follows the autostart spec.
+    pub struct XdgAutostart {
+        path: PathBuf,
+        executable: PathBuf,
+    }
+
+    impl XdgAutostart {
+        pub fn new(config_home: PathBuf, executable: PathBuf) -> Self {
+            XdgAutostart { path: config_home.join("autostart").join("poe-minimizer.desktop"), executable }
+        }
+    }
+
+    impl Autostart for XdgAutostart {
+        fn name(&self) -> &'static str {
+            "xdg"
+        }
+
+        fn status(&self) -> Result<AutostartStatus, Error> {
+            let entry = match Ini::load(&self.path) {
+                Ok(entry) => entry,
+                Err(e) if e.kind() == ErrorKind::NotFound => return Ok(AutostartStatus::Disabled),
+                Err(e) => return Err(e),
+            };
+            // what the autostart settings of gnome and kde write when it's switched off there
+            let switched_off = entry.get_bool(DESKTOP_ENTRY, "Hidden") == Some(true)
+                || entry.get_bool(DESKTOP_ENTRY, "X-GNOME-Autostart-enabled") == Some(false);
+            if switched_off {
+                return Ok(AutostartStatus::Disabled);
+            }
+            let started = entry.get(DESKTOP_ENTRY, "Exec").and_then(first_argument).map(PathBuf::from).unwrap_or_default();
+            if started == self.executable {
+                Ok(AutostartStatus::Enabled)
+            } else {
+                Ok(AutostartStatus::Stale(started))
+            }
+        }
+
+        fn enable(&self) -> Result<(), Error> {
+            if let Some(directory) = self.path.parent() {
+                fs::create_dir_all(directory)?;
+            }
+            fs::write(&self.path, desktop_entry(&self.executable))
+        }
+
+        fn disable(&self) -> Result<(), Error> {
+            match fs::remove_file(&self.path) {
+                Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+                _ => Ok(()),
+            }
+        }
+    }
+
+    fn desktop_entry(executable: &Path) -> String {
+        format!(
+            "[Desktop Entry]\n\
+             Type=Application\n\
+             Name=poe-minimizer\n\
+             Comment=Minimizes Path of Exile while you are AFK\n\
+             Exec={}\n\
+             Terminal=false\n\
+             X-GNOME-Autostart-enabled=true\n",
+            quote(&executable.to_string_lossy()))
+    }
+
+    /// Quoting of the desktop entry spec, a backslash has to be escaped twice because string values are unescaped
+    /// before the quotes are removed.
+    fn quote(argument: &str) -> String {
+        let mut quoted = String::from("\"");
+        for c in argument.chars() {
+            match c {
+                '\\' => quoted.push_str("\\\\\\\\"),
+                '"' | '`' | '$' => {
+                    quoted.push('\\');
+                    quoted.push(c);
+                }
+                '%' => quoted.push_str("%%"),
+                c => quoted.push(c),
+            }
+        }
+        quoted.push('"');
+        quoted
+    }
+
+    /// The executable of an `Exec` line, reverting `quote`.
+    fn first_argument(exec: &str) -> Option<String> {
+        super::first_argument(&exec.replace("\\\\", "\\").replace("%%", "%"))
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod systemd {
+    use std::fs;
+    use std::io::{Error, ErrorKind};
+    use std::os::unix::fs::symlink;
+    use std::path::{Path, PathBuf};
+    use std::process::{Command, Stdio};
+
+    use super::{Autostart, AutostartStatus};
+    use crate::ini::Ini;
+
+    const UNIT_NAME: &str = "poe-minimizer.service";
+    const TARGET: &str = "graphical-session.target";
+
+    /// A systemd user unit, which also delegates a cgroup subtree for `[cgroup]` throttling. Enabled by linking it into
+    /// `graphical-session.target.wants` like `systemctl --user enable` does.
+    pub struct SystemdAutostart {
+        unit: PathBuf,
+        wants: PathBuf,
+        executable: PathBuf,
+    }
+
+    impl SystemdAutostart {
+        pub fn new(config_home: PathBuf, executable: PathBuf) -> Self {
+            let units = config_home.join("systemd").join("user");
+            SystemdAutostart {
+                unit: units.join(UNIT_NAME),
+                wants: units.join(format!("{}.wants", TARGET)).join(UNIT_NAME),
+                executable,
+            }
+        }
+    }
+
+    impl Autostart for SystemdAutostart {
+        fn name(&self) -> &'static str {
+            "systemd"
+        }
+
+        fn status(&self) -> Result<AutostartStatus, Error> {
+            let unit = match Ini::load(&self.unit) {
+                Ok(unit) => unit,
+                Err(e) if e.kind() == ErrorKind::NotFound => return Ok(AutostartStatus::Disabled),
+                Err(e) => return Err(e),
+            };
+            if fs::symlink_metadata(&self.wants).is_err() {
+                return Ok(AutostartStatus::Disabled);
+            }
+            let started = unit.get("Service", "ExecStart").and_then(first_argument).map(PathBuf::from).unwrap_or_default();
+            if started == self.executable {
+                Ok(AutostartStatus::Enabled)
+            } else {
+                Ok(AutostartStatus::Stale(started))
+            }
+        }
+
+        fn enable(&self) -> Result<(), Error> {
+            if let Some(directory) = self.wants.parent() {
+                fs::create_dir_all(directory)?;
+            }
+            fs::write(&self.unit, unit(&self.executable))?;
+            if fs::symlink_metadata(&self.wants).is_err() {
+                symlink(&self.unit, &self.wants)?;
+            }
+            reload();
+            Ok(())
+        }
+
+        fn disable(&self) -> Result<(), Error> {
+            for path in &[&self.wants, &self.unit] {
+                match fs::remove_file(path) {
+                    Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+                    _ => {}
+                }
+            }
+            reload();
+            Ok(())
+        }
+    }
+
+    fn unit(executable: &Path) -> String {
+        format!(
+            "[Unit]\n\
+             Description=poe-minimizer, minimizes Path of Exile while you are AFK\n\
+             PartOf={target}\n\
+             After={target}\n\
+             \n\
+             [Service]\n\
+             ExecStart={}\n\
+             Restart=on-failure\n\
+             # lets poe-minimizer throttle the game in cgroups of its own, it starts in the {service} leaf of them\n\
+             Delegate=yes\n\
+             DelegateSubgroup={service}\n\
+             # the throttled game lives in a cgroup below this unit, stopping or restarting poe-minimizer must not kill\n\
+             # it. poe-minimizer moves it back where it came from before it exits.\n\
+             KillMode=process\n\
+             \n\
+             [Install]\n\
+             WantedBy={target}\n",
+            quote(&executable.to_string_lossy()),
+            target = TARGET,
+            service = crate::cgroup::SERVICE_CGROUP)
+    }
+
+    /// Quoting of systemd command lines, which also expand `%` specifiers and `$` variables.
+    fn quote(argument: &str) -> String {
+        let mut quoted = String::from("\"");
+        for c in argument.chars() {
+            match c {
+                '\\' | '"' => {
+                    quoted.push('\\');
+                    quoted.push(c);
+                }
+                '%' => quoted.push_str("%%"),
+                '$' => quoted.push_str("$$"),
+                c => quoted.push(c),
+            }
+        }
+        quoted.push('"');
+        quoted
+    }
+
+    /// The executable of an `ExecStart` line, reverting `quote`.
+    fn first_argument(exec: &str) -> Option<String> {
+        super::first_argument(&exec.replace("%%", "%").replace("$$", "$"))
+    }
+
+    /// Lets a running user manager pick up the changed unit, next login it is read anyway.
+    fn reload() {
+        let _ = Command::new("systemctl").args(["--user", "daemon-reload"])
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status();
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+
+    use super::systemd::SystemdAutostart;
+    use super::xdg::XdgAutostart;
+    use super::{Autostart, AutostartStatus};
+    use crate::utils::test_directory;
+
+    /// everything the quoting of desktop entries and systemd units has to get right
+    const AWKWARD_EXECUTABLE: &str = "/opt/my games/100%/$HOME/back\\slash \"quoted\"/poe-minimizer";
+
+    fn check_enable_and_disable(autostart: &dyn Autostart) {
+        assert_eq!(autostart.status().unwrap(), AutostartStatus::Disabled);
+        autostart.enable().unwrap();
+        assert_eq!(autostart.status().unwrap(), AutostartStatus::Enabled);
+        // enabling twice replaces the entry
+        autostart.enable().unwrap();
+        assert_eq!(autostart.status().unwrap(), AutostartStatus::Enabled);
+        autostart.disable().unwrap();
+        assert_eq!(autostart.status().unwrap(), AutostartStatus::Disabled);
+        // nothing to remove is fine
+        autostart.disable().unwrap();
+    }
+
+    fn check_repair(moved_from: &dyn Autostart, moved_to: &dyn Autostart) {
+        assert!(!moved_to.repair().unwrap());
+        moved_from.enable().unwrap();
+        assert_eq!(moved_to.status().unwrap(), AutostartStatus::Stale(PathBuf::from(AWKWARD_EXECUTABLE)));
+        assert!(moved_to.repair().unwrap());
+        assert_eq!(moved_to.status().unwrap(), AutostartStatus::Enabled);
+        assert!(!moved_to.repair().unwrap());
+    }
+
+    #[test]
+    fn xdg_enable_and_disable() {
+        let config_home = test_directory("xdg-enable");
+        check_enable_and_disable(&XdgAutostart::new(config_home.clone(), PathBuf::from("/usr/bin/poe-minimizer")));
+        check_enable_and_disable(&XdgAutostart::new(config_home, PathBuf::from(AWKWARD_EXECUTABLE)));
+    }
+
+    #[test]
+    fn xdg_repairs_stale_entries() {
+        let config_home = test_directory("xdg-repair");
+        check_repair(
+            &XdgAutostart::new(config_home.clone(), PathBuf::from(AWKWARD_EXECUTABLE)),
+            &XdgAutostart::new(config_home, PathBuf::from("/usr/bin/poe-minimizer")));
+    }
+
+    #[test]
+    fn xdg_entries_switched_off_by_the_desktop_are_disabled() {
+        let config_home = test_directory("xdg-switched-off");
+        let path = config_home.join("autostart").join("poe-minimizer.desktop");
+        let autostart = XdgAutostart::new(config_home, PathBuf::from("/usr/bin/poe-minimizer"));
+        autostart.enable().unwrap();
+        let entry = fs::read_to_string(&path).unwrap();
+        fs::write(&path, entry.replace("X-GNOME-Autostart-enabled=true", "X-GNOME-Autostart-enabled=false")).unwrap();
+        assert_eq!(autostart.status().unwrap(), AutostartStatus::Disabled);
+        fs::write(&path, format!("{}Hidden=true\n", entry)).unwrap();
+        assert_eq!(autostart.status().unwrap(), AutostartStatus::Disabled);
+        // a hidden entry is not stale, repairing it would switch autostart on again
+        assert!(!autostart.repair().unwrap());
+    }
+
+    #[test]
+    fn systemd_enable_and_disable() {
+        let config_home = test_directory("systemd-enable");
+        check_enable_and_disable(&SystemdAutostart::new(config_home.clone(), PathBuf::from("/usr/bin/poe-minimizer")));
+        check_enable_and_disable(&SystemdAutostart::new(config_home, PathBuf::from(AWKWARD_EXECUTABLE)));
+    }
+
+    #[test]
+    fn systemd_repairs_stale_units() {
+        let config_home = test_directory("systemd-repair");
+        check_repair(
+            &SystemdAutostart::new(config_home.clone(), PathBuf::from(AWKWARD_EXECUTABLE)),
+            &SystemdAutostart::new(config_home, PathBuf::from("/usr/bin/poe-minimizer")));
+    }
+
+    #[test]
+    fn systemd_units_without_the_wants_link_are_disabled() {
+        let config_home = test_directory("systemd-wants");
+        let units = config_home.join("systemd").join("user");
+        let autostart = SystemdAutostart::new(config_home, PathBuf::from("/usr/bin/poe-minimizer"));
+        autostart.enable().unwrap();
+        fs::remove_file(units.join("graphical-session.target.wants").join("poe-minimizer.service")).unwrap();
+        assert_eq!(autostart.status().unwrap(), AutostartStatus::Disabled);
+    }
+
+    #[test]
+    fn systemd_unit_keeps_the_game_alive() {
+        let config_home = test_directory("systemd-unit");
+        let autostart = SystemdAutostart::new(config_home.clone(), PathBuf::from("/usr/bin/poe-minimizer"));
+        autostart.enable().unwrap();
+        let unit = fs::read_to_string(config_home.join("systemd").join("user").join("poe-minimizer.service")).unwrap();
+        assert!(unit.lines().any(|line| line == "KillMode=process"));
+        assert!(unit.lines().any(|line| line == "DelegateSubgroup=service"));
+    }
+}
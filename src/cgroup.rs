@@ -0,0 +1,327 @@
+use std::ffi::CString;
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+use crate::afk_action::AfkAction;
+
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+const PROC_ROOT: &str = "/proc";
+/// leaf our own process is moved to, cgroup v2 forbids processes in cgroups that distribute controllers
+pub const SERVICE_CGROUP: &str = "service";
+const AFK_CGROUP: &str = "afk";
+const CPU_PERIOD_US: u64 = 100_000;
+/// systemd (since 251) marks the cgroups it delegates with one of these, `cpu` in `cgroup.controllers` alone
+/// doesn't mean the cgroup is ours, e.g. the scope of an app started from the desktop
+const DELEGATE_XATTRS: [&str; 2] = ["trusted.delegate", "user.delegate"];
+
+/// Throttles the game with a cgroup v2 `cpu.max` quota while afk.
+///
+/// This needs a cgroup subtree delegated to us, e.g. by running as a systemd user service with
+/// `Delegate=yes`. Our own cgroup becomes the root of the subtree: we move ourselves into a
+/// `service` leaf, enable the cpu controller and put the game into an `afk` leaf next to it.
+/// On release the game is moved back to the cgroups it came from.
+pub struct CgroupThrottle {
+    cgroup_root: PathBuf,
+    proc_root: PathBuf,
+    quota_percent: u32,
+    engaged: Vec<MovedProcess>,
+}
+
+struct MovedProcess {
+    process_id: u32,
+    original_cgroup: PathBuf,
+}
+
+impl CgroupThrottle {
+    /// `quota_percent` is relative to a single cpu, i.e. 50 allows half a core.
+    pub fn new(quota_percent: u32) -> Self {
+        CgroupThrottle::with_roots(quota_percent, PathBuf::from(CGROUP_ROOT), PathBuf::from(PROC_ROOT))
+    }
+
+    /// Allows pointing at a fake cgroupfs and procfs.
+    pub fn with_roots(quota_percent: u32, cgroup_root: PathBuf, proc_root: PathBuf) -> Self {
+        CgroupThrottle {
+            cgroup_root,
+            proc_root,
+            quota_percent,
+            engaged: Vec::new(),
+        }
+    }
+
+    /// Sets up the cgroups, failing with an explanation if delegation is not available.
+    /// A game left in the afk cgroup by a run that was killed before it could release it is no longer throttled.
+    pub fn prepare(&self) -> Result<(), Error> {
+        let afk = self.afk_cgroup()?;
+        let leftover = fs::read_to_string(afk.join("cgroup.procs")).unwrap_or_default();
+        if !leftover.trim().is_empty() {
+            warn!("processes {} were left throttled by an earlier run, removing their cpu limit", leftover.split_whitespace().collect::<Vec<_>>().join(", "));
+            fs::write(afk.join("cpu.max"), format!("max {}", CPU_PERIOD_US))?;
+        }
+        Ok(())
+    }
+
+    fn afk_cgroup(&self) -> Result<PathBuf, Error> {
+        if !self.cgroup_root.join("cgroup.controllers").is_file() {
+            return Err(Error::new(ErrorKind::NotFound, format!("no cgroup v2 hierarchy mounted at {:?}", self.cgroup_root)));
+        }
+        let own_cgroup = self.cgroup_of("self")?;
+        // after the first engage we already live in the service leaf
+        let base = if own_cgroup.file_name().map(|name| name == SERVICE_CGROUP).unwrap_or(false) {
+            own_cgroup.parent().unwrap_or(&own_cgroup).to_path_buf()
+        } else {
+            own_cgroup
+        };
+        if !is_delegated(&base) {
+            return Err(delegation_error(&base, "it is not marked as delegated"));
+        }
+        let controllers = fs::read_to_string(base.join("cgroup.controllers")).unwrap_or_default();
+        if !controllers.split_whitespace().any(|controller| controller == "cpu") {
+            return Err(delegation_error(&base, "the cpu controller is not available"));
+        }
+        let afk = base.join(AFK_CGROUP);
+        if afk.is_dir() {
+            return Ok(afk);
+        }
+        let service = base.join(SERVICE_CGROUP);
+        create_cgroup(&service).map_err(|e| delegation_error(&base, &e.to_string()))?;
+        fs::write(service.join("cgroup.procs"), std::process::id().to_string())
+            .map_err(|e| delegation_error(&base, &e.to_string()))?;
+        fs::write(base.join("cgroup.subtree_control"), "+cpu")
+            .map_err(|e| delegation_error(&base, &e.to_string()))?;
+        create_cgroup(&afk)?;
+        Ok(afk)
+    }
+
+    /// Absolute path of the cgroup a process belongs to, `self` refers to this process.
+    fn cgroup_of(&self, process: &str) -> Result<PathBuf, Error> {
+        let text = fs::read_to_string(self.proc_root.join(process).join("cgroup"))?;
+        text.lines()
+            .find_map(|line| line.strip_prefix("0::"))
+            .map(|path| self.cgroup_root.join(path.trim().trim_start_matches('/')))
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("process {} is not in a cgroup v2 hierarchy", process)))
+    }
+
+    /// The game and all processes it started.
+    fn process_tree(&self, process_id: u32) -> Vec<u32> {
+        let mut parents: Vec<(u32, u32)> = Vec::new();
+        if let Ok(entries) = fs::read_dir(&self.proc_root) {
+            for entry in entries.filter_map(|entry| entry.ok()) {
+                let pid = match entry.file_name().to_str().and_then(|name| name.parse().ok()) {
+                    Some(pid) => pid,
+                    None => continue,
+                };
+                if let Some(parent) = read_parent_process_id(&entry.path()) {
+                    parents.push((pid, parent));
+                }
+            }
+        }
+        let mut tree = vec![process_id];
+        let mut index = 0;
+        while index < tree.len() {
+            let parent = tree[index];
+            tree.extend(parents.iter().filter(|(_, ppid)| *ppid == parent).map(|(pid, _)| *pid));
+            index += 1;
+        }
+        tree
+    }
+}
+
+impl AfkAction for CgroupThrottle {
+    fn name(&self) -> &'static str {
+        "cgroup cpu throttling"
+    }
+
+    fn engage(&mut self, process_id: u32) -> Result<(), Error> {
+        let afk = self.afk_cgroup()?;
+        let quota = CPU_PERIOD_US * self.quota_percent as u64 / 100;
+        fs::write(afk.join("cpu.max"), format!("{} {}", quota.max(1000), CPU_PERIOD_US))?;
+        for pid in self.process_tree(process_id) {
+            let original_cgroup = match self.cgroup_of(&pid.to_string()) {
+                Ok(cgroup) => cgroup,
+                Err(_) => continue, // exited in the meantime
+            };
+            fs::write(afk.join("cgroup.procs"), pid.to_string())?;
+            self.engaged.push(MovedProcess { process_id: pid, original_cgroup });
+        }
+        Ok(())
+    }
+
+    fn release(&mut self) -> Result<(), Error> {
+        let mut result = Ok(());
+        for moved in self.engaged.drain(..) {
+            if let Err(e) = fs::write(moved.original_cgroup.join("cgroup.procs"), moved.process_id.to_string()) {
+                if e.raw_os_error() == Some(libc::ESRCH) {
+                    continue;
+                }
+                error!("failed to move process {} back to {:?}: {}", moved.process_id, moved.original_cgroup, e);
+                result = Err(e);
+            }
+        }
+        if result.is_err() {
+            // whatever is stuck in the afk cgroup should at least not be throttled anymore
+            if let Ok(afk) = self.afk_cgroup() {
+                fs::write(afk.join("cpu.max"), format!("max {}", CPU_PERIOD_US))?;
+            }
+        }
+        result
+    }
+}
+
+fn create_cgroup(path: &Path) -> Result<(), Error> {
+    match fs::create_dir(path) {
+        Err(e) if e.kind() != ErrorKind::AlreadyExists => Err(e),
+        _ => Ok(()),
+    }
+}
+
+fn is_delegated(cgroup: &Path) -> bool {
+    let path = match CString::new(cgroup.as_os_str().as_bytes()) {
+        Ok(path) => path,
+        Err(_) => return false,
+    };
+    DELEGATE_XATTRS.iter().any(|name| {
+        let name = CString::new(*name).unwrap();
+        let mut value = [0u8; 8];
+        let length = unsafe { libc::getxattr(path.as_ptr(), name.as_ptr(), value.as_mut_ptr() as *mut libc::c_void, value.len()) };
+        length > 0 && &value[..length as usize] == b"1"
+    })
+}
+
+fn read_parent_process_id(process_dir: &Path) -> Option<u32> {
+    let stat = fs::read_to_string(process_dir.join("stat")).ok()?;
+    let after_command = &stat[stat.rfind(')')? + 1..];
+    after_command.split_whitespace().nth(1)?.parse().ok()
+}
+
+fn delegation_error(cgroup: &Path, reason: &str) -> Error {
+    Error::new(ErrorKind::PermissionDenied, format!(
+        "cgroup delegation is not available for {:?} ({}). Run poe-minimizer as a systemd user service with Delegate=yes to enable cpu throttling.",
+        cgroup, reason))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::test_directory;
+
+    const UNIT: &str = "user.slice/user-1000.slice/user@1000.service/app.slice/poe-minimizer.service";
+
+    /// A cgroupfs with poe-minimizer in its own unit and the game, a child of it and an unrelated process in
+    /// scopes of their own, and the procfs to go with it.
+    fn fake_system(name: &str, controllers: &str) -> (PathBuf, PathBuf) {
+        let directory = test_directory(name);
+        let (cgroup_root, proc_root) = (directory.join("cgroup"), directory.join("proc"));
+        fs::create_dir_all(cgroup_root.join(UNIT)).unwrap();
+        fs::write(cgroup_root.join("cgroup.controllers"), "cpuset cpu io memory pids").unwrap();
+        fs::write(cgroup_root.join(UNIT).join("cgroup.controllers"), controllers).unwrap();
+        mark_delegated(&cgroup_root.join(UNIT), "user.delegate", "1");
+        for scope in &["game.scope", "launcher.scope", "other.scope"] {
+            fs::create_dir_all(cgroup_root.join("user.slice/user-1000.slice/user@1000.service/app.slice").join(scope)).unwrap();
+        }
+        add_process(&proc_root, "self", 1, UNIT);
+        add_process(&proc_root, "100", 1, "user.slice/user-1000.slice/user@1000.service/app.slice/game.scope");
+        add_process(&proc_root, "101", 100, "user.slice/user-1000.slice/user@1000.service/app.slice/launcher.scope");
+        add_process(&proc_root, "200", 1, "user.slice/user-1000.slice/user@1000.service/app.slice/other.scope");
+        (cgroup_root, proc_root)
+    }
+
+    fn add_process(proc_root: &Path, process: &str, parent: u32, cgroup: &str) {
+        let directory = proc_root.join(process);
+        fs::create_dir_all(&directory).unwrap();
+        fs::write(directory.join("stat"), format!("{} (Path Of Exile) S {} 1 1 0 -1", process, parent)).unwrap();
+        fs::write(directory.join("cgroup"), format!("0::/{}\n", cgroup)).unwrap();
+    }
+
+    fn mark_delegated(cgroup: &Path, name: &str, value: &str) {
+        let path = CString::new(cgroup.as_os_str().as_bytes()).unwrap();
+        let name = CString::new(name).unwrap();
+        let result = unsafe { libc::setxattr(path.as_ptr(), name.as_ptr(), value.as_ptr() as *const libc::c_void, value.len(), 0) };
+        assert_eq!(result, 0, "{}", Error::last_os_error());
+    }
+
+    fn read(path: PathBuf) -> String {
+        fs::read_to_string(path).unwrap_or_default()
+    }
+
+    #[test]
+    fn engage_moves_the_game_and_release_moves_it_back() {
+        let (cgroup_root, proc_root) = fake_system("cgroup-engage", "cpu memory pids");
+        let unit = cgroup_root.join(UNIT);
+        let mut throttle = CgroupThrottle::with_roots(25, cgroup_root.clone(), proc_root);
+        throttle.prepare().unwrap();
+        assert_eq!(read(unit.join(SERVICE_CGROUP).join("cgroup.procs")), std::process::id().to_string());
+        assert_eq!(read(unit.join("cgroup.subtree_control")), "+cpu");
+        assert!(unit.join(AFK_CGROUP).is_dir());
+
+        throttle.engage(100).unwrap();
+        assert_eq!(read(unit.join(AFK_CGROUP).join("cpu.max")), "25000 100000");
+        let moved: Vec<(u32, PathBuf)> = throttle.engaged.iter().map(|moved| (moved.process_id, moved.original_cgroup.clone())).collect();
+        let app_slice = cgroup_root.join("user.slice/user-1000.slice/user@1000.service/app.slice");
+        assert_eq!(moved, vec![(100, app_slice.join("game.scope")), (101, app_slice.join("launcher.scope"))]);
+        // the fake cgroup.procs only keeps the last write
+        assert_eq!(read(unit.join(AFK_CGROUP).join("cgroup.procs")), "101");
+
+        throttle.release().unwrap();
+        assert!(throttle.engaged.is_empty());
+        assert_eq!(read(app_slice.join("game.scope").join("cgroup.procs")), "100");
+        assert_eq!(read(app_slice.join("launcher.scope").join("cgroup.procs")), "101");
+        assert_eq!(read(app_slice.join("other.scope").join("cgroup.procs")), "");
+    }
+
+    #[test]
+    fn missing_cpu_controller() {
+        let (cgroup_root, proc_root) = fake_system("cgroup-no-cpu", "memory pids");
+        let mut throttle = CgroupThrottle::with_roots(25, cgroup_root.clone(), proc_root);
+        let error = throttle.prepare().unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::PermissionDenied);
+        assert!(error.to_string().contains("the cpu controller is not available"), "{}", error);
+        assert!(throttle.engage(100).is_err());
+        assert!(!cgroup_root.join(UNIT).join(AFK_CGROUP).exists());
+    }
+
+    #[test]
+    fn missing_cgroup_v2() {
+        let (cgroup_root, proc_root) = fake_system("cgroup-v1", "cpu");
+        fs::remove_file(cgroup_root.join("cgroup.controllers")).unwrap();
+        let throttle = CgroupThrottle::with_roots(25, cgroup_root, proc_root);
+        assert_eq!(throttle.prepare().unwrap_err().kind(), ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn prepare_lifts_the_quota_an_earlier_run_left_behind() {
+        let (cgroup_root, proc_root) = fake_system("cgroup-leftover", "cpu");
+        let unit = cgroup_root.join(UNIT);
+        fs::create_dir_all(unit.join(AFK_CGROUP)).unwrap();
+        fs::write(unit.join(AFK_CGROUP).join("cgroup.procs"), "100\n101\n").unwrap();
+        fs::write(unit.join(AFK_CGROUP).join("cpu.max"), "10000 100000").unwrap();
+        add_process(&proc_root, "self", 1, &format!("{}/{}", UNIT, SERVICE_CGROUP));
+        CgroupThrottle::with_roots(25, cgroup_root, proc_root).prepare().unwrap();
+        assert_eq!(read(unit.join(AFK_CGROUP).join("cpu.max")), "max 100000");
+    }
+
+    #[test]
+    fn undelegated_cgroups_are_left_alone() {
+        let (cgroup_root, proc_root) = fake_system("cgroup-undelegated", "cpu");
+        // started from the desktop, in a scope systemd manages that happens to have the cpu controller
+        let scope = "user.slice/user-1000.slice/user@1000.service/app.slice/app-poe\\x2dminimizer-1234.scope";
+        fs::create_dir_all(cgroup_root.join(scope)).unwrap();
+        fs::write(cgroup_root.join(scope).join("cgroup.controllers"), "cpu memory pids").unwrap();
+        add_process(&proc_root, "self", 1, scope);
+        let mut throttle = CgroupThrottle::with_roots(25, cgroup_root.clone(), proc_root.clone());
+        let error = throttle.prepare().unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::PermissionDenied);
+        assert!(error.to_string().contains("not marked as delegated"), "{}", error);
+        assert!(throttle.engage(100).is_err());
+        assert!(!cgroup_root.join(scope).join(SERVICE_CGROUP).exists());
+        assert!(!cgroup_root.join(scope).join(AFK_CGROUP).exists());
+
+        mark_delegated(&cgroup_root.join(scope), "user.delegate", "0");
+        assert!(CgroupThrottle::with_roots(25, cgroup_root.clone(), proc_root.clone()).prepare().is_err());
+        // delegated by the system manager
+        mark_delegated(&cgroup_root.join(scope), "trusted.delegate", "1");
+        CgroupThrottle::with_roots(25, cgroup_root.clone(), proc_root).prepare().unwrap();
+        assert!(cgroup_root.join(scope).join(AFK_CGROUP).is_dir());
+    }
+}
@@ -0,0 +1,208 @@
+use std::fs::File;
+use std::io::{BufReader, Error, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use rev_lines::RevLines;
+
+use crate::language::Language;
+use crate::metrics;
+use crate::redact;
+
+/// How far back to look for the current afk status and zone when starting to follow a log.
+const INITIAL_SCAN_LINES: usize = 5000;
+/// written whenever the game starts, lines before it are from an earlier session
+const SESSION_START: &str = "***** LOG FILE OPENING *****";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LogEvent {
+    AfkChanged(bool),
+    /// a new area was generated, carries the language independent area id, e.g. `HideoutCoastal`
+    AreaEntered(String),
+    /// an incoming whisper, `from` is the character name without guild tag
+    Whisper { from: String, message: String },
+    /// another player invited us into their party
+    PartyInvite { from: String },
+}
+
+/// Follows a Client.txt as the game appends to it.
+pub struct ClientLog {
+    path: PathBuf,
+    position: u64,
+    incomplete_line: Vec<u8>,
+    languages: Vec<Language>,
+}
+
+impl ClientLog {
+    /// Opens the log at its current end, returning the events describing the state the game is in right now.
+    pub fn open(path: &Path, languages: Vec<Language>) -> Result<(ClientLog, Vec<LogEvent>), Error> {
+        let file = File::open(path)?;
+        let position = file.metadata()?.len();
+        let mut afk = None;
+        let mut area = None;
+        for line in RevLines::new(BufReader::new(file))?.take(INITIAL_SCAN_LINES) {
+            if line.contains(SESSION_START) {
+                break;
+            }
+            match parse_log_line(&line, &languages) {
+                Some(LogEvent::AfkChanged(status)) if afk.is_none() => afk = Some(LogEvent::AfkChanged(status)),
+                Some(LogEvent::AreaEntered(id)) if area.is_none() => area = Some(LogEvent::AreaEntered(id)),
+                _ => {}
+            }
+            if afk.is_some() && area.is_some() {
+                break;
+            }
+        }
+        let log = ClientLog {
+            path: path.to_path_buf(),
+            position,
+            incomplete_line: Vec::new(),
+            languages,
+        };
+        Ok((log, area.into_iter().chain(afk).collect()))
+    }
+
+    /// Returns the events of all lines written since the last call.
+    pub fn poll(&mut self) -> Result<Vec<LogEvent>, Error> {
+        let mut file = File::open(&self.path)?;
+        let length = file.metadata()?.len();
+        if length < self.position {
+            info!("{:?} was truncated, reading from the start", self.path);
+            self.position = 0;
+            self.incomplete_line.clear();
+        }
+        if length == self.position {
+            return Ok(Vec::new());
+        }
+        file.seek(SeekFrom::Start(self.position))?;
+        let mut buffer = std::mem::take(&mut self.incomplete_line);
+        let read = file.take(length - self.position).read_to_end(&mut buffer)?;
+        self.position += read as u64;
+
+        let complete = buffer.iter().rposition(|byte| *byte == b'\n').map(|index| index + 1).unwrap_or(0);
+        self.incomplete_line = buffer.split_off(complete);
+        let events = String::from_utf8_lossy(&buffer).lines()
+            .inspect(|_| metrics::LOG_LINES_PARSED.increment())
+            .filter_map(|line| parse_log_line(line, &self.languages))
+            .collect();
+        Ok(events)
+    }
+}
+
+pub fn parse_log_line(log_line: &str, languages: &[Language]) -> Option<LogEvent> {
+    let message = log_message(log_line)?;
+    if let Some(area) = message.strip_prefix("Generating level ") {
+        return count_parse_error(parse_area(area));
+    }
+    if let Some(whisper) = message.strip_prefix("@From ") {
+        return count_parse_error(parse_whisper(whisper));
+    }
+    // system messages have no sender, this keeps players from triggering it via chat or whispers
+    if !message.starts_with(": ") {
+        return None;
+    }
+    let prefix = &log_line[..log_line.len() - message.len()];
+    if let Some((from, suffix)) = languages.iter()
+        .filter_map(|language| language.party_invite_suffix())
+        .find_map(|suffix| {
+            let from = message.strip_suffix(suffix)?.get(2..).filter(|from| !from.is_empty())?;
+            Some((from, suffix))
+        }) {
+        trace!("log line: {}: {}{}", prefix, redact::name(from), suffix);
+        return Some(LogEvent::PartyInvite { from: from.to_owned() });
+    }
+    for language in languages {
+        for &(afk_message, afk) in &[(language.afk_activated_message(), true), (language.afk_deactivated_message(), false)] {
+            if let Some(autoreply) = message.strip_prefix(afk_message) {
+                trace!("log line: {}{}{}", prefix, afk_message, redact::chat(autoreply));
+                return Some(LogEvent::AfkChanged(afk));
+            }
+        }
+    }
+    // other system messages name other players, e.g. when they join the area
+    trace!("log line: {}: {}", prefix, redact::chat(&message[2..]));
+    None
+}
+
+/// Parses `68 area "HideoutCoastal" with seed 1`.
+fn parse_area(area: &str) -> Option<LogEvent> {
+    let start = area.find('"')? + 1;
+    let length = area[start..].find('"')?;
+    Some(LogEvent::AreaEntered(area[start..start + length].to_owned()))
+}
+
+/// Parses `<GUILD> Name: message` or `Name: message`.
+fn parse_whisper(whisper: &str) -> Option<LogEvent> {
+    let separator = whisper.find(": ")?;
+    let sender = &whisper[..separator];
+    let from = match sender.rfind("> ") {
+        Some(guild_end) if sender.starts_with('<') => &sender[guild_end + 2..],
+        _ => sender,
+    };
+    Some(LogEvent::Whisper {
+        from: from.to_owned(),
+        message: whisper[separator + 2..].to_owned(),
+    })
+}
+
+fn count_parse_error(event: Option<LogEvent>) -> Option<LogEvent> {
+    if event.is_none() {
+        metrics::PARSE_ERRORS.increment();
+    }
+    event
+}
+
+/// Strips the `2020/02/07 01:15:50 1918890562 ac9 [INFO Client 28536] ` prefix.
+fn log_message(log_line: &str) -> Option<&str> {
+    let start = log_line.find(" [")?;
+    let end = log_line[start..].find("] ")? + start;
+    Some(&log_line[end + 2..])
+}
+
+pub fn is_hideout(area_id: &str) -> bool {
+    area_id.starts_with("Hideout")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::language::ALL_LANGUAGES;
+    use crate::utils::test_directory;
+
+    const PREFIX: &str = "2020/02/07 01:15:50 1918890562 ac9 [INFO Client 28536] ";
+
+    fn open(name: &str, lines: &[String]) -> Vec<LogEvent> {
+        let path = test_directory(name).join("Client.txt");
+        std::fs::write(&path, lines.join("\n") + "\n").unwrap();
+        ClientLog::open(&path, ALL_LANGUAGES.to_vec()).unwrap().1
+    }
+
+    #[test]
+    fn open_ignores_earlier_sessions() {
+        let events = open("client-log-earlier-sessions", &[
+            format!("{}: AFK mode is now ON. Autoreply \"brb\"", PREFIX),
+            format!("{}Generating level 68 area \"HideoutCoastal\" with seed 1", PREFIX),
+            format!("2020/02/08 10:00:00 {}", SESSION_START),
+        ]);
+        assert_eq!(events, vec![]);
+    }
+
+    #[test]
+    fn open_reports_the_current_session() {
+        let events = open("client-log-current-session", &[
+            format!("2020/02/07 01:00:00 {}", SESSION_START),
+            format!("{}Generating level 68 area \"HideoutCoastal\" with seed 1", PREFIX),
+            format!("{}: AFK mode is now ON. Autoreply \"brb\"", PREFIX),
+        ]);
+        assert_eq!(events, vec![LogEvent::AreaEntered("HideoutCoastal".to_owned()), LogEvent::AfkChanged(true)]);
+    }
+
+    #[test]
+    fn party_invites() {
+        let parse = |message: &str| parse_log_line(&format!("{}{}", PREFIX, message), &ALL_LANGUAGES);
+        assert_eq!(parse(": Someone has invited you to a party."), Some(LogEvent::PartyInvite { from: "Someone".to_owned() }));
+        // without a name, e.g. cut off, it can't be told apart from chat
+        assert_eq!(parse(": has invited you to a party."), None);
+        assert_eq!(parse(":  has invited you to a party."), None);
+        assert_eq!(parse("Someone: has invited you to a party."), None);
+    }
+}
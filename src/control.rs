@@ -0,0 +1,226 @@
+use std::collections::BTreeMap;
+use std::io::{Error, ErrorKind};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::{DateTime, Local};
+use serde::Serialize;
+
+use crate::state::State;
+use crate::trade::TradeRequest;
+
+/// Requests from frontends like the http api to the service loop.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    /// stop minimizing until resumed or the duration has passed
+    Pause(Option<Duration>),
+    Resume,
+    MinimizeNow,
+    Restore,
+    ReloadConfig,
+}
+
+impl Command {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Pause(_) => "pause",
+            Command::Resume => "resume",
+            Command::MinimizeNow => "minimize",
+            Command::Restore => "restore",
+            Command::ReloadConfig => "reload-config",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Command> {
+        [Command::Pause(None), Command::Resume, Command::MinimizeNow, Command::Restore, Command::ReloadConfig].iter()
+            .find(|command| command.name() == name)
+            .copied()
+    }
+}
+
+/// Parses durations like `90s`, `30m` or `2h`, plain numbers are minutes.
+pub fn parse_duration(text: &str) -> Option<Duration> {
+    let text = text.trim();
+    let (number, unit_seconds) = match text.char_indices().last()? {
+        (index, 's') => (&text[..index], 1),
+        (index, 'm') => (&text[..index], 60),
+        (index, 'h') => (&text[..index], 3600),
+        _ => (text, 60),
+    };
+    number.parse::<u64>().ok().map(|number| Duration::from_secs(number * unit_seconds))
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Whisper {
+    pub from: String,
+    pub message: String,
+    pub received: DateTime<Local>,
+}
+
+/// What the service loop currently knows about the game, published for frontends.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Status {
+    pub state: State,
+    /// when `state` was entered
+    pub state_since: DateTime<Local>,
+    pub game_running: bool,
+    pub afk: bool,
+    pub minimized: bool,
+    pub paused: bool,
+    /// when minimizing resumes by itself
+    pub paused_until: Option<DateTime<Local>>,
+    /// language independent id of the current area
+    pub zone: Option<String>,
+    pub last_whisper: Option<Whisper>,
+    /// whispers received since the player went afk
+    pub afk_whispers: u32,
+    /// why the game can't be watched although it runs, e.g. a missing Client.txt
+    pub error: Option<String>,
+    /// what happened to worker threads that crashed, by name
+    pub crashed_workers: BTreeMap<String, String>,
+    /// `None` where autostart is not available
+    pub run_on_startup: Option<bool>,
+}
+
+impl Status {
+    pub fn set_state(&mut self, state: State) {
+        if state == self.state {
+            return;
+        }
+        let was_afk = self.state == State::AfkPending || self.state == State::Minimized;
+        if !was_afk && (state == State::AfkPending || state == State::Minimized) {
+            self.afk_whispers = 0;
+        }
+        self.state = state;
+        self.state_since = Local::now();
+    }
+}
+
+/// Something that happened in the service loop, for frontends that pass on events rather than the status.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    TradeRequest { request: TradeRequest, received: DateTime<Local> },
+}
+
+enum Message {
+    Command(Command),
+    /// ends a `wait_for_commands` early, e.g. to shut down
+    Wake,
+}
+
+/// The service loop's end: publishes the status and receives commands.
+pub struct Controller {
+    status: Arc<Mutex<Status>>,
+    commands: Receiver<Message>,
+    waker: Sender<Message>,
+    subscribers: Arc<Mutex<Vec<Sender<Event>>>>,
+}
+
+/// A frontend's end, cheap to clone for every frontend.
+#[derive(Clone)]
+pub struct Remote {
+    status: Arc<Mutex<Status>>,
+    commands: Sender<Message>,
+    subscribers: Arc<Mutex<Vec<Sender<Event>>>>,
+}
+
+pub fn channel() -> (Controller, Remote) {
+    let status = Arc::new(Mutex::new(Status {
+        state: State::GameNotRunning,
+        state_since: Local::now(),
+        game_running: false,
+        afk: false,
+        minimized: false,
+        paused: false,
+        paused_until: None,
+        zone: None,
+        last_whisper: None,
+        afk_whispers: 0,
+        error: None,
+        crashed_workers: BTreeMap::new(),
+        run_on_startup: None,
+    }));
+    let (sender, receiver) = mpsc::channel();
+    let subscribers = Arc::new(Mutex::new(Vec::new()));
+    (
+        Controller { status: status.clone(), commands: receiver, waker: sender.clone(), subscribers: subscribers.clone() },
+        Remote { status, commands: sender, subscribers },
+    )
+}
+
+impl Controller {
+    pub fn update_status<F: FnOnce(&mut Status)>(&self, update: F) {
+        let mut status = self.status.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        update(&mut status);
+    }
+
+    pub fn pending_commands(&self) -> Vec<Command> {
+        self.commands.try_iter().filter_map(Message::into_command).collect()
+    }
+
+    /// Waits up to `timeout` for commands, returning early once one arrives or the waker is called.
+    pub fn wait_for_commands(&self, timeout: Duration) -> Vec<Command> {
+        match self.commands.recv_timeout(timeout) {
+            Ok(message) => std::iter::once(message).chain(self.commands.try_iter()).filter_map(Message::into_command).collect(),
+            // the controller holds a sender itself, so the channel can't be disconnected
+            Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => Vec::new(),
+        }
+    }
+
+    /// Queues `event` for every subscriber, dropping those that went away.
+    pub fn publish(&self, event: Event) {
+        let mut subscribers = self.subscribers.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        subscribers.retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+
+    /// Ends the current or next `wait_for_commands` without a command, callable from any thread.
+    pub fn waker(&self) -> impl Fn() + Send + 'static {
+        let waker = self.waker.clone();
+        move || {
+            let _ = waker.send(Message::Wake);
+        }
+    }
+}
+
+impl Message {
+    fn into_command(self) -> Option<Command> {
+        match self {
+            Message::Command(command) => Some(command),
+            Message::Wake => None,
+        }
+    }
+}
+
+impl Remote {
+    pub fn status(&self) -> Status {
+        self.status.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).clone()
+    }
+
+    /// For the supervisor, replaces what was reported for the worker before.
+    pub fn report_crash(&self, worker: &str, problem: String) {
+        let mut status = self.status.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        status.crashed_workers.insert(worker.to_owned(), problem);
+    }
+
+    /// For the supervisor, once the worker runs stable again.
+    pub fn clear_crash(&self, worker: &str) {
+        self.status.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).crashed_workers.remove(worker);
+    }
+
+    /// Events published from now on, until the receiver is dropped.
+    pub fn subscribe(&self) -> Receiver<Event> {
+        let (sender, receiver) = mpsc::channel();
+        self.subscribers.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).push(sender);
+        receiver
+    }
+
+    pub fn set_run_on_startup(&self, run_on_startup: Option<bool>) {
+        self.status.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).run_on_startup = run_on_startup;
+    }
+
+    pub fn send(&self, command: Command) -> Result<(), Error> {
+        self.commands.send(Message::Command(command))
+            .map_err(|_| Error::new(ErrorKind::BrokenPipe, "the service loop is not running"))
+    }
+}
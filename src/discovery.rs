@@ -0,0 +1,512 @@
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+const STEAM_APP_ID: &str = "238960";
+const GAME_DIRECTORY: &str = "Path of Exile";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GameVariant {
+    Standalone,
+    Steam,
+    Proton,
+    Wine,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Candidate {
+    pub variant: GameVariant,
+    pub client_log: PathBuf,
+    /// root of the wine prefix (the directory containing `drive_c`) for Proton and Wine installs
+    pub prefix: Option<PathBuf>,
+}
+
+impl Candidate {
+    fn new(variant: GameVariant, install_dir: &Path, prefix: Option<PathBuf>) -> Self {
+        Candidate {
+            variant,
+            client_log: client_log_in(install_dir),
+            prefix,
+        }
+    }
+
+    /// Describes the installation a running game executable belongs to.
+    pub fn from_executable(executable: &Path) -> Option<Self> {
+        let install_dir = executable.parent()?;
+        let is_steam = install_dir.components().any(|component| component.as_os_str().eq_ignore_ascii_case("steamapps"));
+        let variant = if is_steam { GameVariant::Steam } else { GameVariant::Standalone };
+        Some(Candidate::new(variant, install_dir, None))
+    }
+
+    pub fn exists(&self) -> bool {
+        self.client_log.is_file()
+    }
+
+    fn last_modified(&self) -> Option<SystemTime> {
+        fs::metadata(&self.client_log).and_then(|metadata| metadata.modified()).ok()
+    }
+}
+
+fn client_log_in(install_dir: &Path) -> PathBuf {
+    install_dir.join("logs").join("Client.txt")
+}
+
+/// Returns every location a Client.txt could be found at on this machine, whether it exists or not.
+pub fn find_client_logs() -> Vec<Candidate> {
+    let mut candidates = Vec::new();
+    for steam_root in steam_roots() {
+        candidates.extend(steam_candidates(&steam_root));
+    }
+    candidates.extend(standalone_candidates());
+    if let Some(home) = home_dir() {
+        candidates.extend(wine_candidates(&home));
+    }
+    deduplicate(candidates)
+}
+
+/// Picks the existing Client.txt that was written to most recently, i.e. the one of the installation played last.
+pub fn find_most_recent_client_log() -> Option<Candidate> {
+    find_client_logs().into_iter()
+        .filter(|candidate| candidate.exists())
+        .max_by_key(|candidate| candidate.last_modified())
+}
+
+pub fn home_dir() -> Option<PathBuf> {
+    let variable = if cfg!(windows) { "USERPROFILE" } else { "HOME" };
+    std::env::var_os(variable)
+        .filter(|value| !value.is_empty())
+        .map(PathBuf::from)
+}
+
+#[cfg(windows)]
+fn steam_roots() -> Vec<PathBuf> {
+    let mut roots = Vec::new();
+    if let Ok(Some(path)) = crate::winutils::read_registry_string("Software\\Valve\\Steam", "SteamPath") {
+        roots.push(PathBuf::from(path));
+    }
+    roots.push(PathBuf::from("C:\\Program Files (x86)\\Steam"));
+    roots
+}
+
+#[cfg(not(windows))]
+fn steam_roots() -> Vec<PathBuf> {
+    home_dir().map(|home| linux_steam_roots(&home)).unwrap_or_default()
+}
+
+pub fn linux_steam_roots(home: &Path) -> Vec<PathBuf> {
+    vec![
+        home.join(".steam").join("steam"),
+        home.join(".steam").join("root"),
+        home.join(".local").join("share").join("Steam"),
+        home.join(".var").join("app").join("com.valvesoftware.Steam").join(".local").join("share").join("Steam"),
+    ]
+}
+
+/// Looks up Path of Exile in every library registered with the steam installation at `steam_root`.
+pub fn steam_candidates(steam_root: &Path) -> Vec<Candidate> {
+    let variant = if cfg!(windows) { GameVariant::Steam } else { GameVariant::Proton };
+    steam_libraries(steam_root).iter()
+        .filter_map(|library| {
+            let steamapps = library.join("steamapps");
+            let install_dir = read_install_dir(&steamapps.join(format!("appmanifest_{}.acf", STEAM_APP_ID)))?;
+            let prefix = steamapps.join("compatdata").join(STEAM_APP_ID).join("pfx");
+            let prefix = if variant == GameVariant::Proton && prefix.is_dir() { Some(prefix) } else { None };
+            Some(Candidate::new(variant, &steamapps.join("common").join(install_dir), prefix))
+        })
+        .collect()
+}
+
+fn steam_libraries(steam_root: &Path) -> Vec<PathBuf> {
+    let mut libraries = vec![steam_root.to_path_buf()];
+    let library_folders = steam_root.join("steamapps").join("libraryfolders.vdf");
+    let vdf = match fs::read_to_string(&library_folders).and_then(|text| Vdf::parse(&text)) {
+        Ok(vdf) => vdf,
+        Err(e) => {
+            debug!("unable to read steam libraries from {:?}: {}", library_folders, e);
+            return libraries;
+        }
+    };
+    if let Some(folders) = vdf.get("libraryfolders") {
+        for (key, value) in folders.entries() {
+            if !key.chars().all(|c| c.is_ascii_digit()) {
+                continue;
+            }
+            // old format: "1" "D:\\SteamLibrary", new format: "1" { "path" "D:\\SteamLibrary" ... }
+            let path = value.as_str().or_else(|| value.get("path").and_then(Vdf::as_str));
+            if let Some(path) = path {
+                libraries.push(PathBuf::from(path));
+            }
+        }
+    }
+    libraries
+}
+
+fn read_install_dir(app_manifest: &Path) -> Option<String> {
+    let vdf = fs::read_to_string(app_manifest).and_then(|text| Vdf::parse(&text)).ok()?;
+    vdf.get("AppState")
+        .and_then(|state| state.get("installdir"))
+        .and_then(Vdf::as_str)
+        .map(|dir| dir.to_owned())
+}
+
+#[cfg(windows)]
+fn standalone_candidates() -> Vec<Candidate> {
+    let mut install_dirs = Vec::new();
+    if let Ok(Some(path)) = crate::winutils::read_registry_string("Software\\GrindingGearGames\\Path of Exile", "InstallLocation") {
+        install_dirs.push(PathBuf::from(path));
+    }
+    install_dirs.push(PathBuf::from("C:\\Program Files (x86)\\Grinding Gear Games\\Path of Exile"));
+    install_dirs.push(PathBuf::from("C:\\Program Files\\Grinding Gear Games\\Path of Exile"));
+    install_dirs.iter()
+        .map(|dir| Candidate::new(GameVariant::Standalone, dir, None))
+        .collect()
+}
+
+#[cfg(not(windows))]
+fn standalone_candidates() -> Vec<Candidate> {
+    Vec::new()
+}
+
+/// Looks for installations inside plain wine prefixes and the ones managed by Lutris.
+pub fn wine_candidates(home: &Path) -> Vec<Candidate> {
+    let mut candidates = Vec::new();
+    let mut prefixes = vec![home.join(".wine"), home.join("Games").join("path-of-exile")];
+    for game_config in lutris_game_configs(home) {
+        let (prefix, exe) = match fs::read_to_string(&game_config).and_then(|text| parse_lutris_game_config(&text)) {
+            Ok(config) => config,
+            Err(e) => {
+                debug!("ignoring lutris game config {:?}: {}", game_config, e);
+                continue;
+            }
+        };
+        if let (Some(prefix), Some(exe)) = (&prefix, exe) {
+            if exe.contains("PathOfExile") {
+                let exe = prefix.join(exe);
+                if let Some(install_dir) = exe.parent() {
+                    candidates.push(Candidate::new(GameVariant::Wine, install_dir, Some(prefix.clone())));
+                }
+            }
+        }
+        prefixes.extend(prefix);
+    }
+    for prefix in prefixes.iter().filter(|prefix| prefix.join("drive_c").is_dir()) {
+        for install_dir in wine_install_dirs(prefix) {
+            candidates.push(Candidate::new(GameVariant::Wine, &install_dir, Some(prefix.clone())));
+        }
+    }
+    candidates
+}
+
+fn wine_install_dirs(prefix: &Path) -> Vec<PathBuf> {
+    let drive_c = prefix.join("drive_c");
+    vec![
+        drive_c.join("Program Files (x86)").join("Grinding Gear Games").join(GAME_DIRECTORY),
+        drive_c.join("Program Files").join("Grinding Gear Games").join(GAME_DIRECTORY),
+        drive_c.join("Program Files (x86)").join("Steam").join("steamapps").join("common").join(GAME_DIRECTORY),
+    ]
+}
+
+fn lutris_game_configs(home: &Path) -> Vec<PathBuf> {
+    let directories = [
+        home.join(".config").join("lutris").join("games"),
+        home.join(".local").join("share").join("lutris").join("games"),
+    ];
+    directories.iter()
+        .filter_map(|directory| fs::read_dir(directory).ok())
+        .flat_map(|entries| entries.filter_map(|entry| entry.ok()).map(|entry| entry.path()))
+        .filter(|path| path.extension().map(|extension| extension == "yml").unwrap_or(false))
+        .collect()
+}
+
+/// Extracts `prefix` and `exe` from a Lutris game config. Lutris writes these as plain
+/// `key: value` lines, so this avoids pulling in a full yaml parser. Fails if there is neither.
+fn parse_lutris_game_config(text: &str) -> Result<(Option<PathBuf>, Option<String>), Error> {
+    let mut prefix = None;
+    let mut exe = None;
+    for line in text.lines() {
+        let line = line.trim();
+        if let Some(value) = line.strip_prefix("prefix:") {
+            prefix = Some(PathBuf::from(unquote_yaml(value))).filter(|prefix| !prefix.as_os_str().is_empty());
+        } else if let Some(value) = line.strip_prefix("exe:") {
+            exe = Some(unquote_yaml(value)).filter(|exe| !exe.is_empty());
+        }
+    }
+    if prefix.is_none() && exe.is_none() {
+        return Err(Error::new(ErrorKind::InvalidData, "neither prefix nor exe found"));
+    }
+    Ok((prefix, exe))
+}
+
+fn unquote_yaml(value: &str) -> String {
+    value.trim().trim_matches(|c| c == '\'' || c == '"').to_owned()
+}
+
+fn deduplicate(candidates: Vec<Candidate>) -> Vec<Candidate> {
+    let mut seen: Vec<PathBuf> = Vec::new();
+    let mut result = Vec::new();
+    for candidate in candidates {
+        // symlinked steam roots like ~/.steam/steam point to the same libraries
+        let key = fs::canonicalize(&candidate.client_log).unwrap_or_else(|_| candidate.client_log.clone());
+        if !seen.contains(&key) {
+            seen.push(key);
+            result.push(candidate);
+        }
+    }
+    result
+}
+
+/// Minimal reader for Valve's KeyValues format as used by `libraryfolders.vdf` and `appmanifest_*.acf`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Vdf {
+    Value(String),
+    Object(Vec<(String, Vdf)>),
+}
+
+impl Vdf {
+    pub fn parse(text: &str) -> Result<Vdf, Error> {
+        let tokens = tokenize_vdf(text)?;
+        let mut position = 0;
+        let entries = parse_vdf_entries(&tokens, &mut position)?;
+        if position < tokens.len() {
+            return Err(Error::new(ErrorKind::InvalidData, "unexpected '}' in vdf"));
+        }
+        Ok(Vdf::Object(entries))
+    }
+
+    /// Keys are case insensitive, steam is not consistent about them.
+    pub fn get(&self, key: &str) -> Option<&Vdf> {
+        self.entries().iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(key))
+            .map(|(_, value)| value)
+    }
+
+    pub fn entries(&self) -> &[(String, Vdf)] {
+        match self {
+            Vdf::Object(entries) => entries,
+            Vdf::Value(_) => &[],
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Vdf::Value(value) => Some(value),
+            Vdf::Object(_) => None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum VdfToken {
+    Text(String),
+    Open,
+    Close,
+}
+
+fn tokenize_vdf(text: &str) -> Result<Vec<VdfToken>, Error> {
+    let mut tokens = Vec::new();
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' => tokens.push(VdfToken::Open),
+            '}' => tokens.push(VdfToken::Close),
+            '"' => {
+                let mut value = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some('n') => value.push('\n'),
+                            Some('t') => value.push('\t'),
+                            Some(escaped) => value.push(escaped),
+                            None => return Err(Error::new(ErrorKind::InvalidData, "unterminated string in vdf")),
+                        },
+                        Some(c) => value.push(c),
+                        None => return Err(Error::new(ErrorKind::InvalidData, "unterminated string in vdf")),
+                    }
+                }
+                tokens.push(VdfToken::Text(value));
+            }
+            '/' if chars.peek() == Some(&'/') => {
+                for c in chars.by_ref() {
+                    if c == '\n' {
+                        break;
+                    }
+                }
+            }
+            c if c.is_whitespace() => {}
+            c => {
+                let mut value = c.to_string();
+                while let Some(&next) = chars.peek() {
+                    if next.is_whitespace() || next == '{' || next == '}' || next == '"' {
+                        break;
+                    }
+                    value.push(next);
+                    chars.next();
+                }
+                // conditionals like [$WIN32] only restrict the preceding entry, ignore them
+                if !value.starts_with('[') {
+                    tokens.push(VdfToken::Text(value));
+                }
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+fn parse_vdf_entries(tokens: &[VdfToken], position: &mut usize) -> Result<Vec<(String, Vdf)>, Error> {
+    let mut entries = Vec::new();
+    while *position < tokens.len() {
+        let key = match &tokens[*position] {
+            VdfToken::Close => return Ok(entries),
+            VdfToken::Open => return Err(Error::new(ErrorKind::InvalidData, "expected key in vdf, found '{'")),
+            VdfToken::Text(key) => key.clone(),
+        };
+        *position += 1;
+        let value = match tokens.get(*position) {
+            Some(VdfToken::Text(value)) => {
+                *position += 1;
+                Vdf::Value(value.clone())
+            }
+            Some(VdfToken::Open) => {
+                *position += 1;
+                let children = parse_vdf_entries(tokens, position)?;
+                if tokens.get(*position) != Some(&VdfToken::Close) {
+                    return Err(Error::new(ErrorKind::InvalidData, "missing '}' in vdf"));
+                }
+                *position += 1;
+                Vdf::Object(children)
+            }
+            _ => return Err(Error::new(ErrorKind::InvalidData, format!("missing value for key {} in vdf", key))),
+        };
+        entries.push((key, value));
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::test_directory;
+
+    const LIBRARY_FOLDERS: &str = r#"
+// written by steam
+"libraryfolders"
+{
+	"0"
+	{
+		"path"		"/home/exile/.local/share/Steam"
+		"label"		""
+		"apps"
+		{
+			"238960"		"31457280"
+		}
+	}
+	"1"
+	{
+		"path"		"D:\\SteamLibrary"
+	}
+}
+"#;
+
+    fn write(path: &Path, text: &str) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, text).unwrap();
+    }
+
+    #[test]
+    fn parses_library_folders() {
+        let vdf = Vdf::parse(LIBRARY_FOLDERS).unwrap();
+        let folders = vdf.get("LibraryFolders").unwrap();
+        assert_eq!(folders.entries().len(), 2);
+        assert_eq!(folders.get("0").and_then(|folder| folder.get("path")).and_then(Vdf::as_str), Some("/home/exile/.local/share/Steam"));
+        assert_eq!(folders.get("1").and_then(|folder| folder.get("path")).and_then(Vdf::as_str), Some("D:\\SteamLibrary"));
+        assert_eq!(folders.get("0").and_then(|folder| folder.get("apps")).and_then(|apps| apps.get(STEAM_APP_ID)), Some(&Vdf::Value("31457280".to_owned())));
+        assert_eq!(folders.get("0").and_then(|folder| folder.get("label")).and_then(Vdf::as_str), Some(""));
+
+        let old_format = Vdf::parse("\"LibraryFolders\" { \"TimeNextStatsReport\" \"1\" \"1\" \"E:\\\\Games\" }").unwrap();
+        assert_eq!(old_format.get("libraryfolders").and_then(|folders| folders.get("1")).and_then(Vdf::as_str), Some("E:\\Games"));
+
+        let conditional = Vdf::parse("\"AppState\" { \"installdir\" \"Path of Exile\" [$WIN32] unquoted value }").unwrap();
+        let state = conditional.get("appstate").unwrap();
+        assert_eq!(state.get("installdir").and_then(Vdf::as_str), Some("Path of Exile"));
+        assert_eq!(state.get("unquoted").and_then(Vdf::as_str), Some("value"));
+        assert_eq!(Vdf::parse("").unwrap(), Vdf::Object(Vec::new()));
+    }
+
+    #[test]
+    fn malformed_vdf_is_an_error() {
+        let malformed = [
+            "\"libraryfolders\" { \"0\" \"/unterminated }",
+            "\"libraryfolders\" { \"0\" { \"path\" \"/a\" }",
+            "\"libraryfolders\" { } }",
+            "\"libraryfolders\"",
+            "\"libraryfolders\" { \"0\" }",
+            "{ \"path\" \"/a\" }",
+            "\"libraryfolders\" { { } }",
+            "\"key\" \"value\\",
+        ];
+        for text in malformed.iter() {
+            let error = Vdf::parse(text).expect_err(text);
+            assert_eq!(error.kind(), ErrorKind::InvalidData, "{}", text);
+        }
+    }
+
+    #[test]
+    fn finds_the_game_in_every_steam_library() {
+        let directory = test_directory("discovery-steam");
+        let (root, library) = (directory.join("Steam"), directory.join("SteamLibrary"));
+        write(&root.join("steamapps").join("libraryfolders.vdf"), &format!(
+            "\"libraryfolders\" {{ \"0\" {{ \"path\" \"{}\" }} \"1\" {{ \"path\" \"{}\" }} }}", root.display(), library.display()));
+        write(&library.join("steamapps").join("appmanifest_238960.acf"), "\"AppState\" { \"appid\" \"238960\" \"installdir\" \"Path of Exile\" }");
+        fs::create_dir_all(library.join("steamapps").join("compatdata").join(STEAM_APP_ID).join("pfx")).unwrap();
+
+        let candidates = steam_candidates(&root);
+        assert_eq!(candidates, vec![Candidate {
+            variant: GameVariant::Proton,
+            client_log: library.join("steamapps").join("common").join("Path of Exile").join("logs").join("Client.txt"),
+            prefix: Some(library.join("steamapps").join("compatdata").join(STEAM_APP_ID).join("pfx")),
+        }]);
+
+        // the default library is still searched
+        write(&root.join("steamapps").join("libraryfolders.vdf"), "\"libraryfolders\" { \"0\" {");
+        assert_eq!(steam_libraries(&root), vec![root.clone()]);
+        assert!(steam_candidates(&root).is_empty());
+        write(&root.join("steamapps").join("appmanifest_238960.acf"), "\"AppState\" { \"installdir\" ");
+        assert!(steam_candidates(&root).is_empty());
+    }
+
+    #[test]
+    fn parses_lutris_game_configs() {
+        let config = "game:\n  exe: drive_c/Program Files (x86)/Grinding Gear Games/Path of Exile/PathOfExile_x64.exe\n  prefix: '/home/exile/Games/path-of-exile'\nsystem:\n  env: {}\n";
+        assert_eq!(parse_lutris_game_config(config).unwrap(), (
+            Some(PathBuf::from("/home/exile/Games/path-of-exile")),
+            Some("drive_c/Program Files (x86)/Grinding Gear Games/Path of Exile/PathOfExile_x64.exe".to_owned()),
+        ));
+        assert_eq!(parse_lutris_game_config("game:\r\n  prefix: \"/prefix\"\r\n").unwrap(), (Some(PathBuf::from("/prefix")), None));
+        for malformed in ["", "game: [unclosed", "game:\n  exe:\n  prefix: ''\n", "\u{0}\u{ffff}"].iter() {
+            assert_eq!(parse_lutris_game_config(malformed).unwrap_err().kind(), ErrorKind::InvalidData, "{:?}", malformed);
+        }
+    }
+
+    #[test]
+    fn finds_the_game_in_lutris_prefixes() {
+        let home = test_directory("discovery-lutris");
+        let prefix = home.join("Games").join("poe");
+        let games = home.join(".config").join("lutris").join("games");
+        write(&games.join("path-of-exile.yml"), &format!("game:\n  exe: drive_c/Path of Exile/PathOfExile.exe\n  prefix: {}\n", prefix.display()));
+        write(&games.join("broken.yml"), "game: {");
+        fs::write(games.join("binary.yml"), [0xff, 0xfe, 0x00]).unwrap();
+        fs::create_dir_all(prefix.join("drive_c")).unwrap();
+
+        let candidates = wine_candidates(&home);
+        assert_eq!(candidates[0], Candidate {
+            variant: GameVariant::Wine,
+            client_log: prefix.join("drive_c").join("Path of Exile").join("logs").join("Client.txt"),
+            prefix: Some(prefix.clone()),
+        });
+        // the default install locations inside the prefix are candidates as well
+        assert_eq!(candidates.len(), 1 + wine_install_dirs(&prefix).len());
+        assert!(candidates.iter().all(|candidate| candidate.prefix.as_ref() == Some(&prefix)));
+    }
+}
@@ -0,0 +1,288 @@
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::ini::Ini;
+use crate::metrics;
+use crate::state::State;
+use crate::utils::*;
+
+/// a baseline shorter than this is too noisy to estimate savings from
+const MINIMUM_BASELINE: Duration = Duration::from_secs(10);
+
+/// Reads the package energy counters the kernel exposes through powercap, covering intel and amd cpus.
+pub struct EnergyMeter {
+    zones: Vec<RaplZone>,
+}
+
+struct RaplZone {
+    energy_path: PathBuf,
+    max_energy_uj: u64,
+    last_energy_uj: Option<u64>,
+}
+
+impl EnergyMeter {
+    pub fn open(powercap_root: &Path) -> Result<EnergyMeter, Error> {
+        let mut zones = Vec::new();
+        for entry in fs::read_dir(powercap_root)?.filter_map(|entry| entry.ok()) {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            // top level zones (intel-rapl:0) are cpu packages, nested ones (intel-rapl:0:1) are parts of them
+            let is_package = (name.starts_with("intel-rapl:") || name.starts_with("amd-rapl:")) && name.matches(':').count() == 1;
+            if !is_package {
+                continue;
+            }
+            let path = entry.path();
+            let max_energy_uj = read_counter(&path.join("max_energy_range_uj")).unwrap_or(u64::MAX);
+            let energy_path = path.join("energy_uj");
+            if let Err(e) = read_counter(&energy_path) {
+                if e.kind() == ErrorKind::PermissionDenied {
+                    return Err(Error::new(ErrorKind::PermissionDenied, format!(
+                        "{:?} is only readable by root since linux 5.10, allow reading it with a udev rule or chmod to measure energy", energy_path)));
+                }
+                return Err(e);
+            }
+            zones.push(RaplZone { energy_path, max_energy_uj, last_energy_uj: None });
+        }
+        if zones.is_empty() {
+            return Err(Error::new(ErrorKind::NotFound, format!("no rapl package zones found in {:?}", powercap_root)));
+        }
+        Ok(EnergyMeter { zones })
+    }
+
+    /// Microjoules consumed by all packages since the previous call, 0 on the first call.
+    pub fn consumed_since_last(&mut self) -> Result<u64, Error> {
+        let mut consumed = 0;
+        for zone in self.zones.iter_mut() {
+            let energy = read_counter(&zone.energy_path)?;
+            if let Some(last) = zone.last_energy_uj {
+                consumed += if energy >= last {
+                    energy - last
+                } else {
+                    // the counter wrapped around
+                    zone.max_energy_uj.saturating_sub(last) + energy
+                };
+            }
+            zone.last_energy_uj = Some(energy);
+        }
+        Ok(consumed)
+    }
+}
+
+fn read_counter(path: &Path) -> Result<u64, Error> {
+    fs::read_to_string(path)?
+        .trim()
+        .parse()
+        .map_err(|_| Error::new(ErrorKind::InvalidData, format!("{:?} does not contain a number", path)))
+}
+
+/// Energy and time spent in one kind of afk period.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct EnergyUsage {
+    pub microjoules: u64,
+    pub seconds: f64,
+}
+
+impl EnergyUsage {
+    fn add(&mut self, microjoules: u64, duration: Duration) {
+        self.microjoules += microjoules;
+        self.seconds += duration.as_secs_f64();
+    }
+
+    fn watts(&self) -> f64 {
+        if self.seconds > 0.0 { self.microjoules as f64 / 1_000_000.0 / self.seconds } else { 0.0 }
+    }
+}
+
+/// Totals kept across runs.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct EnergyTotals {
+    /// afk, but the game was not minimized (yet)
+    pub afk_visible: EnergyUsage,
+    pub afk_minimized: EnergyUsage,
+    pub saved_wh: f64,
+}
+
+impl EnergyTotals {
+    fn load(path: &Path) -> EnergyTotals {
+        let ini = match Ini::load(path) {
+            Ok(ini) => ini,
+            Err(_) => return EnergyTotals::default(),
+        };
+        let number = |key: &str| ini.get("energy", key).and_then(|value| value.parse::<f64>().ok()).unwrap_or(0.0);
+        EnergyTotals {
+            afk_visible: EnergyUsage { microjoules: number("afk_visible_uj") as u64, seconds: number("afk_visible_seconds") },
+            afk_minimized: EnergyUsage { microjoules: number("afk_minimized_uj") as u64, seconds: number("afk_minimized_seconds") },
+            saved_wh: number("saved_wh"),
+        }
+    }
+
+    fn save(&self, path: &Path) -> Result<(), Error> {
+        fs::write(path, format!(
+            "[energy]\nafk_visible_uj={}\nafk_visible_seconds={:.1}\nafk_minimized_uj={}\nafk_minimized_seconds={:.1}\nsaved_wh={:.3}\n",
+            self.afk_visible.microjoules, self.afk_visible.seconds,
+            self.afk_minimized.microjoules, self.afk_minimized.seconds,
+            self.saved_wh))
+    }
+}
+
+/// Attributes the measured energy to afk periods with and without the game minimized and
+/// estimates the energy each minimized session saved compared to staying visible. Active play draws far more
+/// power than idling, so only afk time counts towards the baseline.
+pub struct EnergyTracker {
+    meter: EnergyMeter,
+    totals_path: PathBuf,
+    totals: EnergyTotals,
+    last_state: State,
+    last_sample: Instant,
+    session: EnergyUsage,
+}
+
+impl EnergyTracker {
+    pub fn new(meter: EnergyMeter, totals_path: PathBuf) -> Self {
+        let totals = EnergyTotals::load(&totals_path);
+        EnergyTracker {
+            meter,
+            totals_path,
+            totals,
+            last_state: State::GameNotRunning,
+            last_sample: Instant::now(),
+            session: EnergyUsage::default(),
+        }
+    }
+
+    pub fn update(&mut self, state: State) {
+        let consumed = match self.meter.consumed_since_last() {
+            Ok(consumed) => consumed,
+            Err(e) => {
+                metrics::BACKEND_ERRORS.increment();
+                debug!("failed to read energy counters: {}", e);
+                return;
+            }
+        };
+        let elapsed = self.last_sample.elapsed();
+        self.last_sample = Instant::now();
+        match self.last_state {
+            State::AfkPending => self.totals.afk_visible.add(consumed, elapsed),
+            State::Minimized => {
+                self.totals.afk_minimized.add(consumed, elapsed);
+                self.session.add(consumed, elapsed);
+            }
+            _ => {}
+        }
+        if self.last_state == State::Minimized && state != State::Minimized {
+            self.finish_session();
+        } else if self.last_state == State::AfkPending && state != State::AfkPending {
+            // the baseline builds up over many short periods, none of them should get lost
+            self.totals.save(&self.totals_path).log_error_and_ignore("failed to save energy totals");
+        }
+        self.last_state = state;
+    }
+
+    fn finish_session(&mut self) {
+        let session = std::mem::take(&mut self.session);
+        let used_wh = session.microjoules as f64 / 3_600_000_000.0;
+        if self.totals.afk_visible.seconds < MINIMUM_BASELINE.as_secs_f64() {
+            info!("afk session: minimized for {:.0} min using {:.2} Wh, not enough data about the game not being minimized yet to estimate savings",
+                  session.seconds / 60.0, used_wh);
+        } else {
+            let baseline_wh = self.totals.afk_visible.watts() * session.seconds / 3600.0;
+            let saved_wh = baseline_wh - used_wh;
+            self.totals.saved_wh += saved_wh;
+            info!("afk session: minimized for {:.0} min using {:.2} Wh, saved {:.2} Wh ({:.2} Wh in total)",
+                  session.seconds / 60.0, used_wh, saved_wh, self.totals.saved_wh);
+        }
+        self.totals.save(&self.totals_path).log_error_and_ignore("failed to save energy totals");
+    }
+}
+
+impl Drop for EnergyTracker {
+    fn drop(&mut self) {
+        self.update(State::GameNotRunning);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::test_directory;
+
+    fn write_zone(powercap_root: &Path, zone: &str, energy_uj: Option<u64>, max_energy_uj: u64) {
+        let directory = powercap_root.join(zone);
+        fs::create_dir_all(&directory).unwrap();
+        fs::write(directory.join("max_energy_range_uj"), format!("{}\n", max_energy_uj)).unwrap();
+        match energy_uj {
+            Some(energy_uj) => fs::write(directory.join("energy_uj"), format!("{}\n", energy_uj)).unwrap(),
+            None => { let _ = fs::remove_file(directory.join("energy_uj")); }
+        }
+    }
+
+    #[test]
+    fn counters_wrap_around() {
+        let root = test_directory("energy-wraparound");
+        write_zone(&root, "intel-rapl:0", Some(900), 1000);
+        write_zone(&root, "intel-rapl:1", Some(10), 1000);
+        // parts of a package are already counted by the package
+        write_zone(&root, "intel-rapl:0:0", Some(500), 1000);
+        let mut meter = EnergyMeter::open(&root).unwrap();
+        assert_eq!(meter.consumed_since_last().unwrap(), 0);
+
+        write_zone(&root, "intel-rapl:0", Some(50), 1000);
+        write_zone(&root, "intel-rapl:1", Some(30), 1000);
+        write_zone(&root, "intel-rapl:0:0", Some(900), 1000);
+        assert_eq!(meter.consumed_since_last().unwrap(), 100 + 50 + 20);
+
+        write_zone(&root, "intel-rapl:0", Some(60), 1000);
+        write_zone(&root, "intel-rapl:1", Some(30), 1000);
+        assert_eq!(meter.consumed_since_last().unwrap(), 10);
+    }
+
+    #[test]
+    fn missing_energy_counter() {
+        let root = test_directory("energy-missing");
+        fs::create_dir_all(&root).unwrap();
+        assert_eq!(EnergyMeter::open(&root).err().map(|e| e.kind()), Some(ErrorKind::NotFound));
+
+        write_zone(&root, "amd-rapl:0", None, 1000);
+        assert_eq!(EnergyMeter::open(&root).err().map(|e| e.kind()), Some(ErrorKind::NotFound));
+
+        write_zone(&root, "amd-rapl:0", Some(1), 1000);
+        let mut meter = EnergyMeter::open(&root).unwrap();
+        write_zone(&root, "amd-rapl:0", None, 1000);
+        assert_eq!(meter.consumed_since_last().unwrap_err().kind(), ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn baseline_covers_only_afk_time_before_minimizing() {
+        let root = test_directory("energy-tracker");
+        write_zone(&root, "intel-rapl:0", Some(0), u64::MAX);
+        let totals_path = root.join("energy.txt");
+        let mut tracker = EnergyTracker::new(EnergyMeter::open(&root).unwrap(), totals_path.clone());
+        let mut counter = 0;
+        // `consumed` is attributed to the state of the previous step
+        let mut step = |tracker: &mut EnergyTracker, consumed: u64, state: State| {
+            counter += consumed;
+            write_zone(&root, "intel-rapl:0", Some(counter), u64::MAX);
+            tracker.update(state);
+        };
+        step(&mut tracker, 0, State::Watching);
+        step(&mut tracker, 70, State::AfkPending);
+        // back before it got minimized
+        step(&mut tracker, 5, State::Watching);
+        assert_eq!(EnergyTotals::load(&totals_path).afk_visible.microjoules, 5);
+        step(&mut tracker, 70, State::AfkPending);
+        step(&mut tracker, 3, State::Minimized);
+        assert_eq!(EnergyTotals::load(&totals_path).afk_visible.microjoules, 8);
+        step(&mut tracker, 2, State::Minimized);
+        step(&mut tracker, 11, State::Watching);
+        step(&mut tracker, 70, State::Paused);
+        step(&mut tracker, 13, State::GameNotRunning);
+        assert_eq!(tracker.totals.afk_visible.microjoules, 5 + 3);
+        assert_eq!(tracker.totals.afk_minimized.microjoules, 2 + 11);
+
+        drop(tracker);
+        let saved = EnergyTotals::load(&totals_path);
+        assert_eq!(saved.afk_visible.microjoules, 8);
+        assert_eq!(saved.afk_minimized.microjoules, 13);
+    }
+}
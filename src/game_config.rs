@@ -0,0 +1,147 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::discovery::{Candidate, home_dir};
+use crate::ini::Ini;
+use crate::language::{ALL_LANGUAGES, Language};
+
+const CONFIG_FILE_NAME: &str = "production_Config.ini";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisplayMode {
+    Windowed,
+    BorderlessFullscreen,
+    ExclusiveFullscreen,
+}
+
+/// The parts of the game's own production_Config.ini we care about.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GameConfig {
+    pub language: Option<Language>,
+    pub display_mode: Option<DisplayMode>,
+}
+
+impl GameConfig {
+    pub fn from_ini(ini: &Ini) -> GameConfig {
+        let language = ini.get("LANGUAGE", "language").and_then(Language::from_code);
+        let fullscreen = ini.get_bool("DISPLAY", "fullscreen");
+        let borderless = ini.get_bool("DISPLAY", "borderless_windowed_fullscreen").unwrap_or(false);
+        let display_mode = fullscreen.map(|fullscreen| {
+            if borderless {
+                DisplayMode::BorderlessFullscreen
+            } else if fullscreen {
+                DisplayMode::ExclusiveFullscreen
+            } else {
+                DisplayMode::Windowed
+            }
+        });
+        GameConfig { language, display_mode }
+    }
+
+    /// Languages whose AFK messages should be looked for, all of them if the language is unknown.
+    pub fn languages(&self) -> Vec<Language> {
+        match self.language {
+            Some(language) => vec![language],
+            None => ALL_LANGUAGES.to_vec(),
+        }
+    }
+}
+
+pub fn load_game_config(installation: &Candidate) -> Option<GameConfig> {
+    let path = find_game_config(installation)?;
+    match Ini::load(&path) {
+        Ok(ini) => {
+            let config = GameConfig::from_ini(&ini);
+            info!("read game config from {:?}: {:?}", path, config);
+            Some(config)
+        }
+        Err(e) => {
+            warn!("failed to read game config {:?}: {}", path, e);
+            None
+        }
+    }
+}
+
+pub fn find_game_config(installation: &Candidate) -> Option<PathBuf> {
+    let documents = match &installation.prefix {
+        Some(prefix) => wine_documents_dirs(prefix),
+        None => documents_dir().into_iter().collect(),
+    };
+    documents.iter()
+        .map(|documents| documents.join("My Games").join("Path of Exile").join(CONFIG_FILE_NAME))
+        .find(|path| path.is_file())
+}
+
+/// Documents folders of every user inside a wine prefix. Proton always uses `steamuser`,
+/// plain wine uses the name of the linux user.
+fn wine_documents_dirs(prefix: &Path) -> Vec<PathBuf> {
+    let users = prefix.join("drive_c").join("users");
+    fs::read_dir(&users).map(|entries| {
+        entries.filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| path.file_name().map(|name| name != "Public").unwrap_or(false))
+            .flat_map(|user| vec![user.join("Documents"), user.join("My Documents")])
+            .collect()
+    }).unwrap_or_default()
+}
+
+#[cfg(windows)]
+fn documents_dir() -> Option<PathBuf> {
+    crate::winutils::read_registry_string("Software\\Microsoft\\Windows\\CurrentVersion\\Explorer\\Shell Folders", "Personal")
+        .ok()
+        .flatten()
+        .map(PathBuf::from)
+        .or_else(|| home_dir().map(|home| home.join("Documents")))
+}
+
+#[cfg(not(windows))]
+fn documents_dir() -> Option<PathBuf> {
+    home_dir().map(|home| home.join("Documents"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::discovery::GameVariant;
+    use crate::utils::test_directory;
+
+    /// Excerpt of a production_Config.ini as written by the game on windows.
+    const PRODUCTION_CONFIG: &str = "\u{feff}[ACCOUNT]\r\nlast_login=exile\r\n\r\n[DISPLAY]\r\nborderless_windowed_fullscreen=true\r\nfullscreen=true\r\nresolution_height=1080\r\n\r\n[LANGUAGE]\r\nlanguage=fr\r\n";
+
+    fn config(text: &str) -> GameConfig {
+        GameConfig::from_ini(&Ini::parse(text))
+    }
+
+    #[test]
+    fn detects_language_and_display_mode() {
+        let detected = config(PRODUCTION_CONFIG);
+        assert_eq!(detected, GameConfig { language: Some(Language::French), display_mode: Some(DisplayMode::BorderlessFullscreen) });
+        assert_eq!(detected.languages(), vec![Language::French]);
+
+        assert_eq!(config("[DISPLAY]\nfullscreen=true\nborderless_windowed_fullscreen=false\n").display_mode, Some(DisplayMode::ExclusiveFullscreen));
+        assert_eq!(config("[DISPLAY]\nfullscreen=false\n").display_mode, Some(DisplayMode::Windowed));
+    }
+
+    #[test]
+    fn missing_sections_fall_back_to_all_languages() {
+        let detected = config("[ACCOUNT]\r\nlast_login=exile\r\n");
+        assert_eq!(detected, GameConfig { language: None, display_mode: None });
+        assert_eq!(detected.languages(), ALL_LANGUAGES.to_vec());
+        assert_eq!(config("[LANGUAGE]\nlanguage=zh\n").languages(), ALL_LANGUAGES.to_vec());
+        assert_eq!(config("[DISPLAY]\r\nresolution_height=1080\r\n").display_mode, None);
+    }
+
+    #[test]
+    fn finds_the_config_in_a_wine_prefix() {
+        let prefix = test_directory("game-config-prefix");
+        let installation = Candidate { variant: GameVariant::Proton, client_log: prefix.join("Client.txt"), prefix: Some(prefix.clone()) };
+        assert_eq!(load_game_config(&installation), None);
+
+        let documents = prefix.join("drive_c").join("users").join("steamuser").join("Documents").join("My Games").join("Path of Exile");
+        fs::create_dir_all(&documents).unwrap();
+        fs::write(documents.join(CONFIG_FILE_NAME), PRODUCTION_CONFIG).unwrap();
+        fs::create_dir_all(prefix.join("drive_c").join("users").join("Public").join("Documents")).unwrap();
+        assert_eq!(find_game_config(&installation), Some(documents.join(CONFIG_FILE_NAME)));
+        assert_eq!(load_game_config(&installation).and_then(|config| config.language), Some(Language::French));
+    }
+}
@@ -0,0 +1,228 @@
+use std::io::{Cursor, Error, ErrorKind};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use crate::control::{Command, Remote};
+use crate::stats;
+use crate::supervisor::Supervisor;
+use crate::utils::*;
+
+/// A json api on localhost for dashboards:
+///
+/// - `GET /api/status` the current state of the game
+/// - `GET /api/stats` the recorded afk sessions with daily and weekly aggregates
+/// - `POST /api/pause`, `/api/resume`, `/api/minimize`, `/api/restore`
+///
+/// `POST /api/pause` takes an optional `{"seconds": 1800}` body like `poe-minimizer pause 30m` does.
+///
+/// Every request has to carry `Authorization: Bearer <token>`.
+pub struct HttpApi {
+    token: String,
+    remote: Remote,
+    sessions_path: PathBuf,
+}
+
+impl HttpApi {
+    pub fn new(token: String, remote: Remote, sessions_path: PathBuf) -> Self {
+        HttpApi { token, remote, sessions_path }
+    }
+
+    /// Binds to `127.0.0.1:<port>` and serves requests on a background thread.
+    pub fn start(self, port: u16, supervisor: &Supervisor) -> Result<(), Error> {
+        if self.token.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidInput, "no token configured, set http.token in the settings"));
+        }
+        let server = Server::http(("127.0.0.1", port))
+            .map_err(|e| Error::new(ErrorKind::AddrInUse, format!("failed to listen on port {}: {}", port, e)))?;
+        info!("http api listening on 127.0.0.1:{}", port);
+        supervisor.spawn("http api", move || {
+            for mut request in server.incoming_requests() {
+                let response = self.handle(&mut request);
+                request.respond(response).log_error_and_ignore("failed to answer http request");
+            }
+        })?;
+        Ok(())
+    }
+
+    fn handle(&self, request: &mut Request) -> Response<Cursor<Vec<u8>>> {
+        if !self.is_authorized(request) {
+            return error_response(401, "missing or wrong token");
+        }
+        let path = request.url().split('?').next().unwrap_or("").to_owned();
+        let route = match path.strip_prefix("/api/") {
+            Some(route) => route,
+            None => return error_response(404, "not found"),
+        };
+        match (request.method().clone(), route) {
+            (Method::Get, "status") => json_response(200, &self.remote.status()),
+            (Method::Get, "stats") => match stats::load_sessions(&self.sessions_path) {
+                Ok(sessions) => json_response(200, &stats::Report::new(&sessions)),
+                Err(e) => error_response(500, &e.to_string()),
+            },
+            (method, name) => match Command::from_name(name) {
+                Some(_) if method != Method::Post => error_response(405, "use POST"),
+                Some(Command::Pause(_)) => match read_pause_duration(request) {
+                    Ok(duration) => self.send(Command::Pause(duration)),
+                    Err(e) => error_response(400, &e.to_string()),
+                },
+                Some(command) => self.send(command),
+                None => error_response(404, "not found"),
+            },
+        }
+    }
+
+    fn send(&self, command: Command) -> Response<Cursor<Vec<u8>>> {
+        match self.remote.send(command) {
+            Ok(()) => json_response(202, &Accepted { accepted: command.name() }),
+            Err(e) => error_response(503, &e.to_string()),
+        }
+    }
+
+    fn is_authorized(&self, request: &Request) -> bool {
+        let expected = format!("Bearer {}", self.token);
+        request.headers().iter()
+            .filter(|header| header.field.equiv("Authorization"))
+            .any(|header| constant_time_equals(header.value.as_str().as_bytes(), expected.as_bytes()))
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PauseBody {
+    seconds: Option<u64>,
+}
+
+/// An empty body pauses until resumed, like `Request::Pause { seconds: None }` over ipc.
+fn read_pause_duration(request: &mut Request) -> Result<Option<Duration>, Error> {
+    let mut body = String::new();
+    request.as_reader().read_to_string(&mut body)?;
+    if body.trim().is_empty() {
+        return Ok(None);
+    }
+    let body: PauseBody = serde_json::from_str(&body)
+        .map_err(|e| Error::new(ErrorKind::InvalidData, format!("expected {{\"seconds\": <number>}}: {}", e)))?;
+    Ok(body.seconds.map(Duration::from_secs))
+}
+
+#[derive(Serialize)]
+struct Accepted {
+    accepted: &'static str,
+}
+
+#[derive(Serialize)]
+struct ErrorMessage<'a> {
+    error: &'a str,
+}
+
+fn json_response<T: Serialize>(status: u16, body: &T) -> Response<Cursor<Vec<u8>>> {
+    let body = serde_json::to_string(body).unwrap_or_default();
+    Response::from_string(body)
+        .with_status_code(status)
+        .with_header(Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).unwrap())
+}
+
+fn error_response(status: u16, message: &str) -> Response<Cursor<Vec<u8>>> {
+    json_response(status, &ErrorMessage { error: message })
+}
+
+/// Compares without returning early, so the token can't be guessed from response times.
+pub fn constant_time_equals(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |difference, (x, y)| difference | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+
+    use super::*;
+    use crate::control::{self, Controller};
+    use crate::shutdown::Shutdown;
+
+    const SESSION: &str = r#"{"start":"2020-02-07T10:00:00+00:00","end":"2020-02-07T10:10:00+00:00","seconds_until_minimized":30.0,"seconds_minimized":540.0,"manual_restores":1,"whispers_received":2,"zone":"Hideout"}"#;
+
+    fn request(port: u16, method: &str, path: &str, authorization: Option<&str>, body: &str) -> (u16, serde_json::Value) {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let authorization = authorization.map(|value| format!("Authorization: {}\r\n", value)).unwrap_or_default();
+        write!(stream, "{} {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nContent-Length: {}\r\n{}\r\n{}", method, path, body.len(), authorization, body).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let status = response.split_whitespace().nth(1).unwrap().parse().unwrap();
+        let body = response.split("\r\n\r\n").nth(1).unwrap_or_default();
+        (status, serde_json::from_str(body).unwrap_or(serde_json::Value::Null))
+    }
+
+    fn start_api(name: &str) -> (u16, Controller, Shutdown) {
+        let directory = test_directory(name);
+        let sessions_path = directory.join("sessions.jsonl");
+        std::fs::write(&sessions_path, format!("{}\n", SESSION)).unwrap();
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let (controller, remote) = control::channel();
+        let shutdown = Shutdown::new();
+        let supervisor = Supervisor::new(remote.clone(), shutdown.clone(), 1);
+        HttpApi::new("secret".to_owned(), remote, sessions_path).start(port, &supervisor).unwrap();
+        (port, controller, shutdown)
+    }
+
+    #[test]
+    fn requests_need_the_token() {
+        let (port, controller, shutdown) = start_api("http-api-token");
+        assert_eq!(request(port, "GET", "/api/status", None, "").0, 401);
+        assert_eq!(request(port, "GET", "/api/status", Some("Bearer secreT"), "").0, 401);
+        assert_eq!(request(port, "GET", "/api/status", Some("secret"), "").0, 401);
+        assert_eq!(request(port, "POST", "/api/minimize", Some("Bearer wrong"), "").0, 401);
+        assert!(controller.pending_commands().is_empty());
+        assert_eq!(request(port, "GET", "/api/status", Some("Bearer secret"), "").0, 200);
+        shutdown.request();
+    }
+
+    #[test]
+    fn routes_commands_and_rejects_wrong_methods() {
+        let (port, controller, shutdown) = start_api("http-api-routes");
+        let token = Some("Bearer secret");
+        assert_eq!(request(port, "GET", "/api/unknown", token, "").0, 404);
+        assert_eq!(request(port, "GET", "/status", token, "").0, 404);
+        assert_eq!(request(port, "GET", "/api/pause", token, "").0, 405);
+        assert_eq!(request(port, "POST", "/api/status", token, "").0, 404);
+
+        let (status, body) = request(port, "POST", "/api/resume", token, "");
+        assert_eq!(status, 202);
+        assert_eq!(body["accepted"], "resume");
+        assert_eq!(request(port, "POST", "/api/minimize?now=1", token, "").0, 202);
+        assert_eq!(request(port, "POST", "/api/pause", token, "").0, 202);
+        assert_eq!(request(port, "POST", "/api/pause", token, r#"{"seconds": 90}"#).0, 202);
+        assert_eq!(request(port, "POST", "/api/pause", token, r#"{"minutes": 90}"#).0, 400);
+        assert_eq!(request(port, "POST", "/api/pause", token, "90").0, 400);
+        assert_eq!(controller.pending_commands(), vec![
+            Command::Resume,
+            Command::MinimizeNow,
+            Command::Pause(None),
+            Command::Pause(Some(Duration::from_secs(90))),
+        ]);
+        shutdown.request();
+    }
+
+    #[test]
+    fn stats_endpoint_reports_the_recorded_sessions() {
+        let (port, _controller, shutdown) = start_api("http-api-stats");
+        let (status, body) = request(port, "GET", "/api/stats", Some("Bearer secret"), "");
+        assert_eq!(status, 200);
+        assert_eq!(body["sessions"].as_array().unwrap().len(), 1);
+        assert_eq!(body["sessions"][0]["zone"], "Hideout");
+        assert_eq!(body["per_day"].as_array().unwrap().len(), 1);
+        assert_eq!(body["per_week"].as_array().unwrap().len(), 1);
+        shutdown.request();
+    }
+
+    #[test]
+    fn refuses_to_start_without_a_token() {
+        let (_controller, remote) = control::channel();
+        let shutdown = Shutdown::new();
+        let supervisor = Supervisor::new(remote.clone(), shutdown.clone(), 1);
+        let error = HttpApi::new(String::new(), remote, PathBuf::new()).start(0, &supervisor).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidInput);
+    }
+}
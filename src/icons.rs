@@ -0,0 +1,58 @@
+use crate::control::Status;
+use crate::state::State;
+
+/// The tray icon variants, compiled into the binary.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrayIcon {
+    GameNotRunning,
+    Watching,
+    AfkPending,
+    Minimized,
+    Paused,
+    Error,
+}
+
+impl TrayIcon {
+    pub fn for_status(status: &Status) -> TrayIcon {
+        if status.error.is_some() || !status.crashed_workers.is_empty() {
+            return TrayIcon::Error;
+        }
+        match status.state {
+            State::GameNotRunning => TrayIcon::GameNotRunning,
+            State::Watching => TrayIcon::Watching,
+            State::AfkPending => TrayIcon::AfkPending,
+            State::Minimized => TrayIcon::Minimized,
+            State::Paused => TrayIcon::Paused,
+        }
+    }
+
+    /// The contents of an .ico file with 32 bit images of several sizes.
+    pub fn ico(&self) -> &'static [u8] {
+        match self {
+            TrayIcon::GameNotRunning => include_bytes!("../assets/icons/game-not-running.ico"),
+            TrayIcon::Watching => include_bytes!("../assets/icons/watching.ico"),
+            TrayIcon::AfkPending => include_bytes!("../assets/icons/afk-pending.ico"),
+            TrayIcon::Minimized => include_bytes!("../assets/icons/minimized.ico"),
+            TrayIcon::Paused => include_bytes!("../assets/icons/paused.ico"),
+            TrayIcon::Error => include_bytes!("../assets/icons/error.ico"),
+        }
+    }
+
+    /// The images of `ico()` as (width, height, bitmap) where the bitmap is a `BITMAPINFOHEADER` followed by the
+    /// pixels and the transparency mask, like in an icon resource.
+    pub fn images(&self) -> Vec<(u32, u32, &'static [u8])> {
+        let ico = self.ico();
+        let read_u32 = |at: usize| u32::from_le_bytes([ico[at], ico[at + 1], ico[at + 2], ico[at + 3]]);
+        let count = u16::from_le_bytes([ico[4], ico[5]]) as usize;
+        (0..count)
+            .filter_map(|index| {
+                let entry = 6 + index * 16;
+                // 0 stands for 256
+                let width = if ico[entry] == 0 { 256 } else { ico[entry] as u32 };
+                let height = if ico[entry + 1] == 0 { 256 } else { ico[entry + 1] as u32 };
+                let (size, offset) = (read_u32(entry + 8) as usize, read_u32(entry + 12) as usize);
+                ico.get(offset..offset + size).map(|bitmap| (width, height, bitmap))
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,137 @@
+use std::io::Error;
+use std::time::Duration;
+
+/// Reports for how long the user has not touched keyboard or mouse.
+pub trait IdleSource {
+    fn idle_time(&mut self) -> Result<Duration, Error>;
+}
+
+/// Picks the best idle source available on this system.
+#[cfg(windows)]
+pub fn system_idle_source() -> Option<Box<dyn IdleSource + Send>> {
+    Some(Box::new(windows::LastInputIdleSource))
+}
+
+/// Picks the best idle source available on this system. X11 knows about input directly,
+/// logind is the fallback for wayland sessions where the compositor reports idleness to it.
+#[cfg(target_os = "linux")]
+pub fn system_idle_source() -> Option<Box<dyn IdleSource + Send>> {
+    use crate::utils::LoggableResult;
+    if std::env::var_os("DISPLAY").is_some() {
+        if let Ok(source) = x11::ScreenSaverIdleSource::connect().log_info("X11 screensaver extension unavailable") {
+            return Some(Box::new(source));
+        }
+    }
+    logind::LogindIdleSource::connect()
+        .log_error("failed to connect to logind, idle detection is disabled")
+        .ok()
+        .map(|source| Box::new(source) as Box<dyn IdleSource + Send>)
+}
+
+#[cfg(windows)]
+mod windows {
+    use std::io::Error;
+    use std::time::Duration;
+
+    use winapi::um::sysinfoapi::GetTickCount;
+    use winapi::um::winuser::{GetLastInputInfo, LASTINPUTINFO};
+
+    use super::IdleSource;
+
+    pub struct LastInputIdleSource;
+
+    impl IdleSource for LastInputIdleSource {
+        fn idle_time(&mut self) -> Result<Duration, Error> {
+            let mut info = LASTINPUTINFO {
+                cbSize: std::mem::size_of::<LASTINPUTINFO>() as u32,
+                dwTime: 0,
+            };
+            if 0 == unsafe { GetLastInputInfo(&mut info) } {
+                return Err(Error::last_os_error());
+            }
+            // both are tick counts that wrap after 49 days, wrapping_sub keeps the difference correct
+            let idle_ms = unsafe { GetTickCount() }.wrapping_sub(info.dwTime);
+            Ok(Duration::from_millis(idle_ms as u64))
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod x11 {
+    use std::io::Error;
+    use std::time::Duration;
+
+    use x11rb::protocol::screensaver;
+    use x11rb::rust_connection::RustConnection;
+
+    use super::IdleSource;
+
+    pub struct ScreenSaverIdleSource {
+        connection: RustConnection,
+        root: u32,
+    }
+
+    impl ScreenSaverIdleSource {
+        pub fn connect() -> Result<Self, Error> {
+            let (connection, screen) = x11rb::connect(None).map_err(to_io_error)?;
+            let root = x11rb::connection::Connection::setup(&connection).roots[screen].root;
+            screensaver::query_version(&connection, 1, 1).map_err(to_io_error)?
+                .reply().map_err(to_io_error)?;
+            Ok(ScreenSaverIdleSource { connection, root })
+        }
+    }
+
+    impl IdleSource for ScreenSaverIdleSource {
+        fn idle_time(&mut self) -> Result<Duration, Error> {
+            let info = screensaver::query_info(&self.connection, self.root).map_err(to_io_error)?
+                .reply().map_err(to_io_error)?;
+            Ok(Duration::from_millis(info.ms_since_user_input as u64))
+        }
+    }
+
+    fn to_io_error<E: std::fmt::Display>(error: E) -> Error {
+        Error::other(format!("X11: {}", error))
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod logind {
+    use std::io::Error;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use dbus::blocking::Connection;
+    use dbus::blocking::stdintf::org_freedesktop_dbus::Properties;
+
+    use super::IdleSource;
+
+    const SESSION_INTERFACE: &str = "org.freedesktop.login1.Session";
+
+    pub struct LogindIdleSource {
+        connection: Connection,
+    }
+
+    impl LogindIdleSource {
+        pub fn connect() -> Result<Self, Error> {
+            let connection = Connection::new_system().map_err(to_io_error)?;
+            Ok(LogindIdleSource { connection })
+        }
+    }
+
+    impl IdleSource for LogindIdleSource {
+        fn idle_time(&mut self) -> Result<Duration, Error> {
+            let session = self.connection.with_proxy("org.freedesktop.login1", "/org/freedesktop/login1/session/auto", Duration::from_secs(1));
+            let idle: bool = session.get(SESSION_INTERFACE, "IdleHint").map_err(to_io_error)?;
+            if !idle {
+                return Ok(Duration::from_secs(0));
+            }
+            // microseconds since the epoch at which the session became idle
+            let idle_since: u64 = session.get(SESSION_INTERFACE, "IdleSinceHint").map_err(to_io_error)?;
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+            Ok(now.checked_sub(Duration::from_micros(idle_since)).unwrap_or_default())
+        }
+    }
+
+    fn to_io_error(error: dbus::Error) -> Error {
+        Error::other(format!("logind: {}", error))
+    }
+}
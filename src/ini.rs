@@ -0,0 +1,102 @@
+use std::fs;
+use std::io::Error;
+use std::path::Path;
+
+/// Just enough of an ini reader for the game's config files: `[section]` headers, `key=value` pairs
+/// and `;`/`#` comments. Section and key lookups are case insensitive.
+#[derive(Debug, Clone, Default)]
+pub struct Ini {
+    sections: Vec<(String, Vec<(String, String)>)>,
+}
+
+impl Ini {
+    pub fn load(path: &Path) -> Result<Ini, Error> {
+        fs::read_to_string(path).map(|text| Ini::parse(&text))
+    }
+
+    pub fn parse(text: &str) -> Ini {
+        let mut sections: Vec<(String, Vec<(String, String)>)> = vec![(String::new(), Vec::new())];
+        for line in text.trim_start_matches('\u{feff}').lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with(';') || line.starts_with('#') {
+                continue;
+            }
+            if line.starts_with('[') && line.ends_with(']') {
+                sections.push((line[1..line.len() - 1].trim().to_owned(), Vec::new()));
+            } else if let Some(separator) = line.find('=') {
+                let key = line[..separator].trim().to_owned();
+                let value = line[separator + 1..].trim().to_owned();
+                sections.last_mut().unwrap().1.push((key, value));
+            }
+        }
+        Ini { sections }
+    }
+
+    pub fn get(&self, section: &str, key: &str) -> Option<&str> {
+        self.sections.iter()
+            .filter(|(name, _)| name.eq_ignore_ascii_case(section))
+            .flat_map(|(_, entries)| entries.iter())
+            .rev() // the last assignment wins
+            .find(|(name, _)| name.eq_ignore_ascii_case(key))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Names of all sections in order of appearance, without duplicates.
+    pub fn section_names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = Vec::new();
+        for (name, _) in self.sections.iter().skip(1) {
+            if !names.iter().any(|known| known.eq_ignore_ascii_case(name)) {
+                names.push(name);
+            }
+        }
+        names
+    }
+
+    pub fn get_bool(&self, section: &str, key: &str) -> Option<bool> {
+        self.get(section, key).and_then(|value| match value.to_ascii_lowercase().as_str() {
+            "true" | "1" | "yes" | "on" => Some(true),
+            "false" | "0" | "no" | "off" => Some(false),
+            _ => None
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sections_keys_and_comments() {
+        let ini = Ini::parse("top=level\n; comment\n# comment=too\n[Display]\nFullscreen = true \nwidth=1920\nbroken line\n[ sound ]\nvolume=0.5=half\n[display]\nwidth=2560\n");
+        assert_eq!(ini.get("", "top"), Some("level"));
+        assert_eq!(ini.get("DISPLAY", "fullscreen"), Some("true"));
+        assert_eq!(ini.get_bool("display", "fullscreen"), Some(true));
+        // the last assignment wins, even in a repeated section
+        assert_eq!(ini.get("display", "width"), Some("2560"));
+        assert_eq!(ini.get("sound", "volume"), Some("0.5=half"));
+        assert_eq!(ini.get("display", "# comment"), None);
+        assert_eq!(ini.get("display", "broken line"), None);
+        assert_eq!(ini.section_names(), vec!["Display", "sound"]);
+    }
+
+    #[test]
+    fn missing_sections_and_keys() {
+        let ini = Ini::parse("[display]\nfullscreen=maybe\n");
+        assert_eq!(ini.get("language", "language"), None);
+        assert_eq!(ini.get("display", "width"), None);
+        assert_eq!(ini.get_bool("display", "fullscreen"), None);
+        assert_eq!(ini.get_bool("display", "vsync"), None);
+        assert!(Ini::parse("").section_names().is_empty());
+        assert_eq!(Ini::parse("[unclosed\nkey=value\n").get("unclosed", "key"), None);
+    }
+
+    #[test]
+    fn crlf_line_endings_and_a_bom() {
+        let ini = Ini::parse("\u{feff}[LANGUAGE]\r\nlanguage=de\r\n\r\n[DISPLAY]\r\nfullscreen=false\r\n");
+        assert_eq!(ini.get("language", "language"), Some("de"));
+        assert_eq!(ini.get_bool("display", "fullscreen"), Some(false));
+        assert_eq!(ini.section_names(), vec!["LANGUAGE", "DISPLAY"]);
+        // a bom in front of a key that is not in a section
+        assert_eq!(Ini::parse("\u{feff}key=value\r\n").get("", "key"), Some("value"));
+    }
+}
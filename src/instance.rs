@@ -0,0 +1,74 @@
+use std::io::Error;
+
+/// Held for the lifetime of the process to keep a second launch from running next to this one.
+/// The operating system releases it when the process dies, no matter how.
+pub struct InstanceLock {
+    _inner: platform::Lock,
+}
+
+/// `None` if another instance holds the lock already.
+pub fn acquire() -> Result<Option<InstanceLock>, Error> {
+    platform::Lock::acquire().map(|lock| lock.map(|inner| InstanceLock { _inner: inner }))
+}
+
+#[cfg(target_os = "linux")]
+mod platform {
+    use std::fs::{File, OpenOptions};
+    use std::io::Error;
+    use std::os::unix::io::AsRawFd;
+
+    use crate::utils::runtime_path;
+
+    pub struct Lock {
+        _file: File,
+    }
+
+    impl Lock {
+        pub fn acquire() -> Result<Option<Lock>, Error> {
+            let file = OpenOptions::new().create(true).truncate(false).write(true).open(runtime_path("poe-minimizer.lock"))?;
+            if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+                let error = Error::last_os_error();
+                return if error.raw_os_error() == Some(libc::EWOULDBLOCK) { Ok(None) } else { Err(error) };
+            }
+            Ok(Some(Lock { _file: file }))
+        }
+    }
+}
+
+#[cfg(windows)]
+mod platform {
+    use std::io::Error;
+    use std::ptr::null_mut;
+
+    use widestring::U16CString;
+    use winapi::shared::winerror::ERROR_ALREADY_EXISTS;
+    use winapi::um::handleapi::CloseHandle;
+    use winapi::um::synchapi::CreateMutexW;
+    use winapi::um::winnt::HANDLE;
+
+    /// A named mutex, its existence is the lock.
+    pub struct Lock {
+        handle: HANDLE,
+    }
+
+    impl Lock {
+        pub fn acquire() -> Result<Option<Lock>, Error> {
+            let name = U16CString::from_str("Local\\poe-minimizer").unwrap();
+            let handle = unsafe { CreateMutexW(null_mut(), 0, name.as_ptr()) };
+            if handle.is_null() {
+                return Err(Error::last_os_error());
+            }
+            if Error::last_os_error().raw_os_error() == Some(ERROR_ALREADY_EXISTS as i32) {
+                unsafe { CloseHandle(handle) };
+                return Ok(None);
+            }
+            Ok(Some(Lock { handle }))
+        }
+    }
+
+    impl Drop for Lock {
+        fn drop(&mut self) {
+            unsafe { CloseHandle(self.handle) };
+        }
+    }
+}
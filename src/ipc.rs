@@ -0,0 +1,493 @@
+use std::io::{BufRead, BufReader, Error, ErrorKind, Read, Write};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::control::{self, Command, Remote};
+use crate::supervisor::Supervisor;
+
+/// Bumped on incompatible changes, both sides reject other versions.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// One line of json per request and response, e.g. `{"version":1,"command":"pause","seconds":1800}`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "kebab-case")]
+pub enum Request {
+    Status,
+    Pause { seconds: Option<u64> },
+    Resume,
+    Minimize,
+    Restore,
+    ReloadConfig,
+    /// sent by a second launch before it exits, carries its command line arguments
+    Launch { args: Vec<String> },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Response {
+    pub ok: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<serde_json::Value>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Message<T> {
+    version: u32,
+    #[serde(flatten)]
+    body: T,
+}
+
+impl Response {
+    fn error(message: String) -> Self {
+        Response { ok: false, error: Some(message), status: None }
+    }
+}
+
+/// Parses the command line of a client invocation like `pause 30m`, `None` if it is no ipc command.
+pub fn parse_command_line(args: &[String]) -> Option<Result<Request, Error>> {
+    let request = match args.first()?.as_str() {
+        "status" => Request::Status,
+        "pause" => match args.get(1) {
+            None => Request::Pause { seconds: None },
+            Some(duration) => match control::parse_duration(duration) {
+                Some(duration) => Request::Pause { seconds: Some(duration.as_secs()) },
+                None => return Some(Err(Error::new(ErrorKind::InvalidInput, format!("invalid duration {:?}, use e.g. 90s, 30m or 2h", duration)))),
+            },
+        },
+        "resume" => Request::Resume,
+        "minimize" => Request::Minimize,
+        "restore" => Request::Restore,
+        "reload-config" => Request::ReloadConfig,
+        _ => return None,
+    };
+    Some(Ok(request))
+}
+
+/// Sends a request to the running instance.
+pub fn send(request: &Request) -> Result<Response, Error> {
+    let connection = platform::connect().map_err(|e| match e.kind() {
+        ErrorKind::NotFound | ErrorKind::ConnectionRefused => Error::new(e.kind(), "poe-minimizer is not running"),
+        _ => e,
+    })?;
+    exchange(connection, request).map_err(|e| match e.kind() {
+        // a read timeout is WouldBlock on unix
+        ErrorKind::WouldBlock | ErrorKind::TimedOut => Error::new(ErrorKind::TimedOut, "the running instance did not answer in time"),
+        _ => e,
+    })
+}
+
+fn exchange<C: Read + Write>(mut connection: C, request: &Request) -> Result<Response, Error> {
+    let line = serde_json::to_string(&Message { version: PROTOCOL_VERSION, body: request })?;
+    connection.write_all(format!("{}\n", line).as_bytes())?;
+    connection.flush()?;
+    let mut answer = String::new();
+    BufReader::new(connection).read_line(&mut answer)?;
+    let answer: Message<Response> = serde_json::from_str(&answer)
+        .map_err(|e| Error::new(ErrorKind::InvalidData, format!("invalid answer from the running instance: {}", e)))?;
+    if answer.version != PROTOCOL_VERSION {
+        return Err(Error::new(ErrorKind::InvalidData, format!(
+            "the running instance speaks protocol version {}, this one {}", answer.version, PROTOCOL_VERSION)));
+    }
+    Ok(answer.body)
+}
+
+/// Listens for requests on a unix socket or named pipe and forwards them to the service loop.
+/// Fails with `AddrInUse` if another instance is listening already.
+pub fn start_server(remote: Remote, supervisor: &Supervisor) -> Result<(), Error> {
+    let mut listener = platform::Listener::bind()?;
+    let shutdown = supervisor.shutdown().clone();
+    supervisor.spawn("ipc", move || loop {
+        match listener.accept() {
+            Ok(connection) => {
+                if let Err(e) = serve(connection, &remote) {
+                    info!("ipc connection failed: {}", e);
+                }
+            }
+            Err(e) => {
+                error!("failed to accept ipc connection: {}", e);
+                if shutdown.wait(Duration::from_secs(1)) {
+                    return;
+                }
+            }
+        }
+    })?;
+    Ok(())
+}
+
+fn serve<C: Read + Write>(connection: C, remote: &Remote) -> Result<(), Error> {
+    let mut reader = BufReader::new(connection);
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 0 {
+        let response = handle(line.trim(), remote);
+        let answer = serde_json::to_string(&Message { version: PROTOCOL_VERSION, body: response })?;
+        let connection = reader.get_mut();
+        connection.write_all(format!("{}\n", answer).as_bytes())?;
+        connection.flush()?;
+        line.clear();
+    }
+    Ok(())
+}
+
+fn handle(line: &str, remote: &Remote) -> Response {
+    let version = serde_json::from_str::<serde_json::Value>(line).ok()
+        .and_then(|message| message.get("version").and_then(|version| version.as_u64()));
+    if version != Some(PROTOCOL_VERSION as u64) {
+        return Response::error(format!("unsupported protocol version {:?}, expected {}", version, PROTOCOL_VERSION));
+    }
+    let request = match serde_json::from_str::<Message<Request>>(line) {
+        Ok(message) => message.body,
+        Err(e) => return Response::error(format!("invalid request: {}", e)),
+    };
+    debug!("ipc request {:?}", request);
+    let command = match request {
+        Request::Status => {
+            return match serde_json::to_value(remote.status()) {
+                Ok(status) => Response { ok: true, error: None, status: Some(status) },
+                Err(e) => Response::error(e.to_string()),
+            };
+        }
+        Request::Pause { seconds } => Command::Pause(seconds.map(Duration::from_secs)),
+        Request::Resume => Command::Resume,
+        Request::Minimize => Command::MinimizeNow,
+        Request::Restore => Command::Restore,
+        Request::ReloadConfig => Command::ReloadConfig,
+        Request::Launch { args } if args.is_empty() => {
+            info!("another launch was handed off to this instance");
+            return Response { ok: true, error: None, status: None };
+        }
+        Request::Launch { args } => {
+            // the log level, the only argument of a regular launch, can't change at runtime
+            info!("another launch with arguments {:?} was handed off to this instance, ignoring them", args);
+            return Response::error(format!("the arguments {} are ignored while an instance is running, restart it to apply them", args.join(" ")));
+        }
+    };
+    match remote.send(command) {
+        Ok(()) => Response { ok: true, error: None, status: None },
+        Err(e) => Response::error(e.to_string()),
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod platform {
+    use std::io::{Error, ErrorKind};
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    /// a peer that never sends or reads its line must not block the other side
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    pub struct Listener {
+        listener: UnixListener,
+    }
+
+    impl Listener {
+        pub fn bind() -> Result<Listener, Error> {
+            let path = socket_path();
+            if path.exists() {
+                if UnixStream::connect(&path).is_ok() {
+                    return Err(Error::new(ErrorKind::AddrInUse, format!("another instance is listening on {:?}", path)));
+                }
+                // left behind by an instance that was killed
+                std::fs::remove_file(&path)?;
+            }
+            let listener = UnixListener::bind(&path)?;
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))?;
+            Ok(Listener { listener })
+        }
+
+        pub fn accept(&mut self) -> Result<UnixStream, Error> {
+            let (stream, _) = self.listener.accept()?;
+            set_timeouts(&stream)?;
+            Ok(stream)
+        }
+    }
+
+    pub fn connect() -> Result<UnixStream, Error> {
+        let stream = UnixStream::connect(socket_path())?;
+        set_timeouts(&stream)?;
+        Ok(stream)
+    }
+
+    fn set_timeouts(stream: &UnixStream) -> Result<(), Error> {
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))
+    }
+
+    fn socket_path() -> PathBuf {
+        crate::utils::runtime_path("poe-minimizer.sock")
+    }
+}
+
+#[cfg(windows)]
+mod platform {
+    use std::fs::OpenOptions;
+    use std::io::{Error, ErrorKind, Read, Write};
+    use std::os::windows::fs::OpenOptionsExt;
+    use std::os::windows::io::IntoRawHandle;
+    use std::ptr::null_mut;
+
+    use widestring::U16CString;
+    use winapi::shared::minwindef::{BOOL, DWORD, FALSE, TRUE};
+    use winapi::shared::winerror::{ERROR_ACCESS_DENIED, ERROR_BROKEN_PIPE, ERROR_IO_PENDING, ERROR_PIPE_CONNECTED, WAIT_TIMEOUT};
+    use winapi::um::fileapi::{FlushFileBuffers, ReadFile, WriteFile};
+    use winapi::um::handleapi::{CloseHandle, INVALID_HANDLE_VALUE};
+    use winapi::um::ioapiset::{CancelIo, GetOverlappedResult};
+    use winapi::um::minwinbase::OVERLAPPED;
+    use winapi::um::namedpipeapi::{ConnectNamedPipe, CreateNamedPipeW, DisconnectNamedPipe};
+    use winapi::um::synchapi::{CreateEventW, WaitForSingleObject};
+    use winapi::um::winbase::{FILE_FLAG_FIRST_PIPE_INSTANCE, FILE_FLAG_OVERLAPPED, INFINITE, PIPE_ACCESS_DUPLEX, PIPE_READMODE_BYTE, PIPE_REJECT_REMOTE_CLIENTS, PIPE_TYPE_BYTE, PIPE_UNLIMITED_INSTANCES, PIPE_WAIT};
+    use winapi::um::winnt::HANDLE;
+
+    const BUFFER_SIZE: u32 = 4096;
+    /// a peer that never sends or reads its line must not block the other side
+    const TIMEOUT_MS: DWORD = 5000;
+
+    /// Holds the pipe instance the next client connects to.
+    pub struct Listener {
+        name: U16CString,
+        next: Pipe,
+    }
+
+    // pipe handles may be used from any thread
+    unsafe impl Send for Listener {}
+
+    impl Listener {
+        pub fn bind() -> Result<Listener, Error> {
+            let name = U16CString::from_str(pipe_name()).unwrap();
+            let next = create_pipe(&name, FILE_FLAG_FIRST_PIPE_INSTANCE).map_err(|e| {
+                if e.raw_os_error() == Some(ERROR_ACCESS_DENIED as i32) {
+                    Error::new(ErrorKind::AddrInUse, format!("another instance is listening on {}", pipe_name()))
+                } else {
+                    e
+                }
+            })?;
+            Ok(Listener { name, next })
+        }
+
+        pub fn accept(&mut self) -> Result<Pipe, Error> {
+            let pipe = &self.next;
+            match pipe.overlapped(INFINITE, |overlapped| unsafe { ConnectNamedPipe(pipe.handle, overlapped) }) {
+                Ok(_) => {}
+                // the client connected between creating the pipe and this call
+                Err(e) if e.raw_os_error() == Some(ERROR_PIPE_CONNECTED as i32) => {}
+                Err(e) => return Err(e),
+            }
+            let next = create_pipe(&self.name, 0)?;
+            // the connected instance is handed out, the new one waits for the next client
+            Ok(std::mem::replace(&mut self.next, next))
+        }
+    }
+
+    /// A pipe instance opened for overlapped io, which is the only way to give up on a read.
+    pub struct Pipe {
+        handle: HANDLE,
+        /// signaled when an operation completes
+        event: HANDLE,
+        /// only the server end is disconnected before closing
+        server: bool,
+    }
+
+    impl Pipe {
+        /// Starts an operation and waits up to `timeout_ms` for it to complete, returning the bytes transferred.
+        fn overlapped<F: FnOnce(*mut OVERLAPPED) -> BOOL>(&self, timeout_ms: DWORD, start: F) -> Result<usize, Error> {
+            let mut overlapped: OVERLAPPED = unsafe { std::mem::zeroed() };
+            overlapped.hEvent = self.event;
+            let mut transferred = 0;
+            unsafe {
+                if start(&mut overlapped) == 0 {
+                    let error = Error::last_os_error();
+                    if error.raw_os_error() != Some(ERROR_IO_PENDING as i32) {
+                        return Err(error);
+                    }
+                    if WaitForSingleObject(self.event, timeout_ms) == WAIT_TIMEOUT {
+                        // the operation must be over before `overlapped` goes out of scope
+                        CancelIo(self.handle);
+                        GetOverlappedResult(self.handle, &mut overlapped, &mut transferred, TRUE);
+                        return Err(Error::new(ErrorKind::TimedOut, "the ipc client did not answer in time"));
+                    }
+                }
+                if GetOverlappedResult(self.handle, &mut overlapped, &mut transferred, TRUE) == 0 {
+                    return Err(Error::last_os_error());
+                }
+            }
+            Ok(transferred as usize)
+        }
+    }
+
+    impl Read for Pipe {
+        fn read(&mut self, buffer: &mut [u8]) -> Result<usize, Error> {
+            let handle = self.handle;
+            let length = buffer.len() as u32;
+            match self.overlapped(TIMEOUT_MS, |overlapped| unsafe { ReadFile(handle, buffer.as_mut_ptr() as *mut _, length, null_mut(), overlapped) }) {
+                // the client closed its end
+                Err(e) if e.raw_os_error() == Some(ERROR_BROKEN_PIPE as i32) => Ok(0),
+                result => result,
+            }
+        }
+    }
+
+    impl Write for Pipe {
+        fn write(&mut self, buffer: &[u8]) -> Result<usize, Error> {
+            let handle = self.handle;
+            self.overlapped(TIMEOUT_MS, |overlapped| unsafe { WriteFile(handle, buffer.as_ptr() as *const _, buffer.len() as u32, null_mut(), overlapped) })
+        }
+
+        fn flush(&mut self) -> Result<(), Error> {
+            if unsafe { FlushFileBuffers(self.handle) } == 0 {
+                return Err(Error::last_os_error());
+            }
+            Ok(())
+        }
+    }
+
+    impl Drop for Pipe {
+        fn drop(&mut self) {
+            unsafe {
+                if self.server {
+                    DisconnectNamedPipe(self.handle);
+                }
+                CloseHandle(self.handle);
+                CloseHandle(self.event);
+            }
+        }
+    }
+
+    fn create_pipe(name: &U16CString, flags: u32) -> Result<Pipe, Error> {
+        let handle = unsafe {
+            CreateNamedPipeW(name.as_ptr(),
+                             PIPE_ACCESS_DUPLEX | FILE_FLAG_OVERLAPPED | flags,
+                             PIPE_TYPE_BYTE | PIPE_READMODE_BYTE | PIPE_WAIT | PIPE_REJECT_REMOTE_CLIENTS,
+                             PIPE_UNLIMITED_INSTANCES,
+                             BUFFER_SIZE,
+                             BUFFER_SIZE,
+                             0,
+                             null_mut())
+        };
+        if handle == INVALID_HANDLE_VALUE {
+            return Err(Error::last_os_error());
+        }
+        with_event(handle, true)
+    }
+
+    /// Opens the client end overlapped as well, so it can give up on an instance that doesn't answer.
+    pub fn connect() -> Result<Pipe, Error> {
+        let file = OpenOptions::new().read(true).write(true).custom_flags(FILE_FLAG_OVERLAPPED).open(pipe_name())?;
+        with_event(file.into_raw_handle() as HANDLE, false)
+    }
+
+    fn with_event(handle: HANDLE, server: bool) -> Result<Pipe, Error> {
+        let event = unsafe { CreateEventW(null_mut(), TRUE, FALSE, null_mut()) };
+        if event.is_null() {
+            let error = Error::last_os_error();
+            unsafe { CloseHandle(handle) };
+            return Err(error);
+        }
+        Ok(Pipe { handle, event, server })
+    }
+
+    /// Pipes are machine wide, the user name keeps instances of different users apart.
+    fn pipe_name() -> String {
+        format!(r"\\.\pipe\poe-minimizer-{}", std::env::var("USERNAME").unwrap_or_default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(str::to_owned).collect()
+    }
+
+    fn request_line(request: &Request) -> String {
+        serde_json::to_string(&Message { version: PROTOCOL_VERSION, body: request }).unwrap()
+    }
+
+    #[test]
+    fn parses_client_command_lines() {
+        assert_eq!(parse_command_line(&args("status")).unwrap().unwrap(), Request::Status);
+        assert_eq!(parse_command_line(&args("pause")).unwrap().unwrap(), Request::Pause { seconds: None });
+        assert_eq!(parse_command_line(&args("pause 30m")).unwrap().unwrap(), Request::Pause { seconds: Some(1800) });
+        assert_eq!(parse_command_line(&args("pause 90s")).unwrap().unwrap(), Request::Pause { seconds: Some(90) });
+        assert_eq!(parse_command_line(&args("pause soon")).unwrap().unwrap_err().kind(), ErrorKind::InvalidInput);
+        assert_eq!(parse_command_line(&args("resume")).unwrap().unwrap(), Request::Resume);
+        assert_eq!(parse_command_line(&args("minimize")).unwrap().unwrap(), Request::Minimize);
+        assert_eq!(parse_command_line(&args("restore")).unwrap().unwrap(), Request::Restore);
+        assert_eq!(parse_command_line(&args("reload-config")).unwrap().unwrap(), Request::ReloadConfig);
+        assert!(parse_command_line(&args("debug")).is_none());
+        assert!(parse_command_line(&[]).is_none());
+    }
+
+    #[test]
+    fn requests_are_forwarded_as_commands() {
+        let (controller, remote) = control::channel();
+        let requests = [
+            (Request::Pause { seconds: None }, Command::Pause(None)),
+            (Request::Pause { seconds: Some(90) }, Command::Pause(Some(Duration::from_secs(90)))),
+            (Request::Resume, Command::Resume),
+            (Request::Minimize, Command::MinimizeNow),
+            (Request::Restore, Command::Restore),
+            (Request::ReloadConfig, Command::ReloadConfig),
+        ];
+        for (request, command) in requests.iter() {
+            assert_eq!(handle(&request_line(request), &remote), Response { ok: true, error: None, status: None });
+            assert_eq!(controller.pending_commands(), vec![*command]);
+        }
+
+        let response = handle(&request_line(&Request::Launch { args: Vec::new() }), &remote);
+        assert_eq!(response, Response { ok: true, error: None, status: None });
+        let response = handle(&request_line(&Request::Launch { args: vec!["debug".to_owned()] }), &remote);
+        assert!(!response.ok);
+        assert!(response.error.unwrap().contains("debug are ignored while an instance is running"));
+        assert!(controller.pending_commands().is_empty());
+
+        let response = handle(&request_line(&Request::Status), &remote);
+        assert!(response.ok);
+        assert_eq!(response.status.unwrap()["state"], serde_json::to_value(remote.status().state).unwrap());
+        assert!(controller.pending_commands().is_empty());
+
+        drop(controller);
+        let response = handle(&request_line(&Request::Resume), &remote);
+        assert!(!response.ok);
+        assert!(response.error.is_some());
+    }
+
+    #[test]
+    fn other_protocol_versions_are_rejected() {
+        let (controller, remote) = control::channel();
+        for line in [r#"{"version":2,"command":"resume"}"#, r#"{"command":"resume"}"#, "resume"].iter() {
+            let response = handle(line, &remote);
+            assert!(!response.ok);
+            assert!(response.error.unwrap().contains("unsupported protocol version"));
+        }
+        let response = handle(r#"{"version":1,"command":"shutdown"}"#, &remote);
+        assert!(response.error.unwrap().contains("invalid request"));
+        assert!(controller.pending_commands().is_empty());
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn client_rejects_other_protocol_versions() {
+        let (client, mut server) = std::os::unix::net::UnixStream::pair().unwrap();
+        server.write_all(b"{\"version\":2,\"ok\":true}\n").unwrap();
+        let error = exchange(client, &Request::Resume).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+        assert!(error.to_string().contains("protocol version 2"), "{}", error);
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn client_and_server_talk_over_a_connection() {
+        let (controller, remote) = control::channel();
+        let (client, server) = std::os::unix::net::UnixStream::pair().unwrap();
+        let server = std::thread::spawn(move || serve(server, &remote));
+        let response = exchange(client, &Request::Pause { seconds: Some(60) }).unwrap();
+        assert!(response.ok);
+        server.join().unwrap().unwrap();
+        assert_eq!(controller.pending_commands(), vec![Command::Pause(Some(Duration::from_secs(60)))]);
+    }
+}
@@ -0,0 +1,114 @@
+/// Client languages Path of Exile writes localized system messages in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Language {
+    English,
+    French,
+    German,
+    Portuguese,
+    Russian,
+    Thai,
+    Spanish,
+    Korean,
+}
+
+pub const ALL_LANGUAGES: [Language; 8] = [
+    Language::English,
+    Language::French,
+    Language::German,
+    Language::Portuguese,
+    Language::Russian,
+    Language::Thai,
+    Language::Spanish,
+    Language::Korean,
+];
+
+impl Language {
+    /// Maps the value of `language` in production_Config.ini, e.g. `en` or `pt-BR`.
+    pub fn from_code(code: &str) -> Option<Language> {
+        let code = code.trim().to_ascii_lowercase();
+        let primary = code.split(&['-', '_'][..]).next().unwrap_or("");
+        match primary {
+            "en" => Some(Language::English),
+            "fr" => Some(Language::French),
+            "de" => Some(Language::German),
+            "pt" => Some(Language::Portuguese),
+            "ru" => Some(Language::Russian),
+            "th" => Some(Language::Thai),
+            "es" => Some(Language::Spanish),
+            "ko" => Some(Language::Korean),
+            _ => None
+        }
+    }
+
+    pub fn afk_activated_message(&self) -> &'static str {
+        match self {
+            Language::English => ": AFK mode is now ON.",
+            Language::French => ": Le mode Absent (AFK) est désormais activé.",
+            Language::German => ": AFK-Modus ist nun AN.",
+            Language::Portuguese => ": Modo LDT Ativado.",
+            Language::Russian => ": Режим \"отошёл\" включён.",
+            Language::Thai => ": เปิดโหมด AFK แล้ว ตอบกลับอัตโนมัติ",
+            Language::Spanish => ": El modo Ausente está habilitado.",
+            Language::Korean => ": 자리 비움 모드를 설정했습니다.",
+        }
+    }
+
+    pub fn afk_deactivated_message(&self) -> &'static str {
+        match self {
+            Language::English => ": AFK mode is now OFF.",
+            Language::French => ": Le mode Absent (AFK) est désactivé.",
+            Language::German => ": AFK-Modus ist nun AUS.",
+            Language::Portuguese => ": Modo LDT Desativado.",
+            Language::Russian => ": Режим \"отошёл\" выключен.",
+            Language::Thai => ": ปิดโหมด AFK แล้ว",
+            Language::Spanish => ": El modo Ausente está deshabilitado.",
+            Language::Korean => ": 자리 비움 모드를 해제했습니다.",
+        }
+    }
+
+    /// End of the system message announcing a party invite, `: Name has invited you to a party.`.
+    /// Only known for English so far.
+    pub fn party_invite_suffix(&self) -> Option<&'static str> {
+        match self {
+            Language::English => Some(" has invited you to a party."),
+            _ => None,
+        }
+    }
+
+    /// Part of the whisper the official trade site generates in this language.
+    pub fn trade_request_marker(&self) -> &'static str {
+        match self {
+            Language::English => "I would like to buy your",
+            Language::French => "je souhaiterais t'acheter",
+            Language::German => "Hi, ich möchte",
+            Language::Portuguese => "gostaria de comprar",
+            Language::Russian => "хочу купить",
+            Language::Thai => "ต้องการจะชื้อ",
+            Language::Spanish => "quisiera comprar",
+            Language::Korean => "구매하고 싶습니다",
+        }
+    }
+}
+
+/// Whether a whisper is a trade request. Buyers use the trade site in their own language, so all are checked.
+pub fn is_trade_request(message: &str) -> bool {
+    // the bulk exchange words it differently than single items
+    message.contains("I'd like to buy your") ||
+        ALL_LANGUAGES.iter().any(|language| message.contains(language.trade_request_marker()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn language_codes() {
+        assert_eq!(Language::from_code("en"), Some(Language::English));
+        assert_eq!(Language::from_code("pt-BR"), Some(Language::Portuguese));
+        assert_eq!(Language::from_code("PT_br"), Some(Language::Portuguese));
+        assert_eq!(Language::from_code(" de\r"), Some(Language::German));
+        assert_eq!(Language::from_code("ko"), Some(Language::Korean));
+        assert_eq!(Language::from_code("zh-TW"), None);
+        assert_eq!(Language::from_code(""), None);
+    }
+}
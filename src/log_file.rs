@@ -0,0 +1,263 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Error, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+use crate::settings::Settings;
+
+/// When to start a new log file and how many old ones to keep, from the `[log]` section.
+#[derive(Debug, Clone)]
+pub struct Rotation {
+    /// 0 for no limit
+    pub max_bytes: u64,
+    pub max_age: Option<Duration>,
+    /// number of rotated files kept besides the current one
+    pub keep: usize,
+    /// rotated files older than this are deleted regardless of `keep`
+    pub keep_for: Option<Duration>,
+    /// gzip rotated files
+    pub compress: bool,
+}
+
+impl Rotation {
+    pub fn from_settings(settings: &Settings) -> Self {
+        let hours = |hours: u64| if hours == 0 { None } else { Some(Duration::from_secs(hours * 60 * 60)) };
+        Rotation {
+            max_bytes: settings.log_max_size_mb * 1024 * 1024,
+            max_age: hours(settings.log_rotate_after_hours),
+            keep: settings.log_keep_files,
+            keep_for: hours(settings.log_keep_days * 24),
+            compress: settings.log_compress,
+        }
+    }
+}
+
+/// `log.txt` that moves to `log.1.txt` when it gets too large or too old, `log.1.txt` moves to `log.2.txt` and so on.
+/// The log of the previous run is rotated at start so it survives a restart.
+pub struct RotatingFile {
+    directory: PathBuf,
+    rotation: Rotation,
+    /// unbuffered, so nothing is lost when the process dies
+    file: File,
+    written: u64,
+    created: SystemTime,
+    /// records arrive in several writes, they are not split across files
+    at_line_start: bool,
+    /// why the log of the previous run could not be rotated, to be logged once logging works
+    rotation_error: Option<Error>,
+}
+
+impl RotatingFile {
+    pub fn open(directory: &Path, rotation: Rotation) -> Result<RotatingFile, Error> {
+        fs::create_dir_all(directory)?;
+        let path = directory.join(file_name(0, false));
+        let previous_run = fs::metadata(&path).map(|metadata| metadata.len() > 0).unwrap_or(false);
+        let rotated = if previous_run {
+            rotate_files(directory, &rotation)
+        } else {
+            delete_expired(directory, &rotation);
+            Ok(())
+        };
+        let file = match rotated {
+            Ok(()) => File::create(&path)?,
+            // rather than losing the log of the previous run, this one is appended to it
+            Err(_) => OpenOptions::new().create(true).append(true).open(&path)?,
+        };
+        Ok(RotatingFile {
+            written: file.metadata()?.len(),
+            file,
+            directory: directory.to_owned(),
+            rotation,
+            created: SystemTime::now(),
+            at_line_start: true,
+            rotation_error: rotated.err(),
+        })
+    }
+
+    /// The error that kept `open` from rotating the log of the previous run.
+    pub fn take_rotation_error(&mut self) -> Option<Error> {
+        self.rotation_error.take()
+    }
+
+    fn needs_rotation(&self, incoming: usize) -> bool {
+        if self.written == 0 || !self.at_line_start {
+            return false;
+        }
+        let too_large = self.rotation.max_bytes > 0 && self.written + incoming as u64 > self.rotation.max_bytes;
+        let too_old = self.rotation.max_age
+            .map(|max_age| self.created.elapsed().map(|age| age >= max_age).unwrap_or(false))
+            .unwrap_or(false);
+        too_large || too_old
+    }
+
+    fn rotate(&mut self) -> Result<(), Error> {
+        self.file.flush()?;
+        rotate_files(&self.directory, &self.rotation)?;
+        self.file = File::create(self.directory.join(file_name(0, false)))?;
+        self.written = 0;
+        self.created = SystemTime::now();
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.needs_rotation(buf.len()) {
+            if let Err(e) = self.rotate() {
+                self.written = 0;
+                // logging from here would wait for the logger this write is part of
+                let _ = thread::Builder::new()
+                    .name("log rotation".to_owned())
+                    .spawn(move || error!("failed to rotate the log: {}", e));
+            }
+        }
+        let written = self.file.write(buf)?;
+        self.written += written as u64;
+        if written > 0 {
+            self.at_line_start = buf[written - 1] == b'\n';
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// `log.txt` for 0, `log.<index>.txt` or `log.<index>.txt.gz` for rotated files.
+fn file_name(index: usize, compressed: bool) -> String {
+    match (index, compressed) {
+        (0, _) => "log.txt".to_owned(),
+        (index, false) => format!("log.{}.txt", index),
+        (index, true) => format!("log.{}.txt.gz", index),
+    }
+}
+
+/// `log.txt` and the rotated logs.
+pub fn is_log_file(file_name: &str) -> bool {
+    let index = file_name.strip_prefix("log.")
+        .and_then(|rest| rest.strip_suffix(".txt.gz").or_else(|| rest.strip_suffix(".txt")));
+    match index {
+        Some(index) => index.parse::<usize>().is_ok(),
+        None => file_name == "log.txt",
+    }
+}
+
+fn rotate_files(directory: &Path, rotation: &Rotation) -> Result<(), Error> {
+    let current = directory.join(file_name(0, false));
+    if rotation.keep == 0 {
+        return remove_if_exists(&current);
+    }
+    for compressed in &[false, true] {
+        remove_if_exists(&directory.join(file_name(rotation.keep, *compressed)))?;
+        for index in (1..rotation.keep).rev() {
+            let from = directory.join(file_name(index, *compressed));
+            if from.exists() {
+                fs::rename(&from, directory.join(file_name(index + 1, *compressed)))?;
+            }
+        }
+    }
+    let rotated = directory.join(file_name(1, false));
+    fs::rename(&current, &rotated)?;
+    if rotation.compress {
+        compress(&rotated, &directory.join(file_name(1, true)))?;
+        fs::remove_file(&rotated)?;
+    }
+    delete_expired(directory, rotation);
+    Ok(())
+}
+
+fn compress(from: &Path, to: &Path) -> Result<(), Error> {
+    let mut encoder = GzEncoder::new(BufWriter::new(File::create(to)?), Compression::default());
+    io::copy(&mut BufReader::new(File::open(from)?), &mut encoder)?;
+    encoder.finish()?.flush()
+}
+
+fn delete_expired(directory: &Path, rotation: &Rotation) {
+    let keep_for = match rotation.keep_for {
+        Some(keep_for) => keep_for,
+        None => return,
+    };
+    for index in 1..=rotation.keep {
+        for compressed in &[false, true] {
+            let path = directory.join(file_name(index, *compressed));
+            let expired = fs::metadata(&path)
+                .and_then(|metadata| metadata.modified())
+                .map(|modified| modified.elapsed().map(|age| age > keep_for).unwrap_or(false))
+                .unwrap_or(false);
+            if expired {
+                let _ = fs::remove_file(&path);
+            }
+        }
+    }
+}
+
+fn remove_if_exists(path: &Path) -> Result<(), Error> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::test_directory;
+
+    fn rotation(max_bytes: u64) -> Rotation {
+        Rotation { max_bytes, max_age: None, keep: 2, keep_for: None, compress: false }
+    }
+
+    fn read(directory: &Path, index: usize) -> String {
+        fs::read_to_string(directory.join(file_name(index, false))).unwrap_or_default()
+    }
+
+    /// Rotating fails once `log.2.txt`, the oldest file kept, can't be removed.
+    fn block_rotation(directory: &Path) {
+        fs::create_dir_all(directory.join("log.2.txt").join("blocker")).unwrap();
+    }
+
+    #[test]
+    fn rotates_by_size_and_at_start() {
+        let directory = test_directory("log-rotation");
+        fs::write(directory.join("log.txt"), "previous run\n").unwrap();
+        let mut log = RotatingFile::open(&directory, rotation(10)).unwrap();
+        assert!(log.take_rotation_error().is_none());
+        assert_eq!(read(&directory, 1), "previous run\n");
+        log.write_all(b"first ").unwrap();
+        log.write_all(b"line\n").unwrap();
+        log.write_all(b"second\n").unwrap();
+        assert_eq!(read(&directory, 0), "second\n");
+        assert_eq!(read(&directory, 1), "first line\n");
+        assert_eq!(read(&directory, 2), "previous run\n");
+    }
+
+    #[test]
+    fn appends_when_the_previous_run_cannot_be_rotated() {
+        let directory = test_directory("log-rotation-blocked");
+        fs::write(directory.join("log.txt"), "previous run\n").unwrap();
+        block_rotation(&directory);
+        let mut log = RotatingFile::open(&directory, rotation(0)).unwrap();
+        assert!(log.take_rotation_error().is_some());
+        assert!(log.take_rotation_error().is_none());
+        log.write_all(b"this run\n").unwrap();
+        assert_eq!(read(&directory, 0), "previous run\nthis run\n");
+    }
+
+    #[test]
+    fn keeps_writing_when_rotating_fails() {
+        let directory = test_directory("log-rotation-failing");
+        let mut log = RotatingFile::open(&directory, rotation(10)).unwrap();
+        log.write_all(b"first line\n").unwrap();
+        log.write_all(b"second line\n").unwrap();
+        block_rotation(&directory);
+        log.write_all(b"third line\n").unwrap();
+        log.write_all(b"fourth\n").unwrap();
+        assert_eq!(read(&directory, 0), "second line\nthird line\nfourth\n");
+        assert_eq!(read(&directory, 1), "first line\n");
+    }
+}
@@ -0,0 +1,196 @@
+use std::io::{Error, ErrorKind, Write};
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use chrono::Local;
+use log::kv::{self, Key, VisitSource};
+use log::{LevelFilter, Log, Metadata, Record};
+use serde_json::{Map, Value};
+
+/// How log records are written, `[log] format` or `--log-format=<format>`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// the plain lines of simplelog
+    Text,
+    /// one json object per line
+    Json,
+    /// straight to the systemd journal, linux only
+    Journald,
+}
+
+impl FromStr for LogFormat {
+    type Err = Error;
+
+    fn from_str(format: &str) -> Result<Self, Error> {
+        match format.to_ascii_lowercase().as_str() {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            "journald" => Ok(LogFormat::Journald),
+            _ => Err(Error::new(ErrorKind::InvalidInput, format!("unknown log format {:?}, use text, json or journald", format))),
+        }
+    }
+}
+
+/// Writes every record as a json object on a line of its own, with the key values of the record as additional
+/// members, e.g. `info!(state:? = state, zone = "HideoutCoastal"; "state changed")`.
+pub struct JsonLogger<W: Write + Send> {
+    level: LevelFilter,
+    writer: Mutex<W>,
+}
+
+impl<W: Write + Send> JsonLogger<W> {
+    pub fn new(level: LevelFilter, writer: W) -> Self {
+        JsonLogger { level, writer: Mutex::new(writer) }
+    }
+}
+
+impl<W: Write + Send> Log for JsonLogger<W> {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let mut object = Map::new();
+        let _ = record.key_values().visit(&mut JsonFields(&mut object));
+        // fields can't replace the members every record has
+        object.insert("timestamp".to_owned(), Value::from(Local::now().to_rfc3339()));
+        object.insert("level".to_owned(), Value::from(record.level().as_str()));
+        object.insert("module".to_owned(), Value::from(record.module_path().unwrap_or_else(|| record.target())));
+        object.insert("message".to_owned(), Value::from(record.args().to_string()));
+        let mut line = Value::Object(object).to_string();
+        line.push('\n');
+        let mut writer = self.writer.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let _ = writer.write_all(line.as_bytes());
+    }
+
+    fn flush(&self) {
+        let _ = self.writer.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).flush();
+    }
+}
+
+struct JsonFields<'a>(&'a mut Map<String, Value>);
+
+impl<'kvs> VisitSource<'kvs> for JsonFields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: kv::Value<'kvs>) -> Result<(), kv::Error> {
+        let value = if let Some(value) = value.to_bool() {
+            Value::from(value)
+        } else if let Some(value) = value.to_u64() {
+            Value::from(value)
+        } else if let Some(value) = value.to_i64() {
+            Value::from(value)
+        } else if let Some(value) = value.to_f64() {
+            Value::from(value)
+        } else {
+            Value::from(value.to_string())
+        };
+        self.0.insert(key.as_str().to_owned(), value);
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub use journald::JournaldLogger;
+
+#[cfg(target_os = "linux")]
+mod journald {
+    use std::io::{Error, ErrorKind};
+    use std::os::unix::net::UnixDatagram;
+    use std::path::Path;
+
+    use log::kv::{self, Key, VisitSource};
+    use log::{Level, LevelFilter, Log, Metadata, Record};
+
+    const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+
+    /// Sends records to journald with their syslog priority, the key values of a record become fields named in upper
+    /// case, e.g. `zone` can be matched with `journalctl ZONE=HideoutCoastal`.
+    pub struct JournaldLogger {
+        level: LevelFilter,
+        socket: UnixDatagram,
+    }
+
+    impl JournaldLogger {
+        pub fn new(level: LevelFilter) -> Result<Self, Error> {
+            if !Path::new(JOURNAL_SOCKET).exists() {
+                return Err(Error::new(ErrorKind::NotFound, format!("journald is not running, {} does not exist", JOURNAL_SOCKET)));
+            }
+            Ok(JournaldLogger { level, socket: UnixDatagram::unbound()? })
+        }
+    }
+
+    impl Log for JournaldLogger {
+        fn enabled(&self, metadata: &Metadata) -> bool {
+            metadata.level() <= self.level
+        }
+
+        fn log(&self, record: &Record) {
+            if !self.enabled(record.metadata()) {
+                return;
+            }
+            let mut entry = Vec::new();
+            append_field(&mut entry, "MESSAGE", &record.args().to_string());
+            append_field(&mut entry, "PRIORITY", priority(record.level()));
+            append_field(&mut entry, "SYSLOG_IDENTIFIER", "poe-minimizer");
+            append_field(&mut entry, "CODE_MODULE", record.module_path().unwrap_or_else(|| record.target()));
+            if let Some(file) = record.file() {
+                append_field(&mut entry, "CODE_FILE", file);
+            }
+            if let Some(line) = record.line() {
+                append_field(&mut entry, "CODE_LINE", &line.to_string());
+            }
+            let _ = record.key_values().visit(&mut JournalFields(&mut entry));
+            // entries too large for a datagram are dropped, the few log lines of poe-minimizer are far from that
+            let _ = self.socket.send_to(&entry, JOURNAL_SOCKET);
+        }
+
+        fn flush(&self) {}
+    }
+
+    struct JournalFields<'a>(&'a mut Vec<u8>);
+
+    impl<'kvs> VisitSource<'kvs> for JournalFields<'_> {
+        fn visit_pair(&mut self, key: Key<'kvs>, value: kv::Value<'kvs>) -> Result<(), kv::Error> {
+            let name = field_name(key.as_str());
+            if !name.is_empty() {
+                append_field(self.0, &name, &value.to_string());
+            }
+            Ok(())
+        }
+    }
+
+    fn priority(level: Level) -> &'static str {
+        match level {
+            Level::Error => "3",
+            Level::Warn => "4",
+            Level::Info => "6",
+            Level::Debug | Level::Trace => "7",
+        }
+    }
+
+    /// Journal fields consist of upper case letters, digits and underscores and may not start with an underscore, those
+    /// are set by journald itself.
+    fn field_name(key: &str) -> String {
+        let name: String = key.chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' })
+            .skip_while(|c| *c == '_' || c.is_ascii_digit())
+            .take(64)
+            .collect();
+        name
+    }
+
+    /// `NAME=value\n`, or the length prefixed form for values spanning several lines.
+    fn append_field(entry: &mut Vec<u8>, name: &str, value: &str) {
+        entry.extend_from_slice(name.as_bytes());
+        if value.contains('\n') {
+            entry.push(b'\n');
+            entry.extend_from_slice(&(value.len() as u64).to_le_bytes());
+        } else {
+            entry.push(b'=');
+        }
+        entry.extend_from_slice(value.as_bytes());
+        entry.push(b'\n');
+    }
+}
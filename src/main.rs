@@ -3,60 +3,168 @@
 extern crate log;
 extern crate rev_lines;
 extern crate simplelog;
+#[cfg(windows)]
 extern crate widestring;
 #[cfg(windows)]
 extern crate winapi;
 
-use std::{thread, time};
+use std::sync::Arc;
+use std::thread;
 use std::fs::File;
-use std::io::{BufReader, ErrorKind};
 use std::io::Error;
-use std::path::PathBuf;
-use std::time::{Duration, SystemTime};
+use std::time::{Duration, Instant};
 
+use chrono::Local;
 use log::LevelFilter;
-use rev_lines::RevLines;
 use simplelog::WriteLogger;
-use winapi::_core::sync::atomic::{AtomicBool, Ordering};
+use std::path::PathBuf;
 
 use utils::*;
 
+use crate::afk_action::{AfkAction, AfkActions};
+use crate::autostart::{Autostart, AutostartStatus};
+use crate::client_log::{ClientLog, LogEvent};
+use crate::control::{Command, Controller, Remote, Whisper};
+use crate::discovery::Candidate;
+use crate::energy::{EnergyMeter, EnergyTracker};
+use crate::game_config::DisplayMode;
+use crate::http_api::HttpApi;
+#[cfg(windows)]
+use crate::icons::TrayIcon;
+use crate::idle::IdleSource;
+use crate::language::ALL_LANGUAGES;
+use crate::log_file::{RotatingFile, Rotation};
+#[cfg(target_os = "linux")]
+use crate::log_output::JournaldLogger;
+use crate::log_output::{JsonLogger, LogFormat};
+use crate::menu::MenuAction;
+use crate::metrics::CountedResult;
+use crate::notify::Notifier;
+use crate::paths::Directory;
+use crate::priority::PriorityLowering;
+use crate::settings::Settings;
+use crate::shutdown::Shutdown;
+use crate::supervisor::Supervisor;
+use crate::sound::{Alert, SoundAlerts};
+use crate::state::{Action, Observation, State, StateMachine};
+use crate::stats::SessionRecorder;
+use crate::trade::TradeRequest;
+#[cfg(windows)]
 use crate::tray::Event;
+use crate::webhook::Webhooks;
+use crate::window::GameWindow;
 
+#[cfg(windows)]
 mod winutils;
+#[cfg(windows)]
 mod tray;
 mod utils;
+mod discovery;
+mod game_config;
+mod ini;
+mod language;
+mod log_file;
+mod log_output;
+mod redact;
+mod paths;
+mod autostart;
+mod settings;
+mod client_log;
+mod idle;
+mod state;
+mod window;
+mod afk_action;
+mod priority;
+#[cfg(target_os = "linux")]
+mod cgroup;
+mod energy;
+mod stats;
+mod control;
+mod http_api;
+mod metrics;
+mod ipc;
+mod instance;
+mod trade;
+mod notify;
+mod webhook;
+mod mqtt;
+mod sound;
+mod menu;
+mod icons;
+#[cfg(target_os = "linux")]
+mod sni;
+mod shutdown;
+mod supervisor;
 
-struct Settings {
-    window_name: String,
-    log_file_polling_interval_ms: u64,
-    seconds_until_minimize: u64,
-    seconds_to_check_for_poe: u64,
-}
+const PRIORITY_RESTORE_RECORD: &str = "priority-restore.txt";
+const ENERGY_TOTALS: &str = "energy.txt";
+const AFK_SESSIONS: &str = "afk-sessions.jsonl";
+const WEBHOOK_OUTBOX: &str = "webhook-outbox.jsonl";
 
-impl Settings {
-    pub fn default() -> Self {
-        Settings {
-            window_name: "Path of Exile".to_owned(),
-            log_file_polling_interval_ms: 500,
-            seconds_until_minimize: 5,
-            seconds_to_check_for_poe: 30,
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(|command| command == "stats").unwrap_or(false) {
+        run_stats_command(&args[2..]);
+        return;
+    }
+    if let Some(request) = ipc::parse_command_line(&args[1..]) {
+        run_client_command(request);
+        return;
+    }
+    let instance_lock = match instance::acquire() {
+        Ok(Some(lock)) => Some(lock),
+        Ok(None) => {
+            hand_off_to_running_instance(&args[1..]);
+            return;
+        }
+        Err(e) => {
+            eprintln!("failed to check for a running instance, starting anyway: {}", e);
+            None
         }
+    };
+    // only the instance holding the lock may move files a running instance could be using
+    let migrated = match instance_lock {
+        Some(_) => migration_messages(paths::migrate_from_executable_directory(directory_of)),
+        None => Vec::new(),
+    };
+    // read once more below, so problems with the settings end up in the log
+    init_logger(&Settings::load());
+    for (level, message) in migrated {
+        log!(level, "{}", message);
     }
-}
-
-static RUNNING: AtomicBool = AtomicBool::new(true);
-
-fn main() {
-    init_logger();
+    supervisor::install_panic_hook();
+    let settings = Settings::load();
+    let (controller, remote) = control::channel();
+    let shutdown = Shutdown::new();
+    #[cfg(target_os = "linux")]
+    shutdown.request_on_signals().log_error_and_ignore("failed to handle SIGTERM and SIGINT");
+    let supervisor = Supervisor::new(remote.clone(), shutdown.clone(), settings.max_worker_crashes);
+    let autostart: Option<Arc<dyn Autostart + Send + Sync>> = autostart::system_autostart(&settings.autostart).map(Arc::from);
+    if let Some(autostart) = autostart.as_deref() {
+        autostart.repair().log_error_and_ignore("failed to repair the autostart entry");
+        remote.set_run_on_startup(is_autostart_enabled(autostart));
+    }
+    let (service_remote, service_supervisor) = (remote.clone(), supervisor.clone());
     let handle = thread::spawn(move || {
-        main_service();
+        main_service(settings, controller, service_remote, service_supervisor);
     });
-    main_window().log_error_and_ignore("error in message event loop");
+    #[cfg(windows)]
+    {
+        main_window(remote, &shutdown, autostart.as_deref()).log_error_and_ignore("error in message event loop");
+        // also when the message loop failed, there is no other way to exit
+        shutdown.request();
+    }
+    #[cfg(target_os = "linux")]
+    {
+        let (tray_remote, tray_shutdown) = (remote.clone(), shutdown.clone());
+        sni::start(remote, shutdown, &supervisor, move |action| handle_menu_action(action, &tray_remote, &tray_shutdown, autostart.as_deref()))
+            .log_error_and_ignore("no tray icon");
+    }
     let _ = handle.join();
+    drop(instance_lock);
 }
 
-fn init_logger() {
+fn init_logger(settings: &Settings) {
     let args: Vec<String> = std::env::args().collect();
     let level = if args.len() < 2 {
         LevelFilter::Warn
@@ -68,37 +176,137 @@ fn init_logger() {
             _ => LevelFilter::Warn
         }
     };
-    let log_path = join_executable_path("log.txt").expect("Unable to determine path for log file");
-    simplelog::CombinedLogger::init(
-        vec![
-            WriteLogger::new(level, simplelog::Config::default(), File::create(log_path).unwrap())
-        ]
-    ).unwrap();
+    redact::set_enabled(settings.redact_logs);
+    let mut problems = Vec::new();
+    let format = match args.iter().rev().find_map(|arg| arg.strip_prefix("--log-format=")) {
+        Some(format) => format.parse().unwrap_or_else(|e: Error| {
+            problems.push(e.to_string());
+            settings.log_format
+        }),
+        None => settings.log_format,
+    };
+    let logger = match format {
+        LogFormat::Journald => journald_logger(level)
+            .map_err(|e| problems.push(format!("{}, logging to log.txt instead", e)))
+            .ok(),
+        _ => None,
+    };
+    let logger = logger.unwrap_or_else(|| match RotatingFile::open(&paths::log_directory(), Rotation::from_settings(settings)) {
+        Ok(mut log_file) => {
+            if let Some(e) = log_file.take_rotation_error() {
+                problems.push(format!("failed to rotate the log of the previous run, appending to it: {}", e));
+            }
+            file_logger(format, level, log_file)
+        }
+        Err(e) => {
+            problems.push(format!("failed to open log.txt in {:?}, logging to stderr: {}", paths::log_directory(), e));
+            file_logger(format, level, std::io::stderr())
+        }
+    });
+    log::set_boxed_logger(logger).unwrap();
+    log::set_max_level(level);
+    for problem in problems {
+        warn!("{}", problem);
+    }
 }
 
-fn main_window() -> Result<(), Error> {
-    let mut run_on_startup = winutils::is_in_autostart()?;
-    if run_on_startup {
-        // refresh auto start entry in case the path has changed.
-        winutils::remove_from_autostart().log_error_and_ignore("Failed to remove from autostart");
-        winutils::add_to_autostart().log_error_and_ignore("Failed to add to autostart");
+fn file_logger<W: std::io::Write + Send + 'static>(format: LogFormat, level: LevelFilter, writer: W) -> Box<dyn log::Log> {
+    match format {
+        LogFormat::Json => Box::new(JsonLogger::new(level, writer)),
+        _ => WriteLogger::new(level, simplelog::Config::default(), writer),
     }
+}
+
+#[cfg(target_os = "linux")]
+fn journald_logger(level: LevelFilter) -> Result<Box<dyn log::Log>, Error> {
+    Ok(Box::new(JournaldLogger::new(level)?))
+}
 
-    let mut system_tray = tray::SystemTray::new(run_on_startup.clone())?;
-    while RUNNING.load(Ordering::Relaxed) {
+#[cfg(not(target_os = "linux"))]
+fn journald_logger(_level: LevelFilter) -> Result<Box<dyn log::Log>, Error> {
+    Err(Error::other("journald is only available on linux"))
+}
+
+fn migration_messages(migrated: Vec<(PathBuf, Result<PathBuf, Error>)>) -> Vec<(log::Level, String)> {
+    migrated.into_iter().map(|(from, to)| match to {
+        Ok(to) => (log::Level::Info, format!("moved {:?} to {:?}", from, to)),
+        Err(e) => (log::Level::Warn, format!("failed to move {:?}: {}", from, e)),
+    }).collect()
+}
+
+fn run_stats_command(args: &[String]) {
+    #[cfg(windows)]
+    winutils::attach_parent_console();
+    if let Err(e) = stats::run_stats_command(&paths::data_path(AFK_SESSIONS), args) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+/// Sends a command like `poe-minimizer pause 30m` to the running instance.
+fn run_client_command(request: Result<ipc::Request, Error>) {
+    #[cfg(windows)]
+    winutils::attach_parent_console();
+    let response = request.and_then(|request| ipc::send(&request));
+    match response {
+        Ok(ipc::Response { ok: true, status: Some(status), .. }) => {
+            println!("{}", serde_json::to_string_pretty(&status).unwrap_or_default());
+        }
+        Ok(ipc::Response { ok: true, .. }) => println!("ok"),
+        Ok(ipc::Response { error, .. }) => {
+            eprintln!("{}", error.unwrap_or_else(|| "failed".to_owned()));
+            std::process::exit(1);
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Another instance is running, e.g. started by autostart. It may still be starting up, so its
+/// ipc server gets a moment to appear.
+fn hand_off_to_running_instance(args: &[String]) {
+    #[cfg(windows)]
+    winutils::attach_parent_console();
+    let request = ipc::Request::Launch { args: args.to_vec() };
+    for attempt in 0..10 {
+        match ipc::send(&request) {
+            Ok(ipc::Response { ok: true, .. }) => {
+                println!("poe-minimizer is already running");
+                return;
+            }
+            Ok(response) => {
+                eprintln!("poe-minimizer is already running: {}", response.error.unwrap_or_default());
+                break;
+            }
+            Err(e) if attempt == 9 => eprintln!("poe-minimizer is already running but does not respond: {}", e),
+            Err(_) => thread::sleep(Duration::from_millis(300)),
+        }
+    }
+    std::process::exit(1);
+}
+
+#[cfg(windows)]
+fn main_window(remote: Remote, shutdown: &Shutdown, autostart: Option<&(dyn Autostart + Send + Sync)>) -> Result<(), Error> {
+    let system_tray = tray::SystemTray::new()?;
+    shutdown.on_request(system_tray.waker());
+    let mut shown = None;
+    while !shutdown.is_requested() {
         let event = system_tray.handle_windows_messages()?;
+        let status = remote.status();
+        let appearance = (TrayIcon::for_status(&status), menu::tooltip(&status));
+        if shown.as_ref() != Some(&appearance) {
+            system_tray.update(appearance.0, &appearance.1)
+                .log_error_and_ignore("failed to update the tray icon");
+            shown = Some(appearance);
+        }
         match event {
-            Event::Exit => {
-                RUNNING.store(false, Ordering::Relaxed);
-            },
-            Event::ToggleAutoStart => {
-                run_on_startup = !run_on_startup;
-                if run_on_startup {
-                    winutils::add_to_autostart().log_error_and_ignore("Failed to add to autostart");
-                } else {
-                    winutils::remove_from_autostart().log_error_and_ignore("Failed to remove from autostart");
+            Event::MenuRequested => {
+                let items = menu::build_menu(&status);
+                if let Some(action) = system_tray.show_menu(&items) {
+                    handle_menu_action(action, &remote, shutdown, autostart);
                 }
-                system_tray.set_run_on_startup(run_on_startup.clone())
             },
             Event::Nothing => {}
         }
@@ -106,103 +314,590 @@ fn main_window() -> Result<(), Error> {
     Ok(())
 }
 
-fn main_service() {
-    let settings: Settings = Settings::default();
-
-    loop {
-        if let Ok(handle) = winutils::get_window_handle(&settings.window_name)
-            .log_info("failed to get window handle for Path Of Exile") {
-            check_for_minimization(handle, &settings)
-                .log_error_and_ignore("failed to minimize window");
-        };
-        if !RUNNING.load(Ordering::Relaxed) {
-            break;
+fn handle_menu_action(action: MenuAction, remote: &Remote, shutdown: &Shutdown, autostart: Option<&(dyn Autostart + Send + Sync)>) {
+    if let Some(command) = action.command() {
+        remote.send(command).log_error_and_ignore("failed to send command from the tray menu");
+        return;
+    }
+    match action {
+        MenuAction::OpenConfig => {
+            let path = paths::config_path(settings::SETTINGS_FILE_NAME);
+            if !path.exists() {
+                // everything is optional, an empty file is a valid config
+                File::create(&path).log_error_and_ignore("failed to create the config file");
+            }
+            utils::open_in_default_app(&path).log_error_and_ignore("failed to open the config file");
+        }
+        MenuAction::OpenLogFolder => {
+            utils::open_in_default_app(&paths::log_directory()).log_error_and_ignore("failed to open the log folder");
         }
-        thread::sleep(Duration::from_secs(settings.seconds_to_check_for_poe))
+        MenuAction::ToggleAutoStart => {
+            if let Some(autostart) = autostart {
+                let result = if remote.status().run_on_startup == Some(true) { autostart.disable() } else { autostart.enable() };
+                result.log_error_and_ignore("failed to toggle autostart");
+                remote.set_run_on_startup(is_autostart_enabled(autostart));
+            }
+        }
+        MenuAction::Exit => shutdown.request(),
+        _ => {}
     }
 }
 
-fn find_log_path(window_handle: winapi::shared::windef::HWND) -> Result<String, Error> {
-    use winutils::*;
-    get_process_path_by_window_handle(window_handle)
-        .map(|poe_path| construct_log_path(poe_path))
-        .and_then(|path_option| path_option.as_result(Error::new(ErrorKind::Other, "failed to construct client.txt path.")))
+/// `None` if it can't be determined, which hides the menu entry.
+fn is_autostart_enabled(autostart: &dyn Autostart) -> Option<bool> {
+    autostart.status()
+        .log_error("failed to check autostart")
+        .ok()
+        .map(|status| status == AutostartStatus::Enabled)
 }
 
-fn construct_log_path(poe_executable_path: PathBuf) -> Option<String> {
-    poe_executable_path.parent()
-        .map(|path| path.join("logs\\Client.txt").as_string())
+fn main_service(settings: Settings, controller: Controller, remote: Remote, supervisor: Supervisor) {
+    if settings.http_api_enabled {
+        HttpApi::new(settings.http_api_token.clone(), remote.clone(), settings.data_path(AFK_SESSIONS))
+            .start(settings.http_api_port, &supervisor)
+            .log_error_and_ignore("failed to start the http api");
+    }
+    ipc::start_server(remote.clone(), &supervisor)
+        .log_error_and_ignore("failed to start the ipc server");
+    if settings.metrics_enabled {
+        metrics::start_exporter(&settings.metrics_listen, settings.metrics_token.clone(), remote.clone(), &supervisor)
+            .log_error_and_ignore("failed to start the metrics exporter");
+    }
+    if settings.mqtt_enabled {
+        mqtt::start_publisher(&settings, remote.clone(), &supervisor)
+            .log_error_and_ignore("failed to start the mqtt publisher");
+    }
+    supervisor.shutdown().on_request(controller.waker());
+    // a restarted service starts from scratch, with the settings as they are now
+    let mut settings = Some(settings);
+    supervisor.run("service", || {
+        let settings = settings.take().unwrap_or_else(Settings::load);
+        Service::new(settings, &controller, supervisor.shutdown().clone()).run()
+    });
 }
 
-fn check_for_minimization(handle: winapi::shared::windef::HWND, settings: &Settings) -> Result<(), Error> {
-    let mut afk_status = false;
-    let mut was_minimized = false;
-    let mut last_time_maximized = SystemTime::now();
-    let log_path = find_log_path(handle)?;
-    while RUNNING.load(Ordering::Relaxed) {
-        match get_last_afk_status_from_log(&log_path)? {
-            Some(afk) => afk_status = afk,
-            None => {}
+/// Where files an earlier version kept next to the executable belong.
+fn directory_of(file_name: &str) -> Option<Directory> {
+    match file_name {
+        settings::SETTINGS_FILE_NAME => Some(Directory::Config),
+        PRIORITY_RESTORE_RECORD | WEBHOOK_OUTBOX => Some(Directory::State),
+        ENERGY_TOTALS | AFK_SESSIONS => Some(Directory::Data),
+        _ if log_file::is_log_file(file_name) => Some(Directory::State),
+        _ => None,
+    }
+}
+
+/// Watches for the game and minimizes it while the player is afk.
+struct Service<'a> {
+    settings: Settings,
+    state: StateMachine,
+    idle_source: Option<Box<dyn IdleSource + Send>>,
+    afk_actions: AfkActions,
+    energy: Option<EnergyTracker>,
+    sessions: SessionRecorder,
+    controller: &'a Controller,
+    shutdown: Shutdown,
+    /// start of the current polling interval if the game was minimized at its beginning
+    minimized_since: Option<Instant>,
+    notifier: Option<Box<dyn Notifier + Send>>,
+    webhooks: Option<Webhooks>,
+    sound_alerts: Option<SoundAlerts>,
+    /// last state sent to the webhooks
+    reported_state: State,
+}
+
+impl<'a> Service<'a> {
+    fn new(settings: Settings, controller: &'a Controller, shutdown: Shutdown) -> Self {
+        Service {
+            state: StateMachine::new(),
+            idle_source: create_idle_source(&settings),
+            afk_actions: AfkActions::new(create_afk_actions(&settings)),
+            energy: create_energy_tracker(&settings),
+            sessions: SessionRecorder::new(settings.data_path(AFK_SESSIONS)),
+            notifier: create_notifier(&settings),
+            webhooks: create_webhooks(&settings),
+            sound_alerts: create_sound_alerts(&settings),
+            reported_state: State::GameNotRunning,
+            controller,
+            shutdown,
+            settings,
+            minimized_since: None,
+        }
+    }
+
+    fn run(&mut self) {
+        while !self.shutdown.is_requested() {
+            if let Ok(window) = GameWindow::find(&self.settings.window_name)
+                .log_info("failed to get window handle for Path Of Exile") {
+                self.state.game_found();
+                self.controller.update_status(|status| status.game_running = true);
+                self.check_for_minimization(&window)
+                    .log_error_and_ignore("failed to minimize window");
+                self.state.game_lost();
+                self.update_trackers(None, None);
+                self.controller.update_status(|status| {
+                    status.game_running = false;
+                    status.afk = false;
+                    status.minimized = false;
+                    status.zone = None;
+                });
+            } else {
+                self.controller.update_status(|status| status.error = None);
+            }
+            if self.shutdown.is_requested() {
+                break;
+            }
+            let mut timeout = Duration::from_secs(self.settings.seconds_to_check_for_poe);
+            if let Some(resume_at) = self.state.resume_at() {
+                timeout = timeout.min(resume_at.saturating_duration_since(Instant::now()));
+            }
+            let commands = self.controller.wait_for_commands(timeout);
+            self.handle_commands(None, commands);
         }
-        let minimized = winutils::is_window_minimized(handle)?;
-        if !minimized && was_minimized {
-            last_time_maximized = SystemTime::now();
+    }
+
+    fn check_for_minimization(&mut self, window: &GameWindow) -> Result<(), Error> {
+        let opened = open_client_log(window);
+        self.controller.update_status(|status| status.error = opened.as_ref().err().map(|e| e.to_string()));
+        let (mut client_log, initial_events) = opened?;
+        let process_id = window.process_id()
+            .log_info("failed to determine the process id of Path of Exile")
+            .ok();
+        let mut observation = Observation::default();
+        self.apply_log_events(&mut observation, initial_events);
+        while !self.shutdown.is_requested() {
+            if self.apply_log_events(&mut observation, client_log.poll()?) {
+                self.afk_actions.interrupt();
+            }
+            let commands = self.controller.pending_commands();
+            self.handle_commands(Some(window), commands);
+            observation.window_minimized = window.is_minimized()?;
+            observation.idle_time = match self.idle_source.as_mut() {
+                Some(source) if observation.in_hideout => source.idle_time()
+                    .count_backend_error()
+                    .log_error("failed to determine idle time")
+                    .ok(),
+                _ => None
+            };
+
+            if let Some(Action::Minimize) = self.state.update(&observation, &self.settings, Instant::now()) {
+                window.minimize().count_backend_error()?;
+            }
+            self.update_trackers(process_id, observation.area.as_deref());
+            let state = self.state.state();
+            self.controller.update_status(|status| {
+                status.afk = state == State::AfkPending || state == State::Minimized;
+                status.minimized = observation.window_minimized;
+                status.zone = observation.area.clone();
+            });
+
+            self.shutdown.wait(Duration::from_millis(self.settings.log_file_polling_interval_ms));
         }
-        was_minimized = minimized;
-        let elapsed = last_time_maximized.elapsed().map(|elapsed| elapsed.as_secs()).unwrap_or(0);
+        Ok(())
+    }
 
-        if afk_status && elapsed > settings.seconds_until_minimize {
-            winutils::minimize_window(handle)?;
+    /// Returns whether a trade request was whispered.
+    fn apply_log_events(&mut self, observation: &mut Observation, events: Vec<LogEvent>) -> bool {
+        let mut trade_request = false;
+        for event in events {
+            match event {
+                LogEvent::AfkChanged(afk) => observation.afk_mode = afk,
+                LogEvent::AreaEntered(area) => {
+                    debug!(zone = area.as_str(); "entered area {}", area);
+                    observation.in_hideout = client_log::is_hideout(&area);
+                    observation.area = Some(area);
+                }
+                LogEvent::Whisper { from, message } => {
+                    self.sessions.whisper_received();
+                    metrics::WHISPERS_RECEIVED.increment();
+                    if let Some(request) = trade::parse_trade_request(&from, &message) {
+                        let buyer = redact::name(&from);
+                        info!(from = buyer.as_str(); "received trade request from {}", buyer);
+                        metrics::TRADES_DETECTED.increment();
+                        self.notify_trade_request(&request);
+                        trade_request = true;
+                    }
+                    self.controller.update_status(|status| {
+                        if status.afk {
+                            status.afk_whispers += 1;
+                        }
+                        status.last_whisper = Some(Whisper { from, message, received: Local::now() });
+                    });
+                }
+                LogEvent::PartyInvite { from } => {
+                    info!("{} invited you to a party", redact::name(&from));
+                    self.play_alert(Alert::PartyInvite);
+                }
+            }
         }
+        trade_request
+    }
 
-        thread::sleep(time::Duration::from_millis(settings.log_file_polling_interval_ms));
+    fn notify_trade_request(&mut self, request: &TradeRequest) {
+        let state = self.state.state();
+        let afk = state == State::AfkPending || state == State::Minimized;
+        if let Some(webhooks) = self.webhooks.as_ref() {
+            webhooks.send(&webhook::Event::trade_request(request), afk);
+        }
+        self.controller.publish(control::Event::TradeRequest { request: request.clone(), received: Local::now() });
+        self.play_alert(Alert::TradeRequest);
+        if self.settings.notify_only_while_afk && !afk {
+            return;
+        }
+        if let Some(notifier) = self.notifier.as_mut() {
+            notifier.notify(&format!("Trade request from {}", request.buyer), &request.summary())
+                .count_backend_error()
+                .log_error_and_ignore("failed to show a notification");
+        }
     }
-    Ok(())
+
+    /// Sounds only make sense while the game, which has its own, is minimized.
+    fn play_alert(&mut self, alert: Alert) {
+        if self.state.state() != State::Minimized {
+            return;
+        }
+        if let Some(sound_alerts) = self.sound_alerts.as_mut() {
+            sound_alerts.play(alert);
+        }
+    }
+
+    /// `window` is `None` while the game is not running.
+    /// A command that fails, e.g. a restore the window manager refused, is logged and does not end watching the game.
+    fn handle_commands(&mut self, window: Option<&GameWindow>, commands: Vec<Command>) {
+        if self.state.resume_if_due(Instant::now()) {
+            info!("pause is over");
+        }
+        for command in commands {
+            info!("received command {}", command.name());
+            match (command, window) {
+                (Command::Pause(duration), _) => self.state.pause(duration),
+                (Command::Resume, _) => self.state.resume(),
+                (Command::ReloadConfig, _) => self.reload_settings(),
+                (Command::MinimizeNow, Some(window)) => window.minimize()
+                    .count_backend_error()
+                    .log_error_and_ignore("failed to minimize Path of Exile"),
+                (Command::Restore, Some(window)) => window.restore()
+                    .count_backend_error()
+                    .log_error_and_ignore("failed to restore Path of Exile"),
+                (_, None) => info!("ignoring {}, Path of Exile is not running", command.name()),
+            }
+        }
+        let (state, paused) = (self.state.state(), self.state.is_paused());
+        let paused_until = self.state.resume_at()
+            .and_then(|resume_at| chrono::Duration::from_std(resume_at.saturating_duration_since(Instant::now())).ok())
+            .map(|remaining| Local::now() + remaining);
+        self.controller.update_status(|status| {
+            status.set_state(state);
+            status.paused = paused;
+            status.paused_until = paused_until;
+        });
+    }
+
+    /// Applies a changed `poe-minimizer.ini`. The http api, metrics and ipc keep their settings until restarted.
+    fn reload_settings(&mut self) {
+        let settings = Settings {
+            state_directory: self.settings.state_directory.clone(),
+            data_directory: self.settings.data_directory.clone(),
+            ..Settings::load()
+        };
+        // release the old actions before the new ones restore leftovers of an unclean shutdown
+        self.afk_actions = AfkActions::new(Vec::new());
+        self.afk_actions = AfkActions::new(create_afk_actions(&settings));
+        self.idle_source = create_idle_source(&settings);
+        if settings.notify_trade_requests != self.settings.notify_trade_requests {
+            self.notifier = create_notifier(&settings);
+        }
+        if settings.webhooks != self.settings.webhooks {
+            // the old worker has to let go of the outbox first
+            self.webhooks = None;
+            self.webhooks = create_webhooks(&settings);
+        }
+        self.sound_alerts = create_sound_alerts(&settings);
+        redact::set_enabled(settings.redact_logs);
+        if settings.measure_energy != self.settings.measure_energy || settings.powercap_root != self.settings.powercap_root {
+            self.energy = create_energy_tracker(&settings);
+        }
+        self.settings = settings;
+        info!("reloaded settings");
+    }
+
+    fn update_trackers(&mut self, process_id: Option<u32>, area: Option<&str>) {
+        let state = self.state.state();
+        if let Some(since) = self.minimized_since.take() {
+            metrics::add_time_minimized(since.elapsed());
+        }
+        if state == State::Minimized {
+            self.minimized_since = Some(Instant::now());
+        }
+        self.afk_actions.update(state, process_id);
+        if let Some(energy) = self.energy.as_mut() {
+            energy.update(state);
+        }
+        self.sessions.update(state, area);
+        if state != self.reported_state {
+            info!(from:? = self.reported_state, state:? = state, zone = area, game_pid = process_id; "{:?} -> {:?}", self.reported_state, state);
+            if let Some(webhooks) = self.webhooks.as_ref() {
+                let afk = state == State::AfkPending || state == State::Minimized;
+                webhooks.send(&webhook::Event::StateChanged { from: self.reported_state, to: state }, afk);
+            }
+            self.reported_state = state;
+        }
+        self.controller.update_status(|status| status.set_state(state));
+    }
+}
+
+fn create_idle_source(settings: &Settings) -> Option<Box<dyn IdleSource + Send>> {
+    if settings.minutes_until_idle_minimize > 0 {
+        idle::system_idle_source()
+    } else {
+        None
+    }
+}
+
+fn create_notifier(settings: &Settings) -> Option<Box<dyn Notifier + Send>> {
+    if settings.notify_trade_requests {
+        notify::system_notifier()
+    } else {
+        None
+    }
+}
+
+fn create_webhooks(settings: &Settings) -> Option<Webhooks> {
+    if settings.webhooks.is_empty() {
+        return None;
+    }
+    let outbox_path = settings.state_path(WEBHOOK_OUTBOX);
+    Webhooks::start(&settings.webhooks, outbox_path)
+        .log_error("failed to start webhooks")
+        .ok()
 }
 
-fn get_last_afk_status_from_log(log_path: &str) -> Result<Option<bool>, Error> {
-    let file = File::open(log_path)?;
-    let rev_lines = RevLines::new(BufReader::new(file))?;
-    let afk_status = rev_lines
-        .take(20)
-        .skip_while(|x| !x.contains("ac9")) // magic constant in log for chat related stuff
-        .skip_while(|x| x.contains("] @")) // otherwise ppl could send forged messages
-        .inspect(|x| trace!("log line: {}", x))
-        .map(|x| log_line_as_afk_status(x.as_str()))
-        .next()
-        .flatten();
-    Ok(afk_status)
-}
-
-fn log_line_as_afk_status(log_line: &str) -> Option<bool> {
-    if is_afk_activated_message(log_line) {
-        Some(true)
-    } else if is_afk_deactivated_message(log_line) {
-        Some(false)
+fn create_sound_alerts(settings: &Settings) -> Option<SoundAlerts> {
+    if settings.sound_enabled {
+        Some(SoundAlerts::new(settings, sound::create_sink(&settings.sound_output)))
     } else {
         None
     }
 }
 
-fn is_afk_activated_message(log_line: &str) -> bool {
-    log_line.contains(": AFK mode is now ON.")
-        || log_line.contains(": Le mode Absent (AFK) est désormais activé.")
-        || log_line.contains(": AFK-Modus ist nun AN.")
-        || log_line.contains(": Modo LDT Ativado.")
-        || log_line.contains(": Режим \"отошёл\" включён.")
-        || log_line.contains(": เปิดโหมด AFK แล้ว ตอบกลับอัตโนมัติ")
-        || log_line.contains(": El modo Ausente está habilitado.")
-        || log_line.contains(": 자리 비움 모드를 설정했습니다.")
+fn create_afk_actions(settings: &Settings) -> Vec<Box<dyn AfkAction>> {
+    let mut actions: Vec<Box<dyn AfkAction>> = Vec::new();
+    let priority_record = settings.state_path(PRIORITY_RESTORE_RECORD);
+    priority::restore_from_record(&priority_record)
+        .log_error_and_ignore("failed to restore the priority of the game after an unclean shutdown");
+    if settings.lower_priority_while_afk {
+        actions.push(Box::new(PriorityLowering::new(settings.afk_nice, settings.afk_cpus.clone(), priority_record.clone())));
+    }
+    #[cfg(target_os = "linux")]
+    {
+        if settings.throttle_cgroup_while_afk {
+            let throttle = cgroup::CgroupThrottle::new(settings.afk_cpu_quota_percent);
+            match throttle.prepare() {
+                Ok(()) => actions.push(Box::new(throttle)),
+                Err(e) if settings.lower_priority_while_afk => warn!("cpu throttling while afk is disabled: {}", e),
+                Err(e) => {
+                    warn!("cpu throttling while afk is disabled, lowering the priority of the game instead: {}", e);
+                    actions.push(Box::new(PriorityLowering::new(settings.afk_nice, settings.afk_cpus.clone(), priority_record)));
+                }
+            }
+        }
+    }
+    actions
+}
+
+fn create_energy_tracker(settings: &Settings) -> Option<EnergyTracker> {
+    if !settings.measure_energy {
+        return None;
+    }
+    let meter = EnergyMeter::open(&settings.powercap_root)
+        .log_info("energy measurement is not available")
+        .ok()?;
+    let totals_path = settings.data_path(ENERGY_TOTALS);
+    Some(EnergyTracker::new(meter, totals_path))
+}
+
+fn open_client_log(window: &GameWindow) -> Result<(ClientLog, Vec<LogEvent>), Error> {
+    let installation = find_installation(window)?;
+    let game_config = game_config::load_game_config(&installation);
+    if let Some(DisplayMode::ExclusiveFullscreen) = game_config.as_ref().and_then(|config| config.display_mode) {
+        warn!("Path of Exile runs in exclusive fullscreen, minimizing it may flicker or fail. Consider using windowed fullscreen.");
+    }
+    let languages = game_config.map(|config| config.languages()).unwrap_or_else(|| ALL_LANGUAGES.to_vec());
+    ClientLog::open(&installation.client_log, languages)
 }
 
-fn is_afk_deactivated_message(log_line: &str) -> bool {
-    log_line.contains(": AFK mode is now OFF.")
-        || log_line.contains(": Le mode Absent (AFK) est désactivé.")
-        || log_line.contains(": AFK-Modus ist nun AUS.")
-        || log_line.contains(": Modo LDT Desativado.")
-        || log_line.contains(": Режим \"отошёл\" выключен.")
-        || log_line.contains(": ปิดโหมด AFK แล้ว")
-        || log_line.contains(": El modo Ausente está deshabilitado.")
-        || log_line.contains(": 자리 비움 모드를 해제했습니다.")
+fn find_installation(window: &GameWindow) -> Result<Candidate, Error> {
+    let from_process = window.executable()
+        .log_info("failed to determine the path of the running Path of Exile")
+        .ok()
+        .and_then(|poe_path| Candidate::from_executable(&poe_path))
+        .filter(|installation| installation.exists());
+    from_process
+        .or_else(|| discovery::find_most_recent_client_log().inspect(|installation| {
+            info!("using {:?} client.txt found at {:?}", installation.variant, installation.client_log);
+        }))
+        .as_result(Error::other("failed to construct client.txt path."))
 }
 
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::utils::test_directory;
+    use crate::window::fake;
+
+    /// Settings without any of the parts that talk to the desktop, for a service watching for a window titled
+    /// `window_name`.
+    fn test_settings(window_name: &str) -> Settings {
+        let directory = test_directory(window_name);
+        Settings {
+            window_name: window_name.to_owned(),
+            measure_energy: false,
+            notify_trade_requests: false,
+            sound_enabled: false,
+            // keeps the restore record and afk sessions of a real instance out of reach
+            state_directory: Some(directory.clone()),
+            data_directory: Some(directory),
+            ..Settings::default()
+        }
+    }
+
+    fn start_service(window_name: &str) -> (Remote, Shutdown, thread::JoinHandle<()>) {
+        let settings = test_settings(window_name);
+        let (controller, remote) = control::channel();
+        let shutdown = Shutdown::new();
+        shutdown.on_request(controller.waker());
+        let service_shutdown = shutdown.clone();
+        let handle = thread::spawn(move || Service::new(settings, &controller, service_shutdown).run());
+        (remote, shutdown, handle)
+    }
+
+    fn assert_stops_within(shutdown: &Shutdown, handle: thread::JoinHandle<()>, timeout: Duration) {
+        let requested = Instant::now();
+        shutdown.request();
+        while !handle.is_finished() {
+            assert!(requested.elapsed() < timeout, "the service did not stop within {:?}", timeout);
+            thread::sleep(Duration::from_millis(10));
+        }
+        handle.join().unwrap();
+    }
+
+    fn wait_until<F: Fn() -> bool>(condition: F) {
+        let started = Instant::now();
+        while !condition() {
+            assert!(started.elapsed() < Duration::from_secs(5), "timed out");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    /// Keeps what would have been played.
+    struct RecordingSink(std::sync::Arc<std::sync::Mutex<Vec<(String, f32)>>>);
+
+    impl sound::AudioSink for RecordingSink {
+        fn play(&mut self, sound: &sound::Sound, volume: f32) -> Result<(), Error> {
+            self.0.lock().unwrap().push((sound.name.clone(), volume));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn alerts_play_only_while_minimized() {
+        let mut settings = test_settings("alerts");
+        let trade_request_sound = test_directory("alerts-sound").join("trade.wav");
+        fs::write(&trade_request_sound, b"RIFF").unwrap();
+        settings.trade_request_sound = trade_request_sound.to_string_lossy().into_owned();
+        settings.trade_request_volume = 50;
+        settings.party_invite_volume = 30;
+        let played = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let sound_alerts = SoundAlerts::new(&settings, Box::new(RecordingSink(played.clone())));
+        let (controller, _remote) = control::channel();
+        let mut service = Service::new(settings, &controller, Shutdown::new());
+        service.sound_alerts = Some(sound_alerts);
+        let events = || vec![
+            LogEvent::Whisper {
+                from: "Buyer".to_owned(),
+                message: "Hi, I would like to buy your Tabula Rasa Simple Robe listed for 5 chaos in Standard (stash tab \"~price 5 chaos\"; position: left 1, top 2)".to_owned(),
+            },
+            LogEvent::PartyInvite { from: "Buyer".to_owned() },
+        ];
+
+        service.state.game_found();
+        let mut observation = Observation::default();
+        service.apply_log_events(&mut observation, events());
+        assert_eq!(service.state.state(), State::Watching);
+        assert!(played.lock().unwrap().is_empty());
+
+        service.state.update(&Observation { afk_mode: true, window_minimized: true, ..Observation::default() }, &service.settings, Instant::now());
+        assert_eq!(service.state.state(), State::Minimized);
+        service.apply_log_events(&mut observation, events());
+        assert_eq!(*played.lock().unwrap(), vec![(service.settings.trade_request_sound.clone(), 0.5), ("bundled".to_owned(), 0.3)]);
+    }
+
+    /// Remembers whether it is enabled instead of touching the system.
+    struct FakeAutostart(std::sync::Mutex<bool>);
+
+    impl Autostart for FakeAutostart {
+        fn name(&self) -> &'static str {
+            "fake"
+        }
+
+        fn status(&self) -> Result<AutostartStatus, Error> {
+            Ok(if *self.0.lock().unwrap() { AutostartStatus::Enabled } else { AutostartStatus::Disabled })
+        }
+
+        fn enable(&self) -> Result<(), Error> {
+            *self.0.lock().unwrap() = true;
+            Ok(())
+        }
+
+        fn disable(&self) -> Result<(), Error> {
+            *self.0.lock().unwrap() = false;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn menu_actions_are_dispatched() {
+        let (controller, remote) = control::channel();
+        let shutdown = Shutdown::new();
+        handle_menu_action(MenuAction::PauseFor15Minutes, &remote, &shutdown, None);
+        handle_menu_action(MenuAction::RestoreNow, &remote, &shutdown, None);
+        assert_eq!(controller.pending_commands(), vec![Command::Pause(Some(Duration::from_secs(15 * 60))), Command::Restore]);
+
+        // without a backend there is nothing to toggle
+        handle_menu_action(MenuAction::ToggleAutoStart, &remote, &shutdown, None);
+        assert_eq!(remote.status().run_on_startup, None);
+        let autostart = FakeAutostart(std::sync::Mutex::new(false));
+        handle_menu_action(MenuAction::ToggleAutoStart, &remote, &shutdown, Some(&autostart));
+        assert_eq!(remote.status().run_on_startup, Some(true));
+        handle_menu_action(MenuAction::ToggleAutoStart, &remote, &shutdown, Some(&autostart));
+        assert_eq!(remote.status().run_on_startup, Some(false));
+        assert!(controller.pending_commands().is_empty());
+
+        assert!(!shutdown.is_requested());
+        handle_menu_action(MenuAction::Exit, &remote, &shutdown, None);
+        assert!(shutdown.is_requested());
+    }
+
+    #[test]
+    fn stops_while_looking_for_the_game() {
+        let (_remote, shutdown, handle) = start_service("shutdown-without-game");
+        // waiting for the next check for the game
+        thread::sleep(Duration::from_millis(200));
+        assert_stops_within(&shutdown, handle, Duration::from_secs(1));
+    }
+
+    #[test]
+    fn stops_while_watching_the_game() {
+        let window_name = "shutdown-watching";
+        let installation = test_directory("shutdown-installation");
+        fs::create_dir_all(installation.join("logs")).unwrap();
+        fs::write(installation.join("logs").join("Client.txt"), "2020/02/07 01:15:50 ***** LOG FILE OPENING *****\n").unwrap();
+        fake::open(window_name, std::process::id(), installation.join("PathOfExile.exe"));
+        let (remote, shutdown, handle) = start_service(window_name);
+        wait_until(|| remote.status().game_running);
+        assert_stops_within(&shutdown, handle, Duration::from_secs(1));
+        fake::close(window_name);
+    }
+}
@@ -0,0 +1,267 @@
+use std::time::Duration;
+
+use chrono::Local;
+
+use crate::client_log;
+use crate::control::{Command, Status};
+use crate::state::State;
+
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Everything a tray menu item can do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MenuAction {
+    PauseFor15Minutes,
+    PauseForAnHour,
+    PauseUntilRestart,
+    Resume,
+    MinimizeNow,
+    RestoreNow,
+    ToggleAutoStart,
+    OpenConfig,
+    OpenLogFolder,
+    Exit,
+}
+
+const ALL_ACTIONS: [MenuAction; 10] = [
+    MenuAction::PauseFor15Minutes,
+    MenuAction::PauseForAnHour,
+    MenuAction::PauseUntilRestart,
+    MenuAction::Resume,
+    MenuAction::MinimizeNow,
+    MenuAction::RestoreNow,
+    MenuAction::ToggleAutoStart,
+    MenuAction::OpenConfig,
+    MenuAction::OpenLogFolder,
+    MenuAction::Exit,
+];
+
+impl MenuAction {
+    /// Identifies the action in win32 menus and dbusmenu, never 0.
+    pub fn id(&self) -> u32 {
+        ALL_ACTIONS.iter().position(|action| action == self).unwrap() as u32 + 1
+    }
+
+    #[cfg(any(windows, test))]
+    pub fn from_id(id: u32) -> Option<MenuAction> {
+        ALL_ACTIONS.get(id.checked_sub(1)? as usize).copied()
+    }
+
+    /// What the service loop has to do, `None` for actions the frontend handles itself.
+    pub fn command(&self) -> Option<Command> {
+        match self {
+            MenuAction::PauseFor15Minutes => Some(Command::Pause(Some(Duration::from_secs(15 * 60)))),
+            MenuAction::PauseForAnHour => Some(Command::Pause(Some(Duration::from_secs(60 * 60)))),
+            // pauses are not persisted, so one without a duration lasts until the next start
+            MenuAction::PauseUntilRestart => Some(Command::Pause(None)),
+            MenuAction::Resume => Some(Command::Resume),
+            MenuAction::MinimizeNow => Some(Command::MinimizeNow),
+            MenuAction::RestoreNow => Some(Command::Restore),
+            MenuAction::ToggleAutoStart | MenuAction::OpenConfig | MenuAction::OpenLogFolder | MenuAction::Exit => None,
+        }
+    }
+}
+
+/// One entry of the tray menu, rendered by the platform's tray backend.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MenuItem {
+    /// read only text
+    Label(String),
+    Action {
+        label: String,
+        action: MenuAction,
+        enabled: bool,
+        /// `Some` for checkboxes
+        checked: Option<bool>,
+    },
+    Submenu {
+        label: String,
+        items: Vec<MenuItem>,
+    },
+    Separator,
+}
+
+impl MenuItem {
+    fn action(label: &str, action: MenuAction, enabled: bool) -> Self {
+        MenuItem::Action { label: label.to_owned(), action, enabled, checked: None }
+    }
+}
+
+/// The menu for the current status.
+pub fn build_menu(status: &Status) -> Vec<MenuItem> {
+    let mut items = vec![
+        MenuItem::Label(format!("poe-minimizer v{}", VERSION)),
+        MenuItem::Label(status_line(status)),
+    ];
+    items.extend(status.crashed_workers.values().map(|problem| MenuItem::Label(problem.clone())));
+    items.push(MenuItem::Separator);
+    if status.paused {
+        items.push(MenuItem::action("Resume", MenuAction::Resume, true));
+    } else {
+        items.push(MenuItem::Submenu {
+            label: "Pause".to_owned(),
+            items: vec![
+                MenuItem::action("For 15 minutes", MenuAction::PauseFor15Minutes, true),
+                MenuItem::action("For 1 hour", MenuAction::PauseForAnHour, true),
+                MenuItem::action("Until restart", MenuAction::PauseUntilRestart, true),
+            ],
+        });
+    }
+    items.push(MenuItem::action("Minimize now", MenuAction::MinimizeNow, status.game_running && !status.minimized));
+    items.push(MenuItem::action("Restore now", MenuAction::RestoreNow, status.game_running && status.minimized));
+    items.push(MenuItem::Separator);
+    items.push(MenuItem::action("Open config", MenuAction::OpenConfig, true));
+    items.push(MenuItem::action("Open log folder", MenuAction::OpenLogFolder, true));
+    if let Some(run_on_startup) = status.run_on_startup {
+        items.push(MenuItem::Action {
+            label: "Run on startup".to_owned(),
+            action: MenuAction::ToggleAutoStart,
+            enabled: true,
+            checked: Some(run_on_startup),
+        });
+    }
+    items.push(MenuItem::Separator);
+    items.push(MenuItem::action("Exit", MenuAction::Exit, true));
+    items
+}
+
+pub fn status_line(status: &Status) -> String {
+    match status.state {
+        State::GameNotRunning => "Path of Exile is not running".to_owned(),
+        State::Watching => "Watching Path of Exile".to_owned(),
+        State::AfkPending => "AFK, minimizing soon".to_owned(),
+        State::Minimized => "Minimized while AFK".to_owned(),
+        State::Paused => match status.paused_until {
+            Some(until) => format!("Paused until {}", until.format("%H:%M")),
+            None => "Paused until restart".to_owned(),
+        },
+    }
+}
+
+/// Like "AFK in Hideout – minimized for 42 min, 3 whispers".
+pub fn tooltip(status: &Status) -> String {
+    if let Some(error) = status.error.as_ref() {
+        return format!("poe-minimizer: {}", error);
+    }
+    if let Some(problem) = status.crashed_workers.values().next() {
+        return format!("poe-minimizer is degraded: {}", problem);
+    }
+    let place = match status.zone.as_deref() {
+        Some(zone) if client_log::is_hideout(zone) => " in Hideout".to_owned(),
+        Some(zone) => format!(" in {}", zone),
+        None => String::new(),
+    };
+    let mut tooltip = match status.state {
+        State::Watching => format!("Watching Path of Exile{}", place),
+        State::AfkPending => format!("AFK{} – minimizing soon", place),
+        State::Minimized => {
+            let minutes = (Local::now() - status.state_since).num_minutes();
+            if minutes < 1 {
+                format!("AFK{} – just minimized", place)
+            } else if minutes < 60 {
+                format!("AFK{} – minimized for {} min", place, minutes)
+            } else {
+                format!("AFK{} – minimized for {} h {} min", place, minutes / 60, minutes % 60)
+            }
+        }
+        State::GameNotRunning | State::Paused => status_line(status),
+    };
+    if status.afk && status.afk_whispers > 0 {
+        let plural = if status.afk_whispers == 1 { "" } else { "s" };
+        tooltip.push_str(&format!(", {} whisper{}", status.afk_whispers, plural));
+    }
+    tooltip
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::control;
+
+    fn status() -> Status {
+        control::channel().1.status()
+    }
+
+    fn find_action(items: &[MenuItem], wanted: MenuAction) -> Option<&MenuItem> {
+        items.iter().find_map(|item| match item {
+            MenuItem::Action { action, .. } if *action == wanted => Some(item),
+            MenuItem::Submenu { items, .. } => find_action(items, wanted),
+            _ => None,
+        })
+    }
+
+    fn is_enabled(items: &[MenuItem], wanted: MenuAction) -> bool {
+        match find_action(items, wanted) {
+            Some(MenuItem::Action { enabled, .. }) => *enabled,
+            _ => panic!("no {:?} in the menu", wanted),
+        }
+    }
+
+    #[test]
+    fn pausing_and_resuming() {
+        let items = build_menu(&status());
+        assert!(find_action(&items, MenuAction::Resume).is_none());
+        let pause = items.iter().find_map(|item| match item {
+            MenuItem::Submenu { label, items } if label == "Pause" => Some(items),
+            _ => None,
+        });
+        let pause_actions: Vec<MenuAction> = pause.unwrap().iter()
+            .filter_map(|item| match item {
+                MenuItem::Action { action, .. } => Some(*action),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(pause_actions, vec![MenuAction::PauseFor15Minutes, MenuAction::PauseForAnHour, MenuAction::PauseUntilRestart]);
+
+        let mut paused = status();
+        paused.paused = true;
+        let items = build_menu(&paused);
+        assert!(is_enabled(&items, MenuAction::Resume));
+        assert!(find_action(&items, MenuAction::PauseFor15Minutes).is_none());
+        assert!(!items.iter().any(|item| matches!(item, MenuItem::Submenu { .. })));
+    }
+
+    #[test]
+    fn minimize_and_restore_depend_on_the_window() {
+        let mut status = status();
+        let items = build_menu(&status);
+        assert!(!is_enabled(&items, MenuAction::MinimizeNow));
+        assert!(!is_enabled(&items, MenuAction::RestoreNow));
+
+        status.game_running = true;
+        let items = build_menu(&status);
+        assert!(is_enabled(&items, MenuAction::MinimizeNow));
+        assert!(!is_enabled(&items, MenuAction::RestoreNow));
+
+        status.minimized = true;
+        let items = build_menu(&status);
+        assert!(!is_enabled(&items, MenuAction::MinimizeNow));
+        assert!(is_enabled(&items, MenuAction::RestoreNow));
+    }
+
+    #[test]
+    fn autostart_checkbox() {
+        let mut status = status();
+        assert!(find_action(&build_menu(&status), MenuAction::ToggleAutoStart).is_none());
+        for run_on_startup in [false, true] {
+            status.run_on_startup = Some(run_on_startup);
+            match find_action(&build_menu(&status), MenuAction::ToggleAutoStart) {
+                Some(MenuItem::Action { checked, enabled, .. }) => {
+                    assert_eq!(*checked, Some(run_on_startup));
+                    assert!(*enabled);
+                }
+                _ => panic!("no autostart checkbox"),
+            }
+        }
+    }
+
+    #[test]
+    fn action_ids() {
+        for action in ALL_ACTIONS.iter() {
+            assert_ne!(action.id(), 0);
+            assert_eq!(MenuAction::from_id(action.id()), Some(*action));
+        }
+        assert_eq!(MenuAction::from_id(0), None);
+        assert_eq!(MenuAction::from_id(ALL_ACTIONS.len() as u32 + 1), None);
+    }
+}
@@ -0,0 +1,147 @@
+use std::fmt::Write;
+use std::io::{Error, ErrorKind};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use tiny_http::{Header, Response, Server};
+
+use crate::control::{Remote, Status};
+use crate::http_api::constant_time_equals;
+use crate::supervisor::Supervisor;
+use crate::utils::*;
+
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    const fn new() -> Self {
+        Counter(AtomicU64::new(0))
+    }
+
+    pub fn increment(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, value: u64) {
+        self.0.fetch_add(value, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+pub static MILLISECONDS_MINIMIZED: Counter = Counter::new();
+pub static WHISPERS_RECEIVED: Counter = Counter::new();
+pub static TRADES_DETECTED: Counter = Counter::new();
+pub static LOG_LINES_PARSED: Counter = Counter::new();
+/// log lines that looked like an area change or whisper but could not be parsed
+pub static PARSE_ERRORS: Counter = Counter::new();
+/// failures of the window, idle time, afk action and energy backends
+pub static BACKEND_ERRORS: Counter = Counter::new();
+/// panics caught by the supervisor
+pub static WORKER_CRASHES: Counter = Counter::new();
+
+pub trait CountedResult {
+    fn count_backend_error(self) -> Self;
+}
+
+impl<T, E> CountedResult for Result<T, E> {
+    fn count_backend_error(self) -> Self {
+        if self.is_err() {
+            BACKEND_ERRORS.increment();
+        }
+        self
+    }
+}
+
+pub fn add_time_minimized(duration: Duration) {
+    MILLISECONDS_MINIMIZED.add(duration.as_millis() as u64);
+}
+
+/// Serves `/metrics` in the prometheus text format. Unlike the http api this may listen on other
+/// interfaces so remote prometheus servers can scrape it, it is read only.
+pub fn start_exporter(address: &str, token: String, remote: Remote, supervisor: &Supervisor) -> Result<(), Error> {
+    let server = Server::http(address)
+        .map_err(|e| Error::new(ErrorKind::AddrInUse, format!("failed to listen on {}: {}", address, e)))?;
+    info!("metrics exporter listening on {}", address);
+    let expected = format!("Bearer {}", token);
+    supervisor.spawn("metrics exporter", move || {
+        for request in server.incoming_requests() {
+            let authorized = token.is_empty() || request.headers().iter()
+                .any(|header| header.field.equiv("Authorization") && constant_time_equals(header.value.as_str().as_bytes(), expected.as_bytes()));
+            let path = request.url().split('?').next().unwrap_or("");
+            let response = if !authorized {
+                Response::from_string("missing or wrong token\n").with_status_code(401)
+            } else if path != "/metrics" {
+                Response::from_string("not found\n").with_status_code(404)
+            } else {
+                Response::from_string(render(&remote.status()))
+                    .with_header(Header::from_bytes(&b"Content-Type"[..], &b"text/plain; version=0.0.4"[..]).unwrap())
+            };
+            request.respond(response).log_error_and_ignore("failed to answer metrics request");
+        }
+    })?;
+    Ok(())
+}
+
+pub fn render(status: &Status) -> String {
+    let mut text = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, value: f64| {
+        let _ = write!(text, "# HELP poe_minimizer_{0} {1}\n# TYPE poe_minimizer_{0} {2}\npoe_minimizer_{0} {3}\n", name, help, kind, value);
+    };
+    let flag = |value: bool| if value { 1.0 } else { 0.0 };
+    metric("game_running", "gauge", "Whether Path of Exile is running.", flag(status.game_running));
+    metric("afk", "gauge", "Whether the player is afk.", flag(status.afk));
+    metric("minimized", "gauge", "Whether the game window is minimized.", flag(status.minimized));
+    metric("paused", "gauge", "Whether minimizing is paused.", flag(status.paused));
+    metric("minimized_seconds_total", "counter", "Time the game spent minimized because the player was afk.",
+           MILLISECONDS_MINIMIZED.get() as f64 / 1000.0);
+    metric("whispers_received_total", "counter", "Whispers received.", WHISPERS_RECEIVED.get() as f64);
+    metric("trades_detected_total", "counter", "Whispers recognized as trade requests.", TRADES_DETECTED.get() as f64);
+    metric("log_lines_parsed_total", "counter", "Lines read from Client.txt.", LOG_LINES_PARSED.get() as f64);
+    metric("parse_errors_total", "counter", "Client.txt lines that could not be parsed.", PARSE_ERRORS.get() as f64);
+    metric("backend_errors_total", "counter", "Errors of the window, idle time, afk action and energy backends.",
+           BACKEND_ERRORS.get() as f64);
+    metric("worker_crashes_total", "counter", "Worker threads that panicked.", WORKER_CRASHES.get() as f64);
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+
+    use super::*;
+    use crate::control;
+    use crate::shutdown::Shutdown;
+
+    fn get(address: &str, path: &str, authorization: Option<&str>) -> (u16, String) {
+        let mut stream = TcpStream::connect(address).unwrap();
+        let authorization = authorization.map(|value| format!("Authorization: {}\r\n", value)).unwrap_or_default();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n{}\r\n", path, address, authorization).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let status = response.split_whitespace().nth(1).unwrap().parse().unwrap();
+        (status, response)
+    }
+
+    #[test]
+    fn exporter_checks_the_token_and_the_path() {
+        let address = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+        let (_controller, remote) = control::channel();
+        let shutdown = Shutdown::new();
+        let supervisor = Supervisor::new(remote.clone(), shutdown.clone(), 1);
+        start_exporter(&address, "secret".to_owned(), remote, &supervisor).unwrap();
+
+        assert_eq!(get(&address, "/metrics", None).0, 401);
+        assert_eq!(get(&address, "/metrics", Some("Bearer secreT")).0, 401);
+        assert_eq!(get(&address, "/metrics", Some("Bearer secret2")).0, 401);
+        let (status, body) = get(&address, "/metrics", Some("Bearer secret"));
+        assert_eq!(status, 200);
+        assert!(body.contains("poe_minimizer_game_running 0"), "{}", body);
+        assert_eq!(get(&address, "/metrics?name[]=poe_minimizer_afk", Some("Bearer secret")).0, 200);
+        assert_eq!(get(&address, "/metricsx", Some("Bearer secret")).0, 404);
+        assert_eq!(get(&address, "/", Some("Bearer secret")).0, 404);
+        shutdown.request();
+    }
+}
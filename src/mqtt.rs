@@ -0,0 +1,437 @@
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::sync::mpsc::Receiver;
+use std::time::{Duration, Instant};
+
+use serde_json::{json, Value};
+
+use crate::control::{Event, Remote, Status};
+use crate::metrics;
+use crate::settings::Settings;
+use crate::shutdown::Shutdown;
+use crate::supervisor::Supervisor;
+
+const KEEP_ALIVE_SECONDS: u16 = 60;
+const POLLING_INTERVAL: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Where and as whom to publish, from the `[mqtt]` section.
+#[derive(Debug, Clone)]
+struct Config {
+    host: String,
+    port: u16,
+    username: String,
+    password: String,
+    client_id: String,
+    topic_prefix: String,
+    /// empty disables home assistant discovery
+    discovery_prefix: String,
+}
+
+impl Config {
+    fn topic(&self, name: &str) -> String {
+        format!("{}/{}", self.topic_prefix, name)
+    }
+}
+
+/// Publishes the game state as retained topics and trade whispers as events to an mqtt broker, reconnecting
+/// whenever the connection is lost. The broker marks poe-minimizer offline through the last will.
+pub fn start_publisher(settings: &Settings, remote: Remote, supervisor: &Supervisor) -> Result<(), Error> {
+    if settings.mqtt_host.is_empty() {
+        return Err(Error::new(ErrorKind::InvalidInput, "no mqtt broker configured"));
+    }
+    let config = Config {
+        host: settings.mqtt_host.clone(),
+        port: settings.mqtt_port,
+        username: settings.mqtt_username.clone(),
+        password: settings.mqtt_password.clone(),
+        client_id: settings.mqtt_client_id.clone(),
+        topic_prefix: settings.mqtt_topic_prefix.trim_end_matches('/').to_owned(),
+        discovery_prefix: settings.mqtt_discovery_prefix.trim_end_matches('/').to_owned(),
+    };
+    let shutdown = supervisor.shutdown().clone();
+    supervisor.spawn("mqtt", move || Publisher::new(config.clone(), remote.clone(), shutdown.clone()).run())?;
+    Ok(())
+}
+
+struct Publisher {
+    config: Config,
+    remote: Remote,
+    shutdown: Shutdown,
+    /// retained values sent over the current connection
+    published: HashMap<String, String>,
+    /// trade requests detected by the service, queued while disconnected
+    events: Receiver<Event>,
+}
+
+impl Publisher {
+    fn new(config: Config, remote: Remote, shutdown: Shutdown) -> Self {
+        let events = remote.subscribe();
+        Publisher { config, remote, shutdown, published: HashMap::new(), events }
+    }
+
+    fn run(mut self) {
+        let mut delay = Duration::from_secs(1);
+        while !self.shutdown.is_requested() {
+            match Connection::open(&self.config) {
+                Ok(connection) => {
+                    info!("connected to mqtt broker {}:{}", self.config.host, self.config.port);
+                    delay = Duration::from_secs(1);
+                    if let Err(e) = self.serve(connection) {
+                        warn!("lost connection to mqtt broker: {}", e);
+                    }
+                }
+                Err(e) => {
+                    metrics::BACKEND_ERRORS.increment();
+                    warn!("failed to connect to mqtt broker {}:{}, retrying in {}s: {}", self.config.host, self.config.port, delay.as_secs(), e);
+                }
+            }
+            if self.shutdown.wait(delay) {
+                break;
+            }
+            delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+        }
+    }
+
+    fn serve(&mut self, mut connection: Connection) -> Result<(), Error> {
+        self.published.clear();
+        connection.publish(&self.config.topic("availability"), "online", true)?;
+        if !self.config.discovery_prefix.is_empty() {
+            for (topic, payload) in discovery_configs(&self.config) {
+                connection.publish(&topic, &payload.to_string(), true)?;
+            }
+        }
+        loop {
+            let status = self.remote.status();
+            for (name, value) in state_values(&status) {
+                let topic = self.config.topic(name);
+                if self.published.get(&topic) != Some(&value) {
+                    connection.publish(&topic, &value, true)?;
+                    self.published.insert(topic, value);
+                }
+            }
+            for event in self.events.try_iter() {
+                let Event::TradeRequest { request, received } = event;
+                let event = json!({
+                    "event_type": "trade_request",
+                    "buyer": request.buyer,
+                    "item": request.item,
+                    "price": request.price,
+                    "league": request.league,
+                    "received": received.to_rfc3339(),
+                });
+                connection.publish(&self.config.topic("trade"), &event.to_string(), false)?;
+            }
+            if connection.last_sent.elapsed() >= Duration::from_secs(KEEP_ALIVE_SECONDS as u64 / 2) {
+                connection.ping()?;
+            }
+            if self.shutdown.wait(POLLING_INTERVAL) {
+                // unlike a lost connection a clean disconnect does not publish the will
+                connection.publish(&self.config.topic("availability"), "offline", true)?;
+                return connection.disconnect();
+            }
+        }
+    }
+}
+
+fn state_values(status: &Status) -> Vec<(&'static str, String)> {
+    let on_off = |value: bool| if value { "ON" } else { "OFF" }.to_owned();
+    let state = serde_json::to_value(status.state).ok()
+        .and_then(|state| state.as_str().map(str::to_owned))
+        .unwrap_or_default();
+    vec![
+        ("state", state),
+        ("running", on_off(status.game_running)),
+        ("afk", on_off(status.afk)),
+        ("minimized", on_off(status.minimized)),
+        ("zone", status.zone.clone().unwrap_or_default()),
+    ]
+}
+
+/// Home assistant's mqtt discovery, one device with a sensor per state topic and an event entity for trades.
+fn discovery_configs(config: &Config) -> Vec<(String, Value)> {
+    let node_id: String = config.client_id.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+        .collect();
+    let device = json!({
+        "identifiers": [node_id],
+        "name": "poe-minimizer",
+        "model": "poe-minimizer",
+        "sw_version": VERSION,
+    });
+    let entities = vec![
+        ("binary_sensor", "running", json!({"name": "Game running", "payload_on": "ON", "payload_off": "OFF", "device_class": "running"})),
+        ("binary_sensor", "afk", json!({"name": "AFK", "payload_on": "ON", "payload_off": "OFF"})),
+        ("binary_sensor", "minimized", json!({"name": "Minimized", "payload_on": "ON", "payload_off": "OFF"})),
+        ("sensor", "state", json!({"name": "State", "device_class": "enum",
+            "options": ["game_not_running", "watching", "afk_pending", "minimized", "paused"]})),
+        ("sensor", "zone", json!({"name": "Zone", "icon": "mdi:map-marker"})),
+        ("event", "trade", json!({"name": "Trade request", "event_types": ["trade_request"]})),
+    ];
+    entities.into_iter()
+        .map(|(component, name, mut payload)| {
+            payload["unique_id"] = Value::String(format!("{}_{}", node_id, name));
+            payload["state_topic"] = Value::String(config.topic(name));
+            payload["availability_topic"] = Value::String(config.topic("availability"));
+            payload["device"] = device.clone();
+            (format!("{}/{}/{}/{}/config", config.discovery_prefix, component, node_id, name), payload)
+        })
+        .collect()
+}
+
+/// Just enough of mqtt 3.1.1 to publish at qos 0: connect with a last will, publish and ping.
+struct Connection {
+    stream: TcpStream,
+    last_sent: Instant,
+}
+
+impl Connection {
+    fn open(config: &Config) -> Result<Connection, Error> {
+        let stream = TcpStream::connect((config.host.as_str(), config.port))?;
+        stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+        stream.set_write_timeout(Some(Duration::from_secs(10)))?;
+        let mut connection = Connection { stream, last_sent: Instant::now() };
+
+        // clean session, will flag and a retained will at qos 0
+        let mut flags = 0x02 | 0x04 | 0x20;
+        let mut packet = Vec::new();
+        write_string(&mut packet, "MQTT");
+        packet.push(4); // protocol level 3.1.1
+        if !config.username.is_empty() {
+            flags |= 0x80;
+            if !config.password.is_empty() {
+                flags |= 0x40;
+            }
+        }
+        packet.push(flags);
+        packet.extend_from_slice(&KEEP_ALIVE_SECONDS.to_be_bytes());
+        write_string(&mut packet, &config.client_id);
+        write_string(&mut packet, &config.topic("availability"));
+        write_string(&mut packet, "offline");
+        if !config.username.is_empty() {
+            write_string(&mut packet, &config.username);
+            if !config.password.is_empty() {
+                write_string(&mut packet, &config.password);
+            }
+        }
+        connection.send(0x10, &packet)?;
+
+        let (header, connack) = connection.receive()?;
+        if header != 0x20 || connack.len() != 2 {
+            return Err(Error::new(ErrorKind::InvalidData, "the broker did not answer with CONNACK"));
+        }
+        match connack[1] {
+            0 => Ok(connection),
+            4 | 5 => Err(Error::new(ErrorKind::PermissionDenied, "the broker rejected the username or password")),
+            code => Err(Error::new(ErrorKind::ConnectionRefused, format!("the broker refused the connection with code {}", code))),
+        }
+    }
+
+    fn publish(&mut self, topic: &str, payload: &str, retain: bool) -> Result<(), Error> {
+        let mut packet = Vec::with_capacity(topic.len() + payload.len() + 2);
+        write_string(&mut packet, topic);
+        packet.extend_from_slice(payload.as_bytes());
+        self.send(0x30 | retain as u8, &packet)
+    }
+
+    /// Skips whatever else the broker sends until the PINGRESP, nothing else is of interest to a publisher.
+    fn ping(&mut self) -> Result<(), Error> {
+        self.send(0xc0, &[])?;
+        loop {
+            let (header, body) = self.receive()?;
+            if header & 0xf0 == 0xd0 {
+                return Ok(());
+            }
+            debug!("ignoring mqtt packet {:#04x} with {} bytes", header, body.len());
+        }
+    }
+
+    fn disconnect(&mut self) -> Result<(), Error> {
+        self.send(0xe0, &[])
+    }
+
+    fn send(&mut self, header: u8, body: &[u8]) -> Result<(), Error> {
+        let mut packet = Vec::with_capacity(body.len() + 5);
+        packet.push(header);
+        // the remaining length, 7 bits per byte
+        let mut length = body.len();
+        loop {
+            let mut byte = (length % 128) as u8;
+            length /= 128;
+            if length > 0 {
+                byte |= 0x80;
+            }
+            packet.push(byte);
+            if length == 0 {
+                break;
+            }
+        }
+        packet.extend_from_slice(body);
+        self.stream.write_all(&packet)?;
+        self.last_sent = Instant::now();
+        Ok(())
+    }
+
+    /// Reads a whole packet, its fixed header byte and body.
+    fn receive(&mut self) -> Result<(u8, Vec<u8>), Error> {
+        let mut byte = [0u8; 1];
+        self.stream.read_exact(&mut byte)?;
+        let header = byte[0];
+        let mut length = 0usize;
+        for shift in (0..28).step_by(7) {
+            self.stream.read_exact(&mut byte)?;
+            length |= ((byte[0] & 0x7f) as usize) << shift;
+            if byte[0] & 0x80 == 0 {
+                let mut body = vec![0u8; length];
+                self.stream.read_exact(&mut body)?;
+                return Ok((header, body));
+            }
+        }
+        Err(Error::new(ErrorKind::InvalidData, "malformed mqtt packet length"))
+    }
+}
+
+fn write_string(packet: &mut Vec<u8>, value: &str) {
+    packet.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    packet.extend_from_slice(value.as_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use std::process::Stdio;
+    use std::thread;
+
+    use chrono::Local;
+
+    use super::*;
+    use crate::control::{self, Controller};
+    use crate::trade::TradeRequest;
+
+    fn config(host: &str, port: u16, topic_prefix: &str) -> Config {
+        Config {
+            host: host.to_owned(),
+            port,
+            username: String::new(),
+            password: String::new(),
+            client_id: topic_prefix.to_owned(),
+            topic_prefix: topic_prefix.to_owned(),
+            discovery_prefix: String::new(),
+        }
+    }
+
+    fn trade_request() -> Event {
+        let request = TradeRequest {
+            buyer: "Buyer".to_owned(),
+            item: "Tabula Rasa Simple Robe".to_owned(),
+            price: Some("5 chaos".to_owned()),
+            league: Some("Standard".to_owned()),
+        };
+        Event::TradeRequest { request, received: Local::now() }
+    }
+
+    /// A publisher on a thread of its own, subscribed before this returns.
+    fn start_publisher(config: Config) -> (Controller, Shutdown, thread::JoinHandle<()>) {
+        let (controller, remote) = control::channel();
+        let shutdown = Shutdown::new();
+        let publisher = Publisher::new(config, remote, shutdown.clone());
+        (controller, shutdown, thread::spawn(move || publisher.run()))
+    }
+
+    /// The broker's end of a connection, which speaks the same packets.
+    fn accept(listener: &TcpListener) -> Connection {
+        let (stream, _) = listener.accept().unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut broker = Connection { stream, last_sent: Instant::now() };
+        assert_eq!(broker.receive().unwrap().0, 0x10);
+        broker.send(0x20, &[0, 0]).unwrap();
+        broker
+    }
+
+    /// Topic and payload of the next PUBLISH.
+    fn next_publish(broker: &mut Connection) -> (String, String) {
+        loop {
+            let (header, body) = broker.receive().unwrap();
+            if header & 0xf0 == 0x30 {
+                let topic_length = u16::from_be_bytes([body[0], body[1]]) as usize;
+                let topic = String::from_utf8(body[2..2 + topic_length].to_vec()).unwrap();
+                return (topic, String::from_utf8(body[2 + topic_length..].to_vec()).unwrap());
+            }
+        }
+    }
+
+    fn next_publish_to(broker: &mut Connection, topic: &str) -> String {
+        loop {
+            let (published_topic, payload) = next_publish(broker);
+            if published_topic == topic {
+                return payload;
+            }
+        }
+    }
+
+    #[test]
+    fn publishes_trade_requests_of_the_service() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (controller, shutdown, handle) = start_publisher(config("127.0.0.1", port, "test"));
+        let mut broker = accept(&listener);
+        assert_eq!(next_publish_to(&mut broker, "test/availability"), "online");
+        assert_eq!(next_publish_to(&mut broker, "test/state"), "game_not_running");
+
+        controller.publish(trade_request());
+        let event: Value = serde_json::from_str(&next_publish_to(&mut broker, "test/trade")).unwrap();
+        assert_eq!(event["event_type"], "trade_request");
+        assert_eq!(event["buyer"], "Buyer");
+        assert_eq!(event["item"], "Tabula Rasa Simple Robe");
+        assert_eq!(event["price"], "5 chaos");
+
+        shutdown.request();
+        assert_eq!(next_publish_to(&mut broker, "test/availability"), "offline");
+        assert_eq!(broker.receive().unwrap().0, 0xe0);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn ping_skips_other_packets() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let broker = thread::spawn(move || {
+            let mut broker = accept(&listener);
+            assert_eq!(broker.receive().unwrap().0, 0xc0);
+            let mut publish = Vec::new();
+            write_string(&mut publish, "elsewhere");
+            publish.extend_from_slice(b"payload");
+            broker.send(0x30, &publish).unwrap();
+            broker.send(0xd0, &[]).unwrap();
+            assert_eq!(broker.receive().unwrap().0, 0xc0);
+            broker.send(0xd0, &[]).unwrap();
+        });
+        let mut connection = Connection::open(&config("127.0.0.1", port, "test")).unwrap();
+        connection.ping().unwrap();
+        connection.ping().unwrap();
+        broker.join().unwrap();
+    }
+
+    /// Needs mosquitto on localhost:1883 and mosquitto_sub, run with `cargo test -- --ignored`.
+    #[test]
+    #[ignore]
+    fn publishes_to_mosquitto() {
+        let prefix = format!("poe-minimizer-test-{}", std::process::id());
+        let subscriber = std::process::Command::new("mosquitto_sub")
+            .args(["-h", "localhost", "-t", &format!("{}/trade", prefix), "-C", "1", "-W", "10"])
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        // mosquitto_sub does not tell when it is subscribed
+        thread::sleep(Duration::from_millis(500));
+        let (controller, shutdown, handle) = start_publisher(config("localhost", 1883, &prefix));
+        controller.publish(trade_request());
+        let output = subscriber.wait_with_output().unwrap();
+        let event: Value = serde_json::from_slice(&output.stdout).unwrap();
+        assert_eq!(event["buyer"], "Buyer");
+        shutdown.request();
+        handle.join().unwrap();
+    }
+}
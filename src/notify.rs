@@ -0,0 +1,182 @@
+use std::io::Error;
+
+/// Shows desktop notifications.
+pub trait Notifier {
+    fn notify(&mut self, title: &str, body: &str) -> Result<(), Error>;
+}
+
+/// Balloon tips of the tray icon.
+#[cfg(windows)]
+pub fn system_notifier() -> Option<Box<dyn Notifier + Send>> {
+    Some(Box::new(windows::BalloonNotifier))
+}
+
+/// The notification daemon of the desktop on the session bus.
+#[cfg(target_os = "linux")]
+pub fn system_notifier() -> Option<Box<dyn Notifier + Send>> {
+    use crate::utils::LoggableResult;
+    dbus_notifications::DbusNotifier::connect()
+        .log_error("failed to connect to the session bus, notifications are disabled")
+        .ok()
+        .map(|notifier| Box::new(notifier) as Box<dyn Notifier + Send>)
+}
+
+#[cfg(windows)]
+mod windows {
+    use std::io::Error;
+
+    use super::Notifier;
+
+    pub struct BalloonNotifier;
+
+    impl Notifier for BalloonNotifier {
+        fn notify(&mut self, title: &str, body: &str) -> Result<(), Error> {
+            crate::tray::show_balloon(title, body)
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod dbus_notifications {
+    use std::io::Error;
+    use std::time::Duration;
+
+    use dbus::arg::{PropMap, Variant};
+    use dbus::blocking::Connection;
+
+    use super::Notifier;
+
+    const INTERFACE: &str = "org.freedesktop.Notifications";
+    /// lets the notification server pick how long to show it
+    const DEFAULT_TIMEOUT: i32 = -1;
+
+    /// Talks to whatever implements `org.freedesktop.Notifications` on the session bus
+    /// `DBUS_SESSION_BUS_ADDRESS` points to.
+    pub struct DbusNotifier {
+        connection: Connection,
+        /// notifications replace the previous one instead of piling up
+        last_id: u32,
+    }
+
+    impl DbusNotifier {
+        pub fn connect() -> Result<Self, Error> {
+            Ok(DbusNotifier::on(Connection::new_session().map_err(to_io_error)?))
+        }
+
+        fn on(connection: Connection) -> Self {
+            DbusNotifier { connection, last_id: 0 }
+        }
+    }
+
+    impl Notifier for DbusNotifier {
+        fn notify(&mut self, title: &str, body: &str) -> Result<(), Error> {
+            let proxy = self.connection.with_proxy(INTERFACE, "/org/freedesktop/Notifications", Duration::from_secs(2));
+            let mut hints = PropMap::new();
+            hints.insert("category".to_owned(), Variant(Box::new("im.received".to_owned())));
+            let actions: Vec<String> = Vec::new();
+            let (id,): (u32,) = proxy.method_call(INTERFACE, "Notify", (
+                "poe-minimizer",
+                self.last_id,
+                "",
+                title,
+                escape_markup(body),
+                actions,
+                hints,
+                DEFAULT_TIMEOUT,
+            )).map_err(to_io_error)?;
+            self.last_id = id;
+            Ok(())
+        }
+    }
+
+    /// Servers may interpret a subset of html in the body, item names must not be mistaken for it.
+    fn escape_markup(text: &str) -> String {
+        text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+    }
+
+    fn to_io_error(error: dbus::Error) -> Error {
+        Error::other(format!("notifications: {}", error))
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use std::sync::mpsc;
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::sync::Arc;
+        use std::thread;
+
+        use dbus::channel::{MatchingReceiver, Sender};
+        use dbus::message::MatchRule;
+        use dbus::Message;
+
+        use crate::utils::TestBus;
+
+        use super::*;
+
+        #[derive(Debug, PartialEq)]
+        struct Notification {
+            replaces_id: u32,
+            summary: String,
+            body: String,
+            category: String,
+        }
+
+        /// Plays the notification server, handing out ids starting at 7 and failing for a summary of "fail".
+        fn scripted_server(bus: &TestBus) -> (mpsc::Receiver<Notification>, Arc<AtomicBool>) {
+            let server = bus.connect();
+            server.request_name(INTERFACE, false, true, true).unwrap();
+            let (sender, notifications) = mpsc::channel();
+            let stop = Arc::new(AtomicBool::new(false));
+            let stopped = stop.clone();
+            thread::spawn(move || {
+                let mut next_id: u32 = 7;
+                server.start_receive(MatchRule::new_method_call(), Box::new(move |message: Message, connection| {
+                    if message.member().as_deref() != Some("Notify") {
+                        return true;
+                    }
+                    let (_, replaces_id, _, summary, body, _, hints, _): (String, u32, String, String, String, Vec<String>, PropMap, i32) =
+                        message.read_all().unwrap();
+                    let reply = if summary == "fail" {
+                        message.error(&"org.freedesktop.DBus.Error.Failed".into(), &std::ffi::CString::new("no").unwrap())
+                    } else {
+                        next_id += 1;
+                        message.method_return().append1(next_id - 1)
+                    };
+                    let category = hints["category"].0.as_str().unwrap_or_default().to_owned();
+                    let _ = sender.send(Notification { replaces_id, summary, body, category });
+                    let _ = connection.send(reply);
+                    true
+                }));
+                while !stopped.load(Ordering::SeqCst) {
+                    server.process(Duration::from_millis(50)).unwrap();
+                }
+            });
+            (notifications, stop)
+        }
+
+        #[test]
+        fn notifications_over_a_private_session_bus() {
+            let bus = TestBus::start();
+            let (notifications, stop) = scripted_server(&bus);
+            let mut notifier = DbusNotifier::on(bus.connect());
+            let next = || notifications.recv_timeout(Duration::from_secs(2)).unwrap();
+
+            notifier.notify("Trade request", "<Buyer> wants Tabula Rasa & more").unwrap();
+            assert_eq!(next(), Notification {
+                replaces_id: 0,
+                summary: "Trade request".to_owned(),
+                body: "&lt;Buyer&gt; wants Tabula Rasa &amp; more".to_owned(),
+                category: "im.received".to_owned(),
+            });
+            notifier.notify("Party invite", "from Someone").unwrap();
+            assert_eq!(next().replaces_id, 7);
+
+            assert!(notifier.notify("fail", "").is_err());
+            assert_eq!(next().replaces_id, 8);
+            // a failed notification does not lose the one to replace
+            notifier.notify("Trade request", "again").unwrap();
+            assert_eq!(next().replaces_id, 8);
+            stop.store(true, Ordering::SeqCst);
+        }
+    }
+}
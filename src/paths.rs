@@ -0,0 +1,110 @@
+use std::fs;
+use std::io::Error;
+use std::path::{Path, PathBuf};
+
+/// A file with this name next to the executable keeps everything there, like on windows.
+pub const PORTABLE_MARKER: &str = "portable";
+
+/// The kinds of files poe-minimizer keeps, each in its own xdg base directory on linux.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Directory {
+    /// `poe-minimizer.ini`
+    Config,
+    /// logs and what is needed to recover from a crash, like the priority restore record
+    State,
+    /// history worth keeping, like the afk sessions
+    Data,
+}
+
+pub fn config_path(file_name: &str) -> PathBuf {
+    directory(Directory::Config).join(file_name)
+}
+
+pub fn state_path(file_name: &str) -> PathBuf {
+    directory(Directory::State).join(file_name)
+}
+
+pub fn data_path(file_name: &str) -> PathBuf {
+    directory(Directory::Data).join(file_name)
+}
+
+pub fn log_directory() -> PathBuf {
+    directory(Directory::State)
+}
+
+/// Next to the executable on windows and in portable mode, otherwise `$XDG_CONFIG_HOME/poe-minimizer` and so on.
+/// Created if it does not exist yet.
+pub fn directory(directory: Directory) -> PathBuf {
+    match base_directory(directory) {
+        Some(base) if !is_portable() => {
+            let path = base.join("poe-minimizer");
+            let _ = fs::create_dir_all(&path);
+            path
+        }
+        _ => executable_directory(),
+    }
+}
+
+pub fn is_portable() -> bool {
+    cfg!(not(target_os = "linux")) || executable_directory().join(PORTABLE_MARKER).exists()
+}
+
+fn executable_directory() -> PathBuf {
+    std::env::current_exe().ok()
+        .and_then(|executable| executable.parent().map(Path::to_path_buf))
+        .unwrap_or_default()
+}
+
+/// `$XDG_CONFIG_HOME` and so on, regardless of portable mode.
+#[cfg(target_os = "linux")]
+pub fn base_directory(directory: Directory) -> Option<PathBuf> {
+    let (variable, fallback) = match directory {
+        Directory::Config => ("XDG_CONFIG_HOME", ".config"),
+        Directory::State => ("XDG_STATE_HOME", ".local/state"),
+        Directory::Data => ("XDG_DATA_HOME", ".local/share"),
+    };
+    // relative paths are invalid according to the spec and have to be ignored
+    let absolute = |path: PathBuf| if path.is_absolute() { Some(path) } else { None };
+    std::env::var_os(variable).map(PathBuf::from).and_then(absolute)
+        .or_else(|| std::env::var_os("HOME").map(PathBuf::from).and_then(absolute).map(|home| home.join(fallback)))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn base_directory(_directory: Directory) -> Option<PathBuf> {
+    None
+}
+
+/// Moves files an earlier version kept next to the executable to where they belong now, unless there is a file
+/// already. Files that can't be removed from a read only installation are copied. Returns what was moved, for
+/// logging once the logger is set up.
+pub fn migrate_from_executable_directory<F: Fn(&str) -> Option<Directory>>(directory_of: F) -> Vec<(PathBuf, Result<PathBuf, Error>)> {
+    if is_portable() {
+        return Vec::new();
+    }
+    let entries = match fs::read_dir(executable_directory()) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let target_directory = directory_of(entry.file_name().to_str()?)?;
+            let target = directory(target_directory).join(entry.file_name());
+            if target.exists() || !entry.path().is_file() {
+                return None;
+            }
+            let moved = move_file(&entry.path(), &target).map(|()| target);
+            Some((entry.path(), moved))
+        })
+        .collect()
+}
+
+fn move_file(from: &Path, to: &Path) -> Result<(), Error> {
+    if fs::rename(from, to).is_ok() {
+        return Ok(());
+    }
+    // another file system or a read only directory
+    fs::copy(from, to).map_err(|e| Error::other(format!("failed to copy {:?} to {:?}: {}", from, to, e)))?;
+    let _ = fs::remove_file(from);
+    Ok(())
+}
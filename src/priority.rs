@@ -0,0 +1,546 @@
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+
+use crate::afk_action::AfkAction;
+
+/// Lowers the scheduling priority of the game and pins it to a subset of cpus while afk.
+///
+/// The original values are written to a restore record before anything is changed, so they
+/// can still be restored on the next start if this process dies while the game is throttled.
+pub struct PriorityLowering {
+    nice: i32,
+    cpus: Vec<usize>,
+    record_path: PathBuf,
+    engaged: Option<RestoreRecord>,
+}
+
+impl PriorityLowering {
+    /// `nice` follows the unix convention (0 is normal, 19 the lowest priority),
+    /// an empty `cpus` leaves the affinity untouched.
+    pub fn new(nice: i32, cpus: Vec<usize>, record_path: PathBuf) -> Self {
+        PriorityLowering {
+            nice,
+            cpus,
+            record_path,
+            engaged: None,
+        }
+    }
+}
+
+impl AfkAction for PriorityLowering {
+    fn name(&self) -> &'static str {
+        "priority lowering"
+    }
+
+    fn engage(&mut self, process_id: u32) -> Result<(), Error> {
+        let record = platform::snapshot(process_id)?;
+        record.save(&self.record_path)?;
+        let nice = if platform::can_restore(&record) {
+            Some(self.nice)
+        } else {
+            warn!("not lowering the priority of the game, it could not be raised again without CAP_SYS_NICE or a higher RLIMIT_NICE");
+            None
+        };
+        let cpus = if self.cpus.is_empty() { None } else { Some(self.cpus.as_slice()) };
+        let result = platform::apply(&record, nice, cpus);
+        self.engaged = Some(record);
+        result
+    }
+
+    fn release(&mut self) -> Result<(), Error> {
+        match self.engaged.take() {
+            Some(record) => {
+                restore(&record)?;
+                remove_record(&self.record_path)
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+/// Restores the values of a record left behind by a previous run that did not shut down cleanly.
+pub fn restore_from_record(record_path: &Path) -> Result<(), Error> {
+    if !record_path.is_file() {
+        return Ok(());
+    }
+    let record = RestoreRecord::load(record_path)?;
+    info!("found priority restore record for process {}, restoring it", record.process_id);
+    restore(&record)?;
+    remove_record(record_path)
+}
+
+fn restore(record: &RestoreRecord) -> Result<(), Error> {
+    // the game may have exited and its pid been reused since, only touch the process we changed
+    match platform::start_time(record.process_id) {
+        Ok(start_time) if start_time == record.start_time => platform::restore(record),
+        _ => {
+            info!("process {} is gone, nothing to restore", record.process_id);
+            Ok(())
+        }
+    }
+}
+
+fn remove_record(record_path: &Path) -> Result<(), Error> {
+    match fs::remove_file(record_path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Scheduling settings of a process before they were changed. On linux priority and affinity
+/// are per thread, so there is one entry per thread. On windows there is a single entry for the
+/// process holding its priority class.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RestoreRecord {
+    pub process_id: u32,
+    pub start_time: u64,
+    pub entries: Vec<SavedPriority>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SavedPriority {
+    pub id: u32,
+    pub priority: i32,
+    pub cpus: Vec<usize>,
+}
+
+impl RestoreRecord {
+    fn save(&self, path: &Path) -> Result<(), Error> {
+        let mut text = format!("process {} {}\n", self.process_id, self.start_time);
+        for entry in &self.entries {
+            text.push_str(&format!("entry {} {} {}\n", entry.id, entry.priority, format_cpu_list(&entry.cpus)));
+        }
+        fs::write(path, text)
+    }
+
+    fn load(path: &Path) -> Result<RestoreRecord, Error> {
+        RestoreRecord::parse(&fs::read_to_string(path)?)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, format!("malformed restore record {:?}", path)))
+    }
+
+    fn parse(text: &str) -> Option<RestoreRecord> {
+        let mut lines = text.lines();
+        let header: Vec<&str> = lines.next()?.split_whitespace().collect();
+        if header.len() != 3 || header[0] != "process" {
+            return None;
+        }
+        let mut record = RestoreRecord {
+            process_id: header[1].parse().ok()?,
+            start_time: header[2].parse().ok()?,
+            entries: Vec::new(),
+        };
+        for line in lines.filter(|line| !line.trim().is_empty()) {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() != 4 || fields[0] != "entry" {
+                return None;
+            }
+            record.entries.push(SavedPriority {
+                id: fields[1].parse().ok()?,
+                priority: fields[2].parse().ok()?,
+                cpus: parse_cpu_list(fields[3])?,
+            });
+        }
+        Some(record)
+    }
+}
+
+/// Parses cpu lists like `0-3,8,10-11`, cpus beyond what an affinity mask of the platform holds are invalid.
+pub fn parse_cpu_list(list: &str) -> Option<Vec<usize>> {
+    let mut cpus = Vec::new();
+    for part in list.split(',').map(|part| part.trim()).filter(|part| !part.is_empty()) {
+        match part.find('-') {
+            Some(separator) => {
+                let first: usize = part[..separator].trim().parse().ok()?;
+                let last: usize = part[separator + 1..].trim().parse().ok()?;
+                if last < first || last >= platform::MAX_CPUS {
+                    return None;
+                }
+                cpus.extend(first..=last);
+            }
+            None => cpus.push(part.parse().ok().filter(|cpu| *cpu < platform::MAX_CPUS)?),
+        }
+    }
+    cpus.sort_unstable();
+    cpus.dedup();
+    Some(cpus)
+}
+
+pub fn format_cpu_list(cpus: &[usize]) -> String {
+    let mut ranges: Vec<String> = Vec::new();
+    let mut index = 0;
+    while index < cpus.len() {
+        let first = cpus[index];
+        while index + 1 < cpus.len() && cpus[index + 1] == cpus[index] + 1 {
+            index += 1;
+        }
+        let last = cpus[index];
+        ranges.push(if first == last { first.to_string() } else { format!("{}-{}", first, last) });
+        index += 1;
+    }
+    ranges.join(",")
+}
+
+#[cfg(target_os = "linux")]
+mod platform {
+    use std::fs;
+    use std::io::{Error, ErrorKind};
+
+    use super::{RestoreRecord, SavedPriority};
+
+    pub const MAX_CPUS: usize = libc::CPU_SETSIZE as usize;
+
+    pub fn snapshot(process_id: u32) -> Result<RestoreRecord, Error> {
+        let mut entries = Vec::new();
+        for thread_id in thread_ids(process_id)? {
+            // threads may exit while we look at them
+            if let (Ok(priority), Ok(cpus)) = (get_nice(thread_id), get_affinity(thread_id)) {
+                entries.push(SavedPriority { id: thread_id, priority, cpus });
+            }
+        }
+        Ok(RestoreRecord {
+            process_id,
+            start_time: start_time(process_id)?,
+            entries,
+        })
+    }
+
+    pub fn apply(record: &RestoreRecord, nice: Option<i32>, cpus: Option<&[usize]>) -> Result<(), Error> {
+        for entry in &record.entries {
+            let mut applied = Ok(());
+            if let Some(nice) = nice {
+                applied = set_nice(entry.id, nice.max(entry.priority));
+            }
+            if let Some(cpus) = cpus {
+                applied = applied.and_then(|()| set_affinity(entry.id, cpus));
+            }
+            match applied {
+                // exited since the snapshot
+                Err(e) if e.raw_os_error() == Some(libc::ESRCH) => {}
+                applied => applied?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Threads started while afk inherited the lowered values, they get those of the process, i.e. its main thread.
+    pub fn restore(record: &RestoreRecord) -> Result<(), Error> {
+        let mut result = Ok(());
+        let process = record.entries.iter().find(|entry| entry.id == record.process_id).or_else(|| record.entries.first());
+        for thread_id in thread_ids(record.process_id)? {
+            let entry = match record.entries.iter().find(|entry| entry.id == thread_id).or(process) {
+                Some(entry) => entry,
+                None => continue,
+            };
+            match set_nice(thread_id, entry.priority).and_then(|_| set_affinity(thread_id, &entry.cpus)) {
+                Err(e) if e.raw_os_error() == Some(libc::ESRCH) => {}
+                Err(e) => {
+                    error!("failed to restore priority of thread {}: {}", thread_id, e);
+                    result = Err(e);
+                }
+                Ok(()) => {}
+            }
+        }
+        result
+    }
+
+    /// Unprivileged processes may only raise their priority down to `20 - RLIMIT_NICE`.
+    pub fn can_restore(record: &RestoreRecord) -> bool {
+        if unsafe { libc::geteuid() } == 0 {
+            return true;
+        }
+        let mut limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+        if unsafe { libc::getrlimit(libc::RLIMIT_NICE, &mut limit) } != 0 {
+            return false;
+        }
+        let lowest_nice = 20 - limit.rlim_cur.min(40) as i32;
+        record.entries.iter().all(|entry| entry.priority >= lowest_nice)
+    }
+
+    /// Time the process started at in clock ticks since boot, field 22 of /proc/<pid>/stat.
+    pub fn start_time(process_id: u32) -> Result<u64, Error> {
+        let stat = fs::read_to_string(format!("/proc/{}/stat", process_id))?;
+        // the command name in parentheses may contain spaces, so start after it
+        let after_command = stat.rfind(')').map(|index| &stat[index + 1..]).unwrap_or("");
+        after_command.split_whitespace()
+            .nth(19)
+            .and_then(|value| value.parse().ok())
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, format!("malformed /proc/{}/stat", process_id)))
+    }
+
+    fn thread_ids(process_id: u32) -> Result<Vec<u32>, Error> {
+        Ok(fs::read_dir(format!("/proc/{}/task", process_id))?
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| entry.file_name().to_str().and_then(|name| name.parse().ok()))
+            .collect())
+    }
+
+    fn get_nice(thread_id: u32) -> Result<i32, Error> {
+        // -1 is a valid priority, errno tells errors apart
+        unsafe {
+            *libc::__errno_location() = 0;
+            let nice = libc::getpriority(libc::PRIO_PROCESS, thread_id as libc::id_t);
+            if nice == -1 && *libc::__errno_location() != 0 {
+                return Err(Error::last_os_error());
+            }
+            Ok(nice)
+        }
+    }
+
+    fn set_nice(thread_id: u32, nice: i32) -> Result<(), Error> {
+        if unsafe { libc::setpriority(libc::PRIO_PROCESS, thread_id as libc::id_t, nice) } != 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn get_affinity(thread_id: u32) -> Result<Vec<usize>, Error> {
+        unsafe {
+            let mut set: libc::cpu_set_t = std::mem::zeroed();
+            if libc::sched_getaffinity(thread_id as libc::pid_t, std::mem::size_of::<libc::cpu_set_t>(), &mut set) != 0 {
+                return Err(Error::last_os_error());
+            }
+            Ok((0..libc::CPU_SETSIZE as usize).filter(|cpu| libc::CPU_ISSET(*cpu, &set)).collect())
+        }
+    }
+
+    fn set_affinity(thread_id: u32, cpus: &[usize]) -> Result<(), Error> {
+        unsafe {
+            let mut set: libc::cpu_set_t = std::mem::zeroed();
+            for cpu in cpus {
+                libc::CPU_SET(*cpu, &mut set);
+            }
+            if libc::sched_setaffinity(thread_id as libc::pid_t, std::mem::size_of::<libc::cpu_set_t>(), &set) != 0 {
+                return Err(Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(windows)]
+mod platform {
+    use std::io::Error;
+    use std::ptr::null_mut;
+
+    use winapi::shared::minwindef::FILETIME;
+    use winapi::um::handleapi::CloseHandle;
+    use winapi::um::processthreadsapi::{GetPriorityClass, GetProcessTimes, OpenProcess, SetPriorityClass};
+    use winapi::um::winbase::{BELOW_NORMAL_PRIORITY_CLASS, GetProcessAffinityMask, IDLE_PRIORITY_CLASS, SetProcessAffinityMask};
+    use winapi::um::winnt::{HANDLE, PROCESS_QUERY_INFORMATION, PROCESS_SET_INFORMATION};
+
+    use super::{RestoreRecord, SavedPriority};
+
+    /// bits of an affinity mask
+    pub const MAX_CPUS: usize = usize::MAX.count_ones() as usize;
+
+    /// Closes the process handle when dropped.
+    struct Process(HANDLE);
+
+    impl Process {
+        fn open(process_id: u32) -> Result<Process, Error> {
+            let handle = unsafe { OpenProcess(PROCESS_QUERY_INFORMATION | PROCESS_SET_INFORMATION, 0, process_id) };
+            if handle == null_mut() {
+                Err(Error::last_os_error())
+            } else {
+                Ok(Process(handle))
+            }
+        }
+    }
+
+    impl Drop for Process {
+        fn drop(&mut self) {
+            unsafe { CloseHandle(self.0) };
+        }
+    }
+
+    pub fn snapshot(process_id: u32) -> Result<RestoreRecord, Error> {
+        let process = Process::open(process_id)?;
+        let priority_class = unsafe { GetPriorityClass(process.0) };
+        if priority_class == 0 {
+            return Err(Error::last_os_error());
+        }
+        let mut process_mask = 0;
+        let mut system_mask = 0;
+        if 0 == unsafe { GetProcessAffinityMask(process.0, &mut process_mask, &mut system_mask) } {
+            return Err(Error::last_os_error());
+        }
+        let cpus = (0..MAX_CPUS)
+            .filter(|cpu| process_mask & (1 << cpu) != 0)
+            .collect();
+        Ok(RestoreRecord {
+            process_id,
+            start_time: start_time(process_id)?,
+            entries: vec![SavedPriority { id: process_id, priority: priority_class as i32, cpus }],
+        })
+    }
+
+    pub fn apply(record: &RestoreRecord, nice: Option<i32>, cpus: Option<&[usize]>) -> Result<(), Error> {
+        let process = Process::open(record.process_id)?;
+        if let Some(nice) = nice {
+            let priority_class = if nice >= 15 { IDLE_PRIORITY_CLASS } else { BELOW_NORMAL_PRIORITY_CLASS };
+            if 0 == unsafe { SetPriorityClass(process.0, priority_class) } {
+                return Err(Error::last_os_error());
+            }
+        }
+        if let Some(cpus) = cpus {
+            set_affinity(&process, cpus)?;
+        }
+        Ok(())
+    }
+
+    pub fn restore(record: &RestoreRecord) -> Result<(), Error> {
+        let process = Process::open(record.process_id)?;
+        for entry in &record.entries {
+            if 0 == unsafe { SetPriorityClass(process.0, entry.priority as u32) } {
+                return Err(Error::last_os_error());
+            }
+            set_affinity(&process, &entry.cpus)?;
+        }
+        Ok(())
+    }
+
+    /// Lowering the priority class of a process we own can always be undone.
+    pub fn can_restore(_record: &RestoreRecord) -> bool {
+        true
+    }
+
+    pub fn start_time(process_id: u32) -> Result<u64, Error> {
+        let process = Process::open(process_id)?;
+        let empty = || FILETIME { dwLowDateTime: 0, dwHighDateTime: 0 };
+        let (mut creation, mut exit, mut kernel, mut user) = (empty(), empty(), empty(), empty());
+        if 0 == unsafe { GetProcessTimes(process.0, &mut creation, &mut exit, &mut kernel, &mut user) } {
+            return Err(Error::last_os_error());
+        }
+        Ok((creation.dwHighDateTime as u64) << 32 | creation.dwLowDateTime as u64)
+    }
+
+    fn set_affinity(process: &Process, cpus: &[usize]) -> Result<(), Error> {
+        // parse_cpu_list already rejects cpus beyond the mask
+        let mask = cpus.iter().fold(0usize, |mask, cpu| mask | 1usize.checked_shl(*cpu as u32).unwrap_or(0));
+        if 0 == unsafe { SetProcessAffinityMask(process.0, mask) } {
+            return Err(Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cpu_lists() {
+        assert_eq!(parse_cpu_list("0-3,8, 10-11,2"), Some(vec![0, 1, 2, 3, 8, 10, 11]));
+        assert_eq!(format_cpu_list(&[0, 1, 2, 3, 8, 10, 11]), "0-3,8,10-11");
+        assert_eq!(parse_cpu_list(""), Some(Vec::new()));
+        assert_eq!(parse_cpu_list("3-1"), None);
+        assert_eq!(parse_cpu_list("x"), None);
+    }
+
+    #[test]
+    fn cpus_beyond_the_affinity_mask_are_invalid() {
+        let last = platform::MAX_CPUS - 1;
+        assert_eq!(parse_cpu_list(&last.to_string()), Some(vec![last]));
+        assert_eq!(parse_cpu_list(&platform::MAX_CPUS.to_string()), None);
+        assert_eq!(parse_cpu_list(&format!("0-{}", platform::MAX_CPUS)), None);
+        assert_eq!(parse_cpu_list("0-18446744073709551615"), None);
+    }
+
+    #[test]
+    fn restore_records() {
+        let record = RestoreRecord {
+            process_id: 42,
+            start_time: 1234,
+            entries: vec![
+                SavedPriority { id: 42, priority: 0, cpus: vec![0, 1, 2, 3] },
+                SavedPriority { id: 43, priority: -5, cpus: vec![1] },
+            ],
+        };
+        let path = crate::utils::test_directory("restore-record").join("priority-restore.txt");
+        record.save(&path).unwrap();
+        assert_eq!(RestoreRecord::load(&path).unwrap(), record);
+        assert!(RestoreRecord::parse("process 42\n").is_none());
+    }
+
+    #[cfg(target_os = "linux")]
+    fn thread_id() -> u32 {
+        unsafe { libc::syscall(libc::SYS_gettid) as u32 }
+    }
+
+    /// Nice value and cpus of a thread of `process_id`.
+    #[cfg(target_os = "linux")]
+    fn scheduling(process_id: u32, thread_id: u32) -> (i32, Vec<usize>) {
+        let record = platform::snapshot(process_id).unwrap();
+        record.entries.into_iter().find(|entry| entry.id == thread_id).map(|entry| (entry.priority, entry.cpus)).unwrap()
+    }
+
+    /// Set to run `priority_test_child` as the game stand-in of `release_resets_threads_started_while_afk`.
+    #[cfg(target_os = "linux")]
+    const CHILD_VARIABLE: &str = "POE_MINIMIZER_PRIORITY_TEST_CHILD";
+
+    /// Starts a thread once told to on stdin, prints its id and exits when stdin is closed. Does nothing in a regular
+    /// test run.
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn priority_test_child() {
+        use std::io::{BufRead, Write};
+
+        if std::env::var_os(CHILD_VARIABLE).is_none() {
+            return;
+        }
+        let stdin = std::io::stdin();
+        let mut lines = stdin.lock().lines();
+        lines.next();
+        std::thread::spawn(|| {
+            // written directly, the test harness only captures print!, and after its "test ... " without a line break
+            writeln!(std::io::stdout(), "child thread {}", thread_id()).unwrap();
+            loop {
+                std::thread::park();
+            }
+        });
+        while let Some(Ok(_)) = lines.next() {}
+    }
+
+    /// Lowers a child process while afk, and pins it to its first cpu. A thread it starts meanwhile inherits that and
+    /// has to be reset on release. The nice value is only lowered if it may be raised again, e.g. as root.
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn release_resets_threads_started_while_afk() {
+        use std::io::{BufRead, BufReader, Write};
+        use std::process::{Command, Stdio};
+
+        let mut child = Command::new(std::env::current_exe().unwrap())
+            .args(["priority::tests::priority_test_child", "--exact", "--nocapture"])
+            .env(CHILD_VARIABLE, "1")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let process_id = child.id();
+        let original = scheduling(process_id, process_id);
+        let record_path = crate::utils::test_directory("priority-threads").join("priority-restore.txt");
+        let mut lowering = PriorityLowering::new(original.0 + 5, original.1[..1].to_vec(), record_path.clone());
+        lowering.engage(process_id).unwrap();
+        assert!(record_path.is_file());
+        let lowered = scheduling(process_id, process_id);
+        assert_eq!(lowered.1, original.1[..1].to_vec());
+
+        let mut stdin = child.stdin.take().unwrap();
+        writeln!(stdin, "start a thread").unwrap();
+        // kept open until the child exited, it reports its test result as well
+        let mut stdout = BufReader::new(child.stdout.take().unwrap());
+        let thread = (&mut stdout).lines()
+            .map(Result::unwrap)
+            .find_map(|line| line.split("child thread ").nth(1).map(|id| id.parse::<u32>().unwrap()))
+            .unwrap();
+        assert_eq!(scheduling(process_id, thread), lowered);
+
+        lowering.release().unwrap();
+        assert_eq!(scheduling(process_id, process_id), original);
+        assert_eq!(scheduling(process_id, thread), original);
+        assert!(!record_path.exists());
+        drop(stdin);
+        assert!(child.wait().unwrap().success());
+    }
+}
@@ -0,0 +1,97 @@
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+
+/// Logs end up attached to public issues, so character names and chat are replaced unless `[log] redact=false`.
+static ENABLED: AtomicBool = AtomicBool::new(true);
+/// random for every run, pseudonyms can't be reversed by hashing a list of known names
+static KEY: OnceLock<RandomState> = OnceLock::new();
+
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// A pseudonym like `player-3f9a1c` for a character name or `<GUILD> Name`, the same for every occurrence until
+/// poe-minimizer is restarted.
+pub fn name(name: &str) -> String {
+    if !enabled() {
+        return name.to_owned();
+    }
+    format!("player-{:06x}", KEY.get_or_init(RandomState::new).hash_one(name) & 0xff_ffff)
+}
+
+/// Free text written by players, only its length is kept.
+pub fn chat(text: &str) -> String {
+    if !enabled() || text.is_empty() {
+        return text.to_owned();
+    }
+    format!("[{} chars]", text.chars().count())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::client_log::{self, LogEvent};
+    use crate::language::ALL_LANGUAGES;
+    use crate::utils::capture_logs;
+
+    const PREFIX: &str = "2020/02/07 01:15:50 1918890562 ac9 [INFO Client 28536] ";
+
+    /// redaction is switched on and off for the whole process
+    static ENABLED_LOCK: Mutex<()> = Mutex::new(());
+
+    fn parse(message: &str) -> (Option<LogEvent>, Vec<String>) {
+        let mut event = None;
+        let logged = capture_logs(|| event = client_log::parse_log_line(&format!("{}{}", PREFIX, message), &ALL_LANGUAGES));
+        (event, logged)
+    }
+
+    #[test]
+    fn names_get_stable_pseudonyms() {
+        let _lock = ENABLED_LOCK.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let pseudonym = name("<GUILD> Somebody");
+        assert!(pseudonym.starts_with("player-") && pseudonym.len() == "player-".len() + 6, "{}", pseudonym);
+        assert!(!pseudonym.contains("Somebody"));
+        assert_eq!(name("<GUILD> Somebody"), pseudonym);
+        assert_ne!(name("Somebody Else"), pseudonym);
+        assert_eq!(chat("wtb 5 chaos"), "[11 chars]");
+        assert_eq!(chat("ä€"), "[2 chars]");
+        assert_eq!(chat(""), "");
+    }
+
+    #[test]
+    fn client_log_trace_lines_are_redacted() {
+        let _lock = ENABLED_LOCK.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let (event, logged) = parse(": Somebody has invited you to a party.");
+        assert_eq!(event, Some(LogEvent::PartyInvite { from: "Somebody".to_owned() }));
+        assert_eq!(logged, vec![format!("log line: {}: {} has invited you to a party.", PREFIX, name("Somebody"))]);
+
+        let (event, logged) = parse(": AFK mode is now ON. Autoreply \"at the dentist\"");
+        assert_eq!(event, Some(LogEvent::AfkChanged(true)));
+        assert_eq!(logged, vec![format!("log line: {}: AFK mode is now ON.[27 chars]", PREFIX)]);
+
+        let (event, logged) = parse(": Somebody has joined the area.");
+        assert_eq!(event, None);
+        assert_eq!(logged, vec![format!("log line: {}: [29 chars]", PREFIX)]);
+        assert!(logged.iter().all(|line| !line.contains("Somebody") && !line.contains("dentist")));
+    }
+
+    #[test]
+    fn redaction_can_be_turned_off() {
+        let _lock = ENABLED_LOCK.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        set_enabled(false);
+        let names = (name("Somebody"), chat("wtb 5 chaos"));
+        let (_, logged) = parse(": Somebody has invited you to a party.");
+        set_enabled(true);
+        assert_eq!(names, ("Somebody".to_owned(), "wtb 5 chaos".to_owned()));
+        assert_eq!(logged, vec![format!("log line: {}: Somebody has invited you to a party.", PREFIX)]);
+        assert_ne!(name("Somebody"), "Somebody");
+    }
+}
@@ -0,0 +1,279 @@
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::ini::Ini;
+use crate::log_output::LogFormat;
+use crate::paths;
+use crate::priority::parse_cpu_list;
+
+pub const SETTINGS_FILE_NAME: &str = "poe-minimizer.ini";
+
+pub struct Settings {
+    pub window_name: String,
+    pub log_file_polling_interval_ms: u64,
+    pub seconds_until_minimize: u64,
+    pub seconds_to_check_for_poe: u64,
+    /// crashed workers are restarted until they crashed this often, 0 never restarts them
+    pub max_worker_crashes: u32,
+    /// linux only, `xdg` for an autostart desktop entry or `systemd` for a user unit
+    pub autostart: String,
+    /// overridden by `--log-format=<format>`
+    pub log_format: LogFormat,
+    /// replaces character names and chat in the log with pseudonyms
+    pub redact_logs: bool,
+    /// log.txt moves to log.1.txt once it is this large, 0 for no limit
+    pub log_max_size_mb: u64,
+    /// or once it is this old, 0 for no limit
+    pub log_rotate_after_hours: u64,
+    /// rotated logs kept besides log.txt
+    pub log_keep_files: usize,
+    /// rotated logs older than this are deleted, 0 keeps them until `log_keep_files` is exceeded
+    pub log_keep_days: u64,
+    /// gzip rotated logs
+    pub log_compress: bool,
+    /// minimize while in a hideout after this many minutes without keyboard or mouse input, 0 disables it
+    pub minutes_until_idle_minimize: u64,
+    pub lower_priority_while_afk: bool,
+    /// unix style nice value for the game while afk, on windows 15 and above select the idle priority class
+    pub afk_nice: i32,
+    /// cpus the game is restricted to while afk, empty leaves the affinity untouched
+    pub afk_cpus: Vec<usize>,
+    /// linux only, moves the game into a cgroup with a cpu quota while afk
+    pub throttle_cgroup_while_afk: bool,
+    /// share of a single cpu the game may use while throttled
+    pub afk_cpu_quota_percent: u32,
+    /// linux only, logs the energy saved per afk session from the rapl counters
+    pub measure_energy: bool,
+    pub powercap_root: PathBuf,
+    /// serves a json status and control api on localhost
+    pub http_api_enabled: bool,
+    pub http_api_port: u16,
+    /// required as bearer token by the http api, it refuses to start without one
+    pub http_api_token: String,
+    /// serves prometheus metrics on `metrics_listen`, which may be reachable from other machines
+    pub metrics_enabled: bool,
+    pub metrics_listen: String,
+    /// optional bearer token for scraping
+    pub metrics_token: String,
+    /// desktop notification for trade whispers
+    pub notify_trade_requests: bool,
+    pub notify_only_while_afk: bool,
+    /// publishes the state to an mqtt broker, with home assistant discovery
+    pub mqtt_enabled: bool,
+    pub mqtt_host: String,
+    pub mqtt_port: u16,
+    pub mqtt_username: String,
+    pub mqtt_password: String,
+    pub mqtt_client_id: String,
+    pub mqtt_topic_prefix: String,
+    /// empty disables discovery
+    pub mqtt_discovery_prefix: String,
+    /// plays a sound on trade whispers and party invites while minimized
+    pub sound_enabled: bool,
+    /// `default` or `null`
+    pub sound_output: String,
+    /// `bundled`, `none` or the path of a wav or ogg file
+    pub trade_request_sound: String,
+    /// percent
+    pub trade_request_volume: u32,
+    pub party_invite_sound: String,
+    pub party_invite_volume: u32,
+    /// one per `[webhook.<name>]` section
+    pub webhooks: Vec<WebhookSettings>,
+    /// not configurable, `None` uses the directories of `paths`
+    pub state_directory: Option<PathBuf>,
+    pub data_directory: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct WebhookSettings {
+    pub name: String,
+    pub url: String,
+    /// `json`, `discord` or `template`
+    pub format: String,
+    /// json file with `{{placeholders}}`, used with the `template` format
+    pub template: Option<PathBuf>,
+    /// `trade_request` and/or `state_changed`
+    pub events: Vec<String>,
+    /// trade requests are only sent while afk
+    pub only_while_afk: bool,
+    pub max_per_minute: u32,
+}
+
+impl WebhookSettings {
+    fn new(name: &str) -> Self {
+        WebhookSettings {
+            name: name.to_owned(),
+            url: String::new(),
+            format: "json".to_owned(),
+            template: None,
+            events: vec!["trade_request".to_owned(), "state_changed".to_owned()],
+            only_while_afk: true,
+            // the limit discord applies per webhook
+            max_per_minute: 30,
+        }
+    }
+}
+
+impl Settings {
+    pub fn default() -> Self {
+        Settings {
+            window_name: "Path of Exile".to_owned(),
+            log_file_polling_interval_ms: 500,
+            seconds_until_minimize: 5,
+            seconds_to_check_for_poe: 30,
+            max_worker_crashes: 5,
+            autostart: "xdg".to_owned(),
+            log_format: LogFormat::Text,
+            redact_logs: true,
+            log_max_size_mb: 10,
+            log_rotate_after_hours: 24,
+            log_keep_files: 5,
+            log_keep_days: 30,
+            log_compress: false,
+            minutes_until_idle_minimize: 0,
+            lower_priority_while_afk: false,
+            afk_nice: 10,
+            afk_cpus: Vec::new(),
+            throttle_cgroup_while_afk: false,
+            afk_cpu_quota_percent: 10,
+            measure_energy: true,
+            powercap_root: PathBuf::from("/sys/class/powercap"),
+            http_api_enabled: false,
+            http_api_port: 8637,
+            http_api_token: String::new(),
+            metrics_enabled: false,
+            metrics_listen: "127.0.0.1:9637".to_owned(),
+            metrics_token: String::new(),
+            notify_trade_requests: true,
+            notify_only_while_afk: true,
+            mqtt_enabled: false,
+            mqtt_host: "localhost".to_owned(),
+            mqtt_port: 1883,
+            mqtt_username: String::new(),
+            mqtt_password: String::new(),
+            mqtt_client_id: "poe-minimizer".to_owned(),
+            mqtt_topic_prefix: "poe-minimizer".to_owned(),
+            mqtt_discovery_prefix: "homeassistant".to_owned(),
+            sound_enabled: true,
+            sound_output: "default".to_owned(),
+            trade_request_sound: "bundled".to_owned(),
+            trade_request_volume: 80,
+            party_invite_sound: "bundled".to_owned(),
+            party_invite_volume: 80,
+            webhooks: Vec::new(),
+            state_directory: None,
+            data_directory: None,
+        }
+    }
+
+    pub fn state_path(&self, file_name: &str) -> PathBuf {
+        self.state_directory.as_ref().map(|directory| directory.join(file_name)).unwrap_or_else(|| paths::state_path(file_name))
+    }
+
+    pub fn data_path(&self, file_name: &str) -> PathBuf {
+        self.data_directory.as_ref().map(|directory| directory.join(file_name)).unwrap_or_else(|| paths::data_path(file_name))
+    }
+
+    /// Reads `poe-minimizer.ini` from the config directory, falling back to the defaults for anything missing.
+    pub fn load() -> Self {
+        Settings::load_from(&paths::config_path(SETTINGS_FILE_NAME))
+    }
+
+    pub fn load_from(path: &Path) -> Self {
+        let mut settings = Settings::default();
+        if !path.is_file() {
+            return settings;
+        }
+        match Ini::load(path) {
+            Ok(ini) => settings.apply(&ini),
+            Err(e) => error!("failed to read settings from {:?}, using defaults: {}", path, e),
+        }
+        settings
+    }
+
+    fn apply(&mut self, ini: &Ini) {
+        read_value(ini, "general", "seconds_until_minimize", &mut self.seconds_until_minimize);
+        read_value(ini, "general", "seconds_to_check_for_poe", &mut self.seconds_to_check_for_poe);
+        read_value(ini, "general", "log_file_polling_interval_ms", &mut self.log_file_polling_interval_ms);
+        read_value(ini, "general", "max_worker_crashes", &mut self.max_worker_crashes);
+        read_value(ini, "general", "autostart", &mut self.autostart);
+        read_value(ini, "log", "format", &mut self.log_format);
+        read_value(ini, "log", "redact", &mut self.redact_logs);
+        read_value(ini, "log", "max_size_mb", &mut self.log_max_size_mb);
+        read_value(ini, "log", "rotate_after_hours", &mut self.log_rotate_after_hours);
+        read_value(ini, "log", "keep_files", &mut self.log_keep_files);
+        read_value(ini, "log", "keep_days", &mut self.log_keep_days);
+        read_value(ini, "log", "compress", &mut self.log_compress);
+        read_value(ini, "idle", "minimize_after_minutes", &mut self.minutes_until_idle_minimize);
+        read_value(ini, "priority", "enabled", &mut self.lower_priority_while_afk);
+        read_value(ini, "priority", "nice", &mut self.afk_nice);
+        if let Some(cpus) = ini.get("priority", "cpus") {
+            match parse_cpu_list(cpus) {
+                Some(cpus) => self.afk_cpus = cpus,
+                None => warn!("ignoring invalid setting priority.cpus = {}", cpus),
+            }
+        }
+        read_value(ini, "cgroup", "enabled", &mut self.throttle_cgroup_while_afk);
+        read_value(ini, "cgroup", "cpu_quota_percent", &mut self.afk_cpu_quota_percent);
+        read_value(ini, "energy", "enabled", &mut self.measure_energy);
+        read_value(ini, "energy", "powercap_root", &mut self.powercap_root);
+        read_value(ini, "http", "enabled", &mut self.http_api_enabled);
+        read_value(ini, "http", "port", &mut self.http_api_port);
+        read_value(ini, "http", "token", &mut self.http_api_token);
+        read_value(ini, "metrics", "enabled", &mut self.metrics_enabled);
+        read_value(ini, "metrics", "listen", &mut self.metrics_listen);
+        read_value(ini, "metrics", "token", &mut self.metrics_token);
+        read_value(ini, "notifications", "enabled", &mut self.notify_trade_requests);
+        read_value(ini, "notifications", "only_while_afk", &mut self.notify_only_while_afk);
+        read_value(ini, "mqtt", "enabled", &mut self.mqtt_enabled);
+        read_value(ini, "mqtt", "host", &mut self.mqtt_host);
+        read_value(ini, "mqtt", "port", &mut self.mqtt_port);
+        read_value(ini, "mqtt", "username", &mut self.mqtt_username);
+        read_value(ini, "mqtt", "password", &mut self.mqtt_password);
+        read_value(ini, "mqtt", "client_id", &mut self.mqtt_client_id);
+        read_value(ini, "mqtt", "topic_prefix", &mut self.mqtt_topic_prefix);
+        read_value(ini, "mqtt", "discovery_prefix", &mut self.mqtt_discovery_prefix);
+        read_value(ini, "sound", "enabled", &mut self.sound_enabled);
+        read_value(ini, "sound", "output", &mut self.sound_output);
+        read_value(ini, "sound", "trade_request", &mut self.trade_request_sound);
+        read_value(ini, "sound", "trade_request_volume", &mut self.trade_request_volume);
+        read_value(ini, "sound", "party_invite", &mut self.party_invite_sound);
+        read_value(ini, "sound", "party_invite_volume", &mut self.party_invite_volume);
+        for section in ini.section_names() {
+            if section.len() > 8 && section.get(..8).map(|prefix| prefix.eq_ignore_ascii_case("webhook.")).unwrap_or(false) {
+                if let Some(webhook) = read_webhook(ini, section) {
+                    self.webhooks.push(webhook);
+                }
+            }
+        }
+    }
+}
+
+fn read_webhook(ini: &Ini, section: &str) -> Option<WebhookSettings> {
+    let mut webhook = WebhookSettings::new(&section[8..]);
+    read_value(ini, section, "url", &mut webhook.url);
+    read_value(ini, section, "format", &mut webhook.format);
+    if let Some(template) = ini.get(section, "template") {
+        webhook.template = Some(PathBuf::from(template));
+    }
+    if let Some(events) = ini.get(section, "events") {
+        webhook.events = events.split(',').map(|event| event.trim().to_owned()).filter(|event| !event.is_empty()).collect();
+    }
+    read_value(ini, section, "only_while_afk", &mut webhook.only_while_afk);
+    read_value(ini, section, "max_per_minute", &mut webhook.max_per_minute);
+    if webhook.url.is_empty() {
+        warn!("ignoring [{}] without url", section);
+        return None;
+    }
+    Some(webhook)
+}
+
+fn read_value<T: FromStr>(ini: &Ini, section: &str, key: &str, target: &mut T) {
+    if let Some(value) = ini.get(section, key) {
+        match value.parse() {
+            Ok(value) => *target = value,
+            Err(_) => warn!("ignoring invalid setting {}.{} = {}", section, key, value),
+        }
+    }
+}
@@ -0,0 +1,103 @@
+#[cfg(target_os = "linux")]
+use std::io::Error;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+#[cfg(target_os = "linux")]
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Tells every worker to stop, waking those that are waiting right away. Cheap to clone for every worker.
+#[derive(Clone)]
+pub struct Shutdown {
+    inner: Arc<(Mutex<Inner>, Condvar)>,
+}
+
+struct Inner {
+    requested: bool,
+    /// for workers blocked on something else than `wait`, like a channel or a message loop
+    wakers: Vec<Box<dyn Fn() + Send>>,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Shutdown { inner: Arc::new((Mutex::new(Inner { requested: false, wakers: Vec::new() }), Condvar::new())) }
+    }
+
+    pub fn request(&self) {
+        let wakers = {
+            let mut inner = self.lock();
+            if inner.requested {
+                return;
+            }
+            info!("shutting down");
+            inner.requested = true;
+            std::mem::take(&mut inner.wakers)
+        };
+        self.inner.1.notify_all();
+        for wake in wakers {
+            wake();
+        }
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.lock().requested
+    }
+
+    /// Sleeps for `timeout` unless shutdown is requested before, returns whether it was.
+    pub fn wait(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut inner = self.lock();
+        while !inner.requested {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining == Duration::from_secs(0) {
+                break;
+            }
+            inner = self.inner.1.wait_timeout(inner, remaining)
+                .map(|(inner, _)| inner)
+                .unwrap_or_else(|poisoned| poisoned.into_inner().0);
+        }
+        inner.requested
+    }
+
+    /// Calls `wake` once shutdown is requested, right away if it already was.
+    pub fn on_request<F: Fn() + Send + 'static>(&self, wake: F) {
+        let mut inner = self.lock();
+        if inner.requested {
+            drop(inner);
+            wake();
+        } else {
+            inner.wakers.push(Box::new(wake));
+        }
+    }
+
+    /// Requests shutdown on SIGTERM and SIGINT, as sent by `systemctl --user stop` and Ctrl+C, so the game is released
+    /// before exiting. The signals are blocked and waited for on a thread of their own, which only works if no other
+    /// thread has been started yet: threads inherit the blocked signals from the one starting them.
+    #[cfg(target_os = "linux")]
+    pub fn request_on_signals(&self) -> Result<(), Error> {
+        // safe, the set is initialized by sigemptyset before anything reads it
+        let signals = unsafe {
+            let mut signals: libc::sigset_t = std::mem::zeroed();
+            libc::sigemptyset(&mut signals);
+            libc::sigaddset(&mut signals, libc::SIGTERM);
+            libc::sigaddset(&mut signals, libc::SIGINT);
+            signals
+        };
+        let result = unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &signals, std::ptr::null_mut()) };
+        if result != 0 {
+            return Err(Error::from_raw_os_error(result));
+        }
+        let shutdown = self.clone();
+        thread::Builder::new().name("signals".to_owned()).spawn(move || loop {
+            let mut signal = 0;
+            if unsafe { libc::sigwait(&signals, &mut signal) } == 0 {
+                info!("received signal {}", signal);
+                shutdown.request();
+            }
+        })?;
+        Ok(())
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        self.inner.0.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
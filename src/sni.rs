@@ -0,0 +1,619 @@
+use std::io::Error;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use dbus::arg::{IterAppend, PropMap, RefArg, Variant};
+use dbus::blocking::Connection;
+use dbus::channel::{MatchingReceiver, Sender};
+use dbus::message::MatchRule;
+use dbus::strings::{ErrorName, Signature};
+use dbus::Message;
+
+use crate::control::Remote;
+use crate::icons::TrayIcon;
+use crate::menu::{self, MenuAction, MenuItem};
+use crate::shutdown::Shutdown;
+use crate::supervisor::Supervisor;
+
+const ITEM_PATH: &str = "/StatusNotifierItem";
+const ITEM_INTERFACE: &str = "org.kde.StatusNotifierItem";
+const MENU_PATH: &str = "/MenuBar";
+const MENU_INTERFACE: &str = "com.canonical.dbusmenu";
+const WATCHER: &str = "org.kde.StatusNotifierWatcher";
+const PROPERTIES_INTERFACE: &str = "org.freedesktop.DBus.Properties";
+const LAYOUT_SIGNATURE: &str = "(ia{sv}av)";
+
+const INTROSPECTION: &str = r#"<!DOCTYPE node PUBLIC "-//freedesktop//DTD D-BUS Object Introspection 1.0//EN" "http://www.freedesktop.org/standards/dbus/1.0/introspect.dtd">
+<node>
+  <interface name="org.kde.StatusNotifierItem">
+    <property name="Category" type="s" access="read"/>
+    <property name="Id" type="s" access="read"/>
+    <property name="Title" type="s" access="read"/>
+    <property name="Status" type="s" access="read"/>
+    <property name="WindowId" type="i" access="read"/>
+    <property name="IconName" type="s" access="read"/>
+    <property name="IconPixmap" type="a(iiay)" access="read"/>
+    <property name="IconThemePath" type="s" access="read"/>
+    <property name="ToolTip" type="(sa(iiay)ss)" access="read"/>
+    <property name="ItemIsMenu" type="b" access="read"/>
+    <property name="Menu" type="o" access="read"/>
+    <method name="ContextMenu"><arg name="x" type="i" direction="in"/><arg name="y" type="i" direction="in"/></method>
+    <method name="Activate"><arg name="x" type="i" direction="in"/><arg name="y" type="i" direction="in"/></method>
+    <method name="SecondaryActivate"><arg name="x" type="i" direction="in"/><arg name="y" type="i" direction="in"/></method>
+    <method name="Scroll"><arg name="delta" type="i" direction="in"/><arg name="orientation" type="s" direction="in"/></method>
+    <signal name="NewIcon"/>
+    <signal name="NewToolTip"/>
+    <signal name="NewStatus"><arg name="status" type="s"/></signal>
+  </interface>
+  <interface name="com.canonical.dbusmenu">
+    <property name="Version" type="u" access="read"/>
+    <property name="TextDirection" type="s" access="read"/>
+    <property name="Status" type="s" access="read"/>
+    <property name="IconThemePath" type="as" access="read"/>
+    <method name="GetLayout">
+      <arg name="parentId" type="i" direction="in"/>
+      <arg name="recursionDepth" type="i" direction="in"/>
+      <arg name="propertyNames" type="as" direction="in"/>
+      <arg name="revision" type="u" direction="out"/>
+      <arg name="layout" type="(ia{sv}av)" direction="out"/>
+    </method>
+    <method name="GetGroupProperties">
+      <arg name="ids" type="ai" direction="in"/>
+      <arg name="propertyNames" type="as" direction="in"/>
+      <arg name="properties" type="a(ia{sv})" direction="out"/>
+    </method>
+    <method name="GetProperty">
+      <arg name="id" type="i" direction="in"/>
+      <arg name="name" type="s" direction="in"/>
+      <arg name="value" type="v" direction="out"/>
+    </method>
+    <method name="Event">
+      <arg name="id" type="i" direction="in"/>
+      <arg name="eventId" type="s" direction="in"/>
+      <arg name="data" type="v" direction="in"/>
+      <arg name="timestamp" type="u" direction="in"/>
+    </method>
+    <method name="EventGroup">
+      <arg name="events" type="a(isvu)" direction="in"/>
+      <arg name="idErrors" type="ai" direction="out"/>
+    </method>
+    <method name="AboutToShow">
+      <arg name="id" type="i" direction="in"/>
+      <arg name="needUpdate" type="b" direction="out"/>
+    </method>
+    <method name="AboutToShowGroup">
+      <arg name="ids" type="ai" direction="in"/>
+      <arg name="updatesNeeded" type="ai" direction="out"/>
+      <arg name="idErrors" type="ai" direction="out"/>
+    </method>
+    <signal name="ItemsPropertiesUpdated">
+      <arg name="updatedProps" type="a(ia{sv})"/>
+      <arg name="removedProps" type="a(ias)"/>
+    </signal>
+    <signal name="LayoutUpdated">
+      <arg name="revision" type="u"/>
+      <arg name="parent" type="i"/>
+    </signal>
+  </interface>
+</node>"#;
+
+/// The tray icon on linux: a `StatusNotifierItem` with its menu exported as `com.canonical.dbusmenu`, which
+/// KDE, GNOME's AppIndicator extension and most other panels show. Fails if there is no session bus.
+pub fn start<F>(remote: Remote, shutdown: Shutdown, supervisor: &Supervisor, on_action: F) -> Result<(), Error>
+    where F: Fn(MenuAction) + Send + 'static {
+    start_on(Connection::new_session().map_err(to_io_error)?, remote, shutdown, supervisor, on_action)
+}
+
+fn start_on<F>(connection: Connection, remote: Remote, shutdown: Shutdown, supervisor: &Supervisor, on_action: F) -> Result<(), Error>
+    where F: Fn(MenuAction) + Send + 'static {
+    let name = format!("org.kde.StatusNotifierItem-{}-1", std::process::id());
+    connection.request_name(name.as_str(), false, true, true).map_err(to_io_error)?;
+    // to register again whenever the panel restarts
+    connection.add_match_no_cb(&format!("type='signal',sender='org.freedesktop.DBus',member='NameOwnerChanged',arg0='{}'", WATCHER))
+        .map_err(to_io_error)?;
+
+    let tray = Arc::new(Mutex::new(Tray::new(remote, Box::new(on_action))));
+    let receiver = tray.clone();
+    connection.start_receive(MatchRule::new(), Box::new(move |message, connection| {
+        let mut tray = receiver.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Some(reply) = tray.handle(&message) {
+            let _ = connection.send(reply);
+        }
+        for signal in tray.signals.drain(..) {
+            let _ = connection.send(signal);
+        }
+        true
+    }));
+
+    supervisor.spawn("tray", move || {
+        register(&connection, &name);
+        while !shutdown.is_requested() {
+            if let Err(e) = connection.process(Duration::from_millis(500)) {
+                error!("lost the session bus, the tray icon is gone: {}", e);
+                return;
+            }
+            let (register_again, signals) = {
+                let mut tray = tray.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+                tray.refresh();
+                (std::mem::replace(&mut tray.watcher_restarted, false), tray.signals.drain(..).collect::<Vec<_>>())
+            };
+            for signal in signals {
+                let _ = connection.send(signal);
+            }
+            if register_again {
+                register(&connection, &name);
+            }
+        }
+    })?;
+    Ok(())
+}
+
+fn register(connection: &Connection, name: &str) {
+    let watcher = connection.with_proxy(WATCHER, "/StatusNotifierWatcher", Duration::from_secs(2));
+    match watcher.method_call::<(), _, _, _>(WATCHER, "RegisterStatusNotifierItem", (name,)) {
+        Ok(()) => info!("registered the tray icon as {}", name),
+        Err(e) => info!("no tray to show the icon in yet, waiting for one to start: {}", e),
+    }
+}
+
+fn to_io_error(error: dbus::Error) -> Error {
+    Error::other(format!("tray: {}", error))
+}
+
+/// id, event name, data and timestamp of an `EventGroup` entry
+type MenuEvent = (i32, String, Variant<Box<dyn RefArg>>, u32);
+
+/// Ids of items without an action start here, below are the ids of `MenuAction`.
+const PATH_IDS: i32 = 1000;
+
+/// A menu item with the id dbusmenu knows it by, 0 is the root.
+struct Node {
+    id: i32,
+    item: Option<MenuItem>,
+    children: Vec<Node>,
+}
+
+impl Node {
+    fn tree(items: &[MenuItem]) -> Node {
+        Node { id: 0, item: None, children: Node::list(items, 0) }
+    }
+
+    /// Ids stay the same when the menu is rebuilt, so a click on a layout the host fetched before still means the
+    /// same action: actions are identified by their `MenuAction`, other items by their position.
+    fn list(items: &[MenuItem], parent_path: i32) -> Vec<Node> {
+        items.iter()
+            .enumerate()
+            .map(|(index, item)| {
+                let path = parent_path * 100 + index as i32 + 1;
+                let id = match item {
+                    MenuItem::Action { action, .. } => action.id() as i32,
+                    _ => PATH_IDS + path,
+                };
+                let children = match item {
+                    MenuItem::Submenu { items, .. } => Node::list(items, path),
+                    _ => Vec::new(),
+                };
+                Node { id, item: Some(item.clone()), children }
+            })
+            .collect()
+    }
+
+    fn find(&self, id: i32) -> Option<&Node> {
+        if self.id == id {
+            return Some(self);
+        }
+        self.children.iter().find_map(|child| child.find(id))
+    }
+
+    fn properties(&self, names: &[String]) -> PropMap {
+        let mut properties = PropMap::new();
+        let mut insert = |name: &str, value: Box<dyn RefArg>| {
+            if names.is_empty() || names.iter().any(|wanted| wanted == name) {
+                properties.insert(name.to_owned(), Variant(value));
+            }
+        };
+        match &self.item {
+            None => insert("children-display", Box::new("submenu".to_owned())),
+            Some(MenuItem::Label(label)) => {
+                insert("label", Box::new(escape_mnemonic(label)));
+                insert("enabled", Box::new(false));
+            }
+            Some(MenuItem::Action { label, enabled, checked, .. }) => {
+                insert("label", Box::new(escape_mnemonic(label)));
+                insert("enabled", Box::new(*enabled));
+                if let Some(checked) = checked {
+                    insert("toggle-type", Box::new("checkmark".to_owned()));
+                    insert("toggle-state", Box::new(*checked as i32));
+                }
+            }
+            Some(MenuItem::Submenu { label, .. }) => {
+                insert("label", Box::new(escape_mnemonic(label)));
+                insert("children-display", Box::new("submenu".to_owned()));
+            }
+            Some(MenuItem::Separator) => insert("type", Box::new("separator".to_owned())),
+        }
+        properties
+    }
+
+    /// Appends the `(ia{sv}av)` layout, `depth` -1 includes all descendants.
+    fn append_layout(&self, iter: &mut IterAppend, depth: i32, names: &[String]) {
+        iter.append_struct(|layout| {
+            layout.append(self.id);
+            layout.append(self.properties(names));
+            layout.append_array(&Signature::from("v"), |children| {
+                if depth != 0 {
+                    for child in &self.children {
+                        children.append_variant(&Signature::from(LAYOUT_SIGNATURE), |variant| {
+                            child.append_layout(variant, depth - 1, names)
+                        });
+                    }
+                }
+            });
+        });
+    }
+}
+
+/// dbusmenu takes `_` as the mnemonic marker.
+fn escape_mnemonic(label: &str) -> String {
+    label.replace('_', "__")
+}
+
+struct Tray {
+    remote: Remote,
+    on_action: Box<dyn Fn(MenuAction) + Send>,
+    items: Vec<MenuItem>,
+    tree: Node,
+    revision: u32,
+    /// revision of the layout the host fetched last, events are only accepted for the current one
+    fetched_revision: u32,
+    icon: TrayIcon,
+    tooltip: String,
+    /// to be sent once the lock is released
+    signals: Vec<Message>,
+    watcher_restarted: bool,
+}
+
+impl Tray {
+    fn new(remote: Remote, on_action: Box<dyn Fn(MenuAction) + Send>) -> Self {
+        let status = remote.status();
+        let items = menu::build_menu(&status);
+        Tray {
+            tree: Node::tree(&items),
+            items,
+            revision: 1,
+            fetched_revision: 1,
+            icon: TrayIcon::for_status(&status),
+            tooltip: menu::tooltip(&status),
+            remote,
+            on_action,
+            signals: Vec::new(),
+            watcher_restarted: false,
+        }
+    }
+
+    /// Rebuilds the menu, icon and tooltip from the current status, queueing signals for what changed.
+    /// Returns whether the menu changed.
+    fn refresh(&mut self) -> bool {
+        let status = self.remote.status();
+        let icon = TrayIcon::for_status(&status);
+        if icon != self.icon {
+            self.icon = icon;
+            self.signal(ITEM_PATH, ITEM_INTERFACE, "NewIcon", |_| {});
+        }
+        let tooltip = menu::tooltip(&status);
+        if tooltip != self.tooltip {
+            self.tooltip = tooltip;
+            self.signal(ITEM_PATH, ITEM_INTERFACE, "NewToolTip", |_| {});
+        }
+        let items = menu::build_menu(&status);
+        if items == self.items {
+            return false;
+        }
+        self.tree = Node::tree(&items);
+        self.items = items;
+        self.revision += 1;
+        let revision = self.revision;
+        self.signal(MENU_PATH, MENU_INTERFACE, "LayoutUpdated", |signal| {
+            let mut iter = IterAppend::new(signal);
+            iter.append(revision);
+            iter.append(0i32);
+        });
+        true
+    }
+
+    fn signal<F: FnOnce(&mut Message)>(&mut self, path: &str, interface: &str, name: &str, append: F) {
+        if let Ok(mut signal) = Message::new_signal(path, interface, name) {
+            append(&mut signal);
+            self.signals.push(signal);
+        }
+    }
+
+    fn handle(&mut self, message: &Message) -> Option<Message> {
+        if message.msg_type() == dbus::MessageType::Signal {
+            let (name, _, new_owner): (Option<&str>, Option<&str>, Option<&str>) = message.get3();
+            if message.member().as_deref() == Some("NameOwnerChanged") && name == Some(WATCHER) && new_owner.map(|owner| !owner.is_empty()).unwrap_or(false) {
+                self.watcher_restarted = true;
+            }
+            return None;
+        }
+        if message.msg_type() != dbus::MessageType::MethodCall {
+            return None;
+        }
+        let path = message.path().map(|path| path.to_string()).unwrap_or_default();
+        let interface = message.interface().map(|interface| interface.to_string()).unwrap_or_default();
+        let member = message.member().map(|member| member.to_string()).unwrap_or_default();
+        trace!("tray call {} {}.{}", path, interface, member);
+        let reply = match (path.as_str(), interface.as_str(), member.as_str()) {
+            (_, "org.freedesktop.DBus.Introspectable", "Introspect") => Ok(message.method_return().append1(INTROSPECTION)),
+            (_, "org.freedesktop.DBus.Peer", "Ping") => Ok(message.method_return()),
+            (ITEM_PATH, PROPERTIES_INTERFACE, _) => self.handle_properties(message, &member, ITEM_INTERFACE),
+            (MENU_PATH, PROPERTIES_INTERFACE, _) => self.handle_properties(message, &member, MENU_INTERFACE),
+            // everything happens in the menu
+            (ITEM_PATH, ITEM_INTERFACE, "Activate") | (ITEM_PATH, ITEM_INTERFACE, "SecondaryActivate") |
+            (ITEM_PATH, ITEM_INTERFACE, "ContextMenu") | (ITEM_PATH, ITEM_INTERFACE, "Scroll") => Ok(message.method_return()),
+            (MENU_PATH, MENU_INTERFACE, _) => self.handle_menu(message, &member),
+            _ => Err(("org.freedesktop.DBus.Error.UnknownMethod", format!("no {}.{} at {}", interface, member, path))),
+        };
+        Some(reply.unwrap_or_else(|(name, text)| {
+            let text = std::ffi::CString::new(text).unwrap_or_default();
+            message.error(&ErrorName::from(name), &text)
+        }))
+    }
+
+    fn handle_properties(&mut self, message: &Message, member: &str, interface: &str) -> Result<Message, (&'static str, String)> {
+        let mut properties = if interface == ITEM_INTERFACE { self.item_properties() } else { menu_properties() };
+        match member {
+            "Get" => {
+                let (_, name): (&str, &str) = message.read2().map_err(invalid_args)?;
+                let value = properties.remove(name)
+                    .ok_or_else(|| ("org.freedesktop.DBus.Error.UnknownProperty", format!("no property {}", name)))?;
+                Ok(message.method_return().append1(value))
+            }
+            "GetAll" => Ok(message.method_return().append1(properties)),
+            _ => Err(("org.freedesktop.DBus.Error.PropertyReadOnly", "all properties are read only".to_owned())),
+        }
+    }
+
+    fn item_properties(&self) -> PropMap {
+        let mut properties = PropMap::new();
+        let mut insert = |name: &str, value: Box<dyn RefArg>| {
+            properties.insert(name.to_owned(), Variant(value));
+        };
+        insert("Category", Box::new("ApplicationStatus".to_owned()));
+        insert("Id", Box::new("poe-minimizer".to_owned()));
+        insert("Title", Box::new("poe-minimizer".to_owned()));
+        insert("Status", Box::new("Active".to_owned()));
+        insert("WindowId", Box::new(0i32));
+        // hosts prefer a themed icon by name over the pixmaps
+        insert("IconName", Box::new("".to_owned()));
+        insert("IconPixmap", Box::new(pixmaps(self.icon)));
+        insert("IconThemePath", Box::new("".to_owned()));
+        let no_icon: Vec<(i32, i32, Vec<u8>)> = Vec::new();
+        insert("ToolTip", Box::new((String::new(), no_icon, "poe-minimizer".to_owned(), self.tooltip.clone())));
+        insert("ItemIsMenu", Box::new(true));
+        insert("Menu", Box::new(dbus::Path::from(MENU_PATH)));
+        properties
+    }
+
+    fn handle_menu(&mut self, message: &Message, member: &str) -> Result<Message, (&'static str, String)> {
+        match member {
+            "GetLayout" => {
+                let (parent, depth, names): (i32, i32, Vec<String>) = message.read3().map_err(invalid_args)?;
+                let node = self.tree.find(parent).ok_or_else(|| unknown_item(parent))?;
+                let mut reply = message.method_return();
+                let mut iter = IterAppend::new(&mut reply);
+                iter.append(self.revision);
+                node.append_layout(&mut iter, depth, &names);
+                self.fetched_revision = self.revision;
+                Ok(reply)
+            }
+            "GetGroupProperties" => {
+                let (ids, names): (Vec<i32>, Vec<String>) = message.read2().map_err(invalid_args)?;
+                let properties: Vec<(i32, PropMap)> = ids.iter()
+                    .filter_map(|id| self.tree.find(*id))
+                    .map(|node| (node.id, node.properties(&names)))
+                    .collect();
+                Ok(message.method_return().append1(properties))
+            }
+            "GetProperty" => {
+                let (id, name): (i32, String) = message.read2().map_err(invalid_args)?;
+                let node = self.tree.find(id).ok_or_else(|| unknown_item(id))?;
+                let value = node.properties(std::slice::from_ref(&name)).remove(&name)
+                    .ok_or_else(|| ("org.freedesktop.DBus.Error.InvalidArgs", format!("item {} has no property {}", id, name)))?;
+                Ok(message.method_return().append1(value))
+            }
+            "Event" => {
+                let (id, event): (Option<i32>, Option<&str>) = message.get2();
+                let id = id.ok_or_else(|| invalid_args("missing id"))?;
+                if !self.event(id, event.unwrap_or_default()) {
+                    if self.fetched_revision != self.revision {
+                        return Err(invalid_args(format!("layout revision {} is outdated, the current one is {}", self.fetched_revision, self.revision)));
+                    }
+                    return Err(unknown_item(id));
+                }
+                Ok(message.method_return())
+            }
+            "EventGroup" => {
+                let events: Vec<MenuEvent> = message.read1().map_err(invalid_args)?;
+                let unknown: Vec<i32> = events.iter()
+                    .filter(|(id, event, _, _)| !self.event(*id, event))
+                    .map(|(id, _, _, _)| *id)
+                    .collect();
+                Ok(message.method_return().append1(unknown))
+            }
+            "AboutToShow" => {
+                let id: i32 = message.read1().map_err(invalid_args)?;
+                Ok(message.method_return().append1(id == 0 && self.refresh()))
+            }
+            "AboutToShowGroup" => {
+                let ids: Vec<i32> = message.read1().map_err(invalid_args)?;
+                let updated = if ids.contains(&0) && self.refresh() { vec![0] } else { Vec::new() };
+                Ok(message.method_return().append2(updated, Vec::<i32>::new()))
+            }
+            _ => Err(("org.freedesktop.DBus.Error.UnknownMethod", format!("no {}.{}", MENU_INTERFACE, member))),
+        }
+    }
+
+    /// Returns false for unknown ids and events on an outdated layout, e.g. a click on "Minimize now" that was
+    /// disabled in the meantime.
+    fn event(&self, id: i32, event: &str) -> bool {
+        if self.fetched_revision != self.revision {
+            debug!("tray menu: ignoring {} of item {} on layout revision {}, the current one is {}", event, id, self.fetched_revision, self.revision);
+            return false;
+        }
+        let node = match self.tree.find(id) {
+            Some(node) => node,
+            None => return false,
+        };
+        if event == "clicked" {
+            if let Some(MenuItem::Action { action, enabled: true, .. }) = &node.item {
+                debug!("tray menu: {:?}", action);
+                (self.on_action)(*action);
+            }
+        }
+        true
+    }
+}
+
+/// The icon's images as the ARGB32 pixels in network byte order hosts expect.
+fn pixmaps(icon: TrayIcon) -> Vec<(i32, i32, Vec<u8>)> {
+    icon.images().into_iter()
+        .filter_map(|(width, height, bitmap)| {
+            let header_size = u32::from_le_bytes([bitmap[0], bitmap[1], bitmap[2], bitmap[3]]) as usize;
+            let bit_count = u16::from_le_bytes([bitmap[14], bitmap[15]]);
+            let (width, height) = (width as usize, height as usize);
+            let pixels = bitmap.get(header_size..header_size + width * height * 4).filter(|_| bit_count == 32)?;
+            // rows are stored bottom up in BGRA
+            let argb = pixels.chunks(width * 4).rev()
+                .flat_map(|row| row.chunks(4).flat_map(|bgra| vec![bgra[3], bgra[2], bgra[1], bgra[0]]))
+                .collect();
+            Some((width as i32, height as i32, argb))
+        })
+        .collect()
+}
+
+fn menu_properties() -> PropMap {
+    let mut properties = PropMap::new();
+    properties.insert("Version".to_owned(), Variant(Box::new(3u32)));
+    properties.insert("TextDirection".to_owned(), Variant(Box::new("ltr".to_owned())));
+    properties.insert("Status".to_owned(), Variant(Box::new("normal".to_owned())));
+    properties.insert("IconThemePath".to_owned(), Variant(Box::new(Vec::<String>::new())));
+    properties
+}
+
+fn invalid_args<E: ToString>(error: E) -> (&'static str, String) {
+    ("org.freedesktop.DBus.Error.InvalidArgs", error.to_string())
+}
+
+fn unknown_item(id: i32) -> (&'static str, String) {
+    ("org.freedesktop.DBus.Error.InvalidArgs", format!("no menu item {}", id))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::{self, Receiver};
+    use std::time::Instant;
+
+    use dbus::blocking::Proxy;
+
+    use super::*;
+    use crate::control;
+    use crate::utils::TestBus;
+
+    enum WatcherEvent {
+        Registered(String),
+        LayoutUpdated(u32),
+    }
+
+    /// Stands in for the panel: owns the watcher name and reports registered items and layout changes.
+    fn scripted_watcher(bus: &TestBus) -> (Connection, Receiver<WatcherEvent>) {
+        let watcher = bus.connect();
+        watcher.request_name(WATCHER, false, true, true).unwrap();
+        watcher.add_match_no_cb(&format!("type='signal',interface='{}',member='LayoutUpdated'", MENU_INTERFACE)).unwrap();
+        let (sender, events) = mpsc::channel();
+        watcher.start_receive(MatchRule::new(), Box::new(move |message, connection| {
+            match message.member().as_deref() {
+                Some("RegisterStatusNotifierItem") => {
+                    let _ = sender.send(WatcherEvent::Registered(message.read1::<&str>().unwrap().to_owned()));
+                    let _ = connection.send(message.method_return());
+                }
+                Some("LayoutUpdated") => {
+                    let _ = sender.send(WatcherEvent::LayoutUpdated(message.read1().unwrap()));
+                }
+                _ => {}
+            }
+            true
+        }));
+        (watcher, events)
+    }
+
+    fn next_event(watcher: &Connection, events: &Receiver<WatcherEvent>) -> WatcherEvent {
+        let started = Instant::now();
+        loop {
+            if let Ok(event) = events.try_recv() {
+                return event;
+            }
+            assert!(started.elapsed() < Duration::from_secs(5), "the tray did not call");
+            watcher.process(Duration::from_millis(50)).unwrap();
+        }
+    }
+
+    fn get_layout(menu: &Proxy<&Connection>) -> u32 {
+        let (revision,): (u32,) = menu.method_call(MENU_INTERFACE, "GetLayout", (0i32, -1i32, Vec::<String>::new())).unwrap();
+        revision
+    }
+
+    fn label(menu: &Proxy<&Connection>, action: MenuAction) -> String {
+        let (properties,): (Vec<(i32, PropMap)>,) = menu
+            .method_call(MENU_INTERFACE, "GetGroupProperties", (vec![action.id() as i32], vec!["label".to_owned()]))
+            .unwrap();
+        properties[0].1["label"].0.as_str().unwrap().to_owned()
+    }
+
+    fn click(menu: &Proxy<&Connection>, action: MenuAction) -> Result<(), dbus::Error> {
+        let data: Variant<Box<dyn RefArg>> = Variant(Box::new(0i32));
+        menu.method_call(MENU_INTERFACE, "Event", (action.id() as i32, "clicked", data, 0u32))
+    }
+
+    #[test]
+    fn menu_over_a_private_session_bus() {
+        let bus = TestBus::start();
+        let (watcher, events) = scripted_watcher(&bus);
+        let (_controller, remote) = control::channel();
+        let shutdown = Shutdown::new();
+        let supervisor = Supervisor::new(remote.clone(), shutdown.clone(), 1);
+        let (clicked, clicks) = mpsc::channel();
+        start_on(bus.connect(), remote.clone(), shutdown.clone(), &supervisor, move |action| {
+            let _ = clicked.send(action);
+        }).unwrap();
+        let item = match next_event(&watcher, &events) {
+            WatcherEvent::Registered(item) => item,
+            WatcherEvent::LayoutUpdated(_) => panic!("the tray did not register"),
+        };
+        let menu = watcher.with_proxy(item.as_str(), MENU_PATH, Duration::from_secs(2));
+
+        let revision = get_layout(&menu);
+        assert_eq!(label(&menu, MenuAction::Exit), "Exit");
+        assert_eq!(label(&menu, MenuAction::MinimizeNow), "Minimize now");
+        click(&menu, MenuAction::Exit).unwrap();
+        assert_eq!(clicks.recv_timeout(Duration::from_secs(1)), Ok(MenuAction::Exit));
+        // disabled while the game is not running
+        click(&menu, MenuAction::MinimizeNow).unwrap();
+
+        // a new item moves the others, but not their ids
+        remote.set_run_on_startup(Some(false));
+        let updated = match next_event(&watcher, &events) {
+            WatcherEvent::LayoutUpdated(updated) => updated,
+            WatcherEvent::Registered(_) => panic!("expected a layout update"),
+        };
+        assert!(updated > revision);
+        assert!(click(&menu, MenuAction::Exit).is_err(), "a click on the outdated layout was accepted");
+        assert_eq!(get_layout(&menu), updated);
+        assert_eq!(label(&menu, MenuAction::ToggleAutoStart), "Run on startup");
+        assert_eq!(label(&menu, MenuAction::Exit), "Exit");
+        click(&menu, MenuAction::Exit).unwrap();
+        assert_eq!(clicks.recv_timeout(Duration::from_secs(1)), Ok(MenuAction::Exit));
+        assert!(clicks.try_recv().is_err());
+        shutdown.request();
+    }
+}
@@ -0,0 +1,181 @@
+use std::fs;
+use std::io::Error;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::settings::Settings;
+use crate::utils::*;
+
+const TRADE_REQUEST_SOUND: &[u8] = include_bytes!("../assets/sounds/trade-request.wav");
+const PARTY_INVITE_SOUND: &[u8] = include_bytes!("../assets/sounds/party-invite.wav");
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Alert {
+    TradeRequest,
+    PartyInvite,
+}
+
+/// A wav or ogg file loaded into memory.
+#[derive(Clone)]
+pub struct Sound {
+    /// `bundled` or the path it was loaded from
+    pub name: String,
+    pub data: Arc<[u8]>,
+}
+
+/// Where sounds go, a sound card or nowhere.
+pub trait AudioSink {
+    /// Starts playing without waiting for the sound to finish, `volume` is between 0 and 1.
+    fn play(&mut self, sound: &Sound, volume: f32) -> Result<(), Error>;
+}
+
+/// Plays nothing and logs what it would have played. Used without a sound device and in builds without the
+/// `audio` feature.
+pub struct NullSink;
+
+impl AudioSink for NullSink {
+    fn play(&mut self, sound: &Sound, volume: f32) -> Result<(), Error> {
+        info!("would play {} ({} bytes) at {:.0}% volume", sound.name, sound.data.len(), volume * 100.0);
+        Ok(())
+    }
+}
+
+/// The sink for `output` in `[sound]`, `null` or `default` for the system's default device.
+pub fn create_sink(output: &str) -> Box<dyn AudioSink + Send> {
+    if output == "null" {
+        return Box::new(NullSink);
+    }
+    if output != "default" {
+        warn!("unknown sound output {:?}, using the default device", output);
+    }
+    default_sink()
+}
+
+#[cfg(feature = "audio")]
+fn default_sink() -> Box<dyn AudioSink + Send> {
+    match rodio_sink::RodioSink::open() {
+        Ok(sink) => Box::new(sink),
+        Err(e) => {
+            error!("no sound device, alerts stay silent: {}", e);
+            Box::new(NullSink)
+        }
+    }
+}
+
+#[cfg(not(feature = "audio"))]
+fn default_sink() -> Box<dyn AudioSink + Send> {
+    warn!("built without audio support, alerts stay silent");
+    Box::new(NullSink)
+}
+
+/// Plays a sound per alert as configured in `[sound]`.
+pub struct SoundAlerts {
+    sink: Box<dyn AudioSink + Send>,
+    trade_request: Option<(Sound, f32)>,
+    party_invite: Option<(Sound, f32)>,
+}
+
+impl SoundAlerts {
+    pub fn new(settings: &Settings, sink: Box<dyn AudioSink + Send>) -> Self {
+        SoundAlerts {
+            sink,
+            trade_request: load_sound(&settings.trade_request_sound, TRADE_REQUEST_SOUND)
+                .map(|sound| (sound, volume(settings.trade_request_volume))),
+            party_invite: load_sound(&settings.party_invite_sound, PARTY_INVITE_SOUND)
+                .map(|sound| (sound, volume(settings.party_invite_volume))),
+        }
+    }
+
+    pub fn play(&mut self, alert: Alert) {
+        let sound = match alert {
+            Alert::TradeRequest => self.trade_request.as_ref(),
+            Alert::PartyInvite => self.party_invite.as_ref(),
+        };
+        if let Some((sound, volume)) = sound {
+            debug!("playing {} for {:?}", sound.name, alert);
+            self.sink.play(sound, *volume)
+                .log_error_and_ignore("failed to play alert");
+        }
+    }
+}
+
+/// `none` disables the alert, `bundled` or an unreadable file use the bundled sound.
+fn load_sound(setting: &str, bundled: &'static [u8]) -> Option<Sound> {
+    match setting {
+        "none" | "" => None,
+        "bundled" => Some(Sound { name: "bundled".to_owned(), data: Arc::from(bundled) }),
+        path => match fs::read(Path::new(path)) {
+            Ok(data) => Some(Sound { name: path.to_owned(), data: Arc::from(data) }),
+            Err(e) => {
+                error!("failed to read sound {:?}, using the bundled one: {}", path, e);
+                Some(Sound { name: "bundled".to_owned(), data: Arc::from(bundled) })
+            }
+        },
+    }
+}
+
+fn volume(percent: u32) -> f32 {
+    percent.min(100) as f32 / 100.0
+}
+
+#[cfg(feature = "audio")]
+mod rodio_sink {
+    use std::io::{Cursor, Error, ErrorKind};
+    use std::sync::mpsc::{self, Sender};
+    use std::sync::Arc;
+    use std::thread;
+
+    use rodio::{Decoder, OutputStream, Sink};
+
+    use super::{AudioSink, Sound};
+    use crate::metrics;
+
+    /// Plays on the default output device. The output stream can't leave the thread it was opened on, so it lives on
+    /// a thread of its own until the sink is dropped.
+    pub struct RodioSink {
+        sounds: Sender<(Arc<[u8]>, f32)>,
+    }
+
+    impl RodioSink {
+        pub fn open() -> Result<RodioSink, Error> {
+            let (sounds, receiver) = mpsc::channel::<(Arc<[u8]>, f32)>();
+            let (opened, result) = mpsc::channel();
+            thread::Builder::new()
+                .name("audio".to_owned())
+                .spawn(move || {
+                    let (_stream, handle) = match OutputStream::try_default() {
+                        Ok(stream) => {
+                            let _ = opened.send(Ok(()));
+                            stream
+                        }
+                        Err(e) => {
+                            let _ = opened.send(Err(Error::new(ErrorKind::NotFound, e.to_string())));
+                            return;
+                        }
+                    };
+                    for (data, volume) in receiver {
+                        let played = Sink::try_new(&handle).map_err(|e| e.to_string()).and_then(|sink| {
+                            let source = Decoder::new(Cursor::new(data)).map_err(|e| e.to_string())?;
+                            sink.set_volume(volume);
+                            sink.append(source);
+                            sink.detach();
+                            Ok(())
+                        });
+                        if let Err(e) = played {
+                            metrics::BACKEND_ERRORS.increment();
+                            error!("failed to play sound: {}", e);
+                        }
+                    }
+                })?;
+            result.recv().unwrap_or_else(|_| Err(Error::other("the audio thread died")))?;
+            Ok(RodioSink { sounds })
+        }
+    }
+
+    impl AudioSink for RodioSink {
+        fn play(&mut self, sound: &Sound, volume: f32) -> Result<(), Error> {
+            self.sounds.send((sound.data.clone(), volume))
+                .map_err(|_| Error::new(ErrorKind::BrokenPipe, "the audio thread is gone"))
+        }
+    }
+}
@@ -0,0 +1,302 @@
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crate::settings::Settings;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum State {
+    GameNotRunning,
+    /// the game runs and the player is active
+    Watching,
+    /// the player is afk, waiting for `seconds_until_minimize` to pass
+    AfkPending,
+    /// the player is afk and the game window is minimized
+    Minimized,
+    /// minimizing was paused by the user
+    Paused,
+}
+
+/// Everything the decision whether to minimize is based on, gathered once per polling interval.
+#[derive(Debug, Clone, Default)]
+pub struct Observation {
+    /// afk mode as reported by Client.txt
+    pub afk_mode: bool,
+    pub in_hideout: bool,
+    /// language independent id of the current area, e.g. `HideoutCoastal`
+    pub area: Option<String>,
+    /// time without user input, `None` if it could not be determined
+    pub idle_time: Option<Duration>,
+    pub window_minimized: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Minimize,
+}
+
+pub struct StateMachine {
+    state: State,
+    paused: bool,
+    /// end of a pause with a duration
+    resume_at: Option<Instant>,
+    was_minimized: bool,
+    last_time_restored: Instant,
+}
+
+impl StateMachine {
+    pub fn new() -> Self {
+        StateMachine {
+            state: State::GameNotRunning,
+            paused: false,
+            resume_at: None,
+            was_minimized: false,
+            last_time_restored: Instant::now(),
+        }
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn resume_at(&self) -> Option<Instant> {
+        self.resume_at
+    }
+
+    /// Pauses until resumed, or until `duration` passed.
+    pub fn pause(&mut self, duration: Option<Duration>) {
+        self.paused = true;
+        self.resume_at = duration.map(|duration| Instant::now() + duration);
+        if self.state != State::GameNotRunning {
+            self.transition(State::Paused);
+        }
+    }
+
+    pub fn resume(&mut self) {
+        self.paused = false;
+        self.resume_at = None;
+        if self.state == State::Paused {
+            self.transition(State::Watching);
+        }
+    }
+
+    /// Ends a pause with a duration once `now` reached its end, returns whether it did.
+    pub fn resume_if_due(&mut self, now: Instant) -> bool {
+        if self.resume_at.map(|resume_at| resume_at <= now).unwrap_or(false) {
+            self.resume();
+            return true;
+        }
+        false
+    }
+
+    pub fn game_found(&mut self) {
+        self.was_minimized = false;
+        self.last_time_restored = Instant::now();
+        self.transition(if self.paused { State::Paused } else { State::Watching });
+    }
+
+    pub fn game_lost(&mut self) {
+        self.transition(State::GameNotRunning);
+    }
+
+    /// `now` is the time of the observation.
+    pub fn update(&mut self, observation: &Observation, settings: &Settings, now: Instant) -> Option<Action> {
+        if !observation.window_minimized && self.was_minimized {
+            self.last_time_restored = now;
+        }
+        self.was_minimized = observation.window_minimized;
+
+        if self.paused {
+            self.transition(State::Paused);
+            return None;
+        }
+        if !is_afk(observation, settings) {
+            self.transition(State::Watching);
+            return None;
+        }
+        if observation.window_minimized {
+            self.transition(State::Minimized);
+            return None;
+        }
+        self.transition(State::AfkPending);
+        if now.saturating_duration_since(self.last_time_restored) > Duration::from_secs(settings.seconds_until_minimize) {
+            Some(Action::Minimize)
+        } else {
+            None
+        }
+    }
+
+    fn transition(&mut self, new_state: State) {
+        if self.state != new_state {
+            debug!("state {:?} -> {:?}", self.state, new_state);
+            self.state = new_state;
+        }
+    }
+}
+
+fn is_afk(observation: &Observation, settings: &Settings) -> bool {
+    observation.afk_mode || is_idle_in_hideout(observation, settings)
+}
+
+fn is_idle_in_hideout(observation: &Observation, settings: &Settings) -> bool {
+    if settings.minutes_until_idle_minimize == 0 || !observation.in_hideout {
+        return false;
+    }
+    observation.idle_time
+        .map(|idle_time| idle_time >= Duration::from_secs(settings.minutes_until_idle_minimize * 60))
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn afk(window_minimized: bool) -> Observation {
+        Observation { afk_mode: true, window_minimized, ..Observation::default() }
+    }
+
+    fn active() -> Observation {
+        Observation::default()
+    }
+
+    /// A running game, with the time it was found.
+    fn watching() -> (StateMachine, Instant) {
+        let mut machine = StateMachine::new();
+        machine.game_found();
+        (machine, Instant::now())
+    }
+
+    #[test]
+    fn minimizes_once_the_timeout_expired() {
+        let settings = Settings::default();
+        let (mut machine, start) = watching();
+        assert_eq!(machine.state(), State::Watching);
+        assert_eq!(machine.update(&active(), &settings, start), None);
+        assert_eq!(machine.state(), State::Watching);
+
+        assert_eq!(machine.update(&afk(false), &settings, start + Duration::from_secs(4)), None);
+        assert_eq!(machine.state(), State::AfkPending);
+        assert_eq!(machine.update(&afk(false), &settings, start + Duration::from_secs(6)), Some(Action::Minimize));
+        assert_eq!(machine.state(), State::AfkPending);
+        assert_eq!(machine.update(&afk(true), &settings, start + Duration::from_secs(7)), None);
+        assert_eq!(machine.state(), State::Minimized);
+
+        assert_eq!(machine.update(&active(), &settings, start + Duration::from_secs(8)), None);
+        assert_eq!(machine.state(), State::Watching);
+    }
+
+    #[test]
+    fn idling_in_a_hideout_counts_as_afk() {
+        let mut settings = Settings::default();
+        let (mut machine, start) = watching();
+        let idle = |in_hideout, minutes: u64| Observation { in_hideout, idle_time: Some(Duration::from_secs(minutes * 60)), ..Observation::default() };
+        machine.update(&idle(true, 20), &settings, start);
+        assert_eq!(machine.state(), State::Watching);
+
+        settings.minutes_until_idle_minimize = 10;
+        machine.update(&idle(true, 9), &settings, start);
+        assert_eq!(machine.state(), State::Watching);
+        machine.update(&idle(false, 20), &settings, start);
+        assert_eq!(machine.state(), State::Watching);
+        assert_eq!(machine.update(&idle(true, 10), &settings, start + Duration::from_secs(6)), Some(Action::Minimize));
+        assert_eq!(machine.state(), State::AfkPending);
+    }
+
+    #[test]
+    fn restoring_while_minimized_restarts_the_timeout() {
+        let settings = Settings::default();
+        let (mut machine, start) = watching();
+        machine.update(&afk(true), &settings, start + Duration::from_secs(10));
+        assert_eq!(machine.state(), State::Minimized);
+
+        // the player restored the window but is still afk
+        let restored = start + Duration::from_secs(20);
+        assert_eq!(machine.update(&afk(false), &settings, restored), None);
+        assert_eq!(machine.state(), State::AfkPending);
+        assert_eq!(machine.update(&afk(false), &settings, restored + Duration::from_secs(4)), None);
+        assert_eq!(machine.update(&afk(false), &settings, restored + Duration::from_secs(6)), Some(Action::Minimize));
+    }
+
+    #[test]
+    fn pausing_until_resumed() {
+        let settings = Settings::default();
+        let (mut machine, start) = watching();
+        machine.update(&afk(false), &settings, start);
+        assert_eq!(machine.state(), State::AfkPending);
+
+        machine.pause(None);
+        assert_eq!(machine.state(), State::Paused);
+        assert!(machine.is_paused());
+        assert_eq!(machine.resume_at(), None);
+        assert_eq!(machine.update(&afk(false), &settings, start + Duration::from_secs(60)), None);
+        assert_eq!(machine.state(), State::Paused);
+        assert!(!machine.resume_if_due(start + Duration::from_secs(24 * 60 * 60)));
+
+        machine.resume();
+        assert_eq!(machine.state(), State::Watching);
+        assert!(!machine.is_paused());
+        assert_eq!(machine.update(&afk(false), &settings, start + Duration::from_secs(61)), Some(Action::Minimize));
+    }
+
+    #[test]
+    fn pausing_for_a_duration() {
+        let settings = Settings::default();
+        let (mut machine, start) = watching();
+        machine.update(&afk(true), &settings, start + Duration::from_secs(10));
+        assert_eq!(machine.state(), State::Minimized);
+
+        machine.pause(Some(Duration::from_secs(60)));
+        let resume_at = machine.resume_at().unwrap();
+        assert!(resume_at >= start + Duration::from_secs(60));
+        assert_eq!(machine.state(), State::Paused);
+        assert!(!machine.resume_if_due(resume_at - Duration::from_secs(1)));
+        assert_eq!(machine.state(), State::Paused);
+
+        assert!(machine.resume_if_due(resume_at));
+        assert_eq!(machine.state(), State::Watching);
+        assert!(!machine.is_paused());
+        assert_eq!(machine.resume_at(), None);
+        assert!(!machine.resume_if_due(resume_at + Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn a_pause_outlasts_the_game() {
+        let mut machine = StateMachine::new();
+        machine.pause(Some(Duration::from_secs(60)));
+        assert_eq!(machine.state(), State::GameNotRunning);
+        machine.game_found();
+        assert_eq!(machine.state(), State::Paused);
+        machine.resume();
+        assert_eq!(machine.state(), State::Watching);
+        machine.game_lost();
+        machine.resume();
+        assert_eq!(machine.state(), State::GameNotRunning);
+    }
+
+    #[test]
+    fn the_game_can_exit_in_every_state() {
+        let settings = Settings::default();
+        for state in [State::Watching, State::AfkPending, State::Minimized, State::Paused].iter() {
+            let (mut machine, start) = watching();
+            match state {
+                State::AfkPending => { machine.update(&afk(false), &settings, start); }
+                State::Minimized => { machine.update(&afk(true), &settings, start); }
+                State::Paused => machine.pause(None),
+                _ => {}
+            }
+            assert_eq!(machine.state(), *state);
+            machine.game_lost();
+            assert_eq!(machine.state(), State::GameNotRunning);
+            machine.game_lost();
+            assert_eq!(machine.state(), State::GameNotRunning);
+        }
+        let mut machine = StateMachine::new();
+        machine.game_lost();
+        assert_eq!(machine.state(), State::GameNotRunning);
+    }
+}
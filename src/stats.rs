@@ -0,0 +1,427 @@
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::io::{Error, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Datelike, Local};
+use serde::{Deserialize, Serialize};
+
+use crate::state::State;
+use crate::utils::*;
+
+/// One stretch of the player being afk, from becoming afk until being active again or closing the game.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AfkSession {
+    pub start: DateTime<Local>,
+    pub end: DateTime<Local>,
+    /// `None` if the game was never minimized during the session
+    pub seconds_until_minimized: Option<f64>,
+    pub seconds_minimized: f64,
+    /// how often the window was restored while still afk
+    pub manual_restores: u32,
+    pub whispers_received: u32,
+    pub zone: Option<String>,
+}
+
+impl AfkSession {
+    fn seconds(&self) -> f64 {
+        (self.end - self.start).num_milliseconds() as f64 / 1000.0
+    }
+}
+
+struct OpenSession {
+    start: DateTime<Local>,
+    started: Instant,
+    seconds_until_minimized: Option<f64>,
+    minimized: Duration,
+    minimized_since: Option<Instant>,
+    manual_restores: u32,
+    whispers_received: u32,
+    zone: Option<String>,
+}
+
+/// Follows the state machine and appends every finished afk session to a json lines file.
+pub struct SessionRecorder {
+    path: PathBuf,
+    last_state: State,
+    current: Option<OpenSession>,
+}
+
+impl SessionRecorder {
+    pub fn new(path: PathBuf) -> Self {
+        SessionRecorder { path, last_state: State::GameNotRunning, current: None }
+    }
+
+    pub fn whisper_received(&mut self) {
+        if let Some(session) = self.current.as_mut() {
+            session.whispers_received += 1;
+        }
+    }
+
+    pub fn update(&mut self, state: State, zone: Option<&str>) {
+        let afk = state == State::AfkPending || state == State::Minimized;
+        if afk && self.current.is_none() {
+            self.current = Some(OpenSession {
+                start: Local::now(),
+                started: Instant::now(),
+                seconds_until_minimized: None,
+                minimized: Duration::from_secs(0),
+                minimized_since: None,
+                manual_restores: 0,
+                whispers_received: 0,
+                zone: zone.map(str::to_owned),
+            });
+        }
+        if let Some(session) = self.current.as_mut() {
+            if self.last_state == State::Minimized && state != State::Minimized {
+                if let Some(since) = session.minimized_since.take() {
+                    session.minimized += since.elapsed();
+                }
+                if state == State::AfkPending {
+                    session.manual_restores += 1;
+                }
+            }
+            if state == State::Minimized && self.last_state != State::Minimized {
+                session.minimized_since = Some(Instant::now());
+                if session.seconds_until_minimized.is_none() {
+                    session.seconds_until_minimized = Some(session.started.elapsed().as_secs_f64());
+                }
+            }
+        }
+        if !afk {
+            if let Some(session) = self.current.take() {
+                self.finish(session);
+            }
+        }
+        self.last_state = state;
+    }
+
+    fn finish(&self, session: OpenSession) {
+        let session = AfkSession {
+            start: session.start,
+            end: Local::now(),
+            seconds_until_minimized: session.seconds_until_minimized,
+            seconds_minimized: session.minimized.as_secs_f64(),
+            manual_restores: session.manual_restores,
+            whispers_received: session.whispers_received,
+            zone: session.zone,
+        };
+        debug!("afk session finished: {:?}", session);
+        append_session(&self.path, &session).log_error_and_ignore("failed to record afk session");
+    }
+}
+
+impl Drop for SessionRecorder {
+    fn drop(&mut self) {
+        self.update(State::GameNotRunning, None);
+    }
+}
+
+fn append_session(path: &Path, session: &AfkSession) -> Result<(), Error> {
+    let line = serde_json::to_string(session).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "{}", line)
+}
+
+pub fn load_sessions(path: &Path) -> Result<Vec<AfkSession>, Error> {
+    let text = match fs::read_to_string(path) {
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        result => result?,
+    };
+    let sessions = text.lines()
+        .filter(|line| !line.trim().is_empty())
+        .filter_map(|line| serde_json::from_str(line)
+            .map_err(|e| warn!("skipping invalid afk session {:?}: {}", line, e))
+            .ok())
+        .collect();
+    Ok(sessions)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Period {
+    Day,
+    Week,
+}
+
+/// Sums of all sessions started within one day or iso week.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Aggregate {
+    /// `2020-02-07` for days, `2020-W06` for weeks
+    pub period: String,
+    pub sessions: u32,
+    pub seconds_afk: f64,
+    pub seconds_minimized: f64,
+    pub manual_restores: u32,
+    pub whispers_received: u32,
+}
+
+pub fn aggregate(sessions: &[AfkSession], period: Period) -> Vec<Aggregate> {
+    let mut aggregates: BTreeMap<String, Aggregate> = BTreeMap::new();
+    for session in sessions {
+        let date = session.start.date_naive();
+        let key = match period {
+            Period::Day => date.format("%Y-%m-%d").to_string(),
+            Period::Week => format!("{}-W{:02}", date.iso_week().year(), date.iso_week().week()),
+        };
+        let aggregate = aggregates.entry(key.clone()).or_insert_with(|| Aggregate { period: key, ..Aggregate::default() });
+        aggregate.sessions += 1;
+        aggregate.seconds_afk += session.seconds();
+        aggregate.seconds_minimized += session.seconds_minimized;
+        aggregate.manual_restores += session.manual_restores;
+        aggregate.whispers_received += session.whispers_received;
+    }
+    aggregates.into_values().collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    Csv,
+}
+
+/// The table `stats` prints, without one json gets sessions and both aggregates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Table {
+    Sessions,
+    Aggregated(Period),
+}
+
+/// All sessions with both aggregates.
+#[derive(Serialize)]
+pub struct Report<'a> {
+    sessions: &'a [AfkSession],
+    per_day: Vec<Aggregate>,
+    per_week: Vec<Aggregate>,
+}
+
+impl<'a> Report<'a> {
+    pub fn new(sessions: &'a [AfkSession]) -> Self {
+        Report {
+            sessions,
+            per_day: aggregate(sessions, Period::Day),
+            per_week: aggregate(sessions, Period::Week),
+        }
+    }
+}
+
+/// Handles `poe-minimizer stats [json|csv] [sessions|day|week]`.
+pub fn run_stats_command(path: &Path, args: &[String]) -> Result<(), Error> {
+    let mut format = Format::Json;
+    let mut table = None;
+    for arg in args {
+        match arg.as_str() {
+            "json" => format = Format::Json,
+            "csv" => format = Format::Csv,
+            "sessions" => table = Some(Table::Sessions),
+            "day" => table = Some(Table::Aggregated(Period::Day)),
+            "week" => table = Some(Table::Aggregated(Period::Week)),
+            _ => return Err(Error::new(ErrorKind::InvalidInput, format!(
+                "unknown argument {:?}, usage: poe-minimizer stats [json|csv] [sessions|day|week]", arg))),
+        }
+    }
+    let sessions = load_sessions(path)?;
+    let output = match (format, table) {
+        (Format::Json, None) => to_json(&Report::new(&sessions))?,
+        (Format::Json, Some(Table::Sessions)) => to_json(&sessions)?,
+        (Format::Json, Some(Table::Aggregated(period))) => to_json(&aggregate(&sessions, period))?,
+        (Format::Csv, None) | (Format::Csv, Some(Table::Sessions)) => sessions_csv(&sessions),
+        (Format::Csv, Some(Table::Aggregated(period))) => aggregates_csv(&aggregate(&sessions, period)),
+    };
+    let stdout = std::io::stdout();
+    let mut stdout = stdout.lock();
+    stdout.write_all(output.as_bytes())?;
+    stdout.flush()
+}
+
+fn to_json<T: Serialize + ?Sized>(value: &T) -> Result<String, Error> {
+    serde_json::to_string_pretty(value)
+        .map(|json| json + "\n")
+        .map_err(|e| Error::new(ErrorKind::InvalidData, e))
+}
+
+fn sessions_csv(sessions: &[AfkSession]) -> String {
+    let mut csv = String::from("start,end,seconds_until_minimized,seconds_minimized,manual_restores,whispers_received,zone\n");
+    for session in sessions {
+        csv += &format!("{},{},{},{:.1},{},{},{}\n",
+                        session.start.to_rfc3339(),
+                        session.end.to_rfc3339(),
+                        session.seconds_until_minimized.map(|seconds| format!("{:.1}", seconds)).unwrap_or_default(),
+                        session.seconds_minimized,
+                        session.manual_restores,
+                        session.whispers_received,
+                        csv_field(session.zone.as_deref().unwrap_or("")));
+    }
+    csv
+}
+
+fn aggregates_csv(aggregates: &[Aggregate]) -> String {
+    let mut csv = String::from("period,sessions,seconds_afk,seconds_minimized,manual_restores,whispers_received\n");
+    for aggregate in aggregates {
+        csv += &format!("{},{},{:.1},{:.1},{},{}\n",
+                        aggregate.period,
+                        aggregate.sessions,
+                        aggregate.seconds_afk,
+                        aggregate.seconds_minimized,
+                        aggregate.manual_restores,
+                        aggregate.whispers_received);
+    }
+    csv
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+    use crate::utils::test_directory;
+
+    fn session(start: (u32, u32, u32), minutes: i64, minimized: f64, manual_restores: u32, whispers_received: u32, zone: Option<&str>) -> AfkSession {
+        let start = Local.with_ymd_and_hms(2020, start.0, start.1, start.2, 0, 0).unwrap();
+        AfkSession {
+            start,
+            end: start + chrono::Duration::minutes(minutes),
+            seconds_until_minimized: if minimized > 0.0 { Some(30.0) } else { None },
+            seconds_minimized: minimized,
+            manual_restores,
+            whispers_received,
+            zone: zone.map(str::to_owned),
+        }
+    }
+
+    fn sessions() -> Vec<AfkSession> {
+        vec![
+            // friday and saturday of iso week 6, sunday ends it
+            session((2, 7, 10), 10, 540.0, 1, 2, Some("HideoutCoastal")),
+            session((2, 7, 23), 30, 0.0, 0, 0, None),
+            session((2, 8, 12), 60, 3000.0, 2, 5, Some("Hideout, \"Luxurious\"")),
+            session((2, 10, 8), 5, 200.0, 0, 1, None),
+        ]
+    }
+
+    #[test]
+    fn aggregates_per_day_and_week() {
+        let days = aggregate(&sessions(), Period::Day);
+        assert_eq!(days.iter().map(|day| day.period.as_str()).collect::<Vec<_>>(), vec!["2020-02-07", "2020-02-08", "2020-02-10"]);
+        assert_eq!(days[0], Aggregate {
+            period: "2020-02-07".to_owned(),
+            sessions: 2,
+            seconds_afk: 2400.0,
+            seconds_minimized: 540.0,
+            manual_restores: 1,
+            whispers_received: 2,
+        });
+
+        let weeks = aggregate(&sessions(), Period::Week);
+        assert_eq!(weeks.len(), 2);
+        assert_eq!((weeks[0].period.as_str(), weeks[0].sessions, weeks[0].seconds_afk), ("2020-W06", 3, 6000.0));
+        assert_eq!((weeks[0].manual_restores, weeks[0].whispers_received), (3, 7));
+        assert_eq!((weeks[1].period.as_str(), weeks[1].sessions, weeks[1].seconds_minimized), ("2020-W07", 1, 200.0));
+        assert!(aggregate(&[], Period::Day).is_empty());
+    }
+
+    #[test]
+    fn csv_output() {
+        let csv = sessions_csv(&sessions());
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines[0], "start,end,seconds_until_minimized,seconds_minimized,manual_restores,whispers_received,zone");
+        assert_eq!(lines.len(), 5);
+        assert!(lines[1].ends_with(",30.0,540.0,1,2,HideoutCoastal"), "{}", lines[1]);
+        assert!(lines[2].ends_with(",,0.0,0,0,"), "{}", lines[2]);
+        assert!(lines[3].ends_with(",\"Hideout, \"\"Luxurious\"\"\""), "{}", lines[3]);
+
+        let csv = aggregates_csv(&aggregate(&sessions(), Period::Week));
+        assert_eq!(csv, "period,sessions,seconds_afk,seconds_minimized,manual_restores,whispers_received\n\
+                         2020-W06,3,6000.0,3540.0,3,7\n\
+                         2020-W07,1,300.0,200.0,0,1\n");
+    }
+
+    #[test]
+    fn json_output() {
+        let sessions = sessions();
+        let report: serde_json::Value = serde_json::from_str(&to_json(&Report::new(&sessions)).unwrap()).unwrap();
+        assert_eq!(report["sessions"].as_array().unwrap().len(), 4);
+        assert_eq!(report["sessions"][0]["zone"], "HideoutCoastal");
+        assert_eq!(report["sessions"][1]["seconds_until_minimized"], serde_json::Value::Null);
+        assert_eq!(report["per_day"].as_array().unwrap().len(), 3);
+        assert_eq!(report["per_week"][0]["period"], "2020-W06");
+        assert_eq!(report["per_week"][0]["manual_restores"], 3);
+
+        // what is written can be read back
+        let path = test_directory("stats-json").join("afk-sessions.jsonl");
+        for session in &sessions {
+            append_session(&path, session).unwrap();
+        }
+        fs::write(&path, fs::read_to_string(&path).unwrap() + "not json\n\n").unwrap();
+        assert_eq!(load_sessions(&path).unwrap(), sessions);
+        assert_eq!(load_sessions(&path.with_file_name("missing.jsonl")).unwrap(), vec![]);
+        assert_eq!(run_stats_command(&path, &["month".to_owned()]).unwrap_err().kind(), ErrorKind::InvalidInput);
+    }
+
+    fn recorded(path: &Path) -> Vec<AfkSession> {
+        load_sessions(path).unwrap()
+    }
+
+    #[test]
+    fn recorder_follows_the_state_machine() {
+        let path = test_directory("stats-recorder").join("afk-sessions.jsonl");
+        let mut recorder = SessionRecorder::new(path.clone());
+        recorder.update(State::Watching, Some("HideoutCoastal"));
+        // whispers outside of afk sessions don't count
+        recorder.whisper_received();
+        recorder.update(State::AfkPending, Some("HideoutCoastal"));
+        recorder.whisper_received();
+        recorder.update(State::Minimized, Some("HideoutCoastal"));
+        recorder.update(State::Minimized, Some("Lioneye's Watch"));
+        assert!(recorded(&path).is_empty());
+        recorder.update(State::Watching, None);
+        let sessions = recorded(&path);
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].zone.as_deref(), Some("HideoutCoastal"));
+        assert_eq!((sessions[0].whispers_received, sessions[0].manual_restores), (1, 0));
+        assert!(sessions[0].seconds_until_minimized.is_some());
+        assert!(sessions[0].end >= sessions[0].start);
+
+        // afk without being minimized, ended by pausing
+        recorder.update(State::AfkPending, None);
+        recorder.update(State::Paused, None);
+        let sessions = recorded(&path);
+        assert_eq!(sessions.len(), 2);
+        assert_eq!((sessions[1].seconds_until_minimized, sessions[1].seconds_minimized), (None, 0.0));
+
+        // the game exiting and poe-minimizer stopping end sessions too
+        recorder.update(State::Minimized, None);
+        recorder.update(State::GameNotRunning, None);
+        recorder.update(State::AfkPending, None);
+        drop(recorder);
+        assert_eq!(recorded(&path).len(), 4);
+    }
+
+    #[test]
+    fn manual_restores_are_counted() {
+        let path = test_directory("stats-restores").join("afk-sessions.jsonl");
+        let mut recorder = SessionRecorder::new(path.clone());
+        recorder.update(State::AfkPending, None);
+        recorder.update(State::Minimized, None);
+        // restored while still afk, then minimized again after the timeout
+        recorder.update(State::AfkPending, None);
+        recorder.update(State::Minimized, None);
+        recorder.update(State::AfkPending, None);
+        recorder.update(State::AfkPending, None);
+        recorder.update(State::Minimized, None);
+        // coming back is no manual restore
+        recorder.update(State::Watching, None);
+        let sessions = recorded(&path);
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].manual_restores, 2);
+        assert!(sessions[0].seconds_minimized >= 0.0);
+    }
+}
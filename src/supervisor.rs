@@ -0,0 +1,312 @@
+use std::any::Any;
+use std::backtrace::Backtrace;
+use std::io::Error;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::control::Remote;
+use crate::metrics;
+use crate::shutdown::Shutdown;
+
+const FIRST_RESTART_DELAY: Duration = Duration::from_secs(1);
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(5 * 60);
+/// a worker that ran this long since its last restart starts over with the shortest delay and no longer shows up
+/// as crashed
+const STABLE_AFTER: Duration = Duration::from_secs(10 * 60);
+
+/// Logs panics with a backtrace, the default hook only prints them to stderr, which nobody sees without a console.
+pub fn install_panic_hook() {
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        let thread = thread::current();
+        error!("thread '{}' {}\n{}", thread.name().unwrap_or("unnamed"), info, Backtrace::force_capture());
+        default_hook(info);
+    }));
+}
+
+/// The time the restart delays and stable runs are measured in, replaced in tests.
+trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+
+    /// Sleeps until `deadline` unless shutdown is requested before, returns whether it was.
+    fn wait_until(&self, shutdown: &Shutdown, deadline: Instant) -> bool;
+}
+
+struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn wait_until(&self, shutdown: &Shutdown, deadline: Instant) -> bool {
+        shutdown.wait(deadline.saturating_duration_since(Instant::now()))
+    }
+}
+
+/// Restarts workers that panicked, waiting longer after every crash, and gives up after `max_crashes`. Crashed
+/// workers show up in the status until they ran for `STABLE_AFTER` after their restart, or for good once given up on.
+#[derive(Clone)]
+pub struct Supervisor {
+    remote: Remote,
+    shutdown: Shutdown,
+    max_crashes: u32,
+    first_restart_delay: Duration,
+    stable_after: Duration,
+    clock: Arc<dyn Clock>,
+}
+
+impl Supervisor {
+    pub fn new(remote: Remote, shutdown: Shutdown, max_crashes: u32) -> Self {
+        Supervisor { remote, shutdown, max_crashes, first_restart_delay: FIRST_RESTART_DELAY, stable_after: STABLE_AFTER, clock: Arc::new(SystemClock) }
+    }
+
+    pub fn shutdown(&self) -> &Shutdown {
+        &self.shutdown
+    }
+
+    /// Runs `work` on a thread of its own, see `run`.
+    pub fn spawn<F: FnMut() + Send + 'static>(&self, name: &str, work: F) -> Result<JoinHandle<()>, Error> {
+        let (supervisor, worker) = (self.clone(), name.to_owned());
+        thread::Builder::new()
+            .name(name.to_owned())
+            .spawn(move || supervisor.run(&worker, work))
+    }
+
+    /// Calls `work` until it returns without panicking, shutdown is requested or it crashed too often.
+    pub fn run<F: FnMut()>(&self, name: &str, mut work: F) {
+        let mut crashes = 0;
+        let mut delay = self.first_restart_delay;
+        // all crashes, unlike `crashes` not reset once the worker is stable
+        let crash_count = Arc::new(AtomicU32::new(0));
+        loop {
+            let started = self.clock.now();
+            let panic = match panic::catch_unwind(AssertUnwindSafe(&mut work)) {
+                Ok(()) => return,
+                Err(panic) => panic,
+            };
+            let crash = crash_count.fetch_add(1, Ordering::SeqCst) + 1;
+            metrics::WORKER_CRASHES.increment();
+            if self.clock.now().saturating_duration_since(started) >= self.stable_after {
+                crashes = 0;
+                delay = self.first_restart_delay;
+            }
+            crashes += 1;
+            let message = panic_message(panic.as_ref());
+            if crashes >= self.max_crashes {
+                error!("{} crashed {} times, giving up: {}", name, crashes, message);
+                self.remote.report_crash(name, format!("{} crashed {} times and was stopped", name, crashes));
+                return;
+            }
+            error!("{} crashed, restarting it in {}s: {}", name, delay.as_secs(), message);
+            self.remote.report_crash(name, format!("{} crashed {} times, last time: {}", name, crashes, message));
+            if self.clock.wait_until(&self.shutdown, self.clock.now() + delay) {
+                return;
+            }
+            self.clear_crash_when_stable(name, crash, crash_count.clone());
+            delay = (delay * 2).min(MAX_RESTART_DELAY);
+        }
+    }
+
+    /// Removes the worker from the crashed ones once it ran for `stable_after` without crashing again.
+    fn clear_crash_when_stable(&self, name: &str, crash: u32, crash_count: Arc<AtomicU32>) {
+        let (supervisor, worker) = (self.clone(), name.to_owned());
+        let stable_at = self.clock.now() + self.stable_after;
+        let _ = thread::Builder::new()
+            .name(format!("{} watchdog", name))
+            .spawn(move || {
+                if !supervisor.clock.wait_until(&supervisor.shutdown, stable_at) && crash_count.load(Ordering::SeqCst) == crash {
+                    info!("{} runs stable again", worker);
+                    supervisor.remote.clear_crash(&worker);
+                }
+            });
+    }
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> String {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic".to_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::{self, Receiver, Sender};
+    use std::sync::{Condvar, Mutex};
+
+    use super::*;
+    use crate::control;
+
+    const STABLE: Duration = Duration::from_secs(60);
+
+    /// Only moves forward when told to.
+    struct FakeClock {
+        /// the time and how many threads are waiting for it
+        now: Mutex<(Instant, usize)>,
+        advanced: Condvar,
+    }
+
+    impl FakeClock {
+        fn advance(&self, duration: Duration) {
+            self.now.lock().unwrap().0 += duration;
+            self.advanced.notify_all();
+        }
+
+        /// Waits until `waiting` threads wait for the time to advance.
+        fn wait_for_waiting(&self, waiting: usize) {
+            let guard = self.now.lock().unwrap();
+            let (_guard, timeout) = self.advanced.wait_timeout_while(guard, Duration::from_secs(5), |(_, current)| *current != waiting).unwrap();
+            assert!(!timeout.timed_out(), "expected {} waiting threads", waiting);
+        }
+    }
+
+    impl Clock for FakeClock {
+        fn now(&self) -> Instant {
+            self.now.lock().unwrap().0
+        }
+
+        fn wait_until(&self, shutdown: &Shutdown, deadline: Instant) -> bool {
+            let mut now = self.now.lock().unwrap();
+            now.1 += 1;
+            self.advanced.notify_all();
+            while now.0 < deadline && !shutdown.is_requested() {
+                now = self.advanced.wait(now).unwrap();
+            }
+            now.1 -= 1;
+            self.advanced.notify_all();
+            shutdown.is_requested()
+        }
+    }
+
+    /// Restarts right away and considers workers stable after `STABLE` passed on the returned clock.
+    fn supervisor(max_crashes: u32) -> (Supervisor, Remote, Arc<FakeClock>) {
+        let (_controller, remote) = control::channel();
+        let clock = Arc::new(FakeClock { now: Mutex::new((Instant::now(), 0)), advanced: Condvar::new() });
+        let mut supervisor = Supervisor::new(remote.clone(), Shutdown::new(), max_crashes);
+        supervisor.first_restart_delay = Duration::from_secs(0);
+        supervisor.stable_after = STABLE;
+        supervisor.clock = clock.clone();
+        (supervisor, remote, clock)
+    }
+
+    fn crash_report(remote: &Remote) -> Option<String> {
+        remote.status().crashed_workers.get("worker").cloned()
+    }
+
+    /// A worker that panics or returns when told to, and tells when it (re)started.
+    fn spawn_worker(supervisor: &Supervisor) -> (Sender<bool>, Receiver<()>, JoinHandle<()>) {
+        let (panic, panics) = mpsc::channel();
+        let (started, starts) = mpsc::channel();
+        let handle = supervisor.spawn("worker", move || {
+            let _ = started.send(());
+            if panics.recv().unwrap_or(false) {
+                panic!("told to");
+            }
+        }).unwrap();
+        (panic, starts, handle)
+    }
+
+    fn crash(panic: &Sender<bool>, starts: &Receiver<()>) {
+        panic.send(true).unwrap();
+        starts.recv_timeout(Duration::from_secs(5)).expect("the worker was not restarted");
+    }
+
+    #[test]
+    fn restarts_are_delayed_longer_after_every_crash() {
+        let (mut supervisor, _remote, clock) = supervisor(3);
+        supervisor.first_restart_delay = Duration::from_secs(1);
+        let (panic, starts, handle) = spawn_worker(&supervisor);
+        starts.recv().unwrap();
+        for delay in [1, 2].iter() {
+            panic.send(true).unwrap();
+            // the watchdogs of earlier restarts wait as well
+            clock.wait_for_waiting(*delay as usize);
+            clock.advance(Duration::from_secs(delay - 1));
+            assert!(starts.try_recv().is_err(), "restarted before {}s passed", delay);
+            clock.advance(Duration::from_secs(1));
+            starts.recv_timeout(Duration::from_secs(5)).expect("the worker was not restarted");
+        }
+        panic.send(false).unwrap();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn crashes_are_cleared_once_the_worker_is_stable() {
+        let (supervisor, remote, clock) = supervisor(3);
+        let (panic, starts, handle) = spawn_worker(&supervisor);
+        starts.recv().unwrap();
+        crash(&panic, &starts);
+        crash(&panic, &starts);
+        assert_eq!(crash_report(&remote).as_deref(), Some("worker crashed 2 times, last time: told to"));
+        clock.wait_for_waiting(2);
+        clock.advance(STABLE - Duration::from_secs(1));
+        assert_eq!(crash_report(&remote).as_deref(), Some("worker crashed 2 times, last time: told to"));
+        clock.advance(Duration::from_secs(1));
+        clock.wait_for_waiting(0);
+        wait_for(|| crash_report(&remote).is_none());
+        panic.send(false).unwrap();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn crashes_after_a_stable_run_start_over() {
+        let (supervisor, remote, clock) = supervisor(2);
+        let (panic, starts, handle) = spawn_worker(&supervisor);
+        starts.recv().unwrap();
+        crash(&panic, &starts);
+        clock.advance(STABLE);
+        wait_for(|| crash_report(&remote).is_none());
+        // would be the second crash in a row otherwise, which gives up
+        crash(&panic, &starts);
+        assert_eq!(crash_report(&remote).as_deref(), Some("worker crashed 1 times, last time: told to"));
+        panic.send(false).unwrap();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn workers_given_up_on_stay_crashed() {
+        let (supervisor, remote, clock) = supervisor(2);
+        let (panic, starts, handle) = spawn_worker(&supervisor);
+        starts.recv().unwrap();
+        crash(&panic, &starts);
+        panic.send(true).unwrap();
+        handle.join().unwrap();
+        assert_eq!(crash_report(&remote).as_deref(), Some("worker crashed 2 times and was stopped"));
+        // the watchdog of the first restart must not clear it
+        clock.wait_for_waiting(1);
+        clock.advance(STABLE);
+        clock.wait_for_waiting(0);
+        assert_eq!(crash_report(&remote).as_deref(), Some("worker crashed 2 times and was stopped"));
+    }
+
+    #[test]
+    fn shutdown_ends_the_restart_delay() {
+        let (mut supervisor, _remote, clock) = supervisor(3);
+        supervisor.first_restart_delay = Duration::from_secs(1);
+        let (panic, starts, handle) = spawn_worker(&supervisor);
+        starts.recv().unwrap();
+        panic.send(true).unwrap();
+        clock.wait_for_waiting(1);
+        supervisor.shutdown().request();
+        // wakes the supervisor to notice
+        clock.advance(Duration::from_secs(0));
+        handle.join().unwrap();
+        assert!(starts.try_recv().is_err());
+    }
+
+    /// The watchdog clears the crash right after its wait ended.
+    fn wait_for<F: Fn() -> bool>(condition: F) {
+        let started = Instant::now();
+        while !condition() {
+            assert!(started.elapsed() < Duration::from_secs(5), "timed out");
+            thread::yield_now();
+        }
+    }
+}
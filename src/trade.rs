@@ -0,0 +1,55 @@
+use crate::language;
+
+/// A whisper generated by the trade site.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TradeRequest {
+    pub buyer: String,
+    /// the whole message if the template of its language is unknown
+    pub item: String,
+    pub price: Option<String>,
+    pub league: Option<String>,
+}
+
+impl TradeRequest {
+    /// `Tabula Rasa Simple Robe for 5 chaos`
+    pub fn summary(&self) -> String {
+        match &self.price {
+            Some(price) => format!("{} for {}", self.item, price),
+            None => self.item.clone(),
+        }
+    }
+}
+
+/// Recognizes
+/// - `Hi, I would like to buy your <item> listed for <price> in <league> (stash tab ...)`
+/// - `Hi, I would like to buy your <item> in <league> (stash tab ...)` for items without a price
+/// - `Hi, I'd like to buy your <amount> <currency> for my <amount> <currency> in <league>.` from bulk exchange
+///
+/// and falls back to the whole message for trade requests in other languages.
+pub fn parse_trade_request(buyer: &str, message: &str) -> Option<TradeRequest> {
+    if !language::is_trade_request(message) {
+        return None;
+    }
+    let (item, price, league) = ["I would like to buy your ", "I'd like to buy your "].iter()
+        .find_map(|marker| message.find(marker).map(|start| &message[start + marker.len()..]))
+        .and_then(parse_english)
+        .unwrap_or_else(|| (message.to_owned(), None, None));
+    Some(TradeRequest { buyer: buyer.to_owned(), item, price, league })
+}
+
+fn parse_english(rest: &str) -> Option<(String, Option<String>, Option<String>)> {
+    // the league is followed by the stash position or, for bulk trades, a period
+    let rest = match rest.find(" (stash") {
+        Some(end) => &rest[..end],
+        None => rest.trim_end().trim_end_matches('.'),
+    };
+    let league_start = rest.rfind(" in ")?;
+    let league = rest[league_start + 4..].trim().to_owned();
+    let offer = &rest[..league_start];
+    let (item, price) = match offer.find(" listed for ").map(|index| (index, " listed for ".len()))
+        .or_else(|| offer.find(" for my ").map(|index| (index, " for my ".len()))) {
+        Some((index, length)) => (&offer[..index], Some(offer[index + length..].trim().to_owned())),
+        None => (offer, None),
+    };
+    Some((item.trim().to_owned(), price, Some(league)))
+}
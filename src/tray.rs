@@ -1,8 +1,10 @@
+use std::cell::Cell;
 use std::ffi::CString;
-use std::io::Error;
+use std::io::{Error, ErrorKind};
 use std::ptr::null_mut;
 
 use widestring::U16CString;
+use winapi::ctypes::c_int;
 use winapi::shared::minwindef::*;
 use winapi::shared::windef::*;
 use winapi::um::shellapi::*;
@@ -10,49 +12,81 @@ use winapi::um::shellapi::*;
 use winapi::um::wingdi::*;
 use winapi::um::winuser::*;
 
-use crate::tray::Event::{Exit, Nothing, ToggleAutoStart};
-use crate::utils::{LoggableResult, join_executable_path};
+use crate::icons::TrayIcon;
+use crate::menu::{MenuAction, MenuItem};
+use crate::tray::Event::{MenuRequested, Nothing};
+use crate::utils::LoggableResult;
 
 const SYSICON_ID: u32 = 0x10;
-const SYSTEM_TRAY_POPUP_EXIT: usize = 0x111;
-const SYSTEM_TRAY_POPUP_STARTUP: usize = 0x112;
-const SYSTEM_TRAY_POPUP_VERSION: usize = 0x113;
 const SYSTEM_TRAY_MESSAGE: u32 = 0x11;
 const MESSAGE_SHOW_TRAY_POPUP: u32 = WM_APP + 1;
-
-const VERSION: &'static str = env!("CARGO_PKG_VERSION");
+const MESSAGE_WAKE_UP: u32 = WM_APP + 2;
+/// wakes up the message loop so the icon follows the state
+const REFRESH_TIMER_ID: usize = 1;
+const REFRESH_INTERVAL_MS: u32 = 1000;
 
 pub enum Event {
-    Exit,
-    ToggleAutoStart,
+    /// the tray icon was right clicked, answer with `show_menu`
+    MenuRequested,
     Nothing
 }
 
 pub struct SystemTray {
     window_handle: HWND,
-    menu_handle: HMENU,
-    run_on_startup: bool
+    /// the icon shown, destroyed once it is replaced
+    icon: Cell<HICON>,
 }
 
 impl SystemTray {
-    pub fn new(run_on_startup: bool) -> Result<SystemTray, Error> {
+    pub fn new() -> Result<SystemTray, Error> {
         let window_handle = create_message_window()?;
-        let menu_handle = create_tray_menu(run_on_startup);
+        let icon = load_icon(TrayIcon::GameNotRunning)?;
+        create_system_tray(window_handle, icon)?;
         Ok(SystemTray {
             window_handle,
-            menu_handle,
-            run_on_startup
+            icon: Cell::new(icon),
         })
     }
 
+    /// Replaces the icon and the tooltip, which is cut to 127 utf-16 code units.
+    pub fn update(&self, icon: TrayIcon, tooltip: &str) -> Result<(), Error> {
+        let icon = load_icon(icon)?;
+        let mut icon_data = init_notify_icon_data();
+        icon_data.hWnd = self.window_handle;
+        icon_data.uFlags = NIF_ICON | NIF_TIP;
+        icon_data.hIcon = icon;
+        copy_to_wide_buffer(tooltip, &mut icon_data.szTip);
+        unsafe {
+            if 0 == Shell_NotifyIconW(NIM_MODIFY, &mut icon_data) {
+                DestroyIcon(icon);
+                return Err(Error::last_os_error());
+            }
+            DestroyIcon(self.icon.replace(icon));
+        }
+        Ok(())
+    }
+
     pub fn handle_windows_messages(&self) -> Result<Event, Error> {
-        handle_windows_messages(self.window_handle, self.menu_handle)
+        handle_windows_messages(self.window_handle)
+    }
+
+    /// Makes a waiting `handle_windows_messages` return, callable from any thread.
+    pub fn waker(&self) -> impl Fn() + Send + 'static {
+        // window handles may be used from any thread, but the raw pointer isn't Send
+        let window_handle = self.window_handle as usize;
+        move || unsafe {
+            PostMessageA(window_handle as HWND, MESSAGE_WAKE_UP, 0, 0);
+        }
     }
 
-    pub fn set_run_on_startup(&mut self, new_value: bool) {
-        self.run_on_startup = new_value;
-        let mask = bool_as_menu_flag(new_value);
-        update_tray_menu_item_state(self.window_handle, self.menu_handle, SYSTEM_TRAY_POPUP_STARTUP, mask);
+    /// Shows the menu at the cursor until an item is picked or it is dismissed.
+    pub fn show_menu(&self, items: &[MenuItem]) -> Option<MenuAction> {
+        let menu_handle = create_menu(items);
+        let id = show_tray_menu(self.window_handle, menu_handle);
+        unsafe {
+            DestroyMenu(menu_handle);
+        }
+        MenuAction::from_id(id)
     }
 }
 
@@ -60,6 +94,9 @@ impl Drop for SystemTray {
     fn drop(&mut self) {
         destroy_message_window(self.window_handle)
             .log_error_and_ignore("failed to clean up SystemTray, exiting regardless.");
+        unsafe {
+            DestroyIcon(self.icon.get());
+        }
     }
 }
 
@@ -99,36 +136,45 @@ fn create_message_window() -> Result<HWND, Error> {
         if handle == null_mut() {
             return Err(Error::last_os_error());
         }
-        create_system_tray(handle).expect("yo");
+        if 0 == SetTimer(handle, REFRESH_TIMER_ID, REFRESH_INTERVAL_MS, None) {
+            return Err(Error::last_os_error());
+        }
         Ok(handle)
     }
 }
 
 fn destroy_message_window(window_handle: HWND) -> Result<(), Error> {
-    remove_system_tray(window_handle).expect("failed to remove application from system tray");
-    Ok(())
+    remove_system_tray(window_handle)
 }
 
-fn create_system_tray(window_handle: HWND) -> Result<(), Error> {
-    let mut icon_data = init_notify_icon_data();
-    icon_data.hWnd = window_handle;
-    let title = "poe-minimizer".as_bytes();
-    for i in 0..title.len() {
-        icon_data.szTip[i] = title[i] as i8;
-    }
-    let icon_path = join_executable_path("icon.ico").unwrap_or(".\\icon.ico".to_owned());
-    let icon_path: U16CString = U16CString::from_str(icon_path).unwrap();
-    let icon = unsafe {
-        let icon = LoadImageW(null_mut(), icon_path.as_ptr(), IMAGE_ICON, 0, 0, LR_LOADFROMFILE) as HICON;
+/// Creates an icon of the size the tray uses from the embedded images.
+fn load_icon(icon: TrayIcon) -> Result<HICON, Error> {
+    let size = unsafe { GetSystemMetrics(SM_CXSMICON) }.max(16);
+    let images = icon.images();
+    // the smallest image that is large enough, scaling down looks better than scaling up
+    let (_, _, bitmap) = images.iter()
+        .filter(|(width, _, _)| *width as c_int >= size)
+        .min_by_key(|(width, _, _)| *width)
+        .or_else(|| images.iter().max_by_key(|(width, _, _)| *width))
+        .copied()
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, format!("the {:?} icon has no images", icon)))?;
+    unsafe {
+        let icon = CreateIconFromResourceEx(bitmap.as_ptr() as PBYTE, bitmap.len() as DWORD, TRUE, 0x00030000, size, size, LR_DEFAULTCOLOR);
         if icon == null_mut() {
             Err(Error::last_os_error())
         } else {
             Ok(icon)
         }
-    }?;
+    }
+}
+
+fn create_system_tray(window_handle: HWND, icon: HICON) -> Result<(), Error> {
+    let mut icon_data = init_notify_icon_data();
+    icon_data.hWnd = window_handle;
+    copy_to_wide_buffer("poe-minimizer", &mut icon_data.szTip);
     icon_data.hIcon = icon;
     unsafe {
-        if 0 == Shell_NotifyIconA(NIM_ADD, &mut icon_data) {
+        if 0 == Shell_NotifyIconW(NIM_ADD, &mut icon_data) {
             Err(Error::last_os_error())
         } else {
             Ok(())
@@ -140,7 +186,7 @@ fn remove_system_tray(window_handle: HWND) -> Result<(), Error> {
     let mut icon_data = init_notify_icon_data();
     icon_data.hWnd = window_handle;
     unsafe {
-        if 0 == Shell_NotifyIconA(NIM_DELETE, &mut icon_data) {
+        if 0 == Shell_NotifyIconW(NIM_DELETE, &mut icon_data) {
             Err(Error::last_os_error())
         } else {
             Ok(())
@@ -148,12 +194,50 @@ fn remove_system_tray(window_handle: HWND) -> Result<(), Error> {
     }
 }
 
-fn init_notify_icon_data() -> NOTIFYICONDATAA {
-    let wtf_is_this: NOTIFYICONDATAA_u = unsafe {
-        std::mem::transmute::<u32, NOTIFYICONDATAA_u>(0 as u32)
+/// Shows a balloon tip at the tray icon, callable from any thread.
+pub fn show_balloon(title: &str, text: &str) -> Result<(), Error> {
+    let class_name = CString::new("poe-minimizer").unwrap();
+    let window_handle = unsafe { FindWindowExA(HWND_MESSAGE, null_mut(), class_name.as_ptr(), null_mut()) };
+    if window_handle == null_mut() {
+        return Err(Error::new(ErrorKind::NotFound, "the tray icon does not exist"));
+    }
+    let mut icon_data = init_notify_icon_data();
+    icon_data.hWnd = window_handle;
+    icon_data.uFlags = NIF_INFO;
+    icon_data.dwInfoFlags = NIIF_INFO;
+    copy_to_wide_buffer(title, &mut icon_data.szInfoTitle);
+    copy_to_wide_buffer(text, &mut icon_data.szInfo);
+    unsafe {
+        if 0 == Shell_NotifyIconW(NIM_MODIFY, &mut icon_data) {
+            Err(Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Truncates to the buffer without splitting a surrogate pair.
+fn copy_to_wide_buffer(text: &str, buffer: &mut [u16]) {
+    let mut length = 0;
+    for c in text.chars() {
+        let mut units = [0; 2];
+        let units = c.encode_utf16(&mut units);
+        if length + units.len() > buffer.len() - 1 {
+            break;
+        }
+        buffer[length..length + units.len()].copy_from_slice(units);
+        length += units.len();
+    }
+    buffer[length] = 0;
+}
+
+fn init_notify_icon_data() -> NOTIFYICONDATAW {
+    let wtf_is_this: NOTIFYICONDATAW_u = unsafe {
+        std::mem::transmute::<u32, NOTIFYICONDATAW_u>(0 as u32)
     };
-    NOTIFYICONDATAA {
-        cbSize: 0,
+    NOTIFYICONDATAW {
+        // balloon tips are ignored unless the size says the struct has the fields for them
+        cbSize: std::mem::size_of::<NOTIFYICONDATAW>() as u32,
         hWnd: null_mut(),
         uID: SYSICON_ID,
         uFlags: NIF_ICON | NIF_MESSAGE | NIF_TIP,
@@ -176,7 +260,7 @@ fn init_notify_icon_data() -> NOTIFYICONDATAA {
     }
 }
 
-fn handle_windows_messages(window_handle: HWND, menu_handle: HMENU) -> Result<Event, Error> {
+fn handle_windows_messages(window_handle: HWND) -> Result<Event, Error> {
     unsafe {
         let mut msg = MSG {
             hwnd: null_mut(),
@@ -195,21 +279,9 @@ fn handle_windows_messages(window_handle: HWND, menu_handle: HMENU) -> Result<Ev
         }
         trace!("[WM] received windows message: {}", msg.message);
 
-        if msg.message == WM_COMMAND {
-            trace!("[WM] received command: lp: {}, wp: {}", msg.lParam, msg.wParam);
-            return match msg.wParam {
-                SYSTEM_TRAY_POPUP_EXIT => Ok(Exit),
-                SYSTEM_TRAY_POPUP_STARTUP => {
-                    update_tray_menu_item_state(window_handle, menu_handle, SYSTEM_TRAY_POPUP_STARTUP, MFS_UNCHECKED);
-                    Ok(ToggleAutoStart)
-                },
-                _ => Ok(Nothing)
-            };
-        }
-
         if msg.message == MESSAGE_SHOW_TRAY_POPUP {
             trace!("[WM] received show tray popup");
-            show_tray_menu(window_handle, menu_handle);
+            return Ok(MenuRequested);
         }
 
         TranslateMessage(&mut msg);
@@ -234,58 +306,72 @@ unsafe extern "system" fn win_proc_dispatch(hwnd: HWND, msg: UINT, wparam: WPARA
     DefWindowProcW(hwnd, msg, wparam, lparam)
 }
 
-fn create_tray_menu(run_on_startup: bool) -> HMENU {
+/// Renders the platform neutral menu model, submenus are destroyed along with the returned menu.
+fn create_menu(items: &[MenuItem]) -> HMENU {
     unsafe {
         let popup = CreatePopupMenu();
         trace!("creating popup: {:?}", popup);
-        let version = format!("poe-minimizer v{}", VERSION);
-        InsertMenuA(popup, 0xFFFFFFFE, MF_BYPOSITION | MF_STRING | MF_DISABLED, SYSTEM_TRAY_POPUP_VERSION, CString::new(version).unwrap().as_ptr());
-        InsertMenuA(popup, 0xFFFFFFFE, MF_SEPARATOR, 0, CString::new("test").unwrap().as_ptr());
-        InsertMenuA(popup, 0xFFFFFFFE, MF_BYPOSITION | MF_STRING | bool_as_menu_flag(run_on_startup), SYSTEM_TRAY_POPUP_STARTUP, CString::new("Run on startup").unwrap().as_ptr());
-        InsertMenuA(popup, 0xFFFFFFFE, MF_SEPARATOR, 0, CString::new("test").unwrap().as_ptr());
-        InsertMenuA(popup, 0xFFFFFFFE, MF_BYPOSITION | MF_STRING, SYSTEM_TRAY_POPUP_EXIT, CString::new("Exit").unwrap().as_ptr());
+        for item in items {
+            let label = match item {
+                MenuItem::Label(label) | MenuItem::Action { label, .. } | MenuItem::Submenu { label, .. } => label.as_str(),
+                MenuItem::Separator => "",
+            };
+            // labels come from the status, e.g. the message of a crashed worker, and may contain anything
+            let label = match U16CString::from_str(label) {
+                Ok(label) => label,
+                Err(_) => {
+                    warn!("leaving out the menu item {:?}, it contains a NUL character", label);
+                    continue;
+                }
+            };
+            let (flags, id) = match item {
+                MenuItem::Label(_) => (MF_STRING | MF_GRAYED, 0),
+                MenuItem::Action { action, enabled, checked, .. } => {
+                    let mut flags = MF_STRING;
+                    if !enabled {
+                        flags |= MF_GRAYED;
+                    }
+                    if *checked == Some(true) {
+                        flags |= MF_CHECKED;
+                    }
+                    (flags, action.id() as usize)
+                }
+                MenuItem::Submenu { items, .. } => (MF_STRING | MF_POPUP, create_menu(items) as usize),
+                MenuItem::Separator => (MF_SEPARATOR, 0),
+            };
+            AppendMenuW(popup, flags, id, label.as_ptr());
+        }
         popup
     }
 }
 
-fn show_tray_menu(window_handle: HWND, menu_handle: HMENU) {
+/// Returns the id of the picked item, 0 if the menu was dismissed.
+fn show_tray_menu(window_handle: HWND, menu_handle: HMENU) -> u32 {
     unsafe {
         let mut position = POINT { x: 0, y: 0 };
         GetCursorPos(&mut position);
         SetForegroundWindow(window_handle);
-        TrackPopupMenu(menu_handle, TPM_LEFTALIGN | TPM_LEFTBUTTON | TPM_BOTTOMALIGN, position.x, position.y, 0, window_handle, null_mut());
+        let id = TrackPopupMenu(menu_handle, TPM_LEFTALIGN | TPM_LEFTBUTTON | TPM_BOTTOMALIGN | TPM_RETURNCMD | TPM_NONOTIFY,
+                                position.x, position.y, 0, window_handle, null_mut());
         PostMessageA(window_handle, WM_NULL, 0, 0);
+        id as u32
     }
 }
 
-fn update_tray_menu_item_state(window_handle: HWND, menu_handle: HMENU, item_id: usize, flags: u32) {
-    unsafe {
-        let mut item_info = MENUITEMINFOA {
-            cbSize: std::mem::size_of::<MENUITEMINFOA>() as u32,
-            fMask: MIIM_STATE,
-            fType: 0,
-            fState: flags,
-            wID: 0,
-            hSubMenu: null_mut(),
-            hbmpChecked: null_mut(),
-            hbmpUnchecked: null_mut(),
-            dwItemData: 0,
-            dwTypeData: null_mut(),
-            cch: 0,
-            hbmpItem: null_mut()
-        };
+#[cfg(test)]
+mod tests {
+    use super::copy_to_wide_buffer;
 
-        if 0 == SetMenuItemInfoA(menu_handle, item_id as u32, 0, &mut item_info) {
-            Err::<(), Error>(Error::last_os_error()).log_error_and_ignore("failed to update menu item");
-        }
-        DrawMenuBar(window_handle);
-    }
-}
+    #[test]
+    fn wide_buffers() {
+        let mut buffer = [0xffff; 8];
+        copy_to_wide_buffer("Grüße – €", &mut buffer);
+        assert_eq!(String::from_utf16(&buffer[..7]).unwrap(), "Grüße –");
+        assert_eq!(buffer[7], 0);
 
-fn bool_as_menu_flag(value: bool) -> u32 {
-    if value {
-        MFS_CHECKED
-    } else {
-        MFS_UNCHECKED
+        // a surrogate pair that doesn't fit is left out entirely
+        let mut buffer = [0xffff; 4];
+        copy_to_wide_buffer("ab😀", &mut buffer);
+        assert_eq!(&buffer, &[u16::from(b'a'), u16::from(b'b'), 0, 0xffff]);
     }
 }
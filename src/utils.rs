@@ -1,6 +1,6 @@
 use std::result::Result;
 use std::error::Error;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 pub trait OptionAsResult<T, E: Error> {
 
@@ -17,21 +17,11 @@ impl<T: Clone, E: Error> OptionAsResult<T, E> for Option<T> {
     }
 }
 
-pub trait ToOwnedStr {
-    fn as_string(self) -> String;
-}
-
-impl ToOwnedStr for PathBuf {
-    fn as_string(self) -> String {
-        self.to_str().unwrap().to_string()
-    }
-}
-
 pub trait LoggableResult<T, E: Error> {
 
     fn log_info(self, message: &str) -> Result<T, E>;
     fn log_error(self, message: &str) -> Result<T, E>;
-    fn log_error_and_ignore(self, message: &str) -> ();
+    fn log_error_and_ignore(self, message: &str);
 }
 
 impl<T, E: Error> LoggableResult<T, E> for Result<T, E> {
@@ -48,11 +38,114 @@ impl<T, E: Error> LoggableResult<T, E> for Result<T, E> {
         self
     }
 
-    fn log_error_and_ignore(self, message: &str) -> () {
+    fn log_error_and_ignore(self, message: &str) {
         let _ = self.log_error(message);
     }
 }
 
-pub fn join_executable_path(to_join: &str) -> Option<String> {
-    std::env::current_exe().unwrap().parent().map(|path| path.join(to_join).as_string())
+/// An empty directory for the files of a test, unique per test and process.
+#[cfg(test)]
+pub fn test_directory(name: &str) -> PathBuf {
+    let directory = std::env::temp_dir().join(format!("poe-minimizer-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&directory);
+    std::fs::create_dir_all(&directory).unwrap();
+    directory
+}
+
+/// The messages logged by the current thread while `f` runs, at every level.
+#[cfg(test)]
+pub fn capture_logs<F: FnOnce()>(f: F) -> Vec<String> {
+    use std::cell::RefCell;
+
+    thread_local! {
+        static CAPTURED: RefCell<Option<Vec<String>>> = const { RefCell::new(None) };
+    }
+
+    struct CapturingLogger;
+
+    impl log::Log for CapturingLogger {
+        fn enabled(&self, _: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            CAPTURED.with(|captured| {
+                if let Some(messages) = captured.borrow_mut().as_mut() {
+                    messages.push(record.args().to_string());
+                }
+            });
+        }
+
+        fn flush(&self) {}
+    }
+
+    static INSTALL: std::sync::Once = std::sync::Once::new();
+    INSTALL.call_once(|| {
+        log::set_boxed_logger(Box::new(CapturingLogger)).unwrap();
+        log::set_max_level(log::LevelFilter::Trace);
+    });
+    CAPTURED.with(|captured| *captured.borrow_mut() = Some(Vec::new()));
+    f();
+    CAPTURED.with(|captured| captured.borrow_mut().take().unwrap_or_default())
+}
+
+/// A private session bus for a test, stopped when dropped. Needs `dbus-daemon` on the `PATH`.
+#[cfg(all(test, target_os = "linux"))]
+pub struct TestBus {
+    daemon: std::process::Child,
+    address: String,
+}
+
+#[cfg(all(test, target_os = "linux"))]
+impl TestBus {
+    pub fn start() -> TestBus {
+        use std::io::BufRead;
+
+        let mut daemon = std::process::Command::new("dbus-daemon")
+            .args(["--session", "--print-address", "--nofork"])
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::null())
+            .spawn()
+            .expect("dbus-daemon is needed for this test");
+        let mut address = String::new();
+        std::io::BufReader::new(daemon.stdout.as_mut().unwrap()).read_line(&mut address).unwrap();
+        TestBus { daemon, address: address.trim().to_owned() }
+    }
+
+    pub fn connect(&self) -> dbus::blocking::Connection {
+        let mut channel = dbus::channel::Channel::open_private(&self.address).unwrap();
+        channel.register().unwrap();
+        channel.into()
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+impl Drop for TestBus {
+    fn drop(&mut self) {
+        let _ = self.daemon.kill();
+        let _ = self.daemon.wait();
+    }
+}
+
+/// Opens a file or folder the way double clicking it in the file manager would.
+#[cfg(windows)]
+pub fn open_in_default_app(path: &Path) -> std::io::Result<()> {
+    crate::winutils::shell_open(path)
+}
+
+#[cfg(target_os = "linux")]
+pub fn open_in_default_app(path: &Path) -> std::io::Result<()> {
+    let mut child = std::process::Command::new("xdg-open").arg(path).spawn()?;
+    // xdg-open exits once the app is started, reaping it keeps it from lingering as a zombie
+    std::thread::spawn(move || child.wait());
+    Ok(())
+}
+
+/// Location for sockets and locks, private to the user and cleared on logout.
+#[cfg(target_os = "linux")]
+pub fn runtime_path(file_name: &str) -> PathBuf {
+    match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(runtime_dir) => PathBuf::from(runtime_dir).join(file_name),
+        None => std::env::temp_dir().join(format!("{}-{}", unsafe { libc::getuid() }, file_name)),
+    }
 }
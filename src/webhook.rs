@@ -0,0 +1,621 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::metrics;
+use crate::settings::WebhookSettings;
+use crate::state::State;
+use crate::trade::TradeRequest;
+use crate::utils::*;
+
+/// Undelivered notifications older than this are dropped, nobody cares about a day old trade request.
+const MAX_AGE_SECONDS: i64 = 24 * 60 * 60;
+const FIRST_RETRY_SECONDS: u64 = 5;
+const MAX_RETRY_SECONDS: u64 = 10 * 60;
+/// waiting for events while nothing is due
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    TradeRequest {
+        buyer: String,
+        item: String,
+        price: Option<String>,
+        league: Option<String>,
+    },
+    StateChanged { from: State, to: State },
+}
+
+impl Event {
+    pub fn trade_request(request: &TradeRequest) -> Self {
+        Event::TradeRequest {
+            buyer: request.buyer.clone(),
+            item: request.item.clone(),
+            price: request.price.clone(),
+            league: request.league.clone(),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Event::TradeRequest { .. } => "trade_request",
+            Event::StateChanged { .. } => "state_changed",
+        }
+    }
+
+    fn title(&self) -> String {
+        match self {
+            Event::TradeRequest { buyer, .. } => format!("Trade request from {}", buyer),
+            Event::StateChanged { to, .. } => format!("Path of Exile {}", describe(*to)),
+        }
+    }
+
+    fn message(&self) -> String {
+        match self {
+            Event::TradeRequest { item, price: Some(price), .. } => format!("{} for {}", item, price),
+            Event::TradeRequest { item, .. } => item.clone(),
+            Event::StateChanged { from, to } => format!("{} → {}", describe(*from), describe(*to)),
+        }
+    }
+}
+
+fn describe(state: State) -> &'static str {
+    match state {
+        State::GameNotRunning => "is not running",
+        State::Watching => "is played",
+        State::AfkPending => "is about to be minimized",
+        State::Minimized => "is minimized",
+        State::Paused => "is not minimized, paused",
+    }
+}
+
+enum Format {
+    Json,
+    Discord,
+    /// json with `{{placeholders}}`
+    Template(String),
+}
+
+struct Webhook {
+    settings: WebhookSettings,
+    format: Format,
+}
+
+impl Webhook {
+    fn new(settings: &WebhookSettings) -> Result<Webhook, Error> {
+        let format = match settings.format.as_str() {
+            "json" => Format::Json,
+            "discord" => Format::Discord,
+            "template" => {
+                let path = settings.template.as_ref()
+                    .ok_or_else(|| Error::new(ErrorKind::InvalidInput, format!("webhook {} has no template file", settings.name)))?;
+                let template = fs::read_to_string(path)
+                    .map_err(|e| Error::new(e.kind(), format!("failed to read the template of webhook {} from {:?}: {}", settings.name, path, e)))?;
+                Format::Template(template)
+            }
+            format => return Err(Error::new(ErrorKind::InvalidInput, format!("unknown format {:?} of webhook {}", format, settings.name))),
+        };
+        Ok(Webhook { settings: settings.clone(), format })
+    }
+
+    fn wants(&self, event: &Event, afk: bool) -> bool {
+        if self.settings.only_while_afk && !afk && event.name() == "trade_request" {
+            return false;
+        }
+        self.settings.events.iter().any(|name| name == event.name())
+    }
+
+    fn render(&self, event: &Event, time: DateTime<Local>) -> String {
+        match &self.format {
+            Format::Json => {
+                let mut body = serde_json::to_value(event).unwrap_or_else(|_| json!({}));
+                body["title"] = Value::String(event.title());
+                body["message"] = Value::String(event.message());
+                body["time"] = Value::String(time.to_rfc3339());
+                body.to_string()
+            }
+            Format::Discord => discord_payload(event, time).to_string(),
+            Format::Template(template) => fill_template(template, event, time),
+        }
+    }
+
+    /// The minimum time between two requests to stay below `max_per_minute`.
+    fn interval(&self) -> Duration {
+        Duration::from_secs(60) / self.settings.max_per_minute.max(1)
+    }
+}
+
+fn discord_payload(event: &Event, time: DateTime<Local>) -> Value {
+    let (color, fields) = match event {
+        Event::TradeRequest { price, league, .. } => {
+            let fields: Vec<Value> = [("Price", price), ("League", league)].iter()
+                .filter_map(|(name, value)| value.as_ref().map(|value| json!({"name": name, "value": value, "inline": true})))
+                .collect();
+            (0xc8_aa_6e, fields)
+        }
+        Event::StateChanged { .. } => (0x5f_7f_9f, Vec::new()),
+    };
+    json!({
+        "username": "poe-minimizer",
+        "embeds": [{
+            "title": event.title(),
+            "description": event.message(),
+            "color": color,
+            "fields": fields,
+            "timestamp": time.to_rfc3339(),
+        }]
+    })
+}
+
+/// Replaces `{{name}}` with the value escaped for use inside a json string, unknown names with nothing.
+fn fill_template(template: &str, event: &Event, time: DateTime<Local>) -> String {
+    let mut values = match serde_json::to_value(event) {
+        Ok(Value::Object(values)) => values,
+        _ => serde_json::Map::new(),
+    };
+    values.insert("title".to_owned(), Value::String(event.title()));
+    values.insert("message".to_owned(), Value::String(event.message()));
+    values.insert("time".to_owned(), Value::String(time.to_rfc3339()));
+    let mut result = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        result.push_str(&rest[..start]);
+        let end = match rest[start..].find("}}") {
+            Some(end) => start + end,
+            None => {
+                rest = &rest[start..];
+                break;
+            }
+        };
+        let value = match values.get(rest[start + 2..end].trim()) {
+            Some(Value::String(value)) => Value::String(value.clone()).to_string(),
+            Some(Value::Null) | None => "\"\"".to_owned(),
+            Some(value) => Value::String(value.to_string()).to_string(),
+        };
+        result.push_str(&value[1..value.len() - 1]);
+        rest = &rest[end + 2..];
+    }
+    result.push_str(rest);
+    result
+}
+
+/// A rendered request waiting in the outbox.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Delivery {
+    webhook: String,
+    /// the event name, the body can't be logged as it names players
+    #[serde(default)]
+    event: String,
+    url: String,
+    body: String,
+    created: DateTime<Local>,
+    attempts: u32,
+    next_attempt: DateTime<Local>,
+}
+
+/// Posts events to the configured webhooks from a background thread. Undelivered requests are kept in an outbox
+/// file and retried with backoff, also across restarts.
+pub struct Webhooks {
+    webhooks: Vec<Webhook>,
+    sender: Option<Sender<Delivery>>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl Webhooks {
+    pub fn start(settings: &[WebhookSettings], outbox_path: PathBuf) -> Result<Webhooks, Error> {
+        let webhooks = settings.iter().map(Webhook::new).collect::<Result<Vec<_>, _>>()?;
+        let mut outbox = Outbox::load(outbox_path)?;
+        let loaded = outbox.deliveries.len();
+        outbox.deliveries.retain(|delivery| {
+            let configured = webhooks.iter().any(|webhook| webhook.settings.url == delivery.url);
+            if !configured {
+                warn!("dropping a notification for webhook {} which is no longer configured", delivery.webhook);
+            }
+            configured
+        });
+        if outbox.deliveries.len() != loaded {
+            outbox.save();
+        }
+        let intervals = webhooks.iter().map(|webhook| (webhook.settings.url.clone(), webhook.interval())).collect();
+        let (sender, receiver) = mpsc::channel();
+        let worker = thread::Builder::new()
+            .name("webhooks".to_owned())
+            .spawn(move || Worker::new(outbox, intervals).run(receiver))?;
+        Ok(Webhooks { webhooks, sender: Some(sender), worker: Some(worker) })
+    }
+
+    /// Queues the event for every webhook subscribed to it.
+    pub fn send(&self, event: &Event, afk: bool) {
+        let now = Local::now();
+        for webhook in self.webhooks.iter().filter(|webhook| webhook.wants(event, afk)) {
+            let delivery = Delivery {
+                webhook: webhook.settings.name.clone(),
+                event: event.name().to_owned(),
+                url: webhook.settings.url.clone(),
+                body: webhook.render(event, now),
+                created: now,
+                attempts: 0,
+                next_attempt: now,
+            };
+            if let Some(sender) = self.sender.as_ref() {
+                let _ = sender.send(delivery);
+            }
+        }
+    }
+}
+
+impl Drop for Webhooks {
+    fn drop(&mut self) {
+        // the worker saves the outbox and exits once the channel is closed
+        self.sender.take();
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+struct Outbox {
+    path: PathBuf,
+    deliveries: Vec<Delivery>,
+}
+
+impl Outbox {
+    fn load(path: PathBuf) -> Result<Outbox, Error> {
+        let mut deliveries = Vec::new();
+        if path.is_file() {
+            for line in fs::read_to_string(&path)?.lines().filter(|line| !line.trim().is_empty()) {
+                serde_json::from_str(line)
+                    .map(|delivery| deliveries.push(delivery))
+                    .map_err(|e| Error::new(ErrorKind::InvalidData, e))
+                    .log_error_and_ignore("ignoring a broken line in the webhook outbox");
+            }
+        }
+        if !deliveries.is_empty() {
+            info!("{} webhook notifications are waiting to be delivered", deliveries.len());
+        }
+        Ok(Outbox { path, deliveries })
+    }
+
+    fn save(&self) {
+        let mut text = String::new();
+        for delivery in &self.deliveries {
+            if let Ok(line) = serde_json::to_string(delivery) {
+                text.push_str(&line);
+                text.push('\n');
+            }
+        }
+        fs::write(&self.path, text)
+            .log_error_and_ignore("failed to save the webhook outbox");
+    }
+}
+
+enum Outcome {
+    Delivered,
+    /// the webhook refused the request, retrying won't help
+    Rejected(String),
+    RetryAfter(Duration, String),
+}
+
+/// The background thread, it owns the outbox.
+struct Worker {
+    outbox: Outbox,
+    agent: ureq::Agent,
+    /// minimum time between two requests per url
+    intervals: HashMap<String, Duration>,
+    last_request: HashMap<String, Instant>,
+}
+
+impl Worker {
+    fn new(outbox: Outbox, intervals: HashMap<String, Duration>) -> Self {
+        let agent = ureq::AgentBuilder::new()
+            .timeout(Duration::from_secs(10))
+            .user_agent(concat!("poe-minimizer/", env!("CARGO_PKG_VERSION")))
+            .build();
+        Worker { outbox, agent, intervals, last_request: HashMap::new() }
+    }
+
+    fn run(mut self, receiver: Receiver<Delivery>) {
+        loop {
+            self.deliver_due();
+            match receiver.recv_timeout(self.time_until_next_delivery()) {
+                Ok(delivery) => {
+                    self.outbox.deliveries.push(delivery);
+                    self.outbox.save();
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+    }
+
+    /// Sends everything due, oldest first. A url whose request failed or hit the rate limit is skipped for the rest
+    /// of the round so its notifications stay in order.
+    fn deliver_due(&mut self) {
+        let mut blocked: Vec<String> = Vec::new();
+        let mut changed = false;
+        let mut index = 0;
+        while index < self.outbox.deliveries.len() {
+            let now = Local::now();
+            let delivery = &self.outbox.deliveries[index];
+            if blocked.contains(&delivery.url) || delivery.next_attempt > now || self.rate_limit_wait(&delivery.url) > Duration::from_secs(0) {
+                blocked.push(delivery.url.clone());
+                index += 1;
+                continue;
+            }
+            let outcome = self.post(delivery);
+            self.last_request.insert(delivery.url.clone(), Instant::now());
+            changed = true;
+            let delivery = &mut self.outbox.deliveries[index];
+            delivery.attempts += 1;
+            match outcome {
+                Outcome::Delivered => {
+                    debug!("delivered {} to webhook {}", delivery.event, delivery.webhook);
+                    self.outbox.deliveries.remove(index);
+                }
+                Outcome::Rejected(reason) => {
+                    error!("webhook {} rejected a notification, dropping it: {}", delivery.webhook, reason);
+                    self.outbox.deliveries.remove(index);
+                }
+                Outcome::RetryAfter(_, reason) if (now - delivery.created).num_seconds() > MAX_AGE_SECONDS => {
+                    error!("giving up on a notification for webhook {} after {} attempts: {}", delivery.webhook, delivery.attempts, reason);
+                    self.outbox.deliveries.remove(index);
+                }
+                Outcome::RetryAfter(delay, reason) => {
+                    warn!("failed to notify webhook {}, retrying in {}s: {}", delivery.webhook, delay.as_secs(), reason);
+                    delivery.next_attempt = now + chrono::Duration::from_std(delay).unwrap_or_else(|_| chrono::Duration::zero());
+                    blocked.push(delivery.url.clone());
+                    index += 1;
+                }
+            }
+        }
+        if changed {
+            self.outbox.save();
+        }
+    }
+
+    fn post(&self, delivery: &Delivery) -> Outcome {
+        let backoff = Duration::from_secs((FIRST_RETRY_SECONDS << delivery.attempts.min(16)).min(MAX_RETRY_SECONDS));
+        let result = self.agent.post(&delivery.url)
+            .set("Content-Type", "application/json")
+            .send_string(&delivery.body);
+        match result {
+            Ok(_) => Outcome::Delivered,
+            Err(ureq::Error::Status(429, response)) => {
+                // discord sends fractional seconds
+                let retry_after = response.header("Retry-After")
+                    .and_then(|seconds| seconds.parse::<f64>().ok())
+                    .map(|seconds| Duration::from_millis((seconds.max(0.0) * 1000.0) as u64))
+                    .unwrap_or(backoff);
+                Outcome::RetryAfter(retry_after, "rate limited".to_owned())
+            }
+            Err(ureq::Error::Status(code, _)) if code >= 500 || code == 408 => Outcome::RetryAfter(backoff, format!("status code {}", code)),
+            Err(ureq::Error::Status(code, response)) => {
+                let answer = response.into_string().unwrap_or_default();
+                Outcome::Rejected(format!("status code {} {}", code, answer.trim()))
+            }
+            Err(e) => {
+                metrics::BACKEND_ERRORS.increment();
+                Outcome::RetryAfter(backoff, e.to_string())
+            }
+        }
+    }
+
+    fn rate_limit_wait(&self, url: &str) -> Duration {
+        let interval = self.intervals.get(url).copied().unwrap_or_else(|| Duration::from_secs(0));
+        match self.last_request.get(url) {
+            Some(last) => interval.checked_sub(last.elapsed()).unwrap_or_else(|| Duration::from_secs(0)),
+            None => Duration::from_secs(0),
+        }
+    }
+
+    fn time_until_next_delivery(&self) -> Duration {
+        let now = Local::now();
+        let mut seen: Vec<&str> = Vec::new();
+        self.outbox.deliveries.iter()
+            // later notifications for the same url wait for the first one
+            .filter(|delivery| if seen.contains(&delivery.url.as_str()) { false } else { seen.push(&delivery.url); true })
+            .map(|delivery| {
+                let due = (delivery.next_attempt - now).to_std().unwrap_or_else(|_| Duration::from_secs(0));
+                due.max(self.rate_limit_wait(&delivery.url))
+            })
+            .min()
+            .unwrap_or(IDLE_TIMEOUT)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::utils::test_directory;
+
+    /// A request the stub server received.
+    struct Received {
+        body: String,
+        at: Instant,
+    }
+
+    /// An http server answering with the scripted status codes and headers, then with 204.
+    struct StubServer {
+        url: String,
+        script: Arc<Mutex<VecDeque<(u16, &'static str)>>>,
+        requests: Receiver<Received>,
+    }
+
+    impl StubServer {
+        fn start() -> StubServer {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let url = format!("http://{}/hook", listener.local_addr().unwrap());
+            let script: Arc<Mutex<VecDeque<(u16, &'static str)>>> = Arc::default();
+            let (sender, requests) = mpsc::channel();
+            let responses = script.clone();
+            thread::spawn(move || {
+                for stream in listener.incoming().filter_map(|stream| stream.ok()) {
+                    let mut reader = BufReader::new(stream);
+                    let mut content_length = 0;
+                    let mut line = String::new();
+                    while reader.read_line(&mut line).unwrap_or(0) > 0 && line.trim() != "" {
+                        if let Some(length) = line.to_ascii_lowercase().strip_prefix("content-length:") {
+                            content_length = length.trim().parse().unwrap();
+                        }
+                        line.clear();
+                    }
+                    let mut body = vec![0; content_length];
+                    reader.read_exact(&mut body).unwrap();
+                    let (status, headers) = responses.lock().unwrap().pop_front().unwrap_or((204, ""));
+                    let _ = sender.send(Received { body: String::from_utf8(body).unwrap(), at: Instant::now() });
+                    let response = format!("HTTP/1.1 {} Scripted\r\nContent-Length: 2\r\nConnection: close\r\n{}\r\nno", status, headers);
+                    let _ = reader.get_mut().write_all(response.as_bytes());
+                }
+            });
+            StubServer { url, script, requests }
+        }
+
+        fn answer(&self, status: u16, headers: &'static str) {
+            self.script.lock().unwrap().push_back((status, headers));
+        }
+
+        fn next_request(&self) -> Received {
+            self.requests.recv_timeout(Duration::from_secs(5)).expect("the webhook was not called")
+        }
+
+        fn settings(&self, max_per_minute: u32) -> WebhookSettings {
+            WebhookSettings {
+                name: "stub".to_owned(),
+                url: self.url.clone(),
+                format: "json".to_owned(),
+                template: None,
+                events: vec!["trade_request".to_owned(), "state_changed".to_owned()],
+                only_while_afk: false,
+                max_per_minute,
+            }
+        }
+    }
+
+    fn state_changed(to: State) -> Event {
+        Event::StateChanged { from: State::Watching, to }
+    }
+
+    fn delivery(server: &StubServer, body: &str) -> Delivery {
+        let now = Local::now();
+        Delivery { webhook: "stub".to_owned(), event: "state_changed".to_owned(), url: server.url.clone(), body: body.to_owned(), created: now, attempts: 0, next_attempt: now }
+    }
+
+    fn worker(name: &str, server: &StubServer) -> Worker {
+        let outbox = Outbox::load(test_directory(name).join("outbox.jsonl")).unwrap();
+        Worker::new(outbox, vec![(server.url.clone(), Duration::from_secs(0))].into_iter().collect())
+    }
+
+    /// Seconds until the first delivery in the outbox is due.
+    fn retry_in(worker: &Worker) -> f64 {
+        (worker.outbox.deliveries[0].next_attempt - Local::now()).num_milliseconds() as f64 / 1000.0
+    }
+
+    #[test]
+    fn failed_requests_are_retried_with_backoff() {
+        let server = StubServer::start();
+        let mut worker = worker("webhook-backoff", &server);
+        server.answer(503, "");
+        server.answer(500, "");
+        server.answer(404, "");
+        worker.outbox.deliveries.push(delivery(&server, "{}"));
+
+        worker.deliver_due();
+        assert_eq!(worker.outbox.deliveries[0].attempts, 1);
+        assert!((4.0..=5.0).contains(&retry_in(&worker)), "{}", retry_in(&worker));
+        // not due yet
+        worker.deliver_due();
+        assert_eq!(worker.outbox.deliveries[0].attempts, 1);
+
+        worker.outbox.deliveries[0].next_attempt = Local::now();
+        worker.deliver_due();
+        assert_eq!(worker.outbox.deliveries[0].attempts, 2);
+        assert!((9.0..=10.0).contains(&retry_in(&worker)), "{}", retry_in(&worker));
+
+        // other client errors won't go away by retrying
+        worker.outbox.deliveries[0].next_attempt = Local::now();
+        worker.deliver_due();
+        assert!(worker.outbox.deliveries.is_empty());
+        assert_eq!(server.requests.try_iter().count(), 3);
+    }
+
+    #[test]
+    fn retry_after_is_honored() {
+        let server = StubServer::start();
+        let mut worker = worker("webhook-retry-after", &server);
+        server.answer(429, "Retry-After: 1.5\r\n");
+        server.answer(429, "Retry-After: 120\r\n");
+        worker.outbox.deliveries.push(delivery(&server, "{}"));
+        worker.outbox.deliveries.push(delivery(&server, "{\"second\":true}"));
+
+        worker.deliver_due();
+        assert!((1.0..=1.5).contains(&retry_in(&worker)), "{}", retry_in(&worker));
+        // the second notification waits for the first one
+        assert_eq!(worker.outbox.deliveries[1].attempts, 0);
+        assert!(worker.time_until_next_delivery() <= Duration::from_millis(1500));
+
+        worker.outbox.deliveries[0].next_attempt = Local::now();
+        worker.deliver_due();
+        assert!((119.0..=120.0).contains(&retry_in(&worker)), "{}", retry_in(&worker));
+        assert_eq!(worker.outbox.deliveries.len(), 2);
+    }
+
+    #[test]
+    fn the_outbox_survives_a_restart() {
+        let server = StubServer::start();
+        let outbox_path = test_directory("webhook-outbox").join("outbox.jsonl");
+        server.answer(429, "Retry-After: 0.5\r\n");
+        let webhooks = Webhooks::start(&[server.settings(60)], outbox_path.clone()).unwrap();
+        webhooks.send(&state_changed(State::Minimized), true);
+        let first = server.next_request();
+        drop(webhooks);
+        let saved = fs::read_to_string(&outbox_path).unwrap();
+        assert_eq!(saved.lines().count(), 1);
+        assert!(saved.contains("\"attempts\":1"), "{}", saved);
+
+        let webhooks = Webhooks::start(&[server.settings(60)], outbox_path.clone()).unwrap();
+        let second = server.next_request();
+        assert_eq!(second.body, first.body);
+        assert!(second.at - first.at >= Duration::from_millis(500));
+        drop(webhooks);
+        assert_eq!(fs::read_to_string(&outbox_path).unwrap(), "");
+
+        // deliveries for webhooks that are no longer configured are dropped
+        fs::write(&outbox_path, saved).unwrap();
+        let mut other = server.settings(60);
+        other.url.push_str("/other");
+        drop(Webhooks::start(&[other], outbox_path.clone()).unwrap());
+        assert_eq!(fs::read_to_string(&outbox_path).unwrap(), "");
+        assert!(server.requests.try_recv().is_err());
+    }
+
+    #[test]
+    fn requests_stay_below_the_rate_limit() {
+        let server = StubServer::start();
+        let webhooks = Webhooks::start(&[server.settings(300)], test_directory("webhook-rate-limit").join("outbox.jsonl")).unwrap();
+        let states = [State::AfkPending, State::Minimized, State::Watching];
+        for state in &states {
+            webhooks.send(&state_changed(*state), false);
+        }
+        let requests: Vec<Received> = states.iter().map(|_| server.next_request()).collect();
+        for pair in requests.windows(2) {
+            assert!(pair[1].at - pair[0].at >= Duration::from_millis(190), "{:?}", pair[1].at - pair[0].at);
+        }
+        // in order
+        assert!(requests[0].body.contains("is about to be minimized"));
+        assert!(requests[2].body.contains("is played"));
+    }
+}
@@ -0,0 +1,269 @@
+use std::io::Error;
+use std::path::PathBuf;
+
+/// The Path of Exile game window, backed by win32 on windows and X11 (including XWayland) on linux.
+/// Lowering the priority of the game needs its process, which only the window leads to, so the
+/// service loop finds the game through this on both platforms.
+pub struct GameWindow {
+    inner: platform::Window,
+}
+
+impl GameWindow {
+    pub fn find(title: &str) -> Result<GameWindow, Error> {
+        platform::Window::find(title).map(|inner| GameWindow { inner })
+    }
+
+    pub fn is_minimized(&self) -> Result<bool, Error> {
+        self.inner.is_minimized()
+    }
+
+    pub fn minimize(&self) -> Result<(), Error> {
+        self.inner.minimize()
+    }
+
+    /// Shows the window again and brings it to the front.
+    pub fn restore(&self) -> Result<(), Error> {
+        self.inner.restore()
+    }
+
+    /// The process `priority::PriorityLowering` lowers while afk, from `_NET_WM_PID` on X11.
+    pub fn process_id(&self) -> Result<u32, Error> {
+        self.inner.process_id()
+    }
+
+    pub fn executable(&self) -> Result<PathBuf, Error> {
+        self.inner.executable()
+    }
+}
+
+#[cfg(all(windows, not(test)))]
+mod platform {
+    use std::io::Error;
+    use std::path::PathBuf;
+
+    use winapi::shared::windef::HWND;
+
+    use crate::winutils;
+
+    pub struct Window {
+        handle: HWND,
+    }
+
+    impl Window {
+        pub fn find(title: &str) -> Result<Window, Error> {
+            winutils::get_window_handle(title).map(|handle| Window { handle })
+        }
+
+        pub fn is_minimized(&self) -> Result<bool, Error> {
+            winutils::is_window_minimized(self.handle)
+        }
+
+        pub fn minimize(&self) -> Result<(), Error> {
+            winutils::minimize_window(self.handle)
+        }
+
+        pub fn restore(&self) -> Result<(), Error> {
+            winutils::restore_window(self.handle)
+        }
+
+        pub fn process_id(&self) -> Result<u32, Error> {
+            winutils::get_process_id(self.handle)
+        }
+
+        pub fn executable(&self) -> Result<PathBuf, Error> {
+            winutils::get_process_path_by_window_handle(self.handle)
+        }
+    }
+}
+
+#[cfg(all(target_os = "linux", not(test)))]
+mod platform {
+    use std::fs;
+    use std::io::{Error, ErrorKind};
+    use std::path::PathBuf;
+
+    use x11rb::connection::Connection;
+    use x11rb::protocol::xproto::{Atom, AtomEnum, ClientMessageEvent, ConnectionExt, EventMask};
+    use x11rb::rust_connection::RustConnection;
+
+    /// ICCCM state requested via WM_CHANGE_STATE to iconify a window.
+    const ICONIC_STATE: u32 = 3;
+    /// _NET_ACTIVE_WINDOW source indication for requests on behalf of the user, e.g. by pagers
+    const SOURCE_PAGER: u32 = 2;
+
+    pub struct Window {
+        connection: RustConnection,
+        root: u32,
+        window: u32,
+    }
+
+    impl Window {
+        pub fn find(title: &str) -> Result<Window, Error> {
+            let (connection, screen) = x11rb::connect(None).map_err(to_io_error)?;
+            let root = connection.setup().roots[screen].root;
+            let client_list = intern(&connection, "_NET_CLIENT_LIST")?;
+            let windows: Vec<u32> = connection.get_property(false, root, client_list, AtomEnum::WINDOW, 0, u32::MAX)
+                .map_err(to_io_error)?
+                .reply().map_err(to_io_error)?
+                .value32().map(|windows| windows.collect())
+                .unwrap_or_default();
+            for window in windows {
+                if window_title(&connection, window)?.as_deref() == Some(title) {
+                    return Ok(Window { connection, root, window });
+                }
+            }
+            Err(Error::new(ErrorKind::NotFound, format!("no window titled '{}'", title)))
+        }
+
+        pub fn is_minimized(&self) -> Result<bool, Error> {
+            let state = intern(&self.connection, "_NET_WM_STATE")?;
+            let hidden = intern(&self.connection, "_NET_WM_STATE_HIDDEN")?;
+            // fails with BadWindow once the game has been closed
+            let reply = self.connection.get_property(false, self.window, state, AtomEnum::ATOM, 0, 64)
+                .map_err(to_io_error)?
+                .reply().map_err(to_io_error)?;
+            Ok(reply.value32().map(|mut atoms| atoms.any(|atom| atom == hidden)).unwrap_or(false))
+        }
+
+        pub fn minimize(&self) -> Result<(), Error> {
+            let change_state = intern(&self.connection, "WM_CHANGE_STATE")?;
+            let event = ClientMessageEvent::new(32, self.window, change_state, [ICONIC_STATE, 0, 0, 0, 0]);
+            self.connection.send_event(false, self.root, EventMask::SUBSTRUCTURE_REDIRECT | EventMask::SUBSTRUCTURE_NOTIFY, event)
+                .map_err(to_io_error)?;
+            self.connection.flush().map_err(to_io_error)
+        }
+
+        pub fn restore(&self) -> Result<(), Error> {
+            let active_window = intern(&self.connection, "_NET_ACTIVE_WINDOW")?;
+            let event = ClientMessageEvent::new(32, self.window, active_window, [SOURCE_PAGER, 0, 0, 0, 0]);
+            self.connection.send_event(false, self.root, EventMask::SUBSTRUCTURE_REDIRECT | EventMask::SUBSTRUCTURE_NOTIFY, event)
+                .map_err(to_io_error)?;
+            self.connection.flush().map_err(to_io_error)
+        }
+
+        pub fn process_id(&self) -> Result<u32, Error> {
+            let pid = intern(&self.connection, "_NET_WM_PID")?;
+            self.connection.get_property(false, self.window, pid, AtomEnum::CARDINAL, 0, 1)
+                .map_err(to_io_error)?
+                .reply().map_err(to_io_error)?
+                .value32().and_then(|mut values| values.next())
+                .ok_or_else(|| Error::new(ErrorKind::NotFound, "the game window has no _NET_WM_PID"))
+        }
+
+        /// Wine runs the game through its preloader, so /proc/<pid>/exe is of no use. The
+        /// command line holds the windows path of the game instead, which only maps to a
+        /// linux path on wine's `Z:` drive.
+        pub fn executable(&self) -> Result<PathBuf, Error> {
+            let command_line = fs::read(format!("/proc/{}/cmdline", self.process_id()?))?;
+            let executable = command_line.split(|byte| *byte == 0).next().unwrap_or(&[]);
+            let executable = String::from_utf8_lossy(executable);
+            match executable.strip_prefix("Z:").or_else(|| executable.strip_prefix("z:")) {
+                Some(path) => Ok(PathBuf::from(path.replace('\\', "/"))),
+                None => Err(Error::other(format!("unable to map {} to a linux path", executable))),
+            }
+        }
+    }
+
+    fn window_title(connection: &RustConnection, window: u32) -> Result<Option<String>, Error> {
+        let net_wm_name = intern(connection, "_NET_WM_NAME")?;
+        let utf8_string = intern(connection, "UTF8_STRING")?;
+        let mut reply = connection.get_property(false, window, net_wm_name, utf8_string, 0, 256)
+            .map_err(to_io_error)?
+            .reply().map_err(to_io_error)?;
+        if reply.value.is_empty() {
+            reply = connection.get_property(false, window, AtomEnum::WM_NAME, AtomEnum::STRING, 0, 256)
+                .map_err(to_io_error)?
+                .reply().map_err(to_io_error)?;
+        }
+        if reply.value.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(String::from_utf8_lossy(&reply.value).into_owned()))
+        }
+    }
+
+    fn intern(connection: &RustConnection, name: &str) -> Result<Atom, Error> {
+        connection.intern_atom(false, name.as_bytes())
+            .map_err(to_io_error)?
+            .reply()
+            .map(|reply| reply.atom)
+            .map_err(to_io_error)
+    }
+
+    fn to_io_error<E: std::fmt::Display>(error: E) -> Error {
+        Error::other(format!("X11: {}", error))
+    }
+}
+
+#[cfg(test)]
+use fake as platform;
+
+/// Windows opened and closed by the tests in place of a window system.
+#[cfg(test)]
+pub mod fake {
+    use std::collections::HashMap;
+    use std::io::{Error, ErrorKind};
+    use std::path::PathBuf;
+    use std::sync::Mutex;
+
+    static WINDOWS: Mutex<Option<HashMap<String, FakeWindow>>> = Mutex::new(None);
+
+    #[derive(Clone)]
+    struct FakeWindow {
+        process_id: u32,
+        executable: PathBuf,
+        minimized: bool,
+    }
+
+    /// Opens a window titled `title` of a game installed at `executable`.
+    pub fn open(title: &str, process_id: u32, executable: PathBuf) {
+        with_windows(|windows| windows.insert(title.to_owned(), FakeWindow { process_id, executable, minimized: false }));
+    }
+
+    pub fn close(title: &str) {
+        with_windows(|windows| windows.remove(title));
+    }
+
+    fn with_windows<T, F: FnOnce(&mut HashMap<String, FakeWindow>) -> T>(f: F) -> T {
+        let mut windows = WINDOWS.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        f(windows.get_or_insert_with(HashMap::new))
+    }
+
+    pub struct Window {
+        title: String,
+    }
+
+    impl Window {
+        pub fn find(title: &str) -> Result<Window, Error> {
+            with_windows(|windows| windows.contains_key(title))
+                .then(|| Window { title: title.to_owned() })
+                .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("no window titled '{}'", title)))
+        }
+
+        pub fn is_minimized(&self) -> Result<bool, Error> {
+            self.get(|window| window.minimized)
+        }
+
+        pub fn minimize(&self) -> Result<(), Error> {
+            self.get(|window| window.minimized = true)
+        }
+
+        pub fn restore(&self) -> Result<(), Error> {
+            self.get(|window| window.minimized = false)
+        }
+
+        pub fn process_id(&self) -> Result<u32, Error> {
+            self.get(|window| window.process_id)
+        }
+
+        pub fn executable(&self) -> Result<PathBuf, Error> {
+            self.get(|window| window.executable.clone())
+        }
+
+        /// Fails like the real window systems once the window has been closed.
+        fn get<T, F: FnOnce(&mut FakeWindow) -> T>(&self, f: F) -> Result<T, Error> {
+            with_windows(|windows| windows.get_mut(&self.title).map(f))
+                .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("the window '{}' was closed", self.title)))
+        }
+    }
+}
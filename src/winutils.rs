@@ -1,13 +1,18 @@
-use std::io::Error;
+use std::io::{Error, ErrorKind};
 use std::ptr::null_mut;
-use std::path::PathBuf;
-use winapi::um::winreg::{HKEY_CURRENT_USER, RegQueryValueExA};
+use std::path::{Path, PathBuf};
+use winapi::um::winreg::HKEY_CURRENT_USER;
 use winapi::shared::winerror::{ERROR_SUCCESS, ERROR_FILE_NOT_FOUND};
 use std::ffi::CString;
+use widestring::U16CString;
+
+/// where programs started on login are registered, `AUTOSTART_VALUE` holds our command line
+pub const AUTOSTART_KEY: &str = "Software\\Microsoft\\Windows\\CurrentVersion\\Run";
+pub const AUTOSTART_VALUE: &str = "poe-minimizer";
 
 pub fn get_window_handle(title: &str) -> Result<winapi::shared::windef::HWND, Error> {
     let window_handle = unsafe {
-        let window_title = CString::new(title).expect("CString::new failed");
+        let window_title = CString::new(title).map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
         winapi::um::winuser::FindWindowA(null_mut(), window_title.as_ptr())
     };
     if window_handle == null_mut() {
@@ -35,6 +40,17 @@ pub fn minimize_window(window_handle: winapi::shared::windef::HWND) -> Result<()
     }
 }
 
+pub fn restore_window(window_handle: winapi::shared::windef::HWND) -> Result<(), Error> {
+    unsafe {
+        // returns whether the window was visible before, not whether it worked
+        winapi::um::winuser::ShowWindow(window_handle, winapi::um::winuser::SW_RESTORE);
+        if winapi::um::winuser::SetForegroundWindow(window_handle) == 0 {
+            return Err(Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
 pub fn is_window_minimized(window_handle: winapi::shared::windef::HWND) -> Result<bool, Error> {
     let style = unsafe {
         winapi::um::winuser::GetWindowLongA(window_handle, winapi::um::winuser::GWL_STYLE)
@@ -45,7 +61,7 @@ pub fn is_window_minimized(window_handle: winapi::shared::windef::HWND) -> Resul
     Ok(winapi::um::winuser::WS_MINIMIZE as i32 & style != 0)
 }
 
-fn get_process_id(window_handle: winapi::shared::windef::HWND) -> Result<u32, Error> {
+pub fn get_process_id(window_handle: winapi::shared::windef::HWND) -> Result<u32, Error> {
     let mut process_id: u32 = 0;
     unsafe {
         winapi::um::winuser::GetWindowThreadProcessId(window_handle, &mut process_id);
@@ -113,43 +129,84 @@ fn get_process_file_name(process_handle: winapi::um::winnt::HANDLE, module_handl
     Ok(PathBuf::from(String::from_utf16_lossy(&exe_buf[..pos])))
 }
 
-pub fn is_in_autostart() -> Result<bool, Error> {
-    unsafe {
+pub fn add_to_autostart(executable: &Path) -> Result<(), Error> {
+    let executable = executable.to_str()
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "the path of the executable is not valid unicode"))?;
+    let value = CString::new(format!("\"{}\"", executable))
+        .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+    let value = value.as_bytes_with_nul();
+    let result = unsafe {
         let key = get_autostart_hkey()?;
-        let result = RegQueryValueExA(key, CString::new("poe-minimizer").unwrap().as_ptr(), null_mut(), &mut winapi::um::winnt::REG_SZ, null_mut(), null_mut());
-        match result as u32 {
-            ERROR_SUCCESS => Ok(true),
-            ERROR_FILE_NOT_FOUND => Ok(false),
-            _ => Err(Error::last_os_error())
-        }
+        winapi::um::winreg::RegSetValueExA(key, CString::new(AUTOSTART_VALUE).unwrap().as_ptr(), 0, winapi::um::winnt::REG_SZ, value.as_ptr(), value.len() as u32)
+    };
+    match result as u32 {
+        ERROR_SUCCESS => Ok(()),
+        _ => Err(Error::from_raw_os_error(result))
     }
 }
 
-pub fn add_to_autostart() -> Result<(), Error> {
-    unsafe {
+pub fn remove_from_autostart() -> Result<(), Error> {
+    let result = unsafe {
         let key = get_autostart_hkey()?;
-        let current_path = std::env::current_exe()?;
-        let current_path = current_path.to_str().unwrap().clone();
+        winapi::um::winreg::RegDeleteValueA(key, CString::new(AUTOSTART_VALUE).unwrap().as_ptr())
+    };
+    match result as u32 {
+        ERROR_SUCCESS | ERROR_FILE_NOT_FOUND => Ok(()),
+        _ => Err(Error::from_raw_os_error(result))
+    }
+}
 
-        let value = CString::new(format!("\"{}\"", current_path)).unwrap();
-        let value = value.as_bytes_with_nul();
-        winapi::um::winreg::RegSetValueExA(key, CString::new("poe-minimizer").unwrap().as_ptr(), 0, winapi::um::winnt::REG_SZ, value.as_ptr(), value.len() as u32);
+pub fn read_registry_string(sub_key: &str, value_name: &str) -> Result<Option<String>, Error> {
+    const BUFFER_LENGTH: usize = winapi::shared::minwindef::MAX_PATH + 1;
+    let mut buffer = [0u16; BUFFER_LENGTH];
+    let mut buffer_size = (BUFFER_LENGTH * 2) as u32;
+    let sub_key = U16CString::from_str(sub_key).unwrap();
+    let value_name = U16CString::from_str(value_name).unwrap();
+    let result = unsafe {
+        winapi::um::winreg::RegGetValueW(HKEY_CURRENT_USER,
+                                         sub_key.as_ptr(),
+                                         value_name.as_ptr(),
+                                         winapi::um::winreg::RRF_RT_REG_SZ,
+                                         null_mut(),
+                                         buffer.as_mut_ptr() as *mut winapi::ctypes::c_void,
+                                         &mut buffer_size
+        )
+    };
+    match result as u32 {
+        ERROR_SUCCESS => {
+            let length = buffer.iter().position(|x| *x == 0).unwrap_or(BUFFER_LENGTH);
+            Ok(Some(String::from_utf16_lossy(&buffer[..length])))
+        },
+        ERROR_FILE_NOT_FOUND => Ok(None),
+        _ => Err(Error::from_raw_os_error(result))
+    }
+}
+
+pub fn shell_open(path: &std::path::Path) -> Result<(), Error> {
+    let operation = U16CString::from_str("open").unwrap();
+    let file = U16CString::from_os_str(path.as_os_str())
+        .map_err(|_| Error::new(std::io::ErrorKind::InvalidInput, "path contains a nul character"))?;
+    let result = unsafe {
+        winapi::um::shellapi::ShellExecuteW(null_mut(), operation.as_ptr(), file.as_ptr(), null_mut(), null_mut(), winapi::um::winuser::SW_SHOWNORMAL)
+    };
+    // values up to 32 are error codes
+    if result as usize <= 32 {
+        return Err(Error::last_os_error());
     }
     Ok(())
 }
 
-pub fn remove_from_autostart() -> Result<(), Error> {
+/// Lets command line subcommands print to the console they were started from, we are a gui subsystem executable.
+pub fn attach_parent_console() {
     unsafe {
-        let key = get_autostart_hkey()?;
-        winapi::um::winreg::RegDeleteValueA(key, CString::new("poe-minimizer").unwrap().as_ptr());
+        winapi::um::wincon::AttachConsole(winapi::um::wincon::ATTACH_PARENT_PROCESS);
     }
-    Ok(())
 }
 
 fn get_autostart_hkey() -> Result<winapi::shared::minwindef::HKEY, Error> {
     let mut key: winapi::shared::minwindef::HKEY = null_mut();
     unsafe {
-        let result = winapi::um::winreg::RegCreateKeyA(HKEY_CURRENT_USER, CString::new("Software\\Microsoft\\Windows\\CurrentVersion\\Run").unwrap().as_ptr(), &mut key);
+        let result = winapi::um::winreg::RegCreateKeyA(HKEY_CURRENT_USER, CString::new(AUTOSTART_KEY).unwrap().as_ptr(), &mut key);
         if result != ERROR_SUCCESS as i32 {
             Err(Error::last_os_error())
         } else {